@@ -282,6 +282,18 @@ impl main::types::Host for State {
         input: String,
         max_tokens: Option<u32>,
         stop_on: Option<String>,
+    ) -> wasmtime::Result<String> {
+        self.resources
+            .impl_infer(self_, input, max_tokens, stop_on.into_iter().collect())
+            .await
+    }
+
+    async fn infer_with_stop_sequences(
+        &mut self,
+        self_: TextGenerationModelResource,
+        input: String,
+        max_tokens: Option<u32>,
+        stop_on: Vec<String>,
     ) -> wasmtime::Result<String> {
         self.resources
             .impl_infer(self_, input, max_tokens, stop_on)
@@ -299,6 +311,28 @@ impl main::types::Host for State {
             .await
     }
 
+    async fn infer_grammar(
+        &mut self,
+        self_: TextGenerationModelResource,
+        input: String,
+        grammar: String,
+    ) -> wasmtime::Result<String> {
+        self.resources
+            .impl_infer_grammar(self_, input, grammar)
+            .await
+    }
+
+    async fn infer_with_parser(
+        &mut self,
+        self_: TextGenerationModelResource,
+        input: String,
+        parser: main::types::ParserDescription,
+    ) -> wasmtime::Result<String> {
+        self.resources
+            .impl_infer_with_parser(self_, input, parser)
+            .await
+    }
+
     async fn create_embedding_model(
         &mut self,
         ty: main::types::EmbeddingModelType,
@@ -253,7 +253,7 @@ impl ResourceStorage {
         self_: TextGenerationModelResource,
         input: String,
         max_tokens: Option<u32>,
-        stop_on: Option<String>,
+        stop_on: Vec<String>,
     ) -> wasmtime::Result<String> {
         let index = self_.into();
         let model = self.initialize_model(index).await?;
@@ -261,13 +261,15 @@ impl ResourceStorage {
             ConcreteTextGenerationModel::Llama(model) => Ok(model
                 .generate_text(&input)
                 .with_max_length(max_tokens.unwrap_or(u32::MAX))
-                .with_stop_on(stop_on)
-                .await?),
+                .with_stop_sequences(stop_on)
+                .await?
+                .into_text()),
             ConcreteTextGenerationModel::Phi(model) => Ok(model
                 .generate_text(&input)
                 .with_max_length(max_tokens.unwrap_or(u32::MAX))
-                .with_stop_on(stop_on)
-                .await?),
+                .with_stop_sequences(stop_on)
+                .await?
+                .into_text()),
         }
     }
 
@@ -279,6 +281,43 @@ impl ResourceStorage {
     ) -> wasmtime::Result<String> {
         let structure = RegexParser::new(&regex)?;
 
+        self.infer_with_parser(self_, input, structure).await
+    }
+
+    pub(crate) async fn impl_infer_grammar(
+        &self,
+        self_: TextGenerationModelResource,
+        input: String,
+        grammar: String,
+    ) -> wasmtime::Result<String> {
+        let structure = GrammarParser::new(&grammar)?;
+
+        self.infer_with_parser(self_, input, structure).await
+    }
+
+    pub(crate) async fn impl_infer_with_parser(
+        &self,
+        self_: TextGenerationModelResource,
+        input: String,
+        parser: main::types::ParserDescription,
+    ) -> wasmtime::Result<String> {
+        let description = parser_description(&parser.nodes, parser.root, &mut Vec::new(), &mut 0)?;
+        let structure = description.build()?;
+
+        self.infer_with_parser(self_, input, structure).await
+    }
+
+    async fn infer_with_parser<P>(
+        &self,
+        self_: TextGenerationModelResource,
+        input: String,
+        structure: P,
+    ) -> wasmtime::Result<String>
+    where
+        P: CreateParserState + Parser + Send + 'static,
+        P::PartialState: Send + 'static,
+        P::Output: Clone + Send + 'static,
+    {
         let index = self_.into();
 
         let model = self.initialize_model(index).await?;
@@ -305,3 +344,84 @@ impl ResourceStorage {
         Ok(())
     }
 }
+
+/// The maximum number of nodes in the [`ParserDescription`] built from the parser nodes a plugin sends.
+const MAX_PARSER_DESCRIPTION_NODES: usize = 10_000;
+
+/// Convert the flattened parser nodes a plugin sends into a [`ParserDescription`].
+fn parser_description(
+    nodes: &[main::types::ParserNode],
+    index: u32,
+    visiting: &mut Vec<u32>,
+    expanded: &mut usize,
+) -> anyhow::Result<ParserDescription> {
+    use main::types::ParserNode;
+
+    // Nodes reference each other by index, so a plugin could send a cycle
+    if visiting.contains(&index) {
+        anyhow::bail!("Parser node {index} contains itself");
+    }
+    // The description is a tree, so a node referenced from several places is copied for each reference. Without a limit, a small list of nodes could expand into an exponentially large tree
+    *expanded += 1;
+    if *expanded > MAX_PARSER_DESCRIPTION_NODES {
+        anyhow::bail!(
+            "The parser expands to more than {MAX_PARSER_DESCRIPTION_NODES} nodes. Reduce how often nodes are shared"
+        );
+    }
+    let node = nodes
+        .get(index as usize)
+        .ok_or_else(|| anyhow::anyhow!("Parser node {index} does not exist"))?;
+    let max = |max: Option<u32>| max.map_or(usize::MAX, |max| max as usize);
+
+    visiting.push(index);
+    let mut child = |index: u32| parser_description(nodes, index, visiting, expanded).map(Box::new);
+    let description = match node {
+        ParserNode::Literal(value) => ParserDescription::Literal {
+            value: value.clone(),
+        },
+        ParserNode::Or(options) => ParserDescription::Or {
+            options: options
+                .iter()
+                .map(|index| child(*index).map(|option| *option))
+                .collect::<anyhow::Result<_>>()?,
+        },
+        ParserNode::Then(parsers) => ParserDescription::Then {
+            parsers: parsers
+                .iter()
+                .map(|index| child(*index).map(|parser| *parser))
+                .collect::<anyhow::Result<_>>()?,
+        },
+        ParserNode::Repeat(repeat) => ParserDescription::Repeat {
+            parser: child(repeat.parser)?,
+            min: repeat.min as usize,
+            max: max(repeat.max),
+        },
+        ParserNode::Separated(separated) => ParserDescription::Separated {
+            parser: child(separated.parser)?,
+            separator: child(separated.separator)?,
+            min: separated.min as usize,
+            max: max(separated.max),
+        },
+        ParserNode::Integer(range) => ParserDescription::Integer {
+            min: range.min,
+            max: range.max,
+        },
+        ParserNode::Float(range) => ParserDescription::Float {
+            min: range.min,
+            max: range.max,
+        },
+        ParserNode::JsonString(range) => ParserDescription::String {
+            min_length: range.min as usize,
+            max_length: max(range.max),
+        },
+        ParserNode::Regex(pattern) => ParserDescription::Regex {
+            pattern: pattern.clone(),
+        },
+        ParserNode::Grammar(grammar) => ParserDescription::Grammar {
+            grammar: grammar.clone(),
+        },
+    };
+    visiting.pop();
+
+    Ok(description)
+}
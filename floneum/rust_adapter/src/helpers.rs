@@ -137,9 +137,26 @@ impl TextGenerationModel {
         infer(self.model, input, max_tokens, stop_on)
     }
 
+    pub fn infer_with_stop_sequences(
+        &self,
+        input: &str,
+        max_tokens: Option<u32>,
+        stop_on: &[String],
+    ) -> String {
+        infer_with_stop_sequences(self.model, input, max_tokens, stop_on)
+    }
+
     pub fn infer_structured(&self, input: &str, regex: &str) -> String {
         infer_structured(self.model, input, regex)
     }
+
+    pub fn infer_grammar(&self, input: &str, grammar: &str) -> String {
+        infer_grammar(self.model, input, grammar)
+    }
+
+    pub fn infer_with_parser(&self, input: &str, parser: &ParserDescription) -> String {
+        infer_with_parser(self.model, input, parser)
+    }
 }
 
 impl Drop for TextGenerationModel {
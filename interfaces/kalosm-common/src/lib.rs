@@ -4,6 +4,8 @@ use candle_core::{utils::*, Device};
 
 mod cache;
 pub use cache::*;
+mod lora;
+pub use lora::*;
 
 /// Create a candle device that uses any available accelerator.
 pub fn accelerated_device_if_available() -> candle_core::Result<Device> {
@@ -0,0 +1,294 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Context;
+use candle_core::{
+    quantized::{gguf_file, QMatMul},
+    DType, Device, Module, Tensor,
+};
+
+/// The low rank matrices a LoRA adapter adds to one weight. The adapter adds `scale * (x A^T) B^T` to the output of the layer.
+#[derive(Debug, Clone)]
+pub struct LoraWeight {
+    /// The down projection with the shape `(rank, in_features)`
+    pub a: Tensor,
+    /// The up projection with the shape `(out_features, rank)`
+    pub b: Tensor,
+    /// The factor the output of the adapter is multiplied by (`alpha / rank` unless the weight was scaled further)
+    pub scale: f32,
+}
+
+impl LoraWeight {
+    /// The rank of the adapter
+    pub fn rank(&self) -> usize {
+        self.a.dims().first().copied().unwrap_or_default()
+    }
+
+    /// Get a copy of the weight with the scale multiplied by `scale`
+    pub fn scaled(&self, scale: f32) -> Self {
+        Self {
+            scale: self.scale * scale,
+            ..self.clone()
+        }
+    }
+}
+
+/// A LoRA adapter loaded from a [PEFT](https://github.com/huggingface/peft) safetensors file or a llama.cpp GGUF adapter.
+///
+/// Weights are looked up by the name of the weight they adapt without the `.weight` suffix. PEFT adapters use the names of the transformers model (like `model.layers.0.self_attn.q_proj`) and GGUF adapters use the names of the GGUF model (like `blk.0.attn_q`).
+#[derive(Debug, Clone, Default)]
+pub struct LoraAdapter {
+    weights: HashMap<String, LoraWeight>,
+}
+
+impl LoraAdapter {
+    /// Create an adapter from a map of layer names to weights
+    pub fn new(weights: HashMap<String, LoraWeight>) -> Self {
+        Self { weights }
+    }
+
+    /// Load an adapter from a `.safetensors` or `.gguf` file onto a device.
+    ///
+    /// The `lora_alpha` of a safetensors adapter is read from the `adapter_config.json` next to the file if it exists. If the alpha is missing, it defaults to the rank of the adapter.
+    pub fn load(path: impl AsRef<Path>, device: &Device) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let adapter = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gguf") => Self::load_gguf(path, device),
+            _ => Self::load_safetensors(path, device),
+        }
+        .with_context(|| format!("Failed to load LoRA adapter from {}", path.display()))?;
+        if adapter.weights.is_empty() {
+            anyhow::bail!("{} does not contain any LoRA weights", path.display());
+        }
+        Ok(adapter)
+    }
+
+    fn load_safetensors(path: &Path, device: &Device) -> anyhow::Result<Self> {
+        let tensors = candle_core::safetensors::load(path, device)?;
+        let alpha = match std::fs::read_to_string(path.with_file_name("adapter_config.json")) {
+            Ok(config) => serde_json::from_str::<serde_json::Value>(&config)?
+                .get("lora_alpha")
+                .and_then(|alpha| alpha.as_f64())
+                .map(|alpha| alpha as f32),
+            Err(_) => None,
+        };
+
+        let matrices = tensors.into_iter().filter_map(|(name, tensor)| {
+            let name = name.strip_prefix("base_model.model.").unwrap_or(&name);
+            let (layer, matrix) = name.strip_suffix(".weight")?.rsplit_once(".lora_")?;
+            let matrix = match matrix {
+                "A" => LoraMatrix::A,
+                "B" => LoraMatrix::B,
+                _ => return None,
+            };
+            Some((layer.to_string(), matrix, tensor))
+        });
+        Self::from_matrices(matrices, alpha)
+    }
+
+    fn load_gguf(path: &Path, device: &Device) -> anyhow::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        let alpha = content
+            .metadata
+            .get("adapter.lora.alpha")
+            .and_then(|alpha| alpha.to_f32().ok());
+
+        let mut matrices = Vec::new();
+        for name in content.tensor_infos.keys() {
+            let (layer, matrix) = if let Some(layer) = name.strip_suffix(".weight.lora_a") {
+                (layer, LoraMatrix::A)
+            } else if let Some(layer) = name.strip_suffix(".weight.lora_b") {
+                (layer, LoraMatrix::B)
+            } else {
+                continue;
+            };
+            let tensor = content
+                .tensor(&mut file, name, device)?
+                .dequantize(device)?;
+            matrices.push((layer.to_string(), matrix, tensor));
+        }
+        Self::from_matrices(matrices, alpha)
+    }
+
+    fn from_matrices(
+        matrices: impl IntoIterator<Item = (String, LoraMatrix, Tensor)>,
+        alpha: Option<f32>,
+    ) -> anyhow::Result<Self> {
+        let mut halves: HashMap<String, (Option<Tensor>, Option<Tensor>)> = HashMap::new();
+        for (layer, matrix, tensor) in matrices {
+            let tensor = tensor.to_dtype(DType::F32)?;
+            let entry = halves.entry(layer).or_default();
+            match matrix {
+                LoraMatrix::A => entry.0 = Some(tensor),
+                LoraMatrix::B => entry.1 = Some(tensor),
+            }
+        }
+
+        let mut weights = HashMap::new();
+        for (layer, halves) in halves {
+            let (Some(a), Some(b)) = halves else {
+                anyhow::bail!("LoRA weight for {layer} is missing one of the A and B matrices");
+            };
+            let (rank, _) = a.dims2()?;
+            let (_, b_rank) = b.dims2()?;
+            if rank != b_rank {
+                anyhow::bail!(
+                    "LoRA weight for {layer} has an A matrix with rank {rank} and a B matrix with rank {b_rank}"
+                );
+            }
+            let alpha = alpha.unwrap_or(rank as f32);
+            let weight = LoraWeight {
+                a,
+                b,
+                scale: alpha / rank as f32,
+            };
+            weights.insert(layer, weight);
+        }
+        Ok(Self { weights })
+    }
+
+    /// Get the weight that adapts the layer with the given name
+    pub fn weight(&self, name: &str) -> Option<&LoraWeight> {
+        self.weights.get(name)
+    }
+
+    /// Get all of the weights in the adapter by the name of the layer they adapt
+    pub fn weights(&self) -> impl Iterator<Item = (&str, &LoraWeight)> {
+        self.weights
+            .iter()
+            .map(|(name, weight)| (name.as_str(), weight))
+    }
+}
+
+enum LoraMatrix {
+    A,
+    B,
+}
+
+/// A linear layer with LoRA adapters added on top of the output of the base layer.
+///
+/// The base layer is never modified, so quantized weights stay quantized and the adapters can be swapped at any time.
+#[derive(Debug, Clone)]
+pub struct LoraLinear<M = QMatMul> {
+    base: M,
+    name: String,
+    adapters: Vec<LoraWeight>,
+}
+
+impl<M> LoraLinear<M> {
+    /// Wrap a layer with the name adapters use to refer to it
+    pub fn new(base: M, name: impl Into<String>) -> Self {
+        Self {
+            base,
+            name: name.into(),
+            adapters: Vec::new(),
+        }
+    }
+
+    /// The name adapters use to refer to this layer
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The layer the adapters are added to
+    pub fn base(&self) -> &M {
+        &self.base
+    }
+
+    /// Replace the active adapter weights of the layer
+    pub fn set_adapter_weights(&mut self, weights: Vec<LoraWeight>) {
+        self.adapters = weights;
+    }
+
+    /// Replace the active adapters with the weights for this layer from each adapter multiplied by the scale paired with it. Adapters without a weight for this layer are skipped.
+    pub fn set_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) {
+        self.adapters = adapters
+            .iter()
+            .filter_map(|(adapter, scale)| Some(adapter.weight(&self.name)?.scaled(*scale)))
+            .collect();
+    }
+}
+
+impl<M: Module> Module for LoraLinear<M> {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let mut ys = self.base.forward(xs)?;
+        for adapter in &self.adapters {
+            let low_rank = xs
+                .to_dtype(adapter.a.dtype())?
+                .broadcast_matmul(&adapter.a.t()?)?
+                .broadcast_matmul(&adapter.b.t()?)?;
+            let low_rank = (low_rank * adapter.scale as f64)?.to_dtype(ys.dtype())?;
+            ys = (ys + low_rank)?;
+        }
+        Ok(ys)
+    }
+}
+
+#[test]
+fn lora_linear_adds_low_rank_update() -> anyhow::Result<()> {
+    use candle_core::quantized::QTensor;
+
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join(format!("kalosm-lora-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+
+    let base = Tensor::randn(0f32, 1., (6, 4), &device)?;
+    let a = Tensor::randn(0f32, 1., (2, 4), &device)?;
+    let b = Tensor::randn(0f32, 1., (6, 2), &device)?;
+    let adapter_path = dir.join("adapter_model.safetensors");
+    candle_core::safetensors::save(
+        &HashMap::from([
+            (
+                "base_model.model.model.layers.0.mlp.up_proj.lora_A.weight".to_string(),
+                a.clone(),
+            ),
+            (
+                "base_model.model.model.layers.0.mlp.up_proj.lora_B.weight".to_string(),
+                b.clone(),
+            ),
+        ]),
+        &adapter_path,
+    )?;
+    std::fs::write(
+        dir.join("adapter_config.json"),
+        r#"{"lora_alpha": 8, "r": 2}"#,
+    )?;
+
+    let adapter = Arc::new(LoraAdapter::load(&adapter_path, &device)?);
+    std::fs::remove_dir_all(&dir)?;
+    let weight = adapter
+        .weight("model.layers.0.mlp.up_proj")
+        .context("missing weight")?;
+    assert_eq!(weight.rank(), 2);
+    assert_eq!(weight.scale, 4.);
+
+    let mut layer = LoraLinear::new(
+        QMatMul::from_qtensor(QTensor::quantize(
+            &base,
+            candle_core::quantized::GgmlDType::F32,
+        )?)?,
+        "model.layers.0.mlp.up_proj",
+    );
+    let xs = Tensor::randn(0f32, 1., (1, 3, 4), &device)?;
+    let base_output = layer.forward(&xs)?;
+
+    layer.set_adapters(&[(adapter, 0.5)]);
+    let merged = (&base + (b.matmul(&a)? * 2.)?)?;
+    let expected = xs.broadcast_matmul(&merged.t()?)?;
+    let error = (layer.forward(&xs)? - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(error < 1e-4, "error {error} is too large");
+
+    layer.set_adapter_weights(Vec::new());
+    let error = (layer.forward(&xs)? - base_output)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert_eq!(error, 0.);
+
+    Ok(())
+}
@@ -7,6 +7,7 @@ use std::{
 };
 
 use anyhow::Result;
+use futures_util::StreamExt;
 use kalosm_language_model::ChatMarkers;
 use kalosm_language_model::Session;
 use kalosm_language_model::{GenerationParameters, Model, ModelExt, SyncModel, SyncModelExt};
@@ -15,6 +16,10 @@ use kalosm_streams::text_stream::ChannelTextStream;
 use llm_samplers::types::Sampler;
 use tokio::sync::{mpsc::unbounded_channel, oneshot};
 
+use crate::context_manager::{
+    generate_summary, summary_transcript, ContextManager, SUMMARY_INSTRUCTIONS,
+};
+
 type MessageFilter<M> =
     Arc<Mutex<Box<dyn for<'a> FnMut(&'a str, &mut M) -> Option<&'a str> + Send + Sync>>>;
 type UserMessageMapping<M> = Arc<Mutex<Box<dyn FnMut(&str, &mut M) -> String + Send + Sync>>>;
@@ -32,45 +37,12 @@ pub fn prompt_input(prompt: impl Display) -> Result<String> {
     Ok(input)
 }
 
-/// The type of a chat message
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum MessageType {
-    /// A system prompt.
-    SystemPrompt,
-    /// A user message.
-    UserMessage,
-    /// A model answer.
-    ModelAnswer,
-}
-
-/// A single item in the chat history.
-pub struct ChatHistoryItem {
-    ty: MessageType,
-    contents: String,
-}
-
-impl ChatHistoryItem {
-    /// Creates a new chat history item.
-    pub fn new(ty: MessageType, contents: impl Into<String>) -> Self {
-        Self {
-            ty,
-            contents: contents.into(),
-        }
-    }
-
-    /// Returns the type of the item.
-    pub fn ty(&self) -> MessageType {
-        self.ty
-    }
-
-    /// Returns the contents of the item.
-    pub fn contents(&self) -> &str {
-        &self.contents
-    }
-}
+pub use kalosm_language_model::{ChatHistoryItem, MessageType};
 
 /// The history of a chat session.
 struct ChatSession<Model: SyncModel, R = ()> {
+    system_prompt_marker: String,
+    end_system_prompt_marker: String,
     user_marker: String,
     end_user_marker: String,
     assistant_marker: String,
@@ -82,6 +54,7 @@ struct ChatSession<Model: SyncModel, R = ()> {
     bot_constraints: Option<ResponseConstraintGenerator<Model, R>>,
     filter_map_bot_response: Option<MessageFilter<Model>>,
     sampler: Arc<Mutex<dyn Sampler + Send + Sync>>,
+    context_manager: Option<ContextManager>,
 }
 
 impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
@@ -102,6 +75,7 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
         sampler: Arc<Mutex<dyn Sampler + Send + Sync>>,
         session: Option<Model::Session>,
         initial_history: Vec<ChatHistoryItem>,
+        context_manager: Option<ContextManager>,
     ) -> Self {
         let feed_initial_messages = session.is_none();
         let session = session.unwrap_or_else(|| model.new_session().unwrap());
@@ -114,12 +88,14 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
         } else {
             String::new()
         };
-        let history = vec![ChatHistoryItem {
-            ty: MessageType::SystemPrompt,
-            contents: system_prompt,
-        }];
+        let history = vec![ChatHistoryItem::new(
+            MessageType::SystemPrompt,
+            system_prompt,
+        )];
 
         let mut myself = Self {
+            system_prompt_marker,
+            end_system_prompt_marker,
             user_marker,
             end_user_marker,
             assistant_marker,
@@ -131,6 +107,7 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
             bot_constraints,
             filter_map_bot_response,
             sampler,
+            context_manager,
         };
 
         if feed_initial_messages {
@@ -140,10 +117,10 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
                         panic!("Initial history cannot contain a system prompt");
                     }
                     MessageType::UserMessage => {
-                        myself.add_user_message(item.contents, model);
+                        myself.add_user_message(item.contents().to_string(), model);
                     }
                     MessageType::ModelAnswer => {
-                        myself.add_bot_message(item.contents);
+                        myself.add_bot_message(item.contents().to_string());
                     }
                 }
             }
@@ -160,6 +137,7 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
         stream: tokio::sync::mpsc::UnboundedSender<String>,
     ) -> Result<()> {
         self.add_user_message(message, model);
+        self.manage_context(model)?;
         let mut bot_response = String::new();
         self.unfed_text += &self.assistant_marker;
         let prompt = std::mem::take(&mut self.unfed_text);
@@ -215,61 +193,141 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
 
                     if let Some(bot_response) = filter(&bot_response, model) {
                         stream.send(bot_response.to_string())?;
+                        self.history
+                            .push(ChatHistoryItem::new(MessageType::ModelAnswer, bot_response));
                         break;
                     } else {
                         tracing::trace!("Filtered out: {}", bot_response);
                     }
                 }
             }
-            None => match bot_constraints {
-                Some(constraints) => {
-                    let mut constraints = constraints.lock().unwrap();
-                    let constraints = constraints(&self.history, model);
-                    let state = constraints.create_parser_state();
-                    let on_token = |tok: String| {
-                        let tok = tok
-                            .strip_suffix(&self.end_assistant_marker)
-                            .unwrap_or(&tok)
-                            .to_string();
-                        bot_response += &tok;
-                        stream.send(tok)?;
-                        Ok(())
-                    };
-                    model.generate_structured(
-                        &mut self.session,
-                        &prompt,
-                        constraints,
-                        state,
-                        self.sampler.clone(),
-                        on_token,
-                        Some(32),
-                    )?;
-                }
-                None => {
-                    let on_token = |tok: String| {
-                        let tok = tok
-                            .strip_suffix(&self.end_assistant_marker)
-                            .unwrap_or(&tok)
-                            .to_string();
-                        bot_response += &tok;
-                        stream.send(tok)?;
-                        Ok(kalosm_language_model::ModelFeedback::Continue)
-                    };
-                    model.stream_text_with_sampler(
-                        &mut self.session,
-                        &prompt,
-                        None,
-                        Some(&self.end_assistant_marker),
-                        self.sampler.clone(),
-                        on_token,
-                    )?;
+            None => {
+                match bot_constraints {
+                    Some(constraints) => {
+                        let mut constraints = constraints.lock().unwrap();
+                        let constraints = constraints(&self.history, model);
+                        let state = constraints.create_parser_state();
+                        let on_token = |tok: String| {
+                            let tok = tok
+                                .strip_suffix(&self.end_assistant_marker)
+                                .unwrap_or(&tok)
+                                .to_string();
+                            bot_response += &tok;
+                            stream.send(tok)?;
+                            Ok(())
+                        };
+                        model.generate_structured(
+                            &mut self.session,
+                            &prompt,
+                            constraints,
+                            state,
+                            self.sampler.clone(),
+                            on_token,
+                            Some(32),
+                        )?;
+                    }
+                    None => {
+                        let on_token = |tok: String| {
+                            let tok = tok
+                                .strip_suffix(&self.end_assistant_marker)
+                                .unwrap_or(&tok)
+                                .to_string();
+                            bot_response += &tok;
+                            stream.send(tok)?;
+                            Ok(kalosm_language_model::ModelFeedback::Continue)
+                        };
+                        model.stream_text_with_sampler(
+                            &mut self.session,
+                            &prompt,
+                            None,
+                            Some(&self.end_assistant_marker),
+                            self.sampler.clone(),
+                            on_token,
+                        )?;
+                    }
                 }
-            },
+                self.history
+                    .push(ChatHistoryItem::new(MessageType::ModelAnswer, bot_response));
+            }
         }
 
         Ok(())
     }
 
+    /// Shrink the history with the context manager if the conversation no longer fits in the model's context window. If the history changes, the session is restarted with the new history.
+    fn manage_context(&mut self, model: &mut Model) -> Result<()> {
+        let Some(mut context_manager) = self.context_manager.take() else {
+            return Ok(());
+        };
+        let result = self.fit_history(&mut context_manager, model);
+        self.context_manager = Some(context_manager);
+        result
+    }
+
+    fn fit_history(&mut self, context_manager: &mut ContextManager, model: &Model) -> Result<()> {
+        let Some(context_length) = context_manager
+            .context_length()
+            .or_else(|| model.context_length())
+        else {
+            return Ok(());
+        };
+        let tokenizer = model.tokenizer();
+        let count_tokens = |history: &[ChatHistoryItem]| -> Result<usize> {
+            let prompt = self.format_history(history) + &self.assistant_marker;
+            Ok(tokenizer.encode(&prompt, false)?.len())
+        };
+        let summarize = |messages: &[ChatHistoryItem], max_tokens: usize| -> Result<String> {
+            let mut prompt = self.system_prompt_marker.clone();
+            prompt += SUMMARY_INSTRUCTIONS;
+            prompt += &self.end_system_prompt_marker;
+            prompt += &self.user_marker;
+            prompt += &summary_transcript(messages);
+            prompt += &self.end_user_marker;
+            prompt += &self.assistant_marker;
+            generate_summary(
+                model,
+                &prompt,
+                &self.end_assistant_marker,
+                self.sampler.clone(),
+                max_tokens,
+            )
+        };
+        let Some(history) =
+            context_manager.fit(&self.history, context_length, count_tokens, summarize)?
+        else {
+            return Ok(());
+        };
+
+        tracing::trace!(
+            "Shrunk the chat history from {} to {} messages",
+            self.history.len(),
+            history.len()
+        );
+        self.session = model.new_session()?;
+        self.unfed_text = self.format_history(&history);
+        self.history = history;
+
+        Ok(())
+    }
+
+    /// Format the history with the chat markers. User messages are formatted as they were stored, without the user message mapping.
+    fn format_history(&self, history: &[ChatHistoryItem]) -> String {
+        let mut prompt = String::new();
+        for item in history {
+            let (start, end) = match item.ty() {
+                MessageType::SystemPrompt => {
+                    (&self.system_prompt_marker, &self.end_system_prompt_marker)
+                }
+                MessageType::UserMessage => (&self.user_marker, &self.end_user_marker),
+                MessageType::ModelAnswer => (&self.assistant_marker, &self.end_assistant_marker),
+            };
+            prompt += start;
+            prompt += item.contents();
+            prompt += end;
+        }
+        prompt
+    }
+
     fn add_user_message(&mut self, message: String, model: &mut Model) {
         match &self.map_user_message_prompt {
             Some(map) => {
@@ -285,20 +343,16 @@ impl<Model: SyncModel, R: Clone> ChatSession<Model, R> {
                 self.unfed_text += &self.end_user_marker;
             }
         };
-        self.history.push(ChatHistoryItem {
-            ty: MessageType::UserMessage,
-            contents: message,
-        });
+        self.history
+            .push(ChatHistoryItem::new(MessageType::UserMessage, message));
     }
 
     fn add_bot_message(&mut self, message: String) {
         self.unfed_text += &self.assistant_marker;
         self.unfed_text += &message;
         self.unfed_text += &self.end_assistant_marker;
-        self.history.push(ChatHistoryItem {
-            ty: MessageType::ModelAnswer,
-            contents: message,
-        });
+        self.history
+            .push(ChatHistoryItem::new(MessageType::ModelAnswer, message));
     }
 }
 
@@ -309,10 +363,12 @@ pub struct ChatBuilder<M: Model, R = ()> {
     session: Option<<M::SyncModel as kalosm_language_model::SyncModel>::Session>,
     system_prompt: String,
     sampler: Arc<Mutex<dyn Sampler + Send + Sync>>,
+    generation_parameters: Option<GenerationParameters>,
     map_user_message_prompt: Option<UserMessageMapping<M::SyncModel>>,
     bot_constraints: Option<ResponseConstraintGenerator<M::SyncModel, R>>,
     filter_map_bot_response: Option<MessageFilter<M::SyncModel>>,
     initial_history: Vec<ChatHistoryItem>,
+    context_manager: Option<ContextManager>,
 }
 
 impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
@@ -325,10 +381,12 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
             session: None,
             system_prompt: "Always assist with care, respect, and truth. Respond with utmost utility yet securely. Avoid harmful, unethical, prejudiced, or negative content. Ensure replies promote fairness and positivity.".into(),
             sampler: Arc::new(Mutex::new(GenerationParameters::default().sampler())),
+            generation_parameters: Some(GenerationParameters::default().with_max_length(u32::MAX)),
             map_user_message_prompt: None,
             bot_constraints: None,
             filter_map_bot_response: None,
             initial_history: Vec::new(),
+            context_manager: None,
         }
     }
 
@@ -340,8 +398,21 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
     }
 
     /// Sets the [`Sampler`] to use for generating responses.
+    ///
+    /// > **Note**: Custom samplers are only supported for models that run synchronously. Use [`Self::with_generation_parameters`] to set the sampler settings for remote models.
     pub fn with_sampler(mut self, sampler: impl Sampler + Send + Sync + 'static) -> Self {
         self.sampler = Arc::new(Mutex::new(sampler));
+        self.generation_parameters = None;
+        self
+    }
+
+    /// Sets the [`GenerationParameters`] to use for generating responses. Unlike [`Self::with_sampler`], the parameters are also sent to models that don't run synchronously (like remote models).
+    pub fn with_generation_parameters(
+        mut self,
+        generation_parameters: GenerationParameters,
+    ) -> Self {
+        self.sampler = Arc::new(Mutex::new(generation_parameters.clone().sampler()));
+        self.generation_parameters = Some(generation_parameters);
         self
     }
 
@@ -389,6 +460,7 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
             session: self.session,
             system_prompt: self.system_prompt,
             sampler: self.sampler,
+            generation_parameters: self.generation_parameters,
             map_user_message_prompt: self.map_user_message_prompt,
             bot_constraints: Some(Arc::new(Mutex::new(Box::new(
                 move |history: &[ChatHistoryItem], model: &mut M::SyncModel| {
@@ -402,6 +474,7 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
                 >))),
             filter_map_bot_response: self.filter_map_bot_response,
             initial_history: self.initial_history,
+            context_manager: self.context_manager,
         }
     }
 
@@ -462,6 +535,14 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
         self
     }
 
+    /// Keep the conversation inside of the model's context window with a [`ContextManager`]. When the history gets too close to the context length, it is shrunk with the manager's [`crate::context_manager::ContextPolicy`] and the model is fed the new history.
+    ///
+    /// > **Note**: The context is only managed for models that support running synchronously.
+    pub fn with_context_manager(mut self, context_manager: ContextManager) -> Self {
+        self.context_manager = Some(context_manager);
+        self
+    }
+
     /// Builds a [`Chat`] instance.
     pub fn build(self) -> Chat
     where
@@ -472,11 +553,13 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
             chat_markers,
             system_prompt,
             sampler,
+            generation_parameters,
             map_user_message_prompt,
             bot_constraints,
             filter_map_bot_response,
             session,
             initial_history,
+            context_manager,
         } = self;
         let system_prompt_marker = chat_markers.system_prompt_marker.to_string();
         let end_system_prompt_marker = chat_markers.end_system_prompt_marker.to_string();
@@ -486,31 +569,60 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
         let end_assistant_marker = chat_markers.end_assistant_marker.to_string();
         let (sender_tx, mut sender_rx) = unbounded_channel();
         let (result_tx, result_rx) = unbounded_channel();
+        let sync_only_settings: Vec<&str> = [
+            ("a custom sampler", generation_parameters.is_none()),
+            ("user message mappings", map_user_message_prompt.is_some()),
+            ("response constraints", bot_constraints.is_some()),
+            ("response filters", filter_map_bot_response.is_some()),
+            ("sessions", session.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(setting, set)| set.then_some(setting))
+        .collect();
+        let mut streaming_history = vec![ChatHistoryItem::new(
+            MessageType::SystemPrompt,
+            system_prompt.clone(),
+        )];
+        streaming_history.extend(initial_history.iter().cloned());
         tokio::spawn(async move {
             let (tx, rx) = oneshot::channel();
             {
-                model
-                    .run_sync(move |model| {
-                        Box::pin(async move {
-                            let _ = tx.send(ChatSession::new(
-                                model,
-                                system_prompt_marker,
-                                end_system_prompt_marker,
-                                user_marker,
-                                end_user_marker,
-                                assistant_marker,
-                                end_assistant_marker,
-                                system_prompt,
-                                map_user_message_prompt,
-                                bot_constraints,
-                                filter_map_bot_response,
-                                sampler,
-                                session,
-                                initial_history,
-                            ));
-                        })
+                let started = model.run_sync(move |model| {
+                    Box::pin(async move {
+                        let _ = tx.send(ChatSession::new(
+                            model,
+                            system_prompt_marker,
+                            end_system_prompt_marker,
+                            user_marker,
+                            end_user_marker,
+                            assistant_marker,
+                            end_assistant_marker,
+                            system_prompt,
+                            map_user_message_prompt,
+                            bot_constraints,
+                            filter_map_bot_response,
+                            sampler,
+                            session,
+                            initial_history,
+                            context_manager,
+                        ));
                     })
-                    .unwrap();
+                });
+                // Models without a sync interface (like remote models) can still chat by sending the whole history every turn
+                if let Err(err) = started {
+                    tracing::trace!("Falling back to streaming chat: {}", err);
+                    // Settings the model can't honor are reported to every message instead of being silently ignored
+                    let parameters = match generation_parameters {
+                        Some(parameters) if sync_only_settings.is_empty() => Ok(parameters),
+                        _ => Err(format!(
+                            "This model does not run synchronously, so it does not support {}",
+                            sync_only_settings.join(", ")
+                        )),
+                    };
+                    run_streaming_chat(model, streaming_history, parameters, sender_rx, result_tx)
+                        .await;
+                    return;
+                }
             }
 
             let chat_session = Arc::new(Mutex::new(rx.await.unwrap()));
@@ -519,7 +631,7 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
                 match message {
                     Message::AddMessage(message) => {
                         let (tx, rx) = unbounded_channel();
-                        result_tx.send(Response::AddMessage(rx.into())).unwrap();
+                        result_tx.send(Response::AddMessage(Ok(rx.into()))).unwrap();
                         let chat_session = chat_session.clone();
                         model
                             .run_sync(move |model| {
@@ -532,8 +644,8 @@ impl<M: Model, R: Clone + 'static> ChatBuilder<M, R> {
                     }
                     Message::SaveSession(path) => {
                         let chat_session = chat_session.lock().unwrap();
-                        chat_session.session.save_to(path).unwrap();
-                        result_tx.send(Response::SaveSession).unwrap();
+                        let result = chat_session.session.save_to(path);
+                        result_tx.send(Response::SaveSession(result)).unwrap();
                     }
                 }
             }
@@ -552,8 +664,65 @@ enum Message {
 }
 
 enum Response {
-    AddMessage(ChannelTextStream<String>),
-    SaveSession,
+    AddMessage(Result<ChannelTextStream<String>>),
+    SaveSession(Result<()>),
+}
+
+/// Run a chat with a model that doesn't support running synchronously. The chat history is sent to [`Model::stream_chat_inner`] for every new message.
+///
+/// If the chat was built with settings the model can't honor, `parameters` is the error every message is answered with.
+async fn run_streaming_chat<M: Model>(
+    model: M,
+    mut history: Vec<ChatHistoryItem>,
+    parameters: std::result::Result<GenerationParameters, String>,
+    mut sender_rx: tokio::sync::mpsc::UnboundedReceiver<Message>,
+    result_tx: tokio::sync::mpsc::UnboundedSender<Response>,
+) {
+    while let Some(message) = sender_rx.recv().await {
+        match message {
+            Message::AddMessage(message) => {
+                let parameters = match &parameters {
+                    Ok(parameters) => parameters.clone(),
+                    Err(err) => {
+                        let result = Err(anyhow::anyhow!("{err}"));
+                        if result_tx.send(Response::AddMessage(result)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                history.push(ChatHistoryItem::new(MessageType::UserMessage, message));
+                let mut stream = match model.stream_chat_inner(&history, parameters).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        history.pop();
+                        if result_tx.send(Response::AddMessage(Err(err))).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let (tx, rx) = unbounded_channel();
+                if result_tx.send(Response::AddMessage(Ok(rx.into()))).is_err() {
+                    break;
+                }
+                let mut response = String::new();
+                while let Some(token) = stream.next().await {
+                    response += &token;
+                    _ = tx.send(token);
+                }
+                history.push(ChatHistoryItem::new(MessageType::ModelAnswer, response));
+            }
+            Message::SaveSession(_) => {
+                let result = Err(anyhow::anyhow!(
+                    "Saving sessions is not supported for this model"
+                ));
+                if result_tx.send(Response::SaveSession(result)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
 }
 
 /// A chat session.
@@ -588,7 +757,7 @@ impl Chat {
                 Response::AddMessage(c) => c,
                 _ => unreachable!(),
             })
-            .ok_or(anyhow::anyhow!("Model stopped"))
+            .ok_or(anyhow::anyhow!("Model stopped"))?
     }
 
     /// Saves the session to the given path.
@@ -600,10 +769,9 @@ impl Chat {
             .recv()
             .await
             .map(|c| match c {
-                Response::SaveSession => (),
+                Response::SaveSession(result) => result,
                 _ => unreachable!(),
             })
-            .ok_or(anyhow::anyhow!("Model stopped"))?;
-        Ok(())
+            .ok_or(anyhow::anyhow!("Model stopped"))?
     }
 }
@@ -0,0 +1,364 @@
+//! Keep chats and tasks inside of a model's context window.
+//!
+//! A [`ContextManager`] checks the size of a conversation before each response. When the conversation gets too close to the model's context length, it shrinks the history with a [`ContextPolicy`].
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use kalosm_language_model::{ChatHistoryItem, MessageType, SyncModel, SyncModelExt};
+use llm_samplers::types::Sampler;
+
+/// The heading the summary of earlier messages is added to the system prompt under.
+const SUMMARY_HEADING: &str = "\n\nSummary of the earlier conversation:\n";
+
+/// The instructions the model is given when summarizing earlier messages.
+pub(crate) const SUMMARY_INSTRUCTIONS: &str = "You summarize conversations. Write a short summary of the conversation you are given. Keep any facts, names, and decisions that may be needed later.";
+
+/// How a [`ContextManager`] shrinks a conversation that does not fit in the model's context window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextPolicy {
+    /// Drop the oldest turns until the conversation fits.
+    DropOldest,
+    /// Replace the oldest turns with a summary written by the model. The summary is added to the end of the system prompt.
+    Summarize {
+        /// The maximum number of tokens the summary can take up.
+        max_summary_tokens: usize,
+    },
+    /// Drop the oldest turns that are not pinned until the conversation fits.
+    ///
+    /// Messages are identified by their index in the history. The system prompt is at index 0 and is always kept.
+    KeepPinned {
+        /// The indexes of the messages to keep.
+        pinned: Vec<usize>,
+    },
+}
+
+/// Keeps a conversation inside of a model's context window by shrinking the history with a [`ContextPolicy`].
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language::prelude::*;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let model = Llama::new_chat().await.unwrap();
+///     let mut chat = Chat::builder(model)
+///         .with_context_manager(
+///             ContextManager::new(ContextPolicy::DropOldest).with_reserved_tokens(256),
+///         )
+///         .build();
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextManager {
+    policy: ContextPolicy,
+    reserved_tokens: usize,
+    context_length: Option<usize>,
+}
+
+impl ContextManager {
+    /// Create a new context manager with the given policy.
+    pub fn new(policy: ContextPolicy) -> Self {
+        Self {
+            policy,
+            reserved_tokens: 512,
+            context_length: None,
+        }
+    }
+
+    /// Set the number of tokens to leave free for the model's response. (Defaults to 512)
+    pub fn with_reserved_tokens(mut self, reserved_tokens: usize) -> Self {
+        self.reserved_tokens = reserved_tokens;
+        self
+    }
+
+    /// Set the context length to manage the conversation for. If this is not set, the [`kalosm_language_model::Model::context_length`] of the model is used.
+    pub fn with_context_length(mut self, context_length: usize) -> Self {
+        self.context_length = Some(context_length);
+        self
+    }
+
+    /// Get the policy of the context manager.
+    pub fn policy(&self) -> &ContextPolicy {
+        &self.policy
+    }
+
+    /// Get the number of tokens left free for the model's response.
+    pub fn reserved_tokens(&self) -> usize {
+        self.reserved_tokens
+    }
+
+    /// Get the context length set with [`Self::with_context_length`].
+    pub fn context_length(&self) -> Option<usize> {
+        self.context_length
+    }
+
+    /// Shrink the history so that it fits in `context_length` tokens with room left for the response.
+    ///
+    /// The first message is treated as the system prompt and is always kept, as is the newest message. Turns are dropped oldest first. When a user message is dropped, the model answers that follow it are dropped with it.
+    ///
+    /// `count_tokens` returns the number of tokens a history takes up. `summarize` is only called with the [`ContextPolicy::Summarize`] policy: it receives the messages that were dropped (starting with the previous summary as a [`MessageType::SystemPrompt`] message if there is one) and the maximum number of tokens the summary can take up.
+    ///
+    /// Returns `None` if the history already fits, or an error if it cannot be shrunk enough.
+    pub fn fit(
+        &mut self,
+        history: &[ChatHistoryItem],
+        context_length: usize,
+        mut count_tokens: impl FnMut(&[ChatHistoryItem]) -> Result<usize>,
+        mut summarize: impl FnMut(&[ChatHistoryItem], usize) -> Result<String>,
+    ) -> Result<Option<Vec<ChatHistoryItem>>> {
+        let budget = context_length.saturating_sub(self.reserved_tokens);
+        if count_tokens(history)? <= budget {
+            return Ok(None);
+        }
+        let Some((system_prompt, messages)) = history.split_first() else {
+            return Ok(None);
+        };
+
+        let (base_system_prompt, previous_summary) = match system_prompt.ty() {
+            MessageType::SystemPrompt => match system_prompt.contents().split_once(SUMMARY_HEADING)
+            {
+                Some((base, summary)) => (base.to_string(), Some(summary.to_string())),
+                None => (system_prompt.contents().to_string(), None),
+            },
+            _ => anyhow::bail!("The first message in the history must be the system prompt"),
+        };
+        let summary_tokens = match &self.policy {
+            ContextPolicy::Summarize { max_summary_tokens } => *max_summary_tokens,
+            _ => 0,
+        };
+        let is_pinned = |index: usize| match &self.policy {
+            ContextPolicy::KeepPinned { pinned } => pinned.contains(&index),
+            _ => false,
+        };
+
+        // Keep track of the original index of each message so pinned messages can be remapped
+        let mut kept: Vec<(usize, ChatHistoryItem)> = messages
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, item)| (i + 1, item))
+            .collect();
+        let mut dropped = Vec::new();
+        loop {
+            let mut candidate = vec![system_prompt.clone()];
+            candidate.extend(kept.iter().map(|(_, item)| item.clone()));
+            if !dropped.is_empty() && count_tokens(&candidate)? + summary_tokens <= budget {
+                break;
+            }
+
+            let removable = kept.len().saturating_sub(1);
+            let Some(position) = kept[..removable]
+                .iter()
+                .position(|(index, _)| !is_pinned(*index))
+            else {
+                anyhow::bail!(
+                    "The conversation does not fit in the context window of {context_length} tokens"
+                );
+            };
+            let (_, item) = kept.remove(position);
+            let drop_answers = matches!(item.ty(), MessageType::UserMessage);
+            dropped.push(item);
+            // Drop the rest of the turn so the history doesn't start with a dangling answer
+            while drop_answers
+                && position < kept.len().saturating_sub(1)
+                && matches!(kept[position].1.ty(), MessageType::ModelAnswer)
+                && !is_pinned(kept[position].0)
+            {
+                dropped.push(kept.remove(position).1);
+            }
+        }
+
+        let system_prompt = match &self.policy {
+            ContextPolicy::Summarize { max_summary_tokens } => {
+                let mut to_summarize = Vec::new();
+                if let Some(previous_summary) = previous_summary {
+                    to_summarize.push(ChatHistoryItem::new(
+                        MessageType::SystemPrompt,
+                        previous_summary,
+                    ));
+                }
+                to_summarize.extend(dropped);
+                let summary = summarize(&to_summarize, *max_summary_tokens)?;
+                ChatHistoryItem::new(
+                    MessageType::SystemPrompt,
+                    base_system_prompt + SUMMARY_HEADING + summary.trim(),
+                )
+            }
+            _ => system_prompt.clone(),
+        };
+
+        if let ContextPolicy::KeepPinned { pinned } = &mut self.policy {
+            *pinned = kept
+                .iter()
+                .enumerate()
+                .filter(|(_, (index, _))| pinned.contains(index))
+                .map(|(new_index, _)| new_index + 1)
+                .collect();
+        }
+
+        let mut fitted = vec![system_prompt];
+        fitted.extend(kept.into_iter().map(|(_, item)| item));
+        Ok(Some(fitted))
+    }
+}
+
+/// Format messages as a transcript the model can summarize.
+pub(crate) fn summary_transcript(messages: &[ChatHistoryItem]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        let speaker = match message.ty() {
+            MessageType::SystemPrompt => "Summary so far",
+            MessageType::UserMessage => "User",
+            MessageType::ModelAnswer => "Assistant",
+        };
+        transcript += speaker;
+        transcript += ": ";
+        transcript += message.contents().trim();
+        transcript += "\n";
+    }
+    transcript
+}
+
+/// Generate a summary for a fully formatted summarization prompt in a fresh session.
+pub(crate) fn generate_summary<M: SyncModel>(
+    model: &M,
+    prompt: &str,
+    stop_on: &str,
+    sampler: Arc<Mutex<dyn Sampler + Send + Sync>>,
+    max_tokens: usize,
+) -> Result<String> {
+    let mut session = model.new_session()?;
+    let mut summary = String::new();
+    model.stream_text_with_sampler(
+        &mut session,
+        prompt,
+        Some(max_tokens as u32),
+        Some(stop_on),
+        sampler,
+        |token| {
+            summary += token.strip_suffix(stop_on).unwrap_or(&token);
+            Ok(kalosm_language_model::ModelFeedback::Continue)
+        },
+    )?;
+    Ok(summary.trim().to_string())
+}
+
+#[test]
+fn drops_oldest_turns_and_keeps_pinned_messages() {
+    fn count_words(history: &[ChatHistoryItem]) -> Result<usize> {
+        Ok(history
+            .iter()
+            .map(|item| item.contents().split_whitespace().count())
+            .sum())
+    }
+    fn no_summary(_: &[ChatHistoryItem], _: usize) -> Result<String> {
+        unreachable!()
+    }
+
+    let history = vec![
+        ChatHistoryItem::new(MessageType::SystemPrompt, "be nice"),
+        ChatHistoryItem::new(MessageType::UserMessage, "my name is Ada"),
+        ChatHistoryItem::new(MessageType::ModelAnswer, "hello Ada"),
+        ChatHistoryItem::new(MessageType::UserMessage, "what is two plus two"),
+        ChatHistoryItem::new(MessageType::ModelAnswer, "four"),
+        ChatHistoryItem::new(MessageType::UserMessage, "what is my name"),
+    ];
+
+    let mut manager = ContextManager::new(ContextPolicy::DropOldest).with_reserved_tokens(0);
+    assert!(manager
+        .fit(&history, 100, count_words, no_summary)
+        .unwrap()
+        .is_none());
+
+    let fitted = manager
+        .fit(&history, 12, count_words, no_summary)
+        .unwrap()
+        .unwrap();
+    let contents: Vec<_> = fitted.iter().map(|item| item.contents()).collect();
+    assert_eq!(
+        contents,
+        ["be nice", "what is two plus two", "four", "what is my name"]
+    );
+
+    let mut manager =
+        ContextManager::new(ContextPolicy::KeepPinned { pinned: vec![1] }).with_reserved_tokens(0);
+    let fitted = manager
+        .fit(&history, 12, count_words, no_summary)
+        .unwrap()
+        .unwrap();
+    let contents: Vec<_> = fitted.iter().map(|item| item.contents()).collect();
+    assert_eq!(contents, ["be nice", "my name is Ada", "what is my name"]);
+    assert_eq!(
+        manager.policy(),
+        &ContextPolicy::KeepPinned { pinned: vec![1] }
+    );
+
+    assert!(manager.fit(&history, 5, count_words, no_summary).is_err());
+}
+
+#[test]
+fn summarizes_dropped_turns() {
+    fn count_words(history: &[ChatHistoryItem]) -> Result<usize> {
+        Ok(history
+            .iter()
+            .map(|item| item.contents().split_whitespace().count())
+            .sum())
+    }
+
+    let history = vec![
+        ChatHistoryItem::new(MessageType::SystemPrompt, "be nice"),
+        ChatHistoryItem::new(MessageType::UserMessage, "my name is Ada"),
+        ChatHistoryItem::new(MessageType::ModelAnswer, "hello Ada"),
+        ChatHistoryItem::new(MessageType::UserMessage, "what is two plus two"),
+        ChatHistoryItem::new(MessageType::ModelAnswer, "four"),
+        ChatHistoryItem::new(MessageType::UserMessage, "what is my name"),
+    ];
+
+    let mut manager = ContextManager::new(ContextPolicy::Summarize {
+        max_summary_tokens: 3,
+    })
+    .with_reserved_tokens(0);
+    let mut summarized = Vec::new();
+    let fitted = manager
+        .fit(&history, 15, count_words, |messages, max_tokens| {
+            assert_eq!(max_tokens, 3);
+            summarized.push(messages.to_vec());
+            Ok(" the user is Ada ".to_string())
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(summarized, [history[1..3].to_vec()]);
+    let contents: Vec<_> = fitted.iter().map(|item| item.contents()).collect();
+    assert_eq!(
+        contents,
+        [
+            "be nice\n\nSummary of the earlier conversation:\nthe user is Ada",
+            "what is two plus two",
+            "four",
+            "what is my name"
+        ]
+    );
+
+    // The previous summary is passed to the next summary instead of being summarized again as part of the system prompt
+    let mut history = fitted;
+    history.push(ChatHistoryItem::new(MessageType::ModelAnswer, "Ada"));
+    history.push(ChatHistoryItem::new(MessageType::UserMessage, "thanks"));
+    let mut summarized = Vec::new();
+    let fitted = manager
+        .fit(&history, 15, count_words, |messages, _| {
+            summarized.push(messages.to_vec());
+            Ok("Ada asked about math".to_string())
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        summarized[0][0],
+        ChatHistoryItem::new(MessageType::SystemPrompt, "the user is Ada")
+    );
+    assert_eq!(summarized[0][1..], history[1..5]);
+    assert_eq!(
+        fitted[0].contents(),
+        "be nice\n\nSummary of the earlier conversation:\nAda asked about math"
+    );
+}
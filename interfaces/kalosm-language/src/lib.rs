@@ -16,6 +16,7 @@
 
 pub mod chat;
 pub mod context;
+pub mod context_manager;
 pub mod search;
 pub mod task;
 pub mod tool;
@@ -31,6 +32,7 @@ pub use rphi;
 pub mod prelude {
     pub use crate::chat::*;
     pub use crate::context::*;
+    pub use crate::context_manager::*;
     pub use crate::search::*;
     pub use crate::task::*;
     pub use crate::tool::*;
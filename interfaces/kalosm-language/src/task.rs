@@ -5,7 +5,9 @@ use futures_util::Stream;
 use kalosm_language_model::ChatMarkers;
 use kalosm_language_model::Session;
 use kalosm_language_model::StructureParserResult;
-use kalosm_language_model::{GenerationParameters, Model, ModelExt, SyncModel, SyncModelExt};
+use kalosm_language_model::{
+    GenerationParameters, Model, ModelExt, StructuredRetry, SyncModel, SyncModelExt,
+};
 use kalosm_sample::{CreateParserState, Parser};
 use kalosm_streams::text_stream::ChannelTextStream;
 use llm_samplers::types::Sampler;
@@ -16,10 +18,19 @@ use std::sync::Arc;
 use std::sync::RwLock;
 use tokio::sync::{mpsc::unbounded_channel, oneshot};
 
+use crate::context_manager::{
+    generate_summary, summary_transcript, ContextManager, SUMMARY_INSTRUCTIONS,
+};
+use crate::prelude::{ChatHistoryItem, MessageType};
+
 struct TaskSessionEntry<S> {
     cached_prompt: String,
     after_input: String,
     session: Option<S>,
+    // The number of tokens in the cached prompt, counted the first time the context is checked
+    prompt_tokens: Option<usize>,
+    // The entry with the examples the context manager shrunk to fit the last input that did not fit
+    fitted: Option<Box<TaskSessionEntry<S>>>,
 }
 
 impl<S: Session> TaskSessionEntry<S> {
@@ -77,6 +88,20 @@ impl<S: Session> TaskSessionEntry<S> {
             cached_prompt,
             after_input,
             session: None,
+            prompt_tokens: None,
+            fitted: None,
+        }
+    }
+
+    /// Get the number of tokens in the cached prompt.
+    fn prompt_tokens(&mut self, model: &impl SyncModel) -> Result<usize> {
+        match self.prompt_tokens {
+            Some(tokens) => Ok(tokens),
+            None => {
+                let tokens = model.tokenizer().encode(&self.cached_prompt, false)?.len();
+                self.prompt_tokens = Some(tokens);
+                Ok(tokens)
+            }
         }
     }
 
@@ -114,18 +139,147 @@ struct TaskSessions {
     sessions: RwLock<FxHashMap<TypeId, Box<dyn Any + Send + Sync>>>,
     system_prompt: String,
     examples: Vec<TaskExample>,
+    context_manager: Option<ContextManager>,
 }
 
 impl TaskSessions {
     #[allow(clippy::too_many_arguments)]
     /// Creates a new [`TaskSessions`].
-    pub(crate) fn new(system_prompt: String, examples: Vec<TaskExample>) -> Self {
+    pub(crate) fn new(
+        system_prompt: String,
+        examples: Vec<TaskExample>,
+        context_manager: Option<ContextManager>,
+    ) -> Self {
         Self {
             sessions: RwLock::new(FxHashMap::default()),
             system_prompt,
             examples,
+            context_manager,
+        }
+    }
+
+    /// Get the session entry to run the input with. If the task prompt with the input doesn't fit in the model's context window, the examples are shrunk with the context manager.
+    ///
+    /// The shrunk entry is cached and reused for later inputs that fit with it, so the examples are only summarized again once an input no longer fits.
+    fn fit_to_context<'a, M: SyncModel>(
+        &self,
+        model: &M,
+        entry: &'a mut TaskSessionEntry<M::Session>,
+        markers: Option<ChatMarkers>,
+        input: &str,
+        sampler: Arc<std::sync::Mutex<dyn Sampler + Send + Sync>>,
+    ) -> Result<&'a mut TaskSessionEntry<M::Session>> {
+        let Some(context_manager) = &self.context_manager else {
+            return Ok(entry);
+        };
+        let Some(context_length) = context_manager
+            .context_length()
+            .or_else(|| model.context_length())
+        else {
+            return Ok(entry);
+        };
+
+        // Only the input is tokenized on every run. The cached prompts are counted once
+        let budget = context_length.saturating_sub(context_manager.reserved_tokens());
+        let input_tokens = model
+            .tokenizer()
+            .encode(&entry.task_prompt(input), false)?
+            .len();
+        if entry.prompt_tokens(model)? + input_tokens <= budget {
+            return Ok(entry);
+        }
+        let fitted_fits = match &mut entry.fitted {
+            Some(fitted) => fitted.prompt_tokens(model)? + input_tokens <= budget,
+            None => false,
+        };
+        if fitted_fits {
+            return Ok(&mut **entry.fitted.as_mut().unwrap());
+        }
+
+        let mut history = vec![ChatHistoryItem::new(
+            MessageType::SystemPrompt,
+            self.system_prompt.clone(),
+        )];
+        for example in &self.examples {
+            history.push(ChatHistoryItem::new(
+                MessageType::UserMessage,
+                example.input.clone(),
+            ));
+            history.push(ChatHistoryItem::new(
+                MessageType::ModelAnswer,
+                example.output.clone(),
+            ));
+        }
+        history.push(ChatHistoryItem::new(MessageType::UserMessage, input));
+
+        let tokenizer = model.tokenizer();
+        let count_tokens = |history: &[ChatHistoryItem]| -> Result<usize> {
+            let (system_prompt, examples) = split_task_history(history);
+            let entry =
+                TaskSessionEntry::<M::Session>::new(markers.clone(), system_prompt, &examples);
+            let prompt = entry.cached_prompt.clone() + &entry.task_prompt(input);
+            Ok(tokenizer.encode(&prompt, false)?.len())
+        };
+        let summarize = |messages: &[ChatHistoryItem], max_tokens: usize| -> Result<String> {
+            let transcript = summary_transcript(messages);
+            let (prompt, stop_on) = match &markers {
+                Some(markers) => (
+                    markers.system_prompt_marker.to_string()
+                        + SUMMARY_INSTRUCTIONS
+                        + markers.end_system_prompt_marker
+                        + markers.user_marker
+                        + &transcript
+                        + markers.end_user_marker
+                        + markers.assistant_marker,
+                    markers.end_assistant_marker,
+                ),
+                None => (
+                    format!(
+                        "# Instruction\n{SUMMARY_INSTRUCTIONS}\n# Input\n{transcript}# Output\n"
+                    ),
+                    "# Input",
+                ),
+            };
+            generate_summary(model, &prompt, stop_on, sampler.clone(), max_tokens)
+        };
+
+        // The context manager is cloned so every fit starts from the full task prompt
+        let mut context_manager = context_manager.clone();
+        let Some(history) =
+            context_manager.fit(&history, context_length, count_tokens, summarize)?
+        else {
+            return Ok(entry);
+        };
+        let (system_prompt, examples) = split_task_history(&history);
+
+        let fitted = TaskSessionEntry::new(markers, system_prompt, &examples);
+        Ok(&mut **entry.fitted.insert(Box::new(fitted)))
+    }
+}
+
+/// Split a history created by [`TaskSessions::fit_to_context`] back into the system prompt and the examples. The last message is the input.
+fn split_task_history(history: &[ChatHistoryItem]) -> (String, Vec<TaskExample>) {
+    let system_prompt = history
+        .first()
+        .map(|item| item.contents().to_string())
+        .unwrap_or_default();
+    let messages = history
+        .get(1..history.len().saturating_sub(1))
+        .unwrap_or_default();
+    let mut examples = Vec::new();
+    let mut messages = messages.iter().peekable();
+    while let Some(message) = messages.next() {
+        if message.ty() != MessageType::UserMessage {
+            continue;
+        }
+        if let Some(answer) = messages.next_if(|answer| answer.ty() == MessageType::ModelAnswer) {
+            examples.push(TaskExample {
+                input: message.contents().to_string(),
+                output: answer.contents().to_string(),
+            });
         }
     }
+    (system_prompt, examples)
 }
 
 #[derive(Debug, Clone)]
@@ -143,8 +297,10 @@ pub struct NoParser;
 pub struct TaskBuilder<P = NoParser> {
     system_prompt: String,
     sampler: Arc<std::sync::Mutex<dyn Sampler + Send + Sync>>,
+    generation_parameters: Option<GenerationParameters>,
     constraints: P,
     examples: Vec<TaskExample>,
+    context_manager: Option<ContextManager>,
 }
 
 impl TaskBuilder {
@@ -154,16 +310,33 @@ impl TaskBuilder {
             sampler: Arc::new(std::sync::Mutex::new(
                 GenerationParameters::default().sampler(),
             )),
+            generation_parameters: Some(GenerationParameters::default()),
             constraints: NoParser,
             examples: Vec::new(),
+            context_manager: None,
         }
     }
 }
 
 impl<P: TaskBuilderReturn + Send + Sync + 'static> TaskBuilder<P> {
     /// Sets the [`Sampler`] to use for generating responses.
+    ///
+    /// > **Note**: Custom samplers are only supported for models that run synchronously. Use [`Self::with_generation_parameters`] to set the sampler settings for remote models.
     pub fn with_sampler(mut self, sampler: impl Sampler + Send + Sync + 'static) -> Self {
         self.sampler = Arc::new(std::sync::Mutex::new(sampler));
+        self.generation_parameters = None;
+        self
+    }
+
+    /// Sets the [`GenerationParameters`] to use for generating responses. Unlike [`Self::with_sampler`], the parameters are also sent to models that don't run synchronously (like remote models).
+    pub fn with_generation_parameters(
+        mut self,
+        generation_parameters: GenerationParameters,
+    ) -> Self {
+        self.sampler = Arc::new(std::sync::Mutex::new(
+            generation_parameters.clone().sampler(),
+        ));
+        self.generation_parameters = Some(generation_parameters);
         self
     }
 
@@ -176,7 +349,9 @@ impl<P: TaskBuilderReturn + Send + Sync + 'static> TaskBuilder<P> {
             constraints,
             system_prompt: self.system_prompt,
             sampler: self.sampler,
+            generation_parameters: self.generation_parameters,
             examples: self.examples,
+            context_manager: self.context_manager,
         }
     }
 
@@ -201,6 +376,12 @@ impl<P: TaskBuilderReturn + Send + Sync + 'static> TaskBuilder<P> {
         self
     }
 
+    /// Keep the task prompt inside of the model's context window with a [`ContextManager`]. If the examples and input do not fit, the oldest examples are shrunk with the manager's [`crate::context_manager::ContextPolicy`] for that run.
+    pub fn with_context_manager(mut self, context_manager: ContextManager) -> Self {
+        self.context_manager = Some(context_manager);
+        self
+    }
+
     /// Build a [`Task`] from a [`TaskBuilder`].
     pub fn build(self) -> Task<<P as TaskBuilderReturn>::Output> {
         let inner = <P as TaskBuilderReturn>::build(self);
@@ -228,10 +409,11 @@ impl TaskBuilderReturn for NoParser {
             system_prompt,
             sampler,
             examples,
+            context_manager,
             ..
         } = task_builder;
 
-        let sessions = TaskSessions::new(system_prompt.clone(), examples.clone());
+        let sessions = TaskSessions::new(system_prompt.clone(), examples.clone(), context_manager);
         UnstructuredRunner {
             sessions: Arc::new(sessions),
             sampler,
@@ -280,6 +462,13 @@ impl TaskRunner for UnstructuredRunner {
                         .downcast_mut()
                         .unwrap()
                 };
+                let session_entry = match sessions.fit_to_context(&*model, session_entry, chat_markers, &input, sampler.clone()) {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        tracing::error!("Failed to fit the task into the context window: {}", err);
+                        return;
+                    }
+                };
                 let mut session = match session_entry.create_session(model) {
                     Ok(session) => session,
                     Err(err) => {
@@ -312,7 +501,7 @@ impl TaskRunner for UnstructuredRunner {
 impl<P: Parser + CreateParserState + Sync + Send + 'static> TaskBuilderReturn for P
 where
     <P as Parser>::Output: Clone + Send + 'static,
-    <P as Parser>::PartialState: Sync + Send,
+    <P as Parser>::PartialState: Sync + Send + 'static,
 {
     type Output = StructuredRunner<P>;
 
@@ -320,8 +509,10 @@ where
         let TaskBuilder {
             system_prompt,
             sampler,
+            generation_parameters,
             constraints,
             examples,
+            context_manager,
         } = task_builder;
 
         let arc_parser = Arc::new(constraints);
@@ -338,11 +529,12 @@ where
             }
         }
 
-        let sessions = TaskSessions::new(system_prompt, examples);
+        let sessions = TaskSessions::new(system_prompt, examples, context_manager);
 
         StructuredRunner {
             sessions: Arc::new(sessions),
             sampler,
+            generation_parameters,
             parser: arc_parser,
         }
     }
@@ -352,6 +544,7 @@ where
 pub struct StructuredRunner<P> {
     sessions: Arc<TaskSessions>,
     sampler: Arc<std::sync::Mutex<dyn Sampler + Send + Sync>>,
+    generation_parameters: Option<GenerationParameters>,
     parser: Arc<P>,
 }
 
@@ -359,7 +552,7 @@ impl<P> TaskRunner for StructuredRunner<P>
 where
     P: Parser + CreateParserState + Sync + Send + 'static,
     <P as Parser>::Output: Clone + Send + 'static,
-    <P as Parser>::PartialState: Sync + Send,
+    <P as Parser>::PartialState: Sync + Send + 'static,
 {
     type Output = StructureParserResult<ChannelTextStream<String>, P::Output>;
 
@@ -371,6 +564,45 @@ where
         let sessions = self.sessions.clone();
         let chat_markers = model.chat_markers();
 
+        // Models that cannot constrain sampling retry completions in the background instead
+        if let Some(model) = model.structured_retry_handle() {
+            let entry = TaskSessionEntry::<()>::new(
+                chat_markers,
+                sessions.system_prompt.clone(),
+                &sessions.examples,
+            );
+            let prompt = entry.cached_prompt.clone() + &entry.task_prompt(&input);
+            let generation_parameters = self.generation_parameters.clone();
+            tokio::spawn(async move {
+                let on_text = move |tok: String| {
+                    tracing::trace!("Task generated token: {}", tok);
+                    tx.send(tok)?;
+                    Ok(())
+                };
+                // A custom sampler can't be sent to the model, so report it instead of silently using the default parameters
+                let result = match generation_parameters {
+                    Some(parameters) => {
+                        model
+                            .generate_structured_with_retries(
+                                &prompt,
+                                arc_parser,
+                                parameters,
+                                StructuredRetry::default(),
+                                on_text,
+                            )
+                            .await
+                    }
+                    None => Err(anyhow::anyhow!(
+                        "This model does not run synchronously, so it does not support a custom sampler. Use TaskBuilder::with_generation_parameters instead"
+                    )),
+                };
+                if parsed_tx.send(result).is_err() {
+                    tracing::error!("Failed to send parsed result");
+                }
+            });
+            return StructureParserResult::new(rx.into(), parsed_rx);
+        }
+
         model.run_sync(move |model| {
             Box::pin(async move {
                 let mut sessions_write = sessions.sessions.write().unwrap();
@@ -380,7 +612,7 @@ where
                         .or_insert_with(|| {
                             Box::new(
                                 TaskSessionEntry::<<M::SyncModel as SyncModel>::Session>::new(
-                                    chat_markers,
+                                    chat_markers.clone(),
                                     sessions.system_prompt.clone(),
                                     &sessions.examples,
                                 ),
@@ -392,6 +624,13 @@ where
                 let span = tracing::span!(tracing::Level::TRACE, "Task session");
                 let _span = span.enter();
 
+                let session_entry = match sessions.fit_to_context(&*model, session_entry, chat_markers, &input, sampler.clone()) {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        tracing::error!("Failed to fit the task into the context window: {}", err);
+                        return;
+                    }
+                };
                 let mut session = match session_entry.create_session( model) {
                     Ok(session) => session,
                     Err(err) => {
@@ -1,45 +1,16 @@
 use kalosm_sample::{ArcParser, ParseResult};
 use kalosm_sample::{
-    CreateParserState, FloatParser, LiteralParser, ParseStatus, Parser, ParserExt,
+    CreateParserState, FloatParser, LiteralParser, ParseStatus, Parser, ParserExt, RecursiveParser,
 };
-use once_cell::sync::Lazy;
 use std::fmt::Debug;
-use std::ops::Deref;
 use std::sync::Arc;
 
 use crate::tool::Tool;
 
 use super::IndexParser;
 
-#[derive(Debug)]
-struct LazyParser<T>(Box<Lazy<T>>);
-
-impl<T: CreateParserState> CreateParserState for LazyParser<T> {
-    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
-        let _self: &T = self.0.deref();
-        _self.create_parser_state()
-    }
-}
-
-impl<T: Parser + Default> Default for LazyParser<T> {
-    fn default() -> Self {
-        Self(Box::new(Lazy::new(Default::default)))
-    }
-}
-
-impl<T: Parser> Parser for LazyParser<T> {
-    type Output = T::Output;
-
-    type PartialState = T::PartialState;
-
-    fn parse<'a>(
-        &self,
-        state: &Self::PartialState,
-        input: &'a [u8],
-    ) -> ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
-        self.0.parse(state, input)
-    }
-}
+/// The maximum number of nested parentheses and function calls in an equation.
+const MAX_EQUATION_DEPTH: usize = 16;
 
 /// A parser for mathematical equations
 pub struct EquationParser {
@@ -57,54 +28,58 @@ impl CreateParserState for EquationParser {
 
 impl Default for EquationParser {
     fn default() -> Self {
-        let number = FloatParser::new(f64::MIN..=f64::MAX);
-        let function = IndexParser::new(vec![
-            LiteralParser::new("sqrt"),
-            LiteralParser::new("abs"),
-            LiteralParser::new("exp"),
-            LiteralParser::new("ln"),
-            LiteralParser::new("sin"),
-            LiteralParser::new("cos"),
-            LiteralParser::new("tan"),
-            LiteralParser::new("asin"),
-            LiteralParser::new("acos"),
-            LiteralParser::new("atan"),
-            LiteralParser::new("atan2"),
-            LiteralParser::new("sinh"),
-            LiteralParser::new("cosh"),
-            LiteralParser::new("tanh"),
-            LiteralParser::new("asinh"),
-            LiteralParser::new("acosh"),
-            LiteralParser::new("atanh"),
-            LiteralParser::new("floor"),
-            LiteralParser::new("ceil"),
-            LiteralParser::new("round"),
-            LiteralParser::new("signum"),
-            LiteralParser::new("pi"),
-            LiteralParser::new("e"),
-        ]);
-
-        let addition = LiteralParser::new(" + ");
-        let subtraction = LiteralParser::new(" - ");
-        let multiplication = LiteralParser::new(" * ");
-        let division = LiteralParser::new(" / ");
-
-        let operation = addition.or(subtraction).or(multiplication).or(division);
-        let binary_operation = LiteralParser::new("(")
-            .then(LazyParser::<Self>::default())
-            .then(operation)
-            .then(LazyParser::<Self>::default())
-            .then(LiteralParser::new(")"));
-
-        let function_call = function
-            .then(LiteralParser::new("("))
-            .then(LazyParser::<Self>::default())
-            .then(LiteralParser::new(")"));
-
-        let expression = number.or(function_call).or(binary_operation);
+        let parser = RecursiveParser::new(MAX_EQUATION_DEPTH, |equation| {
+            let number = FloatParser::new(f64::MIN..=f64::MAX);
+            let function = IndexParser::new(vec![
+                LiteralParser::new("sqrt"),
+                LiteralParser::new("abs"),
+                LiteralParser::new("exp"),
+                LiteralParser::new("ln"),
+                LiteralParser::new("sin"),
+                LiteralParser::new("cos"),
+                LiteralParser::new("tan"),
+                LiteralParser::new("asin"),
+                LiteralParser::new("acos"),
+                LiteralParser::new("atan"),
+                LiteralParser::new("atan2"),
+                LiteralParser::new("sinh"),
+                LiteralParser::new("cosh"),
+                LiteralParser::new("tanh"),
+                LiteralParser::new("asinh"),
+                LiteralParser::new("acosh"),
+                LiteralParser::new("atanh"),
+                LiteralParser::new("floor"),
+                LiteralParser::new("ceil"),
+                LiteralParser::new("round"),
+                LiteralParser::new("signum"),
+                LiteralParser::new("pi"),
+                LiteralParser::new("e"),
+            ]);
+
+            let addition = LiteralParser::new(" + ");
+            let subtraction = LiteralParser::new(" - ");
+            let multiplication = LiteralParser::new(" * ");
+            let division = LiteralParser::new(" / ");
+
+            let operation = addition.or(subtraction).or(multiplication).or(division);
+            let binary_operation = LiteralParser::new("(")
+                .then(equation.clone())
+                .then(operation)
+                .then(equation.clone())
+                .then(LiteralParser::new(")"));
+
+            let function_call = function
+                .then(LiteralParser::new("("))
+                .then(equation)
+                .then(LiteralParser::new(")"));
+
+            let expression = number.or(function_call).or(binary_operation);
+
+            expression.map_output(|_| ())
+        });
 
         Self {
-            parser: expression.map_output(|_| ()).boxed(),
+            parser: parser.boxed(),
         }
     }
 }
@@ -0,0 +1,456 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use proc_macro_crate::{crate_name, FoundCrate};
+use quote::{format_ident, quote};
+use syn::{
+    ext::IdentExt, parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Expr, ExprLit,
+    ExprRange, Fields, Generics, Ident, Lit, LitStr, Meta, RangeLimits, Type,
+};
+
+/// Derive `HasParser` and `HasDescription` for a struct or enum. See the `Parse` re-export in `kalosm-sample` for more information.
+#[proc_macro_derive(Parse, attributes(parse))]
+pub fn derive_parse(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(expanded) => expanded.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let krate = kalosm_sample_path();
+    let ident = &input.ident;
+    let (parser_generics, description_generics) = bounded_generics(&input.generics, &krate);
+    let (impl_generics, ty_generics, parser_where_clause) = parser_generics.split_for_impl();
+    let description_where_clause = &description_generics.where_clause;
+    let attributes = ParseAttributes::from_attributes(&input.attrs)?;
+    attributes.only_rename(ident)?;
+    let doc = doc_comment(&input.attrs);
+
+    let (parser, description) = match &input.data {
+        Data::Struct(data) => {
+            let mut builder = SequenceBuilder::new(&krate);
+            let (construct, description) =
+                push_fields(&mut builder, &data.fields, quote!(Self), &krate)?;
+            (builder.finish(construct), description)
+        }
+        Data::Enum(data) => {
+            let mut parsers = Vec::new();
+            let mut descriptions = Vec::new();
+            for variant in &data.variants {
+                let attributes = ParseAttributes::from_attributes(&variant.attrs)?;
+                attributes.only_rename(&variant.ident)?;
+                let name = attributes
+                    .rename
+                    .unwrap_or_else(|| variant.ident.unraw().to_string());
+                let variant_ident = &variant.ident;
+                let variant_doc = doc_comment(&variant.attrs);
+
+                let mut builder = SequenceBuilder::new(&krate);
+                let description = if let Fields::Unit = variant.fields {
+                    builder.push_literal(format!("{name:?}"));
+                    let description = format!("{name:?}");
+                    quote!(#description.to_string())
+                } else {
+                    // Variants with data are written as an object with a single key like `{"Variant": data}`
+                    builder.push_literal(format!("{{{name:?}: "));
+                    let (construct, description) = push_fields(
+                        &mut builder,
+                        &variant.fields,
+                        quote!(Self::#variant_ident),
+                        &krate,
+                    )?;
+                    builder.push_literal("}".to_string());
+                    parsers.push(builder.finish(construct));
+                    descriptions.push(quote!((#krate::describe_object(&[(#name, #description, "")]), #variant_doc)));
+                    continue;
+                };
+                parsers.push(builder.finish(quote!(Self::#variant_ident)));
+                descriptions.push(quote!((#description, #variant_doc)));
+            }
+
+            let mut parsers = parsers.into_iter().rev();
+            let Some(last) = parsers.next() else {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "Parse cannot be derived for enums without variants",
+                ));
+            };
+            let parser = parsers.fold(last, |rest, parser| {
+                quote! {
+                    #krate::ParserExt::map_output(
+                        #krate::ParserExt::or(#parser, #rest),
+                        |either| match either {
+                            #krate::Either::Left(value) | #krate::Either::Right(value) => value,
+                        },
+                    )
+                }
+            });
+            (
+                parser,
+                quote!(#krate::describe_choices(&[#(#descriptions),*])),
+            )
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "Parse cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #krate::HasParser for #ident #ty_generics #parser_where_clause {
+            type Parser = #krate::ArcParser<Self>;
+
+            fn new_parser() -> Self::Parser {
+                #krate::ParserExt::boxed(#parser)
+            }
+
+            fn create_parser_state() -> <Self::Parser as #krate::Parser>::PartialState {
+                #krate::CreateParserState::create_parser_state(&<Self as #krate::HasParser>::new_parser())
+            }
+        }
+
+        impl #impl_generics #krate::HasDescription for #ident #ty_generics #description_where_clause {
+            fn description() -> String {
+                #krate::describe_with_doc(#description, #doc)
+            }
+        }
+    })
+}
+
+/// Add the bounds the generated impls need to every type parameter. Returns the generics for the `HasParser` impl and the generics for the `HasDescription` impl.
+fn bounded_generics(generics: &Generics, krate: &TokenStream2) -> (Generics, Generics) {
+    let mut parser_generics = generics.clone();
+    let mut description_generics = generics.clone();
+    for param in generics.type_params() {
+        let ident = &param.ident;
+        let predicates = &mut parser_generics.make_where_clause().predicates;
+        predicates.push(parse_quote!(
+            #ident: #krate::HasParser + #krate::HasDescription + ::std::clone::Clone + ::std::marker::Send + ::std::marker::Sync + 'static
+        ));
+        predicates.push(parse_quote!(
+            <#ident as #krate::HasParser>::Parser: #krate::CreateParserState + ::std::marker::Send + ::std::marker::Sync + 'static
+        ));
+        predicates.push(parse_quote!(
+            <<#ident as #krate::HasParser>::Parser as #krate::Parser>::PartialState: ::std::clone::Clone + ::std::marker::Send + ::std::marker::Sync + 'static
+        ));
+        description_generics
+            .make_where_clause()
+            .predicates
+            .push(parse_quote!(#ident: #krate::HasDescription));
+    }
+    (parser_generics, description_generics)
+}
+
+/// Push the parsers for the fields of a struct or variant. Returns the expression that constructs the value from the bindings and the expression that creates the description.
+fn push_fields(
+    builder: &mut SequenceBuilder,
+    fields: &Fields,
+    constructor: TokenStream2,
+    krate: &TokenStream2,
+) -> syn::Result<(TokenStream2, TokenStream2)> {
+    match fields {
+        // Named fields are written as an object like `{"field": value}`
+        Fields::Named(named) => {
+            let mut idents = Vec::new();
+            let mut bindings = Vec::new();
+            let mut descriptions = Vec::new();
+            for (i, field) in named.named.iter().enumerate() {
+                let attributes = ParseAttributes::from_attributes(&field.attrs)?;
+                let ident = field.ident.as_ref().unwrap();
+                let name = attributes
+                    .rename
+                    .clone()
+                    .unwrap_or_else(|| ident.unraw().to_string());
+                let separator = if i == 0 { "{" } else { ", " };
+                builder.push_literal(format!("{separator}{name:?}: "));
+
+                let binding = format_ident!("__field{}", i);
+                builder.push(attributes.parser(&field.ty, krate)?, quote!(#binding));
+                idents.push(ident);
+                bindings.push(binding);
+
+                let ty = &field.ty;
+                let doc = doc_comment(&field.attrs);
+                descriptions
+                    .push(quote!((#name, <#ty as #krate::HasDescription>::description(), #doc)));
+            }
+            builder.push_literal(if idents.is_empty() { "{}" } else { "}" }.to_string());
+
+            Ok((
+                quote!(#constructor { #(#idents: #bindings),* }),
+                quote!(#krate::describe_object(&[#(#descriptions),*])),
+            ))
+        }
+        // A single unnamed field is written as the inner value. More unnamed fields are written as an array like `[value1, value2]`
+        Fields::Unnamed(unnamed) => {
+            let single = unnamed.unnamed.len() == 1;
+            let mut bindings = Vec::new();
+            let mut descriptions = Vec::new();
+            for (i, field) in unnamed.unnamed.iter().enumerate() {
+                let attributes = ParseAttributes::from_attributes(&field.attrs)?;
+                attributes.no_rename(&field.ty)?;
+                if !single {
+                    builder.push_literal(if i == 0 { "[" } else { ", " }.to_string());
+                }
+
+                let binding = format_ident!("__field{}", i);
+                builder.push(attributes.parser(&field.ty, krate)?, quote!(#binding));
+                bindings.push(binding);
+
+                let ty = &field.ty;
+                let doc = doc_comment(&field.attrs);
+                descriptions.push(quote!((<#ty as #krate::HasDescription>::description(), #doc)));
+            }
+            if !single {
+                builder.push_literal(if bindings.is_empty() { "[]" } else { "]" }.to_string());
+            }
+
+            let description = if single {
+                let (description, doc) = (&descriptions[0], doc_comment(&unnamed.unnamed[0].attrs));
+                quote!({
+                    let (description, _) = #description;
+                    #krate::describe_with_doc(description, #doc)
+                })
+            } else {
+                quote!(#krate::describe_array(&[#(#descriptions),*]))
+            };
+            Ok((quote!(#constructor(#(#bindings),*)), description))
+        }
+        // Unit structs are written as `null`
+        Fields::Unit => {
+            builder.push_literal("null".to_string());
+            Ok((constructor, quote!("null".to_string())))
+        }
+    }
+}
+
+/// Builds a chain of sequence parsers and the pattern that destructures their output.
+struct SequenceBuilder {
+    krate: TokenStream2,
+    parser: Option<TokenStream2>,
+    pattern: TokenStream2,
+}
+
+impl SequenceBuilder {
+    fn new(krate: &TokenStream2) -> Self {
+        Self {
+            krate: krate.clone(),
+            parser: None,
+            pattern: quote!(_),
+        }
+    }
+
+    fn push(&mut self, parser: TokenStream2, binding: TokenStream2) {
+        let krate = &self.krate;
+        match self.parser.take() {
+            Some(previous) => {
+                let pattern = &self.pattern;
+                self.parser = Some(quote!(#krate::ParserExt::then(#previous, #parser)));
+                self.pattern = quote!((#pattern, #binding));
+            }
+            None => {
+                self.parser = Some(parser);
+                self.pattern = binding;
+            }
+        }
+    }
+
+    fn push_literal(&mut self, literal: String) {
+        let krate = &self.krate;
+        self.push(quote!(#krate::LiteralParser::from(#literal)), quote!(_));
+    }
+
+    fn finish(self, construct: TokenStream2) -> TokenStream2 {
+        let krate = &self.krate;
+        let parser = self.parser.expect("at least one parser is always pushed");
+        let pattern = &self.pattern;
+        quote!(#krate::ParserExt::map_output(#parser, |#pattern| #construct))
+    }
+}
+
+/// The options in `#[parse(...)]` attributes.
+#[derive(Default)]
+struct ParseAttributes {
+    rename: Option<String>,
+    with: Option<Expr>,
+    range: Option<ExprRange>,
+    pattern: Option<LitStr>,
+}
+
+impl ParseAttributes {
+    fn from_attributes(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("parse")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    parsed.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("with") {
+                    parsed.with = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("range") {
+                    let range = match meta.value()?.parse()? {
+                        Expr::Range(range) => range,
+                        other => {
+                            return Err(syn::Error::new_spanned(
+                                other,
+                                "`range` must be a range like `0..=10` or `0..10`",
+                            ))
+                        }
+                    };
+                    if range.start.is_none() || range.end.is_none() {
+                        return Err(syn::Error::new_spanned(
+                            range,
+                            "`range` must have both a start and an end, like `0..=10`",
+                        ));
+                    }
+                    parsed.range = Some(range);
+                } else if meta.path.is_ident("pattern") {
+                    let pattern: LitStr = meta.value()?.parse()?;
+                    // Check the regex here so mistakes are reported at compile time instead of when the parser is created
+                    if let Err(err) = regex_syntax::parse(&pattern.value()) {
+                        return Err(syn::Error::new_spanned(
+                            pattern,
+                            format!("`pattern` is not a valid regex: {err}"),
+                        ));
+                    }
+                    parsed.pattern = Some(pattern);
+                } else {
+                    return Err(meta.error(
+                        "unknown parse attribute. Expected `rename`, `with`, `range` or `pattern`",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+        let overrides = [
+            parsed.with.is_some(),
+            parsed.range.is_some(),
+            parsed.pattern.is_some(),
+        ];
+        if overrides.into_iter().filter(|set| *set).count() > 1 {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "only one of `with`, `range` or `pattern` can be set for a field",
+            ));
+        }
+        Ok(parsed)
+    }
+
+    /// Check that only `rename` is set on a type or variant.
+    fn only_rename(&self, ident: &Ident) -> syn::Result<()> {
+        if self.with.is_some() || self.range.is_some() || self.pattern.is_some() {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`with`, `range` and `pattern` can only be set on fields",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that `rename` is not set on an unnamed field.
+    fn no_rename(&self, ty: &Type) -> syn::Result<()> {
+        if self.rename.is_some() {
+            return Err(syn::Error::new_spanned(
+                ty,
+                "`rename` can only be set on named fields",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The parser for a field with these attributes.
+    fn parser(&self, ty: &Type, krate: &TokenStream2) -> syn::Result<TokenStream2> {
+        if let Some(with) = &self.with {
+            return Ok(quote!(#with));
+        }
+        if let Some(range) = &self.range {
+            let start = &range.start;
+            let end = &range.end;
+            let inclusive = matches!(range.limits, RangeLimits::Closed(_));
+            return if is_float(ty) {
+                if !inclusive {
+                    return Err(syn::Error::new_spanned(
+                        range,
+                        "float ranges must include the end, like `0.0..=1.0`",
+                    ));
+                }
+                Ok(quote!(#krate::ParserExt::map_output(
+                    #krate::FloatParser::new((#start) as f64..=(#end) as f64),
+                    |value| value as #ty,
+                )))
+            } else {
+                // The integer parser takes an inclusive range, so exclusive ranges end one before the end
+                let end = if inclusive {
+                    quote!((#end) as i128)
+                } else {
+                    quote!((#end) as i128 - 1)
+                };
+                Ok(quote!(#krate::ParserExt::map_output(
+                    #krate::IntegerParser::new((#start) as i128..=#end),
+                    |value| value as #ty,
+                )))
+            };
+        }
+        if let Some(pattern) = &self.pattern {
+            return Ok(quote!(
+                // The syntax of the pattern is checked when the macro is expanded
+                #krate::RegexStringParser::new(#pattern).expect("The pattern is too large to compile")
+            ));
+        }
+        Ok(quote!(<#ty as #krate::HasParser>::new_parser()))
+    }
+}
+
+fn is_float(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "f32" || segment.ident == "f64"),
+        _ => false,
+    }
+}
+
+/// Join the doc comments on an item into a single line.
+fn doc_comment(attrs: &[Attribute]) -> String {
+    attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(meta) if meta.path.is_ident("doc") => match &meta.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(doc), ..
+                }) => Some(doc.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Find the path to kalosm-sample from the crate that uses the macro. It may be used directly or through kalosm-language or kalosm.
+fn kalosm_sample_path() -> TokenStream2 {
+    let path_to = |name: String| {
+        let ident = Ident::new(&name, Span::call_site());
+        quote!(::#ident)
+    };
+    match crate_name("kalosm-sample") {
+        Ok(FoundCrate::Name(name)) => return path_to(name),
+        Ok(FoundCrate::Itself) => return quote!(::kalosm_sample),
+        Err(_) => {}
+    }
+    if let Ok(FoundCrate::Name(name)) = crate_name("kalosm-language") {
+        let krate = path_to(name);
+        return quote!(#krate::kalosm_sample);
+    }
+    if let Ok(FoundCrate::Name(name)) = crate_name("kalosm") {
+        let krate = path_to(name);
+        return quote!(#krate::language);
+    }
+    quote!(::kalosm_sample)
+}
@@ -0,0 +1,85 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kalosm_sample::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+criterion_group!(benches, allowed_tokens);
+criterion_main!(benches);
+
+/// A vocabulary with a similar mix of words, word pieces, numbers and punctuation to a real tokenizer.
+fn vocabulary(size: usize) -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let alphabet =
+        b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789 ,.:;\"'()[]{}-_\n";
+    let mut vocab: Vec<String> = (0..=255u8)
+        .filter(|byte| byte.is_ascii_graphic() || *byte == b' ')
+        .map(|byte| (byte as char).to_string())
+        .collect();
+    while vocab.len() < size {
+        let len = rng.gen_range(2..10);
+        let mut token: String = (0..len)
+            .map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char)
+            .collect();
+        if rng.gen_bool(0.5) {
+            token.insert(0, ' ');
+        }
+        vocab.push(token);
+    }
+    vocab
+}
+
+fn allowed_tokens(c: &mut Criterion) {
+    let vocab = vocabulary(32000);
+    let trie = TokenTrie::new((0..).zip(&vocab));
+
+    let parser = RegexParser::new(r#"\{"name": "[a-zA-Z ]+", "age": \d{1,3}\}"#).unwrap();
+    let state = parser.create_parser_state();
+    let (state, _) = parser
+        .parse(&state, br#"{"name": "Ada"#)
+        .unwrap()
+        .unwrap_incomplete();
+
+    // The path generation used before the trie: parse the text of every token
+    c.bench_function("regex parse every token", |b| {
+        b.iter(|| {
+            vocab
+                .iter()
+                .filter(|text| parser.parse(&state, text.as_bytes()).is_ok())
+                .count()
+        })
+    });
+
+    c.bench_function("regex token trie", |b| {
+        b.iter(|| trie.allowed_tokens(&parser, black_box(&state)).len())
+    });
+
+    c.bench_function("regex cached mask", |b| {
+        b.iter(|| parser.allowed_tokens(black_box(&state), &trie).len())
+    });
+
+    let parser = GrammarParser::new(
+        r#"
+        root ::= expr
+        expr ::= term ((" + " | " * ") term)*
+        term ::= [0-9]+ | "(" expr ")"
+        "#,
+    )
+    .unwrap();
+    let state = parser.create_parser_state();
+    let (state, _) = parser
+        .parse(&state, b"(12 + 3")
+        .unwrap()
+        .unwrap_incomplete();
+
+    c.bench_function("grammar parse every token", |b| {
+        b.iter(|| {
+            vocab
+                .iter()
+                .filter(|text| parser.parse(&state, text.as_bytes()).is_ok())
+                .count()
+        })
+    });
+
+    c.bench_function("grammar token trie", |b| {
+        b.iter(|| parser.allowed_tokens(black_box(&state), &trie).len())
+    });
+}
@@ -23,8 +23,58 @@ use tokenizers::TokenizerImpl;
 #[doc(hidden)]
 pub use anyhow;
 
+// Lets the code generated by the `Parse` derive refer to this crate as `kalosm_sample` from inside the crate
+extern crate self as kalosm_sample;
+
+/// Derive [`HasParser`] and [`HasDescription`] for a struct or enum.
+///
+/// The parser accepts the same JSON-like format as the built in parsers:
+/// - Structs with named fields are written as an object like `{"name": "Ada", "age": 36}`. Fields are written in the order they are declared.
+/// - Tuple structs with one field are written as the inner value. Tuple structs with more fields are written as an array like `["Ada", 36]`.
+/// - Unit structs are written as `null`.
+/// - Unit enum variants are written as a string like `"Dog"`. Variants with data are written as an object with a single key like `{"Dog": {"name": "Rex"}}`.
+///
+/// Every field must implement [`HasDescription`] and [`Clone`]. Fields must also implement [`HasParser`] unless the parser is changed with an attribute.
+///
+/// # Attributes
+/// - `#[parse(rename = "name")]` changes the key of a field or the name of a variant.
+/// - `#[parse(range = 0..=100)]` limits an integer or float field to a range. Integer fields also accept exclusive ranges like `0..100`.
+/// - `#[parse(pattern = "[a-z]+")]` limits a string field to a regex pattern.
+/// - `#[parse(with = parser)]` parses a field with any parser that outputs the field's type.
+///
+/// Doc comments on the type, fields and variants are added to the [`HasDescription::description`] so you can show the model what each field means.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// /// A pet
+/// #[derive(Parse, Clone, Debug, PartialEq)]
+/// struct Pet {
+///     /// The name of the pet
+///     name: String,
+///     #[parse(range = 0..=30)]
+///     age: u8,
+/// }
+///
+/// let parser = Pet::new_parser();
+/// let state = Pet::create_parser_state();
+/// let result = parser.parse(&state, br#"{"name": "Rex", "age": 3}"#).unwrap();
+/// assert_eq!(
+///     result.unwrap_finished(),
+///     Pet {
+///         name: "Rex".to_string(),
+///         age: 3
+///     }
+/// );
+/// println!("{}", Pet::description());
+/// ```
+pub use kalosm_parse_macro::Parse;
+
 mod structured_parser;
 pub use structured_parser::*;
+mod token_trie;
+pub use token_trie::*;
 
 /// A type erased wrapper for a tokenizer.
 pub struct DynTokenizer {
@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ArcParser, Either, FloatParser, GrammarParser, IntegerParser, LiteralParser, ParserExt,
+    RegexParser, SeparatedParser, StringParser,
+};
+
+/// A serializable description of a parser.
+///
+/// Unlike [`ArcParser`], a description can be sent to another process, a remote server or a plugin and built into a parser there with [`ParserDescription::build`]. The built parser only constrains the text that is generated, so its output is `()`.
+///
+/// Descriptions are serialized with a `type` tag. Range bounds that are left out default to the widest range.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let description: ParserDescription = serde_json::from_value(serde_json::json!({
+///     "type": "then",
+///     "parsers": [
+///         { "type": "literal", "value": "Age: " },
+///         { "type": "integer", "min": 0, "max": 150 }
+///     ]
+/// }))
+/// .unwrap();
+/// let parser = description.build().unwrap();
+/// let state = parser.create_parser_state();
+/// assert!(parser.parse(&state, b"Age: 42").is_ok());
+/// assert!(parser.parse(&state, b"Height: 42").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParserDescription {
+    /// Exactly the given text. Built into a [`LiteralParser`].
+    Literal {
+        /// The text to match.
+        value: String,
+    },
+    /// Any one of the options. Built into a chain of [`crate::ChoiceParser`]s.
+    Or {
+        /// The options to choose from.
+        options: Vec<ParserDescription>,
+    },
+    /// Each parser in order. Built into a chain of [`crate::SequenceParser`]s.
+    Then {
+        /// The parsers to run in order.
+        parsers: Vec<ParserDescription>,
+    },
+    /// The parser repeated a number of times. Built into a [`crate::RepeatParser`].
+    Repeat {
+        /// The parser to repeat.
+        parser: Box<ParserDescription>,
+        /// The minimum number of repetitions.
+        #[serde(default)]
+        min: usize,
+        /// The maximum number of repetitions.
+        #[serde(default = "usize_max")]
+        max: usize,
+    },
+    /// The parser repeated a number of times with a separator between each item. Built into a [`SeparatedParser`].
+    Separated {
+        /// The parser for each item.
+        parser: Box<ParserDescription>,
+        /// The parser for the separator between items.
+        separator: Box<ParserDescription>,
+        /// The minimum number of items.
+        #[serde(default)]
+        min: usize,
+        /// The maximum number of items.
+        #[serde(default = "usize_max")]
+        max: usize,
+    },
+    /// An integer in a range. Built into an [`IntegerParser`].
+    Integer {
+        /// The smallest allowed integer.
+        #[serde(default = "i64_min")]
+        min: i64,
+        /// The largest allowed integer.
+        #[serde(default = "i64_max")]
+        max: i64,
+    },
+    /// A number in a range. Built into a [`FloatParser`].
+    Float {
+        /// The smallest allowed number.
+        #[serde(default = "f64_min")]
+        min: f64,
+        /// The largest allowed number.
+        #[serde(default = "f64_max")]
+        max: f64,
+    },
+    /// A quoted JSON string. Built into a [`StringParser`].
+    String {
+        /// The minimum number of characters in the string.
+        #[serde(default)]
+        min_length: usize,
+        /// The maximum number of characters in the string.
+        #[serde(default = "usize_max")]
+        max_length: usize,
+    },
+    /// Text that matches a regex. Built into a [`RegexParser`].
+    Regex {
+        /// The regex pattern.
+        pattern: String,
+    },
+    /// Text that matches a GBNF grammar. Built into a [`GrammarParser`].
+    Grammar {
+        /// The grammar in the GBNF format.
+        grammar: String,
+    },
+}
+
+fn usize_max() -> usize {
+    usize::MAX
+}
+
+fn i64_min() -> i64 {
+    i64::MIN
+}
+
+fn i64_max() -> i64 {
+    i64::MAX
+}
+
+fn f64_min() -> f64 {
+    f64::MIN
+}
+
+fn f64_max() -> f64 {
+    f64::MAX
+}
+
+impl ParserDescription {
+    /// Build the parser the description describes.
+    pub fn build(&self) -> anyhow::Result<ArcParser> {
+        Ok(match self {
+            Self::Literal { value } => LiteralParser::from(value.clone()).boxed(),
+            Self::Or { options } => {
+                let mut options = options.iter().rev();
+                let last = options
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("`or` must have at least one option"))?
+                    .build()?;
+                options.try_fold(last, |rest, option| {
+                    anyhow::Ok(
+                        option
+                            .build()?
+                            .or(rest)
+                            .map_output(|either| match either {
+                                Either::Left(()) | Either::Right(()) => {}
+                            })
+                            .boxed(),
+                    )
+                })?
+            }
+            Self::Then { parsers } => {
+                let mut parsers = parsers.iter().rev();
+                let last = parsers
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("`then` must have at least one parser"))?
+                    .build()?;
+                parsers.try_fold(last, |rest, parser| {
+                    anyhow::Ok(parser.build()?.then(rest).map_output(|_| ()).boxed())
+                })?
+            }
+            Self::Repeat { parser, min, max } => {
+                check_range("repeat", min, max)?;
+                parser
+                    .build()?
+                    .repeat(*min..=*max)
+                    .map_output(|_| ())
+                    .boxed()
+            }
+            Self::Separated {
+                parser,
+                separator,
+                min,
+                max,
+            } => {
+                check_range("separated", min, max)?;
+                SeparatedParser::new(parser.build()?, separator.build()?, *min..=*max)
+                    .map_output(|_| ())
+                    .boxed()
+            }
+            Self::Integer { min, max } => {
+                check_range("integer", min, max)?;
+                IntegerParser::new(*min as i128..=*max as i128)
+                    .map_output(|_| ())
+                    .boxed()
+            }
+            Self::Float { min, max } => {
+                if min.is_nan() || max.is_nan() {
+                    anyhow::bail!("`float` range must not contain NaN");
+                }
+                check_range("float", min, max)?;
+                FloatParser::new(*min..=*max).map_output(|_| ()).boxed()
+            }
+            Self::String {
+                min_length,
+                max_length,
+            } => {
+                check_range("string", min_length, max_length)?;
+                StringParser::new(*min_length..=*max_length)
+                    .map_output(|_| ())
+                    .boxed()
+            }
+            Self::Regex { pattern } => RegexParser::new(pattern)?.boxed(),
+            Self::Grammar { grammar } => GrammarParser::new(grammar)?.map_output(|_| ()).boxed(),
+        })
+    }
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    parser: &str,
+    min: &T,
+    max: &T,
+) -> anyhow::Result<()> {
+    if min > max {
+        anyhow::bail!(
+            "`{parser}` range is empty: the minimum {min} is larger than the maximum {max}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn parser_description() {
+    use crate::{CreateParserState, ParseStatus, Parser};
+
+    let description = ParserDescription::Separated {
+        parser: Box::new(ParserDescription::Or {
+            options: vec![
+                ParserDescription::Literal {
+                    value: "none".to_string(),
+                },
+                ParserDescription::Integer { min: 1, max: 9 },
+            ],
+        }),
+        separator: Box::new(ParserDescription::Literal {
+            value: ", ".to_string(),
+        }),
+        min: 1,
+        max: 3,
+    };
+    let json = serde_json::to_string(&description).unwrap();
+    let deserialized: ParserDescription = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, description);
+
+    let parser = deserialized.build().unwrap();
+    let state = parser.create_parser_state();
+    assert!(matches!(
+        parser.parse(&state, b"none, 4, 7").unwrap(),
+        ParseStatus::Finished { .. }
+    ));
+    assert!(parser.parse(&state, b"some").is_err());
+
+    let defaults: ParserDescription =
+        serde_json::from_str(r#"{ "type": "string", "max_length": 3 }"#).unwrap();
+    assert_eq!(
+        defaults,
+        ParserDescription::String {
+            min_length: 0,
+            max_length: 3
+        }
+    );
+
+    assert!(ParserDescription::Or { options: vec![] }.build().is_err());
+    assert!(ParserDescription::Integer { min: 2, max: 1 }
+        .build()
+        .is_err());
+    assert!(ParserDescription::Regex {
+        pattern: "(".to_string()
+    }
+    .build()
+    .is_err());
+}
@@ -0,0 +1,137 @@
+use std::fmt::Display;
+
+use crate::{ParseStatus, Parser, ParserError};
+
+/// A description of where and why a parser rejected some input.
+///
+/// Only printable ASCII characters, tabs and newlines are checked to find the characters the parser expects. Checking every Unicode character would require parsing over a million inputs, so a parser that only accepts non-ASCII characters (like a literal `"é"`) has no expected characters.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let parser = LiteralParser::from("[").then(IntegerParser::new(0..=100));
+/// let state = parser.create_parser_state();
+/// let diagnostic = ParseDiagnostic::new(&parser, &state, b"[a").unwrap();
+/// assert_eq!(diagnostic.offset(), 1);
+/// assert_eq!(diagnostic.path(), ["then", "integer"]);
+/// assert!(diagnostic.expected().contains(&'7'));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ParseDiagnostic {
+    offset: usize,
+    path: Vec<&'static str>,
+    expected: Vec<char>,
+    error: Option<ParserError>,
+}
+
+impl ParseDiagnostic {
+    /// Find where the parser rejects the input from the given state. Returns `None` if the parser accepts the whole input or finishes before the end of it.
+    pub fn new<P: Parser + ?Sized>(
+        parser: &P,
+        state: &P::PartialState,
+        input: &[u8],
+    ) -> Option<Self> {
+        let mut current = None;
+        // Feed the input one byte at a time to find the first byte the parser rejects
+        for (offset, byte) in input.iter().enumerate() {
+            let state = current.as_ref().unwrap_or(state);
+            match parser.parse(state, std::slice::from_ref(byte)) {
+                Ok(ParseStatus::Incomplete { new_state, .. }) => current = Some(new_state),
+                Ok(ParseStatus::Finished { .. }) => return None,
+                Err(error) => {
+                    let mut diagnostic = Self::at_state(parser, state);
+                    diagnostic.offset = offset;
+                    diagnostic.path = error.path().to_vec();
+                    diagnostic.error = Some(error);
+                    return Some(diagnostic);
+                }
+            }
+        }
+        None
+    }
+
+    /// Describe what the parser accepts next from the given state without any rejected input. Like [`ParseDiagnostic::expected`], only ASCII characters are checked.
+    pub fn at_state<P: Parser + ?Sized>(parser: &P, state: &P::PartialState) -> Self {
+        let expected = ('\t'..='\n')
+            .chain(' '..='~')
+            .filter(|character| parser.parse(state, &[*character as u8]).is_ok())
+            .collect();
+        Self {
+            offset: 0,
+            path: Vec::new(),
+            expected,
+            error: None,
+        }
+    }
+
+    /// The number of bytes of the input the parser accepted before it rejected the next byte.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The names of the parsers the error passed through, from the outermost parser to the parser that rejected the input.
+    pub fn path(&self) -> &[&'static str] {
+        &self.path
+    }
+
+    /// The ASCII characters the parser would have accepted at the offset. Non-ASCII characters are never included, even if the parser accepts them.
+    pub fn expected(&self) -> &[char] {
+        &self.expected
+    }
+
+    /// The error the parser returned, if it rejected any input.
+    pub fn error(&self) -> Option<&ParserError> {
+        self.error.as_ref()
+    }
+}
+
+impl Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(error) = &self.error {
+            write!(f, "Input rejected at byte {}", self.offset)?;
+            if !self.path.is_empty() {
+                write!(f, " in `{}`", self.path.join(" -> "))?;
+            }
+            write!(f, ": {}. ", &**error)?;
+        }
+        if self.expected.is_empty() {
+            write!(f, "No ASCII character is accepted")
+        } else {
+            write!(f, "Expected one of ")?;
+            for (i, character) in self.expected.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{character:?}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn parse_diagnostic() {
+    use crate::{CreateParserState, LiteralParser, ParserExt, SeparatedParser, StringParser};
+
+    let parser = LiteralParser::from("[").then(SeparatedParser::new(
+        StringParser::new(0..=3),
+        LiteralParser::from(", "),
+        1..=3,
+    ));
+    let state = parser.create_parser_state();
+
+    assert!(ParseDiagnostic::new(&parser, &state, br#"["ab", "c"#).is_none());
+
+    let diagnostic = ParseDiagnostic::new(&parser, &state, br#"["ab", "abcd""#).unwrap();
+    assert_eq!(diagnostic.offset(), 11);
+    assert_eq!(diagnostic.path(), ["then", "separated", "string"]);
+    assert_eq!(diagnostic.expected(), ['"']);
+    assert!(diagnostic
+        .to_string()
+        .contains("`then -> separated -> string`"));
+
+    let diagnostic = ParseDiagnostic::at_state(&parser, &state);
+    assert_eq!(diagnostic.expected(), ['[']);
+    assert!(diagnostic.error().is_none());
+}
@@ -1,4 +1,4 @@
-use crate::{CreateParserState, ParseStatus, Parser};
+use crate::{CreateParserState, ParseStatus, Parser, ParserError};
 use std::ops::RangeInclusive;
 
 #[derive(Debug, PartialEq, Eq, Default, Copy, Clone)]
@@ -67,9 +67,9 @@ impl CreateParserState for FloatParser {
 impl FloatParser {
     fn sign_valid(&self, positive: bool) -> bool {
         if positive {
-            *self.range.start() >= 0.0
+            *self.range.end() >= 0.0
         } else {
-            *self.range.end() <= 0.0
+            *self.range.start() <= 0.0
         }
     }
 
@@ -222,7 +222,7 @@ impl Parser for FloatParser {
                         || state == FloatParserProgress::AfterSign)
                         && input_byte == b'0'
                     {
-                        crate::bail!(LeadingZeroError);
+                        crate::bail!(ParserError::from(LeadingZeroError).in_parser("float"));
                     }
                     input_byte - b'0'
                 }
@@ -232,10 +232,10 @@ impl Parser for FloatParser {
                     let end_digits = self.range.end().abs().log10() + 1.;
                     if positive {
                         if value_digits > end_digits {
-                            crate::bail!(OutOfRangeError);
+                            crate::bail!(ParserError::from(OutOfRangeError).in_parser("float"));
                         }
                     } else if value_digits > start_digits {
-                        crate::bail!(OutOfRangeError);
+                        crate::bail!(ParserError::from(OutOfRangeError).in_parser("float"));
                     }
                     if state == FloatParserProgress::AfterDigit {
                         state = FloatParserProgress::AfterDecimalPoint {
@@ -243,7 +243,7 @@ impl Parser for FloatParser {
                         };
                         continue;
                     } else {
-                        crate::bail!(InvalidDecimalLocation);
+                        crate::bail!(ParserError::from(InvalidDecimalLocation).in_parser("float"));
                     }
                 }
                 b'+' | b'-' => {
@@ -252,11 +252,11 @@ impl Parser for FloatParser {
                         positive = input_byte == b'+';
 
                         if !self.sign_valid(positive) {
-                            crate::bail!(InvalidSignLocation);
+                            crate::bail!(ParserError::from(InvalidSignLocation).in_parser("float"));
                         }
                         continue;
                     } else {
-                        crate::bail!(InvalidSignLocation);
+                        crate::bail!(ParserError::from(InvalidSignLocation).in_parser("float"));
                     }
                 }
                 _ => {
@@ -273,7 +273,7 @@ impl Parser for FloatParser {
                             remaining: &input[index..],
                         });
                     } else {
-                        crate::bail!(EmptyNumber)
+                        crate::bail!(ParserError::from(EmptyNumber).in_parser("float"))
                     }
                 }
             };
@@ -294,7 +294,7 @@ impl Parser for FloatParser {
                         value * if positive { 1.0 } else { -1.0 },
                         FloatParserProgress::AfterDigit,
                     ) {
-                        crate::bail!(OutOfRangeError);
+                        crate::bail!(ParserError::from(OutOfRangeError).in_parser("float"));
                     }
                 }
                 FloatParserProgress::AfterDecimalPoint {
@@ -311,7 +311,7 @@ impl Parser for FloatParser {
                             *digits_after_decimal_point,
                         )
                     {
-                        crate::bail!(OutOfRangeError);
+                        crate::bail!(ParserError::from(OutOfRangeError).in_parser("float"));
                     }
                 }
             }
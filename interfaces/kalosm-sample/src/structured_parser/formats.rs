@@ -0,0 +1,400 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Deref,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use crate::{
+    CreateParserState, HasDescription, HasParser, ParseStatus, Parser, ParserError,
+    RegexStringParser, RegexStringParserState,
+};
+
+/// A parser for a JSON string with a well known format like a date or a URL.
+///
+/// The syntax of the string is checked with a regex as it is generated. Rules the regex cannot express cheaply, like the number of days in a month, are checked against the partial text with `is_valid_prefix`. Once the string is finished, `parse` converts it into the output type.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let parser = <std::net::Ipv4Addr as HasParser>::new_parser();
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, br#""192.168.0.1""#).unwrap();
+/// assert_eq!(result.unwrap_finished(), std::net::Ipv4Addr::new(192, 168, 0, 1));
+/// assert!(parser.parse(&state, br#""256"#).is_err());
+/// ```
+pub struct FormattedStringParser<T> {
+    regex: Arc<RegexStringParser>,
+    is_valid_prefix: fn(&str) -> bool,
+    parse: fn(&str) -> Option<T>,
+}
+
+impl<T> Clone for FormattedStringParser<T> {
+    fn clone(&self) -> Self {
+        Self {
+            regex: self.regex.clone(),
+            is_valid_prefix: self.is_valid_prefix,
+            parse: self.parse,
+        }
+    }
+}
+
+impl<T> FormattedStringParser<T> {
+    /// Create a new parser for strings that match the regex. Every string the regex and `is_valid_prefix` accept must be accepted by `parse`, or generation can get stuck at the end of the string.
+    pub fn new(
+        regex: &str,
+        is_valid_prefix: fn(&str) -> bool,
+        parse: fn(&str) -> Option<T>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            regex: Arc::new(RegexStringParser::new(regex)?),
+            is_valid_prefix,
+            parse,
+        })
+    }
+
+    /// Create a new parser for strings that match the regex and can be parsed with [`FromStr`].
+    pub fn parsed(regex: &str) -> anyhow::Result<Self>
+    where
+        T: FromStr,
+    {
+        Self::new(regex, |_| true, |text| text.parse().ok())
+    }
+}
+
+/// An error that can occur while parsing a string with a well known format.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct InvalidFormatError;
+
+impl Display for InvalidFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "String is not valid for the format")
+    }
+}
+
+impl std::error::Error for InvalidFormatError {}
+
+impl<T> CreateParserState for FormattedStringParser<T> {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        self.regex.create_parser_state()
+    }
+}
+
+impl<T> Parser for FormattedStringParser<T> {
+    type Output = T;
+    type PartialState = RegexStringParserState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        match self.regex.parse(state, input) {
+            Ok(ParseStatus::Finished { result, remaining }) => match (self.parse)(&result) {
+                Some(result) => Ok(ParseStatus::Finished { result, remaining }),
+                None => crate::bail!(ParserError::from(InvalidFormatError).in_parser("format")),
+            },
+            Ok(ParseStatus::Incomplete {
+                new_state,
+                required_next,
+            }) => {
+                if !(self.is_valid_prefix)(new_state.text()) {
+                    crate::bail!(ParserError::from(InvalidFormatError).in_parser("format"));
+                }
+                Ok(ParseStatus::Incomplete {
+                    new_state,
+                    required_next,
+                })
+            }
+            Err(err) => Err(err.in_parser("format")),
+        }
+    }
+}
+
+macro_rules! formatted_string {
+    ($ty:ty, $description:literal, $parser:expr) => {
+        impl HasParser for $ty {
+            type Parser = FormattedStringParser<$ty>;
+
+            fn new_parser() -> Self::Parser {
+                // The regex is only compiled the first time the parser is needed
+                static PARSER: OnceLock<FormattedStringParser<$ty>> = OnceLock::new();
+                PARSER.get_or_init(|| $parser.unwrap()).clone()
+            }
+
+            fn create_parser_state() -> <Self::Parser as Parser>::PartialState {
+                Self::new_parser().create_parser_state()
+            }
+        }
+
+        impl HasDescription for $ty {
+            fn description() -> String {
+                concat!("string // ", $description).to_string()
+            }
+        }
+    };
+}
+
+const IPV4: &str =
+    r"(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)";
+
+const IPV6: &str = concat!(
+    r"(?:[0-9a-fA-F]{1,4}:){7}[0-9a-fA-F]{1,4}",
+    r"|(?:[0-9a-fA-F]{1,4}:){1,7}:",
+    r"|(?:[0-9a-fA-F]{1,4}:){1,6}:[0-9a-fA-F]{1,4}",
+    r"|(?:[0-9a-fA-F]{1,4}:){1,5}(?::[0-9a-fA-F]{1,4}){1,2}",
+    r"|(?:[0-9a-fA-F]{1,4}:){1,4}(?::[0-9a-fA-F]{1,4}){1,3}",
+    r"|(?:[0-9a-fA-F]{1,4}:){1,3}(?::[0-9a-fA-F]{1,4}){1,4}",
+    r"|(?:[0-9a-fA-F]{1,4}:){1,2}(?::[0-9a-fA-F]{1,4}){1,5}",
+    r"|[0-9a-fA-F]{1,4}:(?::[0-9a-fA-F]{1,4}){1,6}",
+    r"|:(?:(?::[0-9a-fA-F]{1,4}){1,7}|:)",
+);
+
+formatted_string!(
+    Ipv4Addr,
+    "an IPv4 address like \"192.168.0.1\"",
+    FormattedStringParser::parsed(IPV4)
+);
+formatted_string!(
+    Ipv6Addr,
+    "an IPv6 address like \"2001:db8::1\"",
+    FormattedStringParser::parsed(IPV6)
+);
+formatted_string!(
+    IpAddr,
+    "an IP address like \"192.168.0.1\" or \"2001:db8::1\"",
+    FormattedStringParser::parsed(&format!("{IPV4}|{IPV6}"))
+);
+
+/// An email address like `ada@example.com`.
+///
+/// The address must match the definition of a [valid email address](https://html.spec.whatwg.org/multipage/input.html#valid-e-mail-address) in the HTML standard.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Email(String);
+
+impl Email {
+    /// Create a new email address if the text is a valid email address.
+    pub fn new(email: impl Into<String>) -> Option<Self> {
+        let email = email.into();
+        email_regex().is_match(&email).then_some(Self(email))
+    }
+}
+
+const EMAIL: &str = r"[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*";
+
+fn email_regex() -> &'static regex_automata::meta::Regex {
+    static REGEX: OnceLock<regex_automata::meta::Regex> = OnceLock::new();
+    REGEX.get_or_init(|| regex_automata::meta::Regex::new(&format!("^(?:{EMAIL})$")).unwrap())
+}
+
+impl Deref for Email {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for Email {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<Email> for String {
+    fn from(email: Email) -> Self {
+        email.0
+    }
+}
+
+formatted_string!(
+    Email,
+    "an email address like \"ada@example.com\"",
+    FormattedStringParser::new(EMAIL, |_| true, |text| Some(Email(text.to_string())))
+);
+
+#[cfg(feature = "uuid")]
+formatted_string!(
+    uuid::Uuid,
+    "a UUID like \"67e55044-10b1-426f-9247-bb680e5fe0c8\"",
+    FormattedStringParser::new(
+        r"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+        |_| true,
+        |text| uuid::Uuid::parse_str(text).ok()
+    )
+);
+
+#[cfg(feature = "url")]
+const URL: &str = concat!(
+    // Scheme and host
+    r"https?://[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?)*",
+    // Port
+    r"(?::(?:6553[0-5]|655[0-2]\d|65[0-4]\d{2}|6[0-4]\d{3}|[1-5]\d{4}|\d{1,4}))?",
+    // Path, query and fragment
+    r"(?:/(?:[a-zA-Z0-9._~!$&'()*+,;=:@-]|%[0-9a-fA-F]{2})*)*",
+    r"(?:\?(?:[a-zA-Z0-9._~!$&'()*+,;=:@/?-]|%[0-9a-fA-F]{2})*)?",
+    r"(?:#(?:[a-zA-Z0-9._~!$&'()*+,;=:@/?-]|%[0-9a-fA-F]{2})*)?",
+);
+
+#[cfg(feature = "url")]
+formatted_string!(
+    url::Url,
+    "an http or https URL like \"https://example.com/path\"",
+    FormattedStringParser::new(URL, |_| true, |text| url::Url::parse(text).ok())
+);
+
+#[cfg(feature = "chrono")]
+const DATE: &str = r"\d{4}-(?:0[1-9]|1[0-2])-(?:0[1-9]|[12]\d|3[01])";
+
+#[cfg(feature = "chrono")]
+const TIME: &str = r"(?:[01]\d|2[0-3]):[0-5]\d:[0-5]\d(?:\.\d{1,9})?";
+
+/// Check if the partial text of a `YYYY-MM-DD` date can still become a real date. The regex only limits the day to 31.
+#[cfg(feature = "chrono")]
+fn is_valid_date_prefix(text: &str) -> bool {
+    let text = text.as_bytes();
+    let digits = |range: std::ops::Range<usize>| {
+        text[range]
+            .iter()
+            .fold(0, |value, digit| value * 10 + (digit - b'0') as u32)
+    };
+    if text.len() < 9 {
+        return true;
+    }
+    let year = digits(0..4);
+    let month = digits(5..7);
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    // The smallest day that starts with the digits written so far
+    let smallest_day = match text.get(9) {
+        Some(_) => digits(8..10),
+        None => digits(8..9) * 10,
+    };
+    smallest_day <= days_in_month
+}
+
+#[cfg(feature = "chrono")]
+formatted_string!(
+    chrono::NaiveDate,
+    "a date like \"2024-01-31\"",
+    FormattedStringParser::new(DATE, is_valid_date_prefix, |text| {
+        chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()
+    })
+);
+
+#[cfg(feature = "chrono")]
+formatted_string!(
+    chrono::NaiveTime,
+    "a time like \"13:45:00\"",
+    FormattedStringParser::new(
+        TIME,
+        |_| true,
+        |text| { chrono::NaiveTime::parse_from_str(text, "%H:%M:%S%.f").ok() }
+    )
+);
+
+#[cfg(feature = "chrono")]
+formatted_string!(
+    chrono::DateTime<chrono::FixedOffset>,
+    "an RFC 3339 date and time like \"2024-01-31T13:45:00+01:00\"",
+    FormattedStringParser::new(
+        &format!(r"{DATE}T{TIME}(?:Z|[+-](?:[01]\d|2[0-3]):[0-5]\d)"),
+        is_valid_date_prefix,
+        |text| chrono::DateTime::parse_from_rfc3339(text).ok()
+    )
+);
+
+#[cfg(feature = "chrono")]
+formatted_string!(
+    chrono::DateTime<chrono::Utc>,
+    "an RFC 3339 date and time like \"2024-01-31T13:45:00Z\"",
+    FormattedStringParser::new(
+        &format!(r"{DATE}T{TIME}(?:Z|[+-](?:[01]\d|2[0-3]):[0-5]\d)"),
+        is_valid_date_prefix,
+        |text| {
+            chrono::DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|date| date.with_timezone(&chrono::Utc))
+        }
+    )
+);
+
+#[test]
+fn formatted_string_parsers() {
+    fn parse<T: HasParser>(text: &str) -> Option<T> {
+        let parser = T::new_parser();
+        let state = T::create_parser_state();
+        // Feed the text one byte at a time to check the partial text at every step
+        let mut state = state;
+        for (i, byte) in text.bytes().enumerate() {
+            match parser.parse(&state, &[byte]).ok()? {
+                ParseStatus::Incomplete { new_state, .. } => state = new_state,
+                ParseStatus::Finished { result, .. } => {
+                    return (i == text.len() - 1).then_some(result)
+                }
+            }
+        }
+        None
+    }
+
+    assert_eq!(
+        parse::<IpAddr>(r#""10.0.0.255""#),
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 255)))
+    );
+    assert_eq!(parse::<Ipv4Addr>(r#""10.0.0.256""#), None);
+    assert_eq!(parse::<Ipv4Addr>(r#""010.0.0.1""#), None);
+    assert_eq!(
+        parse::<Ipv6Addr>(r#""2001:db8::1""#),
+        Some("2001:db8::1".parse().unwrap())
+    );
+    assert_eq!(parse::<Ipv6Addr>(r#""2001:db8:::1""#), None);
+
+    assert_eq!(
+        parse::<Email>(r#""ada.lovelace+notes@example.co.uk""#).as_deref(),
+        Some("ada.lovelace+notes@example.co.uk")
+    );
+    assert_eq!(parse::<Email>(r#""ada@@example.com""#), None);
+    assert_eq!(parse::<Email>(r#""@example.com""#), None);
+    assert!(Email::new("ada@example.com").is_some());
+    assert!(Email::new("ada at example.com").is_none());
+
+    #[cfg(feature = "uuid")]
+    {
+        assert_eq!(
+            parse::<uuid::Uuid>(r#""67e55044-10b1-426f-9247-bb680e5fe0c8""#),
+            Some(uuid::Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap())
+        );
+        assert!(parse::<uuid::Uuid>(r#""67e55044-10b1-426f-9247-bb680e5fe0c""#).is_none());
+        assert!(parse::<uuid::Uuid>(r#""67e55044-10b1-426f-9247-bb680e5fe0cg""#).is_none());
+    }
+
+    #[cfg(feature = "url")]
+    {
+        assert_eq!(
+            parse::<url::Url>(r#""https://example.com:8080/path/to?query=1#top""#),
+            Some(url::Url::parse("https://example.com:8080/path/to?query=1#top").unwrap())
+        );
+        assert!(parse::<url::Url>(r#""ftp://example.com""#).is_none());
+        assert!(parse::<url::Url>(r#""https://-example.com""#).is_none());
+        assert!(parse::<url::Url>(r#""https://example.com:65536""#).is_none());
+        assert!(parse::<url::Url>(r#""https://example.com/a b""#).is_none());
+    }
+
+    #[cfg(feature = "chrono")]
+    {
+        assert!(parse::<chrono::NaiveDate>(r#""2024-02-29""#).is_some());
+        assert!(parse::<chrono::NaiveDate>(r#""2023-02-29""#).is_none());
+        assert!(parse::<chrono::NaiveDate>(r#""2023-04-31""#).is_none());
+        assert!(parse::<chrono::NaiveDate>(r#""2023-13-01""#).is_none());
+        assert!(
+            parse::<chrono::DateTime<chrono::Utc>>(r#""2024-01-31T13:45:00.5+01:00""#).is_some()
+        );
+    }
+}
@@ -0,0 +1,881 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use rustc_hash::FxHashSet;
+
+use crate::{CreateParserState, ParseStatus, Parser, ParserError};
+
+/// The maximum number of characters [`GrammarParser`] will look ahead to find the text that is required next.
+const MAX_REQUIRED_NEXT: usize = 64;
+
+/// A parser for text that matches a context-free grammar written in the [GBNF](https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md) format used by llama.cpp.
+///
+/// Unlike [`crate::RegexParser`], grammars can describe nested structures like JSON, arithmetic expressions, SQL queries or small DSLs. Parsing starts at the `root` rule.
+///
+/// The grammar supports:
+/// - Rules like `name ::= ...`
+/// - String literals like `"text"` with `\n`, `\t`, `\r`, `\\`, `\"`, `\xHH`, `\uHHHH` and `\UHHHHHHHH` escapes
+/// - Character classes like `[a-z0-9_]` or `[^"\\]`, and `.` for any character
+/// - Alternatives with `|` and groups with `(...)`
+/// - Repetition with `*`, `+`, `?`, `{n}`, `{m,}` and `{m,n}`
+/// - Comments that start with `#`
+///
+/// Left recursive rules like `expr ::= expr "+" term` are not supported. They can be rewritten with repetition like `expr ::= term ("+" term)*`.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let parser = GrammarParser::new(
+///     r#"
+///     root ::= expr
+///     expr ::= term (("+" | "-") term)*
+///     term ::= [0-9]+ | "(" expr ")"
+///     "#,
+/// )
+/// .unwrap();
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, b"(1+2)-3\n").unwrap();
+/// assert_eq!(
+///     result,
+///     ParseStatus::Finished {
+///         result: "(1+2)-3".to_string(),
+///         remaining: b"\n",
+///     }
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct GrammarParser {
+    rules: Vec<Vec<Vec<Element>>>,
+    start: Vec<Vec<Position>>,
+}
+
+impl GrammarParser {
+    /// Create a new `GrammarParser` from a GBNF grammar.
+    pub fn new(grammar: &str) -> anyhow::Result<Self> {
+        let tokens = tokenize(grammar)?;
+        let mut builder = GrammarBuilder::default();
+        let mut position = 0;
+        while position < tokens.len() {
+            let (Token::Name(name), Some(Token::Define)) =
+                (&tokens[position], tokens.get(position + 1))
+            else {
+                anyhow::bail!("Expected a rule definition like `name ::= ...`");
+            };
+            position += 2;
+            let id = builder.rule_id(name);
+            if builder.rules[id].is_some() {
+                anyhow::bail!("The rule `{name}` is defined more than once");
+            }
+            let alternatives = builder.parse_alternatives(&tokens, &mut position, name)?;
+            if tokens.get(position) == Some(&Token::Close) {
+                anyhow::bail!("Unexpected `)` in the rule `{name}`");
+            }
+            builder.rules[id] = Some(alternatives);
+        }
+
+        let root = *builder
+            .ids
+            .get("root")
+            .ok_or_else(|| anyhow::anyhow!("The grammar must define a `root` rule"))?;
+        let mut rules = Vec::with_capacity(builder.rules.len());
+        for (rule, name) in builder.rules.into_iter().zip(&builder.names) {
+            rules.push(
+                rule.ok_or_else(|| anyhow::anyhow!("The rule `{name}` is used but never defined"))?,
+            );
+        }
+        check_left_recursion(&rules, &builder.names)?;
+
+        let mut parser = Self {
+            rules,
+            start: Vec::new(),
+        };
+        let mut start = FxHashSet::default();
+        for alternative in 0..parser.rules[root].len() {
+            parser.expand(
+                vec![Position {
+                    rule: root,
+                    alternative,
+                    index: 0,
+                }],
+                &mut start,
+            );
+        }
+        parser.start = sorted(start);
+
+        Ok(parser)
+    }
+
+    /// Expand rule references at the top of the stack until every stack either ends with a character or is empty.
+    fn expand(&self, mut stack: Vec<Position>, stacks: &mut FxHashSet<Vec<Position>>) {
+        let Some(top) = stack.last().copied() else {
+            stacks.insert(stack);
+            return;
+        };
+        let sequence = &self.rules[top.rule][top.alternative];
+        match sequence.get(top.index) {
+            None => {
+                stack.pop();
+                self.expand(stack, stacks);
+            }
+            Some(Element::Chars { .. }) => {
+                stacks.insert(stack);
+            }
+            Some(Element::Rule(rule)) => {
+                stack.pop();
+                // Only keep the current position if there is more to parse. This keeps the stack from growing with right recursion
+                if top.index + 1 < sequence.len() {
+                    stack.push(Position {
+                        index: top.index + 1,
+                        ..top
+                    });
+                }
+                for alternative in 0..self.rules[*rule].len() {
+                    let mut stack = stack.clone();
+                    stack.push(Position {
+                        rule: *rule,
+                        alternative,
+                        index: 0,
+                    });
+                    self.expand(stack, stacks);
+                }
+            }
+        }
+    }
+
+    /// Advance every stack that accepts the character.
+    fn advance(&self, stacks: &[Vec<Position>], character: char) -> Vec<Vec<Position>> {
+        let mut advanced = FxHashSet::default();
+        for stack in stacks {
+            let Some(top) = stack.last().copied() else {
+                continue;
+            };
+            let sequence = &self.rules[top.rule][top.alternative];
+            match sequence.get(top.index) {
+                Some(element @ Element::Chars { .. }) if element.matches(character) => {}
+                _ => continue,
+            }
+            let mut stack = stack.clone();
+            stack.pop();
+            if top.index + 1 < sequence.len() {
+                stack.push(Position {
+                    index: top.index + 1,
+                    ..top
+                });
+            }
+            self.expand(stack, &mut advanced);
+        }
+        sorted(advanced)
+    }
+
+    /// Check if any stack could accept a character that starts with the byte.
+    fn could_accept(&self, stacks: &[Vec<Position>], byte: u8) -> bool {
+        let Some((start, end)) = utf8_character_range(byte) else {
+            return false;
+        };
+        stacks.iter().any(|stack| {
+            stack.last().is_some_and(|top| {
+                self.rules[top.rule][top.alternative][top.index].matches_any(start, end)
+            })
+        })
+    }
+
+    /// Find the text every stack requires next.
+    fn required_next(&self, stacks: &[Vec<Position>]) -> String {
+        let mut required_next = String::new();
+        let mut stacks = stacks.to_vec();
+        while required_next.len() < MAX_REQUIRED_NEXT {
+            let mut next = None;
+            for stack in &stacks {
+                // If the grammar can end here, nothing else is required
+                let Some(top) = stack.last() else {
+                    return required_next;
+                };
+                let character = match &self.rules[top.rule][top.alternative][top.index] {
+                    Element::Chars {
+                        ranges,
+                        negated: false,
+                    } if ranges.len() == 1 && ranges[0].0 == ranges[0].1 => ranges[0].0,
+                    _ => return required_next,
+                };
+                if next.is_some_and(|next| next != character) {
+                    return required_next;
+                }
+                next = Some(character);
+            }
+            let Some(next) = next else {
+                break;
+            };
+            required_next.push(next);
+            stacks = self.advance(&stacks, next);
+        }
+        required_next
+    }
+}
+
+/// The state of a [`GrammarParser`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GrammarParserState {
+    stacks: Vec<Vec<Position>>,
+    text: String,
+    partial_character: Vec<u8>,
+}
+
+/// An error that can occur while parsing text that must match a grammar.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct GrammarMismatchError;
+
+impl std::fmt::Display for GrammarMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Text does not match the grammar")
+    }
+}
+
+impl std::error::Error for GrammarMismatchError {}
+
+impl CreateParserState for GrammarParser {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        GrammarParserState {
+            stacks: self.start.clone(),
+            text: String::new(),
+            partial_character: Vec::new(),
+        }
+    }
+}
+
+impl Parser for GrammarParser {
+    type Output = String;
+    type PartialState = GrammarParserState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        let mut state = state.clone();
+        // The index in this input where the current character starts, or None if it started in an earlier call
+        let mut character_start = None;
+        for (i, byte) in input.iter().enumerate() {
+            if state.partial_character.is_empty() {
+                character_start = Some(i);
+                // Decide if the grammar ends here from the first byte of the character. The bytes of a character that started in an earlier call can't be returned to the next parser
+                if state.stacks.iter().any(Vec::is_empty)
+                    && !self.could_accept(&state.stacks, *byte)
+                {
+                    return Ok(ParseStatus::Finished {
+                        result: state.text,
+                        remaining: &input[i..],
+                    });
+                }
+            }
+            state.partial_character.push(*byte);
+            let character = match std::str::from_utf8(&state.partial_character) {
+                Ok(text) => text.chars().next().unwrap(),
+                // Wait for the rest of the character
+                Err(err) if err.error_len().is_none() => continue,
+                Err(err) => crate::bail!(ParserError::from(err).in_parser("grammar")),
+            };
+
+            let stacks = self.advance(&state.stacks, character);
+            if stacks.is_empty() {
+                // If the grammar can end before this character, the rest of the input is left for the next parser
+                if let (true, Some(start)) =
+                    (state.stacks.iter().any(Vec::is_empty), character_start)
+                {
+                    return Ok(ParseStatus::Finished {
+                        result: state.text,
+                        remaining: &input[start..],
+                    });
+                }
+                crate::bail!(ParserError::from(GrammarMismatchError).in_parser("grammar"));
+            }
+            state.stacks = stacks;
+            state.text.push(character);
+            state.partial_character.clear();
+        }
+
+        if state.partial_character.is_empty() && state.stacks.iter().all(Vec::is_empty) {
+            return Ok(ParseStatus::Finished {
+                result: state.text,
+                remaining: &[],
+            });
+        }
+
+        let required_next = if state.partial_character.is_empty() {
+            self.required_next(&state.stacks)
+        } else {
+            String::new()
+        };
+        Ok(ParseStatus::Incomplete {
+            new_state: state,
+            required_next: required_next.into(),
+        })
+    }
+}
+
+/// A position in a sequence of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Position {
+    rule: usize,
+    alternative: usize,
+    index: usize,
+}
+
+/// An element in a sequence of a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    /// A single character in (or not in) a list of ranges.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// A reference to another rule.
+    Rule(usize),
+}
+
+impl Element {
+    fn character(character: char) -> Self {
+        Self::Chars {
+            ranges: vec![(character, character)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, character: char) -> bool {
+        match self {
+            Self::Chars { ranges, negated } => {
+                ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&character))
+                    != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+
+    /// Check if the element matches any character in the range of code points.
+    fn matches_any(&self, start: u32, end: u32) -> bool {
+        match self {
+            Self::Chars {
+                ranges,
+                negated: false,
+            } => ranges
+                .iter()
+                .any(|(first, last)| *first as u32 <= end && *last as u32 >= start),
+            Self::Chars {
+                ranges,
+                negated: true,
+            } => {
+                // Look for a code point in the range that none of the ranges cover
+                let mut ranges: Vec<_> = ranges
+                    .iter()
+                    .map(|(first, last)| (*first as u32, *last as u32))
+                    .collect();
+                ranges.sort();
+                let mut uncovered = start;
+                for (first, last) in ranges {
+                    if first > uncovered {
+                        break;
+                    }
+                    uncovered = uncovered.max(last.saturating_add(1));
+                }
+                uncovered <= end
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+/// The range of code points of the characters that start with a byte in UTF-8, or None if the byte can't start a character.
+fn utf8_character_range(byte: u8) -> Option<(u32, u32)> {
+    let byte = byte as u32;
+    match byte {
+        0x00..=0x7F => Some((byte, byte)),
+        0xC2..=0xDF => {
+            let start = (byte & 0x1F) << 6;
+            Some((start, start | 0x3F))
+        }
+        0xE0..=0xEF => {
+            let start = (byte & 0x0F) << 12;
+            Some((start.max(0x800), start | 0xFFF))
+        }
+        0xF0..=0xF4 => {
+            let start = (byte & 0x07) << 18;
+            Some((start.max(0x10000), (start | 0x3FFFF).min(0x10FFFF)))
+        }
+        _ => None,
+    }
+}
+
+fn sorted(stacks: FxHashSet<Vec<Position>>) -> Vec<Vec<Position>> {
+    let mut stacks: Vec<_> = stacks.into_iter().collect();
+    stacks.sort();
+    stacks
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Define,
+    Literal(String),
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Or,
+    Open,
+    Close,
+    Repeat {
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+fn tokenize(grammar: &str) -> anyhow::Result<Vec<Token>> {
+    let mut chars = grammar.chars().peekable();
+    let mut tokens = Vec::new();
+    while let Some(character) = chars.next() {
+        let token = match character {
+            character if character.is_whitespace() => continue,
+            '#' => {
+                while chars.next_if(|character| *character != '\n').is_some() {}
+                continue;
+            }
+            ':' => {
+                if chars.next() != Some(':') || chars.next() != Some('=') {
+                    anyhow::bail!("Expected `::=`");
+                }
+                Token::Define
+            }
+            '"' | '\'' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => anyhow::bail!("Unterminated string literal"),
+                        Some(quote) if quote == character => break,
+                        Some('\\') => text.push(escape(&mut chars)?),
+                        Some(character) => text.push(character),
+                    }
+                }
+                Token::Literal(text)
+            }
+            '[' => {
+                let negated = chars.next_if_eq(&'^').is_some();
+                let mut ranges = Vec::new();
+                let next_character = |chars: &mut Peekable<Chars>| match chars.next() {
+                    None => anyhow::bail!("Unterminated character class"),
+                    Some('\\') => escape(chars),
+                    Some(character) => Ok(character),
+                };
+                loop {
+                    if chars.next_if_eq(&']').is_some() {
+                        break;
+                    }
+                    let start = next_character(&mut chars)?;
+                    // A `-` at the end of the class is a literal `-`
+                    if chars.peek() == Some(&'-') && chars.clone().nth(1) != Some(']') {
+                        chars.next();
+                        let end = next_character(&mut chars)?;
+                        if end < start {
+                            anyhow::bail!("The character range `{start}-{end}` is empty");
+                        }
+                        ranges.push((start, end));
+                    } else {
+                        ranges.push((start, start));
+                    }
+                }
+                Token::Chars { ranges, negated }
+            }
+            '.' => Token::Chars {
+                ranges: Vec::new(),
+                negated: true,
+            },
+            '|' => Token::Or,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '*' => Token::Repeat { min: 0, max: None },
+            '+' => Token::Repeat { min: 1, max: None },
+            '?' => Token::Repeat {
+                min: 0,
+                max: Some(1),
+            },
+            '{' => {
+                let mut contents = String::new();
+                loop {
+                    match chars.next() {
+                        None => anyhow::bail!("Unterminated repetition"),
+                        Some('}') => break,
+                        Some(character) => contents.push(character),
+                    }
+                }
+                let parse = |number: &str| {
+                    number
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|_| anyhow::anyhow!("Invalid repetition `{{{contents}}}`"))
+                };
+                let (min, max) = match contents.split_once(',') {
+                    None => {
+                        let count = parse(&contents)?;
+                        (count, Some(count))
+                    }
+                    Some((min, max)) => {
+                        let min = if min.trim().is_empty() {
+                            0
+                        } else {
+                            parse(min)?
+                        };
+                        let max = if max.trim().is_empty() {
+                            None
+                        } else {
+                            Some(parse(max)?)
+                        };
+                        (min, max)
+                    }
+                };
+                if max.is_some_and(|max| max < min) {
+                    anyhow::bail!("Invalid repetition `{{{contents}}}`");
+                }
+                Token::Repeat { min, max }
+            }
+            character if character.is_alphanumeric() || character == '-' || character == '_' => {
+                let mut name = character.to_string();
+                while let Some(character) = chars.next_if(|character| {
+                    character.is_alphanumeric() || *character == '-' || *character == '_'
+                }) {
+                    name.push(character);
+                }
+                Token::Name(name)
+            }
+            character => anyhow::bail!("Unexpected character `{character}` in the grammar"),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn escape(chars: &mut Peekable<Chars>) -> anyhow::Result<char> {
+    fn hex(chars: &mut Peekable<Chars>, digits: usize) -> anyhow::Result<char> {
+        let code: String = chars.take(digits).collect();
+        u32::from_str_radix(&code, 16)
+            .ok()
+            .filter(|_| code.len() == digits)
+            .and_then(char::from_u32)
+            .ok_or_else(|| anyhow::anyhow!("Invalid escape sequence `{code}`"))
+    }
+    match chars.next() {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('x') => hex(chars, 2),
+        Some('u') => hex(chars, 4),
+        Some('U') => hex(chars, 8),
+        Some(character @ ('\\' | '"' | '\'' | '[' | ']' | '-' | '^')) => Ok(character),
+        Some(character) => anyhow::bail!("Unknown escape sequence `\\{character}`"),
+        None => anyhow::bail!("Unterminated escape sequence"),
+    }
+}
+
+#[derive(Default)]
+struct GrammarBuilder {
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl GrammarBuilder {
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.new_rule(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, name: String) -> usize {
+        self.rules.push(None);
+        self.names.push(name);
+        self.rules.len() - 1
+    }
+
+    /// Create an unnamed rule for a group or repetition inside of another rule.
+    fn generated_rule(&mut self, parent: &str, alternatives: Vec<Vec<Element>>) -> usize {
+        let id = self.new_rule(format!("{parent}_{}", self.rules.len()));
+        self.rules[id] = Some(alternatives);
+        id
+    }
+
+    fn parse_alternatives(
+        &mut self,
+        tokens: &[Token],
+        position: &mut usize,
+        rule: &str,
+    ) -> anyhow::Result<Vec<Vec<Element>>> {
+        let mut alternatives = vec![self.parse_sequence(tokens, position, rule)?];
+        while tokens.get(*position) == Some(&Token::Or) {
+            *position += 1;
+            alternatives.push(self.parse_sequence(tokens, position, rule)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(
+        &mut self,
+        tokens: &[Token],
+        position: &mut usize,
+        rule: &str,
+    ) -> anyhow::Result<Vec<Element>> {
+        let mut sequence = Vec::new();
+        loop {
+            let mut item = match tokens.get(*position) {
+                // A name followed by `::=` starts the next rule
+                Some(Token::Name(_)) if tokens.get(*position + 1) == Some(&Token::Define) => break,
+                Some(Token::Name(name)) => vec![Element::Rule(self.rule_id(name))],
+                Some(Token::Literal(text)) => text.chars().map(Element::character).collect(),
+                Some(Token::Chars { ranges, negated }) => vec![Element::Chars {
+                    ranges: ranges.clone(),
+                    negated: *negated,
+                }],
+                Some(Token::Open) => {
+                    *position += 1;
+                    let alternatives = self.parse_alternatives(tokens, position, rule)?;
+                    if tokens.get(*position) != Some(&Token::Close) {
+                        anyhow::bail!("Expected `)` in the rule `{rule}`");
+                    }
+                    vec![Element::Rule(self.generated_rule(rule, alternatives))]
+                }
+                Some(Token::Repeat { .. }) => {
+                    anyhow::bail!("Repetition must follow an item in the rule `{rule}`")
+                }
+                Some(Token::Define) => anyhow::bail!("Unexpected `::=` in the rule `{rule}`"),
+                Some(Token::Or | Token::Close) | None => break,
+            };
+            *position += 1;
+            while let Some(Token::Repeat { min, max }) = tokens.get(*position) {
+                item = self.repeat(rule, item, *min, *max);
+                *position += 1;
+            }
+            sequence.extend(item);
+        }
+        Ok(sequence)
+    }
+
+    /// Repeat an item between `min` and `max` times.
+    fn repeat(
+        &mut self,
+        rule: &str,
+        item: Vec<Element>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Vec<Element> {
+        let mut sequence = Vec::new();
+        for _ in 0..min {
+            sequence.extend(item.iter().cloned());
+        }
+        match max {
+            // item* becomes `rest ::= item rest | ""`
+            None => {
+                let id = self.new_rule(format!("{rule}_{}", self.rules.len()));
+                let mut repeated = item;
+                repeated.push(Element::Rule(id));
+                self.rules[id] = Some(vec![repeated, Vec::new()]);
+                sequence.push(Element::Rule(id));
+            }
+            // Each optional item becomes `rest ::= item rest | ""` with one less item in the inner rest
+            Some(max) => {
+                let mut rest = None;
+                for _ in min..max {
+                    let mut optional = item.clone();
+                    optional.extend(rest.map(Element::Rule));
+                    rest = Some(self.generated_rule(rule, vec![optional, Vec::new()]));
+                }
+                sequence.extend(rest.map(Element::Rule));
+            }
+        }
+        sequence
+    }
+}
+
+/// Return an error if any rule can reference itself without parsing a character first.
+fn check_left_recursion(rules: &[Vec<Vec<Element>>], names: &[String]) -> anyhow::Result<()> {
+    let mut nullable = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (rule, alternatives) in rules.iter().enumerate() {
+            if nullable[rule] {
+                continue;
+            }
+            let is_nullable = alternatives.iter().any(|sequence| {
+                sequence.iter().all(|element| match element {
+                    Element::Rule(rule) => nullable[*rule],
+                    Element::Chars { .. } => false,
+                })
+            });
+            if is_nullable {
+                nullable[rule] = true;
+                changed = true;
+            }
+        }
+    }
+
+    // The rules each rule can start with
+    let left_corners: Vec<Vec<usize>> = rules
+        .iter()
+        .map(|alternatives| {
+            let mut corners = Vec::new();
+            for sequence in alternatives {
+                for element in sequence {
+                    match element {
+                        Element::Rule(rule) => {
+                            corners.push(*rule);
+                            if !nullable[*rule] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+            corners
+        })
+        .collect();
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Visit {
+        New,
+        InProgress,
+        Done,
+    }
+    fn visit(rule: usize, left_corners: &[Vec<usize>], visits: &mut [Visit]) -> Option<usize> {
+        match visits[rule] {
+            Visit::Done => return None,
+            Visit::InProgress => return Some(rule),
+            Visit::New => {}
+        }
+        visits[rule] = Visit::InProgress;
+        for corner in &left_corners[rule] {
+            if let Some(recursive) = visit(*corner, left_corners, visits) {
+                return Some(recursive);
+            }
+        }
+        visits[rule] = Visit::Done;
+        None
+    }
+
+    let mut visits = vec![Visit::New; rules.len()];
+    for rule in 0..rules.len() {
+        if let Some(recursive) = visit(rule, &left_corners, &mut visits) {
+            anyhow::bail!(
+                "The rule `{}` is left recursive. Left recursive grammars are not supported",
+                names[recursive]
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn grammar_parser() {
+    let parser = GrammarParser::new(
+        r#"
+        # A small subset of JSON
+        root   ::= value
+        value  ::= object | array | string | number | ("true" | "false" | "null")
+        object ::=
+          "{" (
+            string ": " value
+            (", " string ": " value)*
+          )? "}"
+        array  ::= "[" (value (", " value)*)? "]"
+        string ::= "\"" ([^"\\] | "\\" ["\\/bfnrt])* "\""
+        number ::= "-"? [0-9]{1,10} ("." [0-9]+)?
+        "#,
+    )
+    .unwrap();
+
+    let state = parser.create_parser_state();
+    let input = r#"{"name": "Ada ☃", "tags": [1, -2.5, true, {}]}"#;
+    let result = parser.parse(&state, input.as_bytes()).unwrap();
+    assert_eq!(
+        result,
+        ParseStatus::Finished {
+            result: input.to_string(),
+            remaining: &[],
+        }
+    );
+
+    // Input can be split anywhere, even inside of a character
+    let bytes = input.as_bytes();
+    let split = input.find('☃').unwrap() + 1;
+    let (state, required_next) = parser
+        .parse(&state, &bytes[..split])
+        .unwrap()
+        .unwrap_incomplete();
+    assert!(required_next.is_empty());
+    let result = parser.parse(&state, &bytes[split..]).unwrap();
+    assert_eq!(result.unwrap_finished(), input);
+
+    // The grammar knows what text is required next
+    let state = parser.create_parser_state();
+    let (_, required_next) = parser.parse(&state, b"fa").unwrap().unwrap_incomplete();
+    assert_eq!(required_next, "lse");
+
+    // Numbers can end at any digit, so the rest of the input is left for the next parser
+    let result = parser.parse(&state, b"123 and more").unwrap();
+    assert_eq!(
+        result,
+        ParseStatus::Finished {
+            result: "123".to_string(),
+            remaining: b" and more",
+        }
+    );
+
+    // A character the grammar can't accept after it ends is left for the next parser, even if it is split between calls
+    let word = GrammarParser::new(r#"root ::= "caf" "é"?"#).unwrap();
+    let word_state = word.create_parser_state();
+    let (split_state, _) = word
+        .parse(&word_state, "caf".as_bytes())
+        .unwrap()
+        .unwrap_incomplete();
+    let bytes = "☃ and more".as_bytes();
+    let result = word.parse(&split_state, &bytes[..1]).unwrap();
+    assert_eq!(
+        result,
+        ParseStatus::Finished {
+            result: "caf".to_string(),
+            remaining: &bytes[..1],
+        }
+    );
+    let bytes = "é".as_bytes();
+    let (accented_state, _) = word
+        .parse(&split_state, &bytes[..1])
+        .unwrap()
+        .unwrap_incomplete();
+    assert_eq!(
+        word.parse(&accented_state, &bytes[1..])
+            .unwrap()
+            .unwrap_finished(),
+        "café"
+    );
+    let bytes = "cafè".as_bytes();
+    let result = word.parse(&word_state, bytes).unwrap();
+    assert_eq!(
+        result,
+        ParseStatus::Finished {
+            result: "caf".to_string(),
+            remaining: "è".as_bytes(),
+        }
+    );
+
+    assert!(parser.parse(&state, b"{\"name\" 1}").is_err());
+    assert!(parser.parse(&state, b"12345678901").is_ok());
+    assert!(parser.parse(&state, b"[12345678901]").is_err());
+
+    assert!(GrammarParser::new("root ::= expr\nexpr ::= expr \"+\" [0-9] | [0-9]").is_err());
+    assert!(GrammarParser::new("root ::= (\"a\"?)*").is_err());
+    assert!(GrammarParser::new("root ::= missing").is_err());
+    assert!(GrammarParser::new("start ::= \"a\"").is_err());
+}
@@ -1,4 +1,4 @@
-use crate::{CreateParserState, SeparatedParser};
+use crate::{CreateParserState, FloatParser, MapOutputParser, SeparatedParser};
 use crate::{
     IntegerParser, LiteralParser, ParseStatus, Parser, SequenceParser, SequenceParserState,
     StringParser,
@@ -16,6 +16,86 @@ pub trait HasParser {
     fn create_parser_state() -> <Self::Parser as Parser>::PartialState;
 }
 
+/// Data with a description of the format its parser accepts. The description can be added to a prompt to tell the model what format to generate.
+pub trait HasDescription {
+    /// Describe the format of the data.
+    fn description() -> String;
+}
+
+fn comment(doc: &str) -> String {
+    if doc.is_empty() {
+        String::new()
+    } else {
+        format!(" // {doc}")
+    }
+}
+
+/// Describe a list of `(description, doc comment)` items between two delimiters. Short lists without doc comments are kept on one line.
+fn describe_items(open: &str, items: &[(String, &str)], close: &str) -> String {
+    if items.is_empty() {
+        return format!("{open}{close}");
+    }
+    let inline = items
+        .iter()
+        .map(|(value, _)| value.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if inline.len() <= 60 && !inline.contains('\n') && items.iter().all(|(_, doc)| doc.is_empty()) {
+        return format!("{open}{inline}{close}");
+    }
+    let mut description = format!("{open}\n");
+    for (i, (value, doc)) in items.iter().enumerate() {
+        let mut value = value.replace('\n', "\n    ");
+        if i + 1 < items.len() {
+            // Keep the separator in front of any comment at the end of the value
+            let last_line = value.rfind('\n').map_or(0, |i| i + 1);
+            match value[last_line..].find(" // ") {
+                Some(comment) => value.insert(last_line + comment, ','),
+                None => value.push(','),
+            }
+        }
+        description += &format!("    {value}{}\n", comment(doc));
+    }
+    description += close;
+    description
+}
+
+/// Describe an object with a list of `(key, description, doc comment)` fields.
+#[doc(hidden)]
+pub fn describe_object(fields: &[(&str, String, &str)]) -> String {
+    let items = fields
+        .iter()
+        .map(|(name, value, doc)| (format!("{name:?}: {value}"), *doc))
+        .collect::<Vec<_>>();
+    describe_items("{", &items, "}")
+}
+
+/// Describe an array with a list of `(description, doc comment)` items.
+#[doc(hidden)]
+pub fn describe_array(items: &[(String, &str)]) -> String {
+    describe_items("[", items, "]")
+}
+
+/// Describe a choice between a list of `(description, doc comment)` options.
+#[doc(hidden)]
+pub fn describe_choices(choices: &[(String, &str)]) -> String {
+    choices
+        .iter()
+        .map(|(value, doc)| format!("{value}{}", comment(doc)))
+        .collect::<Vec<_>>()
+        .join("\n| ")
+}
+
+/// Add a doc comment before a description.
+#[doc(hidden)]
+pub fn describe_with_doc(description: String, doc: &str) -> String {
+    if doc.is_empty() {
+        description
+    } else {
+        format!("// {doc}\n{description}")
+    }
+}
+
 macro_rules! int_parser {
     ($ty:ident, $num:ty, $test:ident) => {
         #[doc = "A parser for `"]
@@ -67,6 +147,12 @@ macro_rules! int_parser {
             }
         }
 
+        impl HasDescription for $num {
+            fn description() -> String {
+                "integer".to_string()
+            }
+        }
+
         #[test]
         fn $test() {
             let parser = <$num as HasParser>::new_parser();
@@ -109,6 +195,52 @@ impl HasParser for String {
     }
 }
 
+impl HasDescription for String {
+    fn description() -> String {
+        "string".to_string()
+    }
+}
+
+impl HasParser for f64 {
+    type Parser = FloatParser;
+
+    fn new_parser() -> Self::Parser {
+        FloatParser::new(f64::MIN..=f64::MAX)
+    }
+
+    fn create_parser_state() -> <Self::Parser as Parser>::PartialState {
+        Default::default()
+    }
+}
+
+impl HasDescription for f64 {
+    fn description() -> String {
+        "number".to_string()
+    }
+}
+
+impl HasParser for f32 {
+    type Parser = MapOutputParser<FloatParser, fn(f64) -> f32, f32>;
+
+    fn new_parser() -> Self::Parser {
+        MapOutputParser {
+            parser: FloatParser::new(f32::MIN as f64..=f32::MAX as f64),
+            map: |value| value as f32,
+            _output: std::marker::PhantomData,
+        }
+    }
+
+    fn create_parser_state() -> <Self::Parser as Parser>::PartialState {
+        Default::default()
+    }
+}
+
+impl HasDescription for f32 {
+    fn description() -> String {
+        "number".to_string()
+    }
+}
+
 /// A parser for a vector of a type.
 #[derive(Clone, Debug)]
 pub struct VecParser<T: HasParser> {
@@ -188,6 +320,12 @@ where
     }
 }
 
+impl<T: HasDescription> HasDescription for Vec<T> {
+    fn description() -> String {
+        describe_items("[", &[(T::description(), ""), ("...".to_string(), "")], "]")
+    }
+}
+
 /// A parser for a fixed size array of a type.
 pub struct ArrayParser<const N: usize, T: HasParser> {
     parser: SequenceParser<
@@ -269,3 +407,114 @@ where
         SequenceParserState::default()
     }
 }
+
+impl<const N: usize, T: HasDescription> HasDescription for [T; N] {
+    fn description() -> String {
+        describe_items(
+            "[",
+            &[(T::description(), ""), (format!("... {N} items"), "")],
+            "]",
+        )
+    }
+}
+
+#[test]
+fn derive_parse() {
+    use crate::Parse;
+
+    /// A person
+    #[derive(Parse, Clone, Debug, PartialEq)]
+    struct Person {
+        /// The name of the person
+        #[parse(rename = "full name")]
+        name: String,
+        #[parse(range = 0..=150)]
+        age: u8,
+        #[parse(pattern = r"[a-z]+@[a-z]+\.com")]
+        email: String,
+        pets: Vec<Pet>,
+    }
+
+    #[derive(Parse, Clone, Debug, PartialEq)]
+    enum Pet {
+        Fish,
+        Dog {
+            name: String,
+        },
+        /// A cat with a name and an age
+        Cat(String, u8),
+    }
+
+    let parser = Person::new_parser();
+    let state = Person::create_parser_state();
+    let result = parser
+        .parse(
+            &state,
+            br#"{"full name": "Ada", "age": 36, "email": "ada@example.com", "pets": ["Fish", {"Dog": {"name": "Rex"}}, {"Cat": ["Tom", 3]}]}"#,
+        )
+        .unwrap();
+    assert_eq!(
+        result.unwrap_finished(),
+        Person {
+            name: "Ada".to_string(),
+            age: 36,
+            email: "ada@example.com".to_string(),
+            pets: vec![
+                Pet::Fish,
+                Pet::Dog {
+                    name: "Rex".to_string()
+                },
+                Pet::Cat("Tom".to_string(), 3)
+            ],
+        }
+    );
+
+    // The age is out of range
+    let state = Person::create_parser_state();
+    assert!(parser
+        .parse(&state, br#"{"full name": "Ada", "age": 200"#)
+        .is_err());
+
+    // Exclusive ranges don't include the end
+    #[derive(Parse, Clone, Debug, PartialEq)]
+    struct Rating(#[parse(range = 1..10)] u8);
+    let parser = Rating::new_parser();
+    let state = Rating::create_parser_state();
+    assert_eq!(
+        parser.parse(&state, b"9 ").unwrap().unwrap_finished(),
+        Rating(9)
+    );
+    // 10 is out of range, so only the 1 is parsed
+    match parser.parse(&state, b"10").unwrap() {
+        ParseStatus::Finished { result, remaining } => {
+            assert_eq!(result, Rating(1));
+            assert_eq!(remaining, b"0");
+        }
+        ParseStatus::Incomplete { .. } => panic!("expected the parser to finish"),
+    }
+
+    // Generic type parameters are bounded in the generated impls
+    #[derive(Parse, Clone, Debug, PartialEq)]
+    struct Labeled<T> {
+        label: String,
+        value: T,
+    }
+    let parser = Labeled::<Rating>::new_parser();
+    let state = Labeled::<Rating>::create_parser_state();
+    assert_eq!(
+        parser
+            .parse(&state, br#"{"label": "stars", "value": 4}"#)
+            .unwrap()
+            .unwrap_finished(),
+        Labeled {
+            label: "stars".to_string(),
+            value: Rating(4),
+        }
+    );
+    assert!(Labeled::<Rating>::description().contains("\"value\": integer"));
+
+    let description = Person::description();
+    assert!(description.starts_with("// A person\n{\n"));
+    assert!(description.contains("\"full name\": string, // The name of the person"));
+    assert!(description.contains("| {\"Cat\": [string, integer]}, // A cat with a name and an age"));
+}
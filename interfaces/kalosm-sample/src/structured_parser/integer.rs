@@ -2,7 +2,7 @@ use crate::bail;
 
 use crate::{
     CreateParserState, EmptyNumber, InvalidSignLocation, LeadingZeroError, OutOfRangeError,
-    ParseStatus, Parser,
+    ParseStatus, Parser, ParserError,
 };
 use std::ops::RangeInclusive;
 
@@ -166,7 +166,7 @@ impl Parser for IntegerParser {
                         && value == 0
                         && input_byte == b'0'
                     {
-                        bail!(LeadingZeroError);
+                        bail!(ParserError::from(LeadingZeroError).in_parser("integer"));
                     }
                     input_byte - b'0'
                 }
@@ -175,11 +175,11 @@ impl Parser for IntegerParser {
                         state = IntegerParserProgress::AfterSign;
                         positive = input_byte == b'+';
                         if !self.sign_valid(positive) {
-                            bail!(OutOfRangeError)
+                            bail!(ParserError::from(OutOfRangeError).in_parser("integer"))
                         }
                         continue;
                     } else {
-                        bail!(InvalidSignLocation)
+                        bail!(ParserError::from(InvalidSignLocation).in_parser("integer"))
                     }
                 }
                 _ => {
@@ -191,9 +191,9 @@ impl Parser for IntegerParser {
                                 remaining: &input[index..],
                             });
                         }
-                        bail!(OutOfRangeError)
+                        bail!(ParserError::from(OutOfRangeError).in_parser("integer"))
                     } else {
-                        bail!(EmptyNumber)
+                        bail!(ParserError::from(EmptyNumber).in_parser("integer"))
                     }
                 }
             };
@@ -209,17 +209,20 @@ impl Parser for IntegerParser {
                             remaining: &input[index..],
                         });
                     }
-                    bail!(OutOfRangeError)
+                    bail!(ParserError::from(OutOfRangeError).in_parser("integer"))
                 }
             }
 
             let signed_value = value as i128 * if positive { 1 } else { -1 };
 
             if self.should_stop(signed_value) {
-                return Ok(ParseStatus::Finished {
-                    result: signed_value,
-                    remaining: &input[index + 1..],
-                });
+                if self.is_number_valid(signed_value) {
+                    return Ok(ParseStatus::Finished {
+                        result: signed_value,
+                        remaining: &input[index + 1..],
+                    });
+                }
+                bail!(ParserError::from(OutOfRangeError).in_parser("integer"))
             }
 
             if !self.could_number_become_valid(signed_value) {
@@ -229,7 +232,7 @@ impl Parser for IntegerParser {
                         remaining: &input[index + 1..],
                     });
                 }
-                bail!(OutOfRangeError)
+                bail!(ParserError::from(OutOfRangeError).in_parser("integer"))
             }
         }
 
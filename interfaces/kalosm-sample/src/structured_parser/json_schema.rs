@@ -0,0 +1,788 @@
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+
+use crate::{
+    ArcParser, CreateParserState, Either, FloatParser, IntegerParser, LiteralParser, ParseStatus,
+    Parser, ParserExt, RegexStringParser, RegexStringParserState, SeparatedParser, StringParser,
+};
+
+/// A parser for JSON that matches a [JSON Schema](https://json-schema.org/).
+///
+/// The schema is compiled into the other parsers in this crate when the parser is created, so schemas can be loaded at runtime. The parser accepts JSON with a single space after each `:` and `,`, and object properties are written in the order they appear in the schema.
+///
+/// [`serde_json::Value`] sorts the keys of objects unless serde_json's `preserve_order` feature is enabled, so parse the schema from text with [`str::parse`] to keep the order of the properties. [`JsonSchemaParser::new`] writes the properties in the order the value stores them.
+///
+/// The supported keywords are:
+/// - `type` (or a list of types)
+/// - `properties` and `required`
+/// - `enum` and `const`
+/// - `items`, `minItems` and `maxItems`
+/// - `minimum`, `maximum`, `exclusiveMinimum` and `exclusiveMaximum`
+/// - `minLength`, `maxLength` and `pattern`. A `pattern` can't be combined with the length keywords
+/// - `oneOf` and `anyOf`
+/// - `$ref`s to other parts of the same document like `#/$defs/item`. Recursive references are not supported.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let schema = serde_json::json!({
+///     "type": "object",
+///     "properties": {
+///         "name": { "type": "string" },
+///         "age": { "type": "integer", "minimum": 0 }
+///     },
+///     "required": ["name"]
+/// });
+/// let parser = JsonSchemaParser::new(&schema).unwrap();
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, br#"{"name": "Ada"}"#).unwrap();
+/// assert_eq!(result.unwrap_finished(), serde_json::json!({ "name": "Ada" }));
+///
+/// // Parsing the schema from text keeps the order of the properties
+/// let parser: JsonSchemaParser = r#"{
+///     "type": "object",
+///     "properties": { "name": { "type": "string" }, "age": { "type": "integer" } }
+/// }"#
+/// .parse()
+/// .unwrap();
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, br#"{"name": "Ada", "age": 36}"#).unwrap();
+/// assert_eq!(result.unwrap_finished(), serde_json::json!({ "name": "Ada", "age": 36 }));
+/// ```
+#[derive(Clone)]
+pub struct JsonSchemaParser {
+    parser: ArcParser<Value>,
+}
+
+impl JsonSchemaParser {
+    /// Compile a JSON Schema into a parser.
+    pub fn new(schema: &Value) -> anyhow::Result<Self> {
+        Self::compile(&SchemaValue::from(schema))
+    }
+
+    fn compile(schema: &SchemaValue) -> anyhow::Result<Self> {
+        let mut compiler = SchemaCompiler {
+            root: schema,
+            references: Vec::new(),
+        };
+        Ok(Self {
+            parser: compiler.compile(schema)?,
+        })
+    }
+}
+
+impl std::str::FromStr for JsonSchemaParser {
+    type Err = anyhow::Error;
+
+    /// Compile a JSON Schema from text into a parser. The properties of objects are written in the order they appear in the text.
+    fn from_str(schema: &str) -> anyhow::Result<Self> {
+        Self::compile(&serde_json::from_str(schema)?)
+    }
+}
+
+impl CreateParserState for JsonSchemaParser {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        self.parser.create_parser_state()
+    }
+}
+
+impl Parser for JsonSchemaParser {
+    type Output = Value;
+    type PartialState = <ArcParser<Value> as Parser>::PartialState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        self.parser.parse(state, input)
+    }
+}
+
+/// A JSON value that keeps the keys of objects in the order they were written. The order only matters for the schema, so this is used instead of turning on serde_json's `preserve_order` feature for every crate that uses serde_json.
+#[derive(Debug, Clone, PartialEq)]
+enum SchemaValue {
+    Array(Vec<SchemaValue>),
+    Object(SchemaObject),
+    /// Any value other than an array or object
+    Scalar(Value),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+struct SchemaObject(Vec<(String, SchemaValue)>);
+
+impl SchemaObject {
+    fn get(&self, key: &str) -> Option<&SchemaValue> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+impl SchemaValue {
+    fn as_scalar(&self) -> Option<&Value> {
+        match self {
+            Self::Scalar(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        self.as_scalar()?.as_str()
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        self.as_scalar()?.as_f64()
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        self.as_scalar()?.as_i64()
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        self.as_scalar()?.as_u64()
+    }
+
+    fn as_array(&self) -> Option<&Vec<SchemaValue>> {
+        match self {
+            Self::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Look up a value by a [JSON Pointer](https://datatracker.ietf.org/doc/html/rfc6901) like `/$defs/item`.
+    fn pointer(&self, pointer: &str) -> Option<&SchemaValue> {
+        if pointer.is_empty() {
+            return Some(self);
+        }
+        let pointer = pointer.strip_prefix('/')?;
+        pointer.split('/').try_fold(self, |value, token| {
+            let token = token.replace("~1", "/").replace("~0", "~");
+            match value {
+                Self::Object(object) => object.get(&token),
+                Self::Array(items) => items.get(token.parse::<usize>().ok()?),
+                Self::Scalar(_) => None,
+            }
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        match self {
+            Self::Array(items) => Value::Array(items.iter().map(Self::to_value).collect()),
+            Self::Object(object) => Value::Object(
+                object
+                    .0
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.to_value()))
+                    .collect(),
+            ),
+            Self::Scalar(value) => value.clone(),
+        }
+    }
+}
+
+impl From<&Value> for SchemaValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Array(items) => Self::Array(items.iter().map(Self::from).collect()),
+            Value::Object(object) => Self::Object(SchemaObject(
+                object
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Self::from(value)))
+                    .collect(),
+            )),
+            value => Self::Scalar(value.clone()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SchemaValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SchemaValueVisitor;
+
+        impl<'de> Visitor<'de> for SchemaValueVisitor {
+            type Value = SchemaValue;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON value")
+            }
+
+            fn visit_bool<E>(self, value: bool) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(value.into()))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(value.into()))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(value.into()))
+            }
+
+            fn visit_f64<E>(self, value: f64) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(value.into()))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(value.into()))
+            }
+
+            fn visit_string<E>(self, value: String) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(value.into()))
+            }
+
+            fn visit_unit<E>(self) -> Result<SchemaValue, E> {
+                Ok(SchemaValue::Scalar(Value::Null))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<SchemaValue, A::Error> {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(SchemaValue::Array(items))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SchemaValue, A::Error> {
+                let mut object = SchemaObject::default();
+                while let Some((name, value)) = map.next_entry::<String, SchemaValue>()? {
+                    // Later keys replace earlier keys with the same name, like in serde_json
+                    object.0.retain(|(existing, _)| *existing != name);
+                    object.0.push((name, value));
+                }
+                Ok(SchemaValue::Object(object))
+            }
+        }
+
+        deserializer.deserialize_any(SchemaValueVisitor)
+    }
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a SchemaValue,
+    // The references that are currently being compiled. Used to detect recursive schemas
+    references: Vec<String>,
+}
+
+impl SchemaCompiler<'_> {
+    fn compile(&mut self, schema: &SchemaValue) -> anyhow::Result<ArcParser<Value>> {
+        let schema = match schema {
+            SchemaValue::Object(schema) => schema,
+            _ => anyhow::bail!("Expected a schema object, found {}", schema.to_value()),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.compile_reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal_value(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("`enum` must be a list of values"))?;
+            return one_of(values.iter().map(literal_value).collect());
+        }
+        for keyword in ["oneOf", "anyOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("`{keyword}` must be a list of schemas"))?;
+                let mut options = Vec::new();
+                for schema in schemas {
+                    options.push(self.compile(schema)?);
+                }
+                return one_of(options);
+            }
+        }
+
+        match schema.get("type") {
+            Some(SchemaValue::Scalar(Value::String(ty))) => self.compile_type(ty, schema),
+            Some(SchemaValue::Array(types)) => {
+                let mut options = Vec::new();
+                for ty in types {
+                    let ty = ty
+                        .as_str()
+                        .ok_or_else(|| anyhow::anyhow!("`type` must be a list of strings"))?;
+                    options.push(self.compile_type(ty, schema)?);
+                }
+                one_of(options)
+            }
+            Some(ty) => anyhow::bail!(
+                "`type` must be a string or a list of strings, found {}",
+                ty.to_value()
+            ),
+            None if schema.contains_key("properties") => self.compile_type("object", schema),
+            None if schema.contains_key("items") => self.compile_type("array", schema),
+            None => anyhow::bail!("Schemas without a type are not supported"),
+        }
+    }
+
+    fn compile_reference(&mut self, reference: &SchemaValue) -> anyhow::Result<ArcParser<Value>> {
+        let reference = reference
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("`$ref` must be a string"))?;
+        let pointer = reference.strip_prefix('#').ok_or_else(|| {
+            anyhow::anyhow!("Only local references are supported, found {reference}")
+        })?;
+        if self.references.iter().any(|r| r == reference) {
+            anyhow::bail!("Recursive references are not supported, found {reference}");
+        }
+        let root = self.root;
+        let schema = root
+            .pointer(pointer)
+            .ok_or_else(|| anyhow::anyhow!("Failed to resolve the reference {reference}"))?;
+
+        self.references.push(reference.to_string());
+        let parser = self.compile(schema);
+        self.references.pop();
+        parser
+    }
+
+    fn compile_type(
+        &mut self,
+        ty: &str,
+        schema: &SchemaObject,
+    ) -> anyhow::Result<ArcParser<Value>> {
+        match ty {
+            "null" => Ok(literal_value(&SchemaValue::Scalar(Value::Null))),
+            "boolean" => one_of(vec![
+                literal_value(&SchemaValue::Scalar(Value::Bool(true))),
+                literal_value(&SchemaValue::Scalar(Value::Bool(false))),
+            ]),
+            "integer" => {
+                // The bounds are compared as integers so large bounds are not rounded by converting them to floats
+                let mut min = i64::MIN as i128;
+                let mut max = i64::MAX as i128;
+                if let Some(minimum) = integer_bound(schema, "minimum", f64::ceil)? {
+                    min = min.max(minimum);
+                }
+                if let Some(minimum) = integer_bound(schema, "exclusiveMinimum", f64::floor)? {
+                    min = min.max(minimum + 1);
+                }
+                if let Some(maximum) = integer_bound(schema, "maximum", f64::floor)? {
+                    max = max.min(maximum);
+                }
+                if let Some(maximum) = integer_bound(schema, "exclusiveMaximum", f64::ceil)? {
+                    max = max.min(maximum - 1);
+                }
+                if min > max {
+                    anyhow::bail!("The integer range {min}..={max} is empty");
+                }
+                Ok(IntegerParser::new(min..=max)
+                    .map_output(|value| Value::from(value as i64))
+                    .boxed())
+            }
+            "number" => {
+                let mut min = f64::MIN;
+                let mut max = f64::MAX;
+                if let Some(minimum) = number(schema, "minimum")? {
+                    min = min.max(minimum);
+                }
+                if let Some(minimum) = number(schema, "exclusiveMinimum")? {
+                    min = min.max(next_up(minimum));
+                }
+                if let Some(maximum) = number(schema, "maximum")? {
+                    max = max.min(maximum);
+                }
+                if let Some(maximum) = number(schema, "exclusiveMaximum")? {
+                    max = max.min(next_down(maximum));
+                }
+                if min > max {
+                    anyhow::bail!("The number range {min}..={max} is empty");
+                }
+                Ok(FloatParser::new(min..=max).map_output(Value::from).boxed())
+            }
+            "string" => {
+                if let Some(pattern) = schema.get("pattern") {
+                    let pattern = pattern
+                        .as_str()
+                        .ok_or_else(|| anyhow::anyhow!("`pattern` must be a string"))?;
+                    if schema.contains_key("minLength") || schema.contains_key("maxLength") {
+                        anyhow::bail!("`minLength` and `maxLength` are not supported together with `pattern`. Add the length limits to the pattern instead");
+                    }
+                    return Ok(PatternStringParser::new(pattern)?
+                        .map_output(Value::String)
+                        .boxed());
+                }
+                let min = length(schema, "minLength")?.unwrap_or(0);
+                let max = length(schema, "maxLength")?.unwrap_or(usize::MAX);
+                Ok(StringParser::new(min..=max)
+                    .map_output(Value::String)
+                    .boxed())
+            }
+            "array" => {
+                let items = match schema.get("items") {
+                    Some(items) => self.compile(items)?,
+                    None => anyhow::bail!("Arrays without `items` are not supported"),
+                };
+                let min = length(schema, "minItems")?.unwrap_or(0);
+                let max = length(schema, "maxItems")?.unwrap_or(usize::MAX);
+                Ok(LiteralParser::from("[")
+                    .then(SeparatedParser::new(
+                        items,
+                        LiteralParser::from(", "),
+                        min..=max,
+                    ))
+                    .then(LiteralParser::from("]"))
+                    .map_output(|((_, items), _)| Value::Array(items))
+                    .boxed())
+            }
+            "object" => self.compile_object(schema),
+            _ => anyhow::bail!("Unsupported type {ty}"),
+        }
+    }
+
+    fn compile_object(&mut self, schema: &SchemaObject) -> anyhow::Result<ArcParser<Value>> {
+        let properties = match schema.get("properties") {
+            Some(SchemaValue::Object(properties)) => properties.clone(),
+            Some(_) => anyhow::bail!("`properties` must be an object"),
+            None => SchemaObject::default(),
+        };
+        let required: Vec<&str> = match schema.get("required") {
+            Some(SchemaValue::Array(required)) => {
+                required.iter().filter_map(SchemaValue::as_str).collect()
+            }
+            Some(_) => anyhow::bail!("`required` must be a list of strings"),
+            None => Vec::new(),
+        };
+
+        let mut fields = Vec::new();
+        for (name, property) in &properties.0 {
+            let parser = self.compile(property)?;
+            fields.push((name.clone(), required.contains(&name.as_str()), parser));
+        }
+
+        // Each parser parses the rest of the properties and the closing brace. Optional properties can be skipped, so
+        // we need one version of the rest for before any property was written (no leading comma) and one for after
+        let end: ArcParser<Vec<(String, Value)>> =
+            LiteralParser::from("}").map_output(|_| Vec::new()).boxed();
+        let mut rest_first = end.clone();
+        let mut rest_after = end;
+        for (name, required, value) in fields.into_iter().rev() {
+            let property = |separator: &str| {
+                let key = format!("{separator}{}: ", Value::String(name.clone()));
+                let name = name.clone();
+                LiteralParser::from(key)
+                    .then(value.clone())
+                    .then(rest_after.clone())
+                    .map_output(move |((_, value), mut rest)| {
+                        rest.insert(0, (name.clone(), value));
+                        rest
+                    })
+                    .boxed()
+            };
+            let first = property("");
+            let after = property(", ");
+            if required {
+                rest_first = first;
+                rest_after = after;
+            } else {
+                rest_first = one_of(vec![first, rest_first])?;
+                rest_after = one_of(vec![after, rest_after])?;
+            }
+        }
+
+        Ok(LiteralParser::from("{")
+            .then(rest_first)
+            .map_output(|(_, fields)| Value::Object(fields.into_iter().collect()))
+            .boxed())
+    }
+}
+
+/// Read an optional number keyword from a schema.
+fn number(schema: &SchemaObject, keyword: &str) -> anyhow::Result<Option<f64>> {
+    schema
+        .get(keyword)
+        .map(|value| {
+            value
+                .as_f64()
+                .ok_or_else(|| anyhow::anyhow!("`{keyword}` must be a number"))
+        })
+        .transpose()
+}
+
+/// Read an optional integer bound from a schema. Integers are read exactly and other numbers are rounded with `round`.
+fn integer_bound(
+    schema: &SchemaObject,
+    keyword: &str,
+    round: fn(f64) -> f64,
+) -> anyhow::Result<Option<i128>> {
+    let Some(value) = schema.get(keyword) else {
+        return Ok(None);
+    };
+    if let Some(value) = value.as_i64() {
+        return Ok(Some(value.into()));
+    }
+    if let Some(value) = value.as_u64() {
+        return Ok(Some(value.into()));
+    }
+    let value = value
+        .as_f64()
+        .ok_or_else(|| anyhow::anyhow!("`{keyword}` must be a number"))?;
+    // Casting a float to an integer saturates, and i128 is wide enough that saturating never changes the clamped i64 range
+    Ok(Some(round(value) as i128))
+}
+
+/// Read an optional length keyword from a schema.
+fn length(schema: &SchemaObject, keyword: &str) -> anyhow::Result<Option<usize>> {
+    schema
+        .get(keyword)
+        .map(|value| {
+            value
+                .as_u64()
+                .map(|value| value as usize)
+                .ok_or_else(|| anyhow::anyhow!("`{keyword}` must be a non-negative integer"))
+        })
+        .transpose()
+}
+
+/// The smallest float greater than the value.
+fn next_up(value: f64) -> f64 {
+    if value == 0. {
+        f64::from_bits(1)
+    } else if value > 0. {
+        f64::from_bits(value.to_bits() + 1)
+    } else {
+        f64::from_bits(value.to_bits() - 1)
+    }
+}
+
+/// The largest float smaller than the value.
+fn next_down(value: f64) -> f64 {
+    -next_up(-value)
+}
+
+/// A parser for exactly the given JSON value.
+fn literal_value(value: &SchemaValue) -> ArcParser<Value> {
+    let value = value.to_value();
+    let literal = value.to_string();
+    LiteralParser::from(literal)
+        .map_output(move |_| value.clone())
+        .boxed()
+}
+
+/// A parser for any one of the options.
+fn one_of<T: Clone + Send + Sync + 'static>(
+    options: Vec<ArcParser<T>>,
+) -> anyhow::Result<ArcParser<T>> {
+    let mut options = options.into_iter().rev();
+    let last = options
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Expected at least one option"))?;
+    Ok(options.fold(last, |rest, option| {
+        option
+            .or(rest)
+            .map_output(|either| match either {
+                Either::Left(value) | Either::Right(value) => value,
+            })
+            .boxed()
+    }))
+}
+
+/// A parser for a JSON string whose contents match a regex.
+struct PatternStringParser {
+    parser: RegexStringParser,
+}
+
+impl PatternStringParser {
+    fn new(pattern: &str) -> anyhow::Result<Self> {
+        // JSON Schema patterns match anywhere in the string unless they are anchored
+        let (pattern, anchored_start) = match pattern.strip_prefix('^') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (pattern, anchored_end) = match pattern.strip_suffix('$') {
+            Some(pattern) if !pattern.ends_with('\\') => (pattern, true),
+            _ => (pattern, false),
+        };
+        // The pattern is matched against the decoded text of the string, so it can match any character
+        let any = r"(?s:.)*";
+        let regex = format!(
+            "{}(?:{pattern}){}",
+            if anchored_start { "" } else { any },
+            if anchored_end { "" } else { any },
+        );
+
+        Ok(Self {
+            parser: RegexStringParser::new(&regex)?,
+        })
+    }
+}
+
+impl CreateParserState for PatternStringParser {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        self.parser.create_parser_state()
+    }
+}
+
+impl Parser for PatternStringParser {
+    type Output = String;
+    type PartialState = RegexStringParserState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        self.parser.parse(state, input)
+    }
+}
+
+#[test]
+fn json_schema_parser() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "age": { "type": "integer", "minimum": 0, "exclusiveMaximum": 150 },
+            "balance": { "type": "number", "minimum": -100, "maximum": 100 },
+            "name": { "type": "string", "pattern": "^[A-Z][a-z]+$" },
+            "nickname": { "type": "string", "maxLength": 10 },
+            "role": { "enum": ["admin", "user"] },
+            "score": { "oneOf": [{ "type": "number" }, { "type": "null" }] },
+            "tags": { "type": "array", "items": { "$ref": "#/$defs/tag" }, "minItems": 1, "maxItems": 3 }
+        },
+        "required": ["age", "balance", "name", "role", "score", "tags"],
+        "$defs": {
+            "tag": { "type": "string" }
+        }
+    });
+    let parser = JsonSchemaParser::new(&schema).unwrap();
+    let state = parser.create_parser_state();
+
+    let input = r#"{"age": 36, "balance": -12.5, "name": "Ada", "role": "admin", "score": null, "tags": ["a", "b"]}"#;
+    let result = parser.parse(&state, input.as_bytes()).unwrap();
+    assert_eq!(
+        result.unwrap_finished(),
+        serde_json::json!({
+            "age": 36,
+            "balance": -12.5,
+            "name": "Ada",
+            "role": "admin",
+            "score": null,
+            "tags": ["a", "b"]
+        })
+    );
+
+    let (state, _) = parser
+        .parse(&state, br#"{"age": 36, "balance": 1, "name": "Ada", "#)
+        .unwrap()
+        .unwrap_incomplete();
+    let result = parser
+        .parse(
+            &state,
+            br#""nickname": "Al", "role": "user", "score": 1.5, "tags": ["a"]}"#,
+        )
+        .unwrap();
+    assert_eq!(
+        result.unwrap_finished()["nickname"],
+        serde_json::json!("Al")
+    );
+
+    let state = parser.create_parser_state();
+    // The age is out of range
+    assert!(parser.parse(&state, br#"{"age": 150,"#).is_err());
+    // The name doesn't match the pattern
+    assert!(parser
+        .parse(&state, br#"{"age": 36, "balance": 1, "name": "ada"#)
+        .is_err());
+    // The role isn't one of the enum values
+    assert!(parser
+        .parse(
+            &state,
+            br#"{"age": 36, "balance": 1, "name": "Ada", "role": "guest"#
+        )
+        .is_err());
+    // There must be at least one tag
+    assert!(parser
+        .parse(
+            &state,
+            br#"{"age": 36, "balance": 1, "name": "Ada", "role": "user", "score": null, "tags": []"#
+        )
+        .is_err());
+
+    // Bounds near the edges of the i64 range are not rounded
+    let schema = serde_json::json!({ "type": "integer", "minimum": i64::MAX - 1 });
+    let parser = JsonSchemaParser::new(&schema).unwrap();
+    let state = parser.create_parser_state();
+    let input = format!("{} ", i64::MAX - 1);
+    assert_eq!(
+        parser
+            .parse(&state, input.as_bytes())
+            .unwrap()
+            .unwrap_finished(),
+        serde_json::json!(i64::MAX - 1)
+    );
+    let input = format!("{} ", i64::MAX - 2);
+    assert!(parser.parse(&state, input.as_bytes()).is_err());
+    let schema = serde_json::json!({ "type": "integer", "exclusiveMaximum": i64::MIN });
+    assert!(JsonSchemaParser::new(&schema).is_err());
+
+    // Length limits can't be combined with a pattern
+    let schema = serde_json::json!({ "type": "string", "pattern": "^a+$", "maxLength": 3 });
+    assert!(JsonSchemaParser::new(&schema).is_err());
+
+    let recursive = serde_json::json!({
+        "$defs": { "node": { "type": "array", "items": { "$ref": "#/$defs/node" } } },
+        "$ref": "#/$defs/node"
+    });
+    assert!(JsonSchemaParser::new(&recursive).is_err());
+}
+
+#[test]
+fn json_schema_parser_from_text_keeps_property_order() {
+    let parser: JsonSchemaParser = r##"{
+        "type": "object",
+        "properties": {
+            "zebra": { "type": "integer" },
+            "apple": { "$ref": "#/$defs/fruit" }
+        },
+        "required": ["zebra", "apple"],
+        "$defs": { "fruit": { "const": "apple" } }
+    }"##
+    .parse()
+    .unwrap();
+    let state = parser.create_parser_state();
+    let result = parser
+        .parse(&state, br#"{"zebra": 1, "apple": "apple"}"#)
+        .unwrap();
+    assert_eq!(
+        result.unwrap_finished(),
+        serde_json::json!({ "zebra": 1, "apple": "apple" })
+    );
+    assert!(parser.parse(&state, br#"{"apple""#).is_err());
+}
+
+#[test]
+fn json_schema_pattern_matches_decoded_text() {
+    let schema = serde_json::json!({ "type": "string", "pattern": "^\"[a-z]+\"$" });
+    let parser = JsonSchemaParser::new(&schema).unwrap();
+    let state = parser.create_parser_state();
+    assert_eq!(
+        parser
+            .parse(&state, br#""\"quoted\"""#)
+            .unwrap()
+            .unwrap_finished(),
+        serde_json::json!("\"quoted\"")
+    );
+    // The backslash of the escape is not part of the text
+    assert!(parser.parse(&state, br#""\\"#).is_err());
+
+    // Unanchored patterns can match anywhere in the text, including around escaped characters
+    let schema = serde_json::json!({ "type": "string", "pattern": "[0-9]" });
+    let parser = JsonSchemaParser::new(&schema).unwrap();
+    let state = parser.create_parser_state();
+    assert_eq!(
+        parser
+            .parse(&state, br#""line\n2\t""#)
+            .unwrap()
+            .unwrap_finished(),
+        serde_json::json!("line\n2\t")
+    );
+}
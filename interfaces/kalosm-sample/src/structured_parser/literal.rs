@@ -2,7 +2,7 @@ use std::borrow::Cow;
 
 use crate::bail;
 
-use crate::{CreateParserState, ParseStatus, Parser};
+use crate::{CreateParserState, ParseStatus, Parser, ParserError};
 
 /// A parser for a literal.
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -74,7 +74,7 @@ impl Parser for LiteralParser {
             .zip(self.literal.as_bytes()[state.offset..].iter())
         {
             if input_byte != literal_byte {
-                bail!(LiteralMismatchError);
+                bail!(ParserError::from(LiteralMismatchError).in_parser("literal"));
             }
             bytes_consumed += 1;
         }
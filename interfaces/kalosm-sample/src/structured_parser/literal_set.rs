@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use crate::{
+    CreateParserState, LiteralMismatchError, MaskCache, ParseStatus, Parser, ParserError,
+    TokenMask, TokenTrie,
+};
+
+/// A parser that accepts exactly one of a set of literals.
+///
+/// The literals are compiled into a prefix trie, so choosing between thousands of values is as fast as matching a single literal. Once only one value can match, the rest of it is returned as the required next text.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let parser = LiteralSetParser::new(["SKU-1001", "SKU-1002", "SKU-2001"]);
+/// let state = parser.create_parser_state();
+/// let (state, required_next) = parser.parse(&state, b"SKU-2").unwrap().unwrap_incomplete();
+/// assert_eq!(required_next, "001");
+/// let result = parser.parse(&state, b"001").unwrap();
+/// assert_eq!(result.unwrap_finished(), "SKU-2001");
+/// ```
+#[derive(Debug)]
+pub struct LiteralSetParser {
+    values: Vec<String>,
+    nodes: Vec<LiteralSetNode>,
+    // A cache for the allowed tokens for each trie and node
+    mask_cache: MaskCache<(u64, usize)>,
+}
+
+#[derive(Debug, Default)]
+struct LiteralSetNode {
+    /// The index of the value that ends at this node.
+    value: Option<usize>,
+    /// The children of this node sorted by byte.
+    children: Vec<(u8, usize)>,
+}
+
+impl LiteralSetParser {
+    /// Create a new parser that accepts any of the values. Duplicate values are ignored.
+    pub fn new(values: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let mut nodes = vec![LiteralSetNode::default()];
+        let mut unique_values = Vec::new();
+        for value in values {
+            let value = value.into();
+            let mut node = 0;
+            for byte in value.bytes() {
+                node = match nodes[node]
+                    .children
+                    .binary_search_by_key(&byte, |(child_byte, _)| *child_byte)
+                {
+                    Ok(index) => nodes[node].children[index].1,
+                    Err(index) => {
+                        nodes.push(LiteralSetNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(index, (byte, child));
+                        child
+                    }
+                };
+            }
+            if nodes[node].value.is_none() {
+                nodes[node].value = Some(unique_values.len());
+                unique_values.push(value);
+            }
+        }
+
+        Self {
+            values: unique_values,
+            nodes,
+            mask_cache: Default::default(),
+        }
+    }
+
+    /// Get the values the parser accepts.
+    pub fn values(&self) -> &[String] {
+        &self.values
+    }
+
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        let children = &self.nodes[node].children;
+        children
+            .binary_search_by_key(&byte, |(child_byte, _)| *child_byte)
+            .ok()
+            .map(|index| children[index].1)
+    }
+}
+
+/// The state of a [`LiteralSetParser`].
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash)]
+pub struct LiteralSetParserState {
+    node: usize,
+}
+
+impl CreateParserState for LiteralSetParser {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        LiteralSetParserState::default()
+    }
+}
+
+impl Parser for LiteralSetParser {
+    type Output = String;
+    type PartialState = LiteralSetParserState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        let mut node = state.node;
+        for (i, byte) in input.iter().enumerate() {
+            match self.child(node, *byte) {
+                Some(child) => node = child,
+                None => {
+                    // If a value ends here, the rest of the input is left for the next parser
+                    if let Some(value) = self.nodes[node].value {
+                        return Ok(ParseStatus::Finished {
+                            result: self.values[value].clone(),
+                            remaining: &input[i..],
+                        });
+                    }
+                    crate::bail!(ParserError::from(LiteralMismatchError).in_parser("literal_set"));
+                }
+            }
+        }
+
+        let current = &self.nodes[node];
+        if let (Some(value), true) = (current.value, current.children.is_empty()) {
+            return Ok(ParseStatus::Finished {
+                result: self.values[value].clone(),
+                remaining: &[],
+            });
+        }
+
+        // Follow the trie until the values branch or one of them ends
+        let mut required_next = Vec::new();
+        let mut next = current;
+        while let (None, [(byte, child)]) = (next.value, next.children.as_slice()) {
+            required_next.push(*byte);
+            next = &self.nodes[*child];
+        }
+        let required_next = match String::from_utf8(required_next) {
+            Ok(required_next) => required_next,
+            Err(err) => {
+                let valid = err.utf8_error().valid_up_to();
+                let mut bytes = err.into_bytes();
+                bytes.truncate(valid);
+                String::from_utf8(bytes).unwrap()
+            }
+        };
+
+        Ok(ParseStatus::Incomplete {
+            new_state: LiteralSetParserState { node },
+            required_next: required_next.into(),
+        })
+    }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        let key = (trie.id(), state.node);
+        self.mask_cache
+            .get_or_insert_with(key, || trie.allowed_tokens(self, state))
+    }
+}
+
+#[test]
+fn literal_set_parser() {
+    let skus: Vec<String> = (0..5000).map(|i| format!("SKU-{i:05}")).collect();
+    let parser = LiteralSetParser::new(skus.iter().map(String::as_str).chain(["ab", "abc", "ab"]));
+    assert_eq!(parser.values().len(), 5002);
+    let state = parser.create_parser_state();
+
+    let (new_state, required_next) = parser.parse(&state, b"S").unwrap().unwrap_incomplete();
+    assert_eq!(required_next, "KU-0");
+    let (new_state, required_next) = parser
+        .parse(&new_state, b"KU-0420")
+        .unwrap()
+        .unwrap_incomplete();
+    assert_eq!(required_next, "");
+    assert_eq!(
+        parser.parse(&new_state, b"7\"").unwrap(),
+        ParseStatus::Finished {
+            result: "SKU-04207".to_string(),
+            remaining: b"\""
+        }
+    );
+    assert!(parser.parse(&state, b"SKU-5").is_err());
+
+    // A value that is a prefix of another value can end before the longer value
+    let (new_state, _) = parser.parse(&state, b"ab").unwrap().unwrap_incomplete();
+    assert_eq!(
+        parser.parse(&new_state, b",").unwrap(),
+        ParseStatus::Finished {
+            result: "ab".to_string(),
+            remaining: b","
+        }
+    );
+    assert_eq!(
+        parser.parse(&new_state, b"c").unwrap().unwrap_finished(),
+        "abc"
+    );
+
+    let trie = TokenTrie::new([
+        (0, "SKU-"),
+        (1, "SKU-0"),
+        (2, "ab"),
+        (3, "xyz"),
+        (4, "SKU-9"),
+    ]);
+    assert_eq!(parser.allowed_tokens(&state, &trie).allowed(), [0, 1, 2]);
+}
@@ -39,4 +39,12 @@ impl<P: Parser, F: Fn(P::Output) -> O, O> Parser for MapOutputParser<P, F, O> {
             }),
         }
     }
+
+    fn allowed_tokens(
+        &self,
+        state: &Self::PartialState,
+        trie: &crate::TokenTrie,
+    ) -> std::sync::Arc<crate::TokenMask> {
+        self.parser.allowed_tokens(state, trie)
+    }
 }
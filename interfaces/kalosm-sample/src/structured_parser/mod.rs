@@ -10,11 +10,15 @@ use std::{
     sync::Arc,
 };
 
+use crate::{TokenMask, TokenTrie};
+
 pub use integer::*;
 mod float;
 pub use float::*;
 mod literal;
 pub use literal::*;
+mod literal_set;
+pub use literal_set::*;
 mod or;
 pub use or::*;
 mod then;
@@ -37,10 +41,25 @@ mod map;
 pub use map::*;
 mod regex;
 pub use regex::*;
+mod json_schema;
+pub use json_schema::*;
+mod grammar;
+pub use grammar::*;
+mod diagnostic;
+pub use diagnostic::*;
+mod formats;
+pub use formats::*;
+mod description;
+pub use description::*;
+mod recursive;
+pub use recursive::*;
 
 /// A parser error.
 #[derive(Debug, Clone)]
-pub struct ParserError(Arc<anyhow::Error>);
+pub struct ParserError {
+    error: Arc<anyhow::Error>,
+    path: Vec<&'static str>,
+}
 
 /// Bail out with the given error.
 #[macro_export]
@@ -59,13 +78,27 @@ macro_rules! bail {
 impl ParserError {
     /// Create a new error with the given message.
     pub fn msg(msg: impl Display + Debug + Send + Sync + 'static) -> Self {
-        Self(Arc::new(anyhow::Error::msg(msg)))
+        Self {
+            error: Arc::new(anyhow::Error::msg(msg)),
+            path: Vec::new(),
+        }
+    }
+
+    /// Record that the error passed through the parser with the given name. Parsers call this on errors from their children, so the outermost parser is added last.
+    pub fn in_parser(mut self, name: &'static str) -> Self {
+        self.path.insert(0, name);
+        self
+    }
+
+    /// Get the names of the parsers the error passed through, from the outermost parser to the parser that rejected the input.
+    pub fn path(&self) -> &[&'static str] {
+        &self.path
     }
 }
 
 impl PartialEq for ParserError {
     fn eq(&self, other: &Self) -> bool {
-        Arc::ptr_eq(&self.0, &other.0)
+        Arc::ptr_eq(&self.error, &other.error) && self.path == other.path
     }
 }
 
@@ -73,14 +106,14 @@ impl Eq for ParserError {}
 
 impl AsRef<dyn Error> for ParserError {
     fn as_ref(&self) -> &(dyn Error + 'static) {
-        let err: &anyhow::Error = self.0.as_ref();
+        let err: &anyhow::Error = self.error.as_ref();
         err.as_ref()
     }
 }
 
 impl AsRef<dyn std::error::Error + Send + Sync + 'static> for ParserError {
     fn as_ref(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
-        let err: &anyhow::Error = self.0.as_ref();
+        let err: &anyhow::Error = self.error.as_ref();
         err.as_ref()
     }
 }
@@ -89,7 +122,7 @@ impl Deref for ParserError {
     type Target = (dyn Error + Send + Sync + 'static);
 
     fn deref(&self) -> &(dyn Error + Send + Sync + 'static) {
-        let err: &anyhow::Error = self.0.as_ref();
+        let err: &anyhow::Error = self.error.as_ref();
         err.deref()
     }
 }
@@ -99,7 +132,10 @@ where
     E: std::error::Error + Send + Sync + 'static,
 {
     fn from(value: E) -> Self {
-        Self(Arc::new(anyhow::Error::from(value)))
+        Self {
+            error: Arc::new(anyhow::Error::from(value)),
+            path: Vec::new(),
+        }
     }
 }
 
@@ -149,6 +185,13 @@ pub trait Parser {
         state: &Self::PartialState,
         input: &'a [u8],
     ) -> ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>>;
+
+    /// Get the tokens in the trie that this parser accepts from the given state.
+    ///
+    /// By default this walks the trie with [`TokenTrie::allowed_tokens`]. Parsers with a small number of states, like [`RegexParser`], cache the mask for each state.
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        Arc::new(trie.allowed_tokens(self, state))
+    }
 }
 
 impl Parser for () {
@@ -178,6 +221,10 @@ impl<P: ?Sized + Parser> Parser for &P {
     ) -> ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
         (*self).parse(state, input)
     }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        (*self).allowed_tokens(state, trie)
+    }
 }
 
 impl<P: ?Sized + Parser> Parser for Box<P> {
@@ -192,6 +239,11 @@ impl<P: ?Sized + Parser> Parser for Box<P> {
         let _self: &P = self;
         _self.parse(state, input)
     }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        let _self: &P = self;
+        _self.allowed_tokens(state, trie)
+    }
 }
 
 impl<P: ?Sized + Parser> Parser for Arc<P> {
@@ -206,6 +258,11 @@ impl<P: ?Sized + Parser> Parser for Arc<P> {
         let _self: &P = self;
         _self.parse(state, input)
     }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        let _self: &P = self;
+        _self.allowed_tokens(state, trie)
+    }
 }
 
 trait AnyCreateParserState<O>:
@@ -257,6 +314,11 @@ impl<O> Parser for ArcParser<O> {
         let _self: &dyn Parser<Output = O, PartialState = Arc<dyn Any + Send + Sync>> = &self.0;
         _self.parse(state, input)
     }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        let _self: &dyn Parser<Output = O, PartialState = Arc<dyn Any + Send + Sync>> = &self.0;
+        _self.allowed_tokens(state, trie)
+    }
 }
 
 /// A wrapper for a parser that implements an easily boxable version of Parser.
@@ -294,6 +356,13 @@ where
             .parse(state, input)
             .map(|result| result.map_state(|state| Arc::new(state) as Arc<dyn Any + Sync + Send>))
     }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        match state.downcast_ref::<P::PartialState>() {
+            Some(state) => self.0.allowed_tokens(state, trie),
+            None => Arc::new(TokenMask::default()),
+        }
+    }
 }
 
 impl<P: CreateParserState> CreateParserState for AnyParser<P>
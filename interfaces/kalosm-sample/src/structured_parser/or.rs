@@ -195,11 +195,14 @@ impl<
                     }
 
                     // If both parsers fail, we return the error from the first parser
-                    (Err(err1), Err(_)) => Err(err1),
+                    (Err(err1), Err(_)) => Err(err1.in_parser("or")),
                 }
             }
             (Ok(p1), Err(err2)) => {
-                let result = self.parser1.parse(p1, input)?;
+                let result = self
+                    .parser1
+                    .parse(p1, input)
+                    .map_err(|err| err.in_parser("or"))?;
                 match result {
                     ParseStatus::Finished { result, remaining } => Ok(ParseStatus::Finished {
                         result: Either::Left(result),
@@ -221,7 +224,10 @@ impl<
                 }
             }
             (Err(err1), Ok(p2)) => {
-                let result = self.parser2.parse(p2, input)?;
+                let result = self
+                    .parser2
+                    .parse(p2, input)
+                    .map_err(|err| err.in_parser("or"))?;
                 match result {
                     ParseStatus::Finished { result, remaining } => Ok(ParseStatus::Finished {
                         result: Either::Right(result),
@@ -0,0 +1,253 @@
+use std::{
+    any::Any,
+    fmt::Display,
+    sync::{Arc, OnceLock, Weak},
+};
+
+use crate::{ArcParser, CreateParserState, ParseStatus, Parser, ParserError, ParserExt};
+use crate::{TokenMask, TokenTrie};
+
+/// A parser that can contain itself, like a nested list, a tree or a JSON value.
+///
+/// The parser is defined by a function that receives a [`RecursiveParserRef`] to the parser being defined. Each level of nesting is built the first time it is used, and the reference fails once the input is nested more than `max_depth` levels deep. The state of each level is boxed, so the state grows linearly with the nesting depth.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// // A nested list of integers like [1, [2, 3]]
+/// let parser = RecursiveParser::new(4, |list| {
+///     let item = IntegerParser::new(0..=9)
+///         .map_output(|_| ())
+///         .or(list)
+///         .map_output(|_| ());
+///     LiteralParser::from("[")
+///         .then(SeparatedParser::new(item, LiteralParser::from(", "), 0..=usize::MAX))
+///         .then(LiteralParser::from("]"))
+///         .map_output(|_| ())
+/// });
+/// let state = parser.create_parser_state();
+/// assert!(parser.parse(&state, b"[1, [2, [3]], []]").is_ok());
+/// assert!(parser.parse(&state, b"[[[[[1]]]]]").is_err());
+/// ```
+pub struct RecursiveParser<O> {
+    levels: Arc<RecursiveLevels<O>>,
+}
+
+impl<O> Clone for RecursiveParser<O> {
+    fn clone(&self) -> Self {
+        Self {
+            levels: self.levels.clone(),
+        }
+    }
+}
+
+type BuildLevel<O> = dyn Fn(RecursiveParserRef<O>) -> ArcParser<O> + Send + Sync;
+
+struct RecursiveLevels<O> {
+    build: Box<BuildLevel<O>>,
+    // The parser for each level of nesting. The reference in each level points to the next level
+    levels: Vec<OnceLock<ArcParser<O>>>,
+}
+
+impl<O> RecursiveLevels<O> {
+    fn level(self: &Arc<Self>, depth: usize) -> Option<&ArcParser<O>> {
+        let level = self.levels.get(depth)?;
+        Some(level.get_or_init(|| {
+            (self.build)(RecursiveParserRef {
+                levels: Arc::downgrade(self),
+                depth: depth + 1,
+            })
+        }))
+    }
+}
+
+impl<O: Send + Sync + 'static> RecursiveParser<O> {
+    /// Create a new recursive parser that allows at most `max_depth` levels of nesting, counting the outermost level. A `max_depth` of zero is treated as one.
+    pub fn new<P>(
+        max_depth: usize,
+        build: impl Fn(RecursiveParserRef<O>) -> P + Send + Sync + 'static,
+    ) -> Self
+    where
+        P: CreateParserState + Parser<Output = O> + Send + Sync + 'static,
+        P::PartialState: Send + Sync + 'static,
+    {
+        Self {
+            levels: Arc::new(RecursiveLevels {
+                build: Box::new(move |parser| build(parser).boxed()),
+                levels: (0..max_depth.max(1)).map(|_| OnceLock::new()).collect(),
+            }),
+        }
+    }
+
+    /// Get the maximum number of levels of nesting the parser allows.
+    pub fn max_depth(&self) -> usize {
+        self.levels.levels.len()
+    }
+}
+
+impl<O> CreateParserState for RecursiveParser<O> {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        self.levels.level(0).unwrap().create_parser_state()
+    }
+}
+
+impl<O> Parser for RecursiveParser<O> {
+    type Output = O;
+    type PartialState = Arc<dyn Any + Send + Sync>;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        self.levels.level(0).unwrap().parse(state, input)
+    }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        self.levels.level(0).unwrap().allowed_tokens(state, trie)
+    }
+}
+
+/// A reference to a [`RecursiveParser`] from inside its own definition. Each reference parses the next level of nesting.
+pub struct RecursiveParserRef<O> {
+    levels: Weak<RecursiveLevels<O>>,
+    depth: usize,
+}
+
+impl<O> Clone for RecursiveParserRef<O> {
+    fn clone(&self) -> Self {
+        Self {
+            levels: self.levels.clone(),
+            depth: self.depth,
+        }
+    }
+}
+
+impl<O> RecursiveParserRef<O> {
+    /// Get the level of nesting this reference parses.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn with_level<R>(&self, f: impl FnOnce(Option<&ArcParser<O>>) -> R) -> R {
+        let levels = self
+            .levels
+            .upgrade()
+            .expect("RecursiveParserRef was used after the RecursiveParser was dropped");
+        f(levels.level(self.depth))
+    }
+}
+
+/// The state of a [`RecursiveParserRef`].
+#[derive(Clone)]
+pub struct RecursiveParserRefState {
+    // The state of the next level, or None if the input is nested too deeply
+    state: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+/// An error that occurs when the input is nested deeper than a [`RecursiveParser`] allows.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct RecursionLimitError;
+
+impl Display for RecursionLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Recursion limit reached")
+    }
+}
+
+impl std::error::Error for RecursionLimitError {}
+
+impl<O> CreateParserState for RecursiveParserRef<O> {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        RecursiveParserRefState {
+            state: self.with_level(|level| level.map(|level| level.create_parser_state())),
+        }
+    }
+}
+
+impl<O> Parser for RecursiveParserRef<O> {
+    type Output = O;
+    type PartialState = RecursiveParserRefState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        let Some(inner_state) = &state.state else {
+            crate::bail!(ParserError::from(RecursionLimitError).in_parser("recursive"));
+        };
+        let result = self
+            .with_level(|level| level.unwrap().parse(inner_state, input))
+            .map_err(|err| err.in_parser("recursive"))?;
+        Ok(match result {
+            ParseStatus::Incomplete {
+                new_state,
+                required_next,
+            } => ParseStatus::Incomplete {
+                new_state: RecursiveParserRefState {
+                    state: Some(new_state),
+                },
+                required_next,
+            },
+            ParseStatus::Finished { result, remaining } => {
+                ParseStatus::Finished { result, remaining }
+            }
+        })
+    }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        match &state.state {
+            Some(inner_state) => {
+                self.with_level(|level| level.unwrap().allowed_tokens(inner_state, trie))
+            }
+            None => Arc::new(trie.allowed_tokens(self, state)),
+        }
+    }
+}
+
+#[test]
+fn recursive_parser() {
+    use crate::{IntegerParser, LiteralParser, SeparatedParser};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Tree {
+        Leaf(i128),
+        Node(Vec<Tree>),
+    }
+
+    let parser = RecursiveParser::new(3, |tree| {
+        let item = IntegerParser::new(0..=9)
+            .map_output(Tree::Leaf)
+            .or(tree)
+            .map_output(|either| match either {
+                crate::Either::Left(tree) | crate::Either::Right(tree) => tree,
+            });
+        LiteralParser::from("(")
+            .then(SeparatedParser::new(item, LiteralParser::from(" "), 1..=3))
+            .then(LiteralParser::from(")"))
+            .map_output(|((_, items), _)| Tree::Node(items))
+    });
+    let state = parser.create_parser_state();
+
+    assert_eq!(
+        parser
+            .parse(&state, b"(1 (2 3) (4 (5)))")
+            .unwrap()
+            .unwrap_finished(),
+        Tree::Node(vec![
+            Tree::Leaf(1),
+            Tree::Node(vec![Tree::Leaf(2), Tree::Leaf(3)]),
+            Tree::Node(vec![Tree::Leaf(4), Tree::Node(vec![Tree::Leaf(5)])]),
+        ])
+    );
+
+    // The fourth level of parentheses is deeper than the maximum depth
+    assert!(parser.parse(&state, b"((((").is_err());
+    let (state, _) = parser.parse(&state, b"(((").unwrap().unwrap_incomplete();
+    assert!(parser.parse(&state, b"(").is_err());
+
+    let trie = TokenTrie::new([(0, "("), (1, "7"), (2, ")")]);
+    assert_eq!(parser.allowed_tokens(&state, &trie).allowed(), [1]);
+}
@@ -1,6 +1,12 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
-use crate::{CreateParserState, Parser};
+use crate::{
+    CreateParserState, MaskCache, ParseStatus, Parser, ParserError, StringParser,
+    StringParserState, TokenMask, TokenTrie,
+};
 use regex_automata::{
     dfa::{dense, Automaton},
     util::primitives::StateID,
@@ -12,6 +18,8 @@ pub struct RegexParser {
     config: regex_automata::util::start::Config,
     // A cache for the required next bytes for each state
     jump_table: RwLock<HashMap<StateID, String>>,
+    // A cache for the allowed tokens for each trie and state
+    mask_cache: MaskCache<(u64, StateID)>,
 }
 
 impl RegexParser {
@@ -27,6 +35,7 @@ impl RegexParser {
             dfa,
             config,
             jump_table: Default::default(),
+            mask_cache: Default::default(),
         })
     }
 }
@@ -57,10 +66,13 @@ impl Parser for RegexParser {
                         remaining: Default::default(),
                     })
                 } else {
-                    crate::bail!(regex_automata::MatchError::quit(b, 0))
+                    crate::bail!(ParserError::from(regex_automata::MatchError::quit(b, 0))
+                        .in_parser("regex"))
                 };
             } else if self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state) {
-                crate::bail!(regex_automata::MatchError::quit(b, 0));
+                crate::bail!(
+                    ParserError::from(regex_automata::MatchError::quit(b, 0)).in_parser("regex")
+                );
             }
         }
 
@@ -98,13 +110,12 @@ impl Parser for RegexParser {
                 }
             }
 
-            if !required_next.is_empty() {
-                drop(jump_table_read);
-                self.jump_table
-                    .write()
-                    .unwrap()
-                    .insert(state, required_next.clone());
-            }
+            // Cache states without any required text too so they don't need to check every byte again
+            drop(jump_table_read);
+            self.jump_table
+                .write()
+                .unwrap()
+                .insert(state, required_next.clone());
         }
 
         Ok(crate::ParseStatus::Incomplete {
@@ -112,4 +123,171 @@ impl Parser for RegexParser {
             required_next: required_next.into(),
         })
     }
+
+    fn allowed_tokens(&self, state: &Self::PartialState, trie: &TokenTrie) -> Arc<TokenMask> {
+        let key = (trie.id(), *state);
+        self.mask_cache
+            .get_or_insert_with(key, || trie.allowed_tokens(self, state))
+    }
+}
+
+/// A parser for a JSON string whose contents match a regex pattern.
+///
+/// The pattern must match the whole contents of the string. Escape sequences in the string are decoded before they are matched, so the pattern describes the text of the string, not how it is written in JSON.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let parser = RegexStringParser::new(r"[a-z]+@[a-z]+\.com").unwrap();
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, br#""ada@example.com""#).unwrap();
+/// assert_eq!(result.unwrap_finished(), "ada@example.com");
+///
+/// // Escaped quotes are matched as quotes
+/// let parser = RegexStringParser::new(r#"say "[a-z]+""#).unwrap();
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, br#""say \"hi\"""#).unwrap();
+/// assert_eq!(result.unwrap_finished(), r#"say "hi""#);
+/// ```
+pub struct RegexStringParser {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    string: StringParser,
+}
+
+impl RegexStringParser {
+    /// Create a new `RegexStringParser` from a regex pattern.
+    pub fn new(regex: &str) -> anyhow::Result<Self> {
+        // Keep every match alive so the string can end after any of them, not just after the first alternative that matched
+        let dfa = dense::Builder::new()
+            .configure(dense::Config::new().match_kind(regex_automata::MatchKind::All))
+            .build(regex)?;
+        let config =
+            regex_automata::util::start::Config::new().anchored(regex_automata::Anchored::Yes);
+        let start = dfa.start_state(&config)?;
+
+        Ok(Self {
+            dfa,
+            start,
+            string: StringParser::new(0..=usize::MAX),
+        })
+    }
+
+    /// Run the regex over the characters that were decoded since the last state.
+    fn advance(&self, mut state: StateID, decoded: &[u8]) -> crate::ParseResult<StateID> {
+        for byte in decoded {
+            state = self.dfa.next_state(state, *byte);
+            if self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state) {
+                crate::bail!(ParserError::from(PatternMismatchError).in_parser("regex"));
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// The state of a [`RegexStringParser`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RegexStringParserState {
+    state: StateID,
+    string: StringParserState,
+}
+
+impl RegexStringParserState {
+    /// The decoded text of the string so far.
+    pub(crate) fn text(&self) -> &str {
+        self.string.text()
+    }
+}
+
+/// An error that can occur while parsing a string that must match a regex pattern.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct PatternMismatchError;
+
+impl std::fmt::Display for PatternMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "String does not match the pattern")
+    }
+}
+
+impl std::error::Error for PatternMismatchError {}
+
+impl CreateParserState for RegexStringParser {
+    fn create_parser_state(&self) -> <Self as Parser>::PartialState {
+        RegexStringParserState {
+            state: self.start,
+            string: self.string.create_parser_state(),
+        }
+    }
+}
+
+impl Parser for RegexStringParser {
+    type Output = String;
+    type PartialState = RegexStringParserState;
+
+    fn parse<'a>(
+        &self,
+        state: &Self::PartialState,
+        input: &'a [u8],
+    ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
+        // The string parser tracks the quotes and escape sequences, so an escaped quote never ends the string
+        let decoded_len = state.text().len();
+        match self
+            .string
+            .parse(&state.string, input)
+            .map_err(|err| err.in_parser("regex"))?
+        {
+            ParseStatus::Incomplete { new_state, .. } => {
+                let regex_state =
+                    self.advance(state.state, &new_state.text().as_bytes()[decoded_len..])?;
+                Ok(ParseStatus::Incomplete {
+                    new_state: RegexStringParserState {
+                        state: regex_state,
+                        string: new_state,
+                    },
+                    required_next: Default::default(),
+                })
+            }
+            ParseStatus::Finished { result, remaining } => {
+                let regex_state = self.advance(state.state, &result.as_bytes()[decoded_len..])?;
+                if !self
+                    .dfa
+                    .is_match_state(self.dfa.next_eoi_state(regex_state))
+                {
+                    crate::bail!(ParserError::from(PatternMismatchError).in_parser("regex"));
+                }
+                Ok(ParseStatus::Finished { result, remaining })
+            }
+        }
+    }
+}
+
+#[test]
+fn regex_string_parser_decodes_escapes() {
+    let parser = RegexStringParser::new(r#"[a-z"\\]+"#).unwrap();
+    let state = parser.create_parser_state();
+    // An escaped quote is part of the string even if the text before it already matches
+    let (state, _) = parser
+        .parse(&state, br#""ab\""#)
+        .unwrap()
+        .unwrap_incomplete();
+    assert_eq!(state.text(), "ab\"");
+    assert_eq!(
+        parser.parse(&state, br#"\\c""#).unwrap().unwrap_finished(),
+        "ab\"\\c"
+    );
+
+    // Escapes are matched as the characters they decode to
+    let parser = RegexStringParser::new("a\nb").unwrap();
+    let state = parser.create_parser_state();
+    assert_eq!(
+        parser
+            .parse(&state, br#""a\nb""#)
+            .unwrap()
+            .unwrap_finished(),
+        "a\nb"
+    );
+    assert!(parser.parse(&state, br#""a\\n"#).is_err());
+    // The string can't end before the pattern matches
+    assert!(parser.parse(&state, br#""a""#).is_err());
 }
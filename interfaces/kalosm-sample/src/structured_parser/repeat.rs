@@ -152,7 +152,7 @@ where
                             remaining,
                         });
                     } else {
-                        return Err(e);
+                        return Err(e.in_parser("repeat"));
                     }
                 }
             }
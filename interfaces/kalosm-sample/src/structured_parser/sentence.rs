@@ -48,7 +48,7 @@ impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> DerefMut
     }
 }
 
-/// A parser for a word.
+/// A parser for a sentence.
 pub struct SentenceParser<const MIN_LENGTH: usize, const MAX_LENGTH: usize> {
     parser: StringParser<fn(char) -> bool>,
 }
@@ -65,6 +65,23 @@ impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> Default
     }
 }
 
+impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> SentenceParser<MIN_LENGTH, MAX_LENGTH> {
+    /// Only allow characters that pass the filter instead of the default sentence characters.
+    pub fn with_allowed_characters(mut self, character_filter: fn(char) -> bool) -> Self {
+        self.parser = self.parser.with_allowed_characters(character_filter);
+        self
+    }
+
+    /// Reject sentences that contain any of the substrings.
+    pub fn with_forbidden_substrings(
+        mut self,
+        substrings: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.parser = self.parser.with_forbidden_substrings(substrings);
+        self
+    }
+}
+
 impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> CreateParserState
     for SentenceParser<MIN_LENGTH, MAX_LENGTH>
 {
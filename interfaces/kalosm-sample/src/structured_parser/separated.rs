@@ -188,7 +188,7 @@ where
                                     remaining,
                                 });
                             } else {
-                                crate::bail!(e);
+                                crate::bail!(e.in_parser("separated"));
                             }
                         }
                     }
@@ -217,6 +217,7 @@ where
                                     }) => required_next = Some(new_required_next),
                                     _ => required_next = None,
                                 }
+                                state.last_state = SeparatedItemState::Item(item_state);
                                 break;
                             }
                             state.last_state = SeparatedItemState::Item(item_state);
@@ -237,7 +238,7 @@ where
                                     remaining,
                                 });
                             } else {
-                                crate::bail!(e);
+                                crate::bail!(e.in_parser("separated"));
                             }
                         }
                     }
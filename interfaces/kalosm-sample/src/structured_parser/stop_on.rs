@@ -1,4 +1,4 @@
-use crate::{CreateParserState, ParseStatus, Parser};
+use crate::{CreateParserState, ParseStatus, Parser, ParserError};
 
 type CharFilter = fn(char) -> bool;
 
@@ -96,7 +96,7 @@ impl<S: AsRef<str>, F: Fn(char) -> bool + 'static> Parser for StopOn<S, F> {
 
         for (i, input_char) in input_str.char_indices() {
             if !(self.character_filter)(input_char) {
-                crate::bail!(StopOnParseError);
+                crate::bail!(ParserError::from(StopOnParseError).in_parser("stop_on"));
             }
 
             let literal_char = literal_iter.next();
@@ -1,26 +1,46 @@
-use crate::{CreateParserState, ParseStatus, Parser};
+use crate::{CreateParserState, ParseStatus, Parser, ParserError};
 
 type CharFilter = fn(char) -> bool;
 
-/// A parser for an ascii string.
+/// A parser for a JSON string.
+///
+/// Escape sequences like `\n` and `\u00e9` are decoded, and the length range, character filters and forbidden substrings apply to the decoded characters. The length range counts characters, not bytes, so `"café"` has a length of 4 even though it is 5 bytes long.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let parser = StringParser::new(1..=20)
+///     .with_disallowed_characters(char::is_whitespace)
+///     .with_forbidden_substrings(["--"]);
+/// let state = parser.create_parser_state();
+/// let result = parser.parse(&state, br#""caf\u00e9-au-lait""#).unwrap();
+/// assert_eq!(result.unwrap_finished(), "café-au-lait");
+/// assert!(parser.parse(&state, br#""caf\u00e9 au lait""#).is_err());
+/// assert!(parser.parse(&state, br#""caf\u00e9--""#).is_err());
+/// ```
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct StringParser<F: Fn(char) -> bool + 'static = CharFilter> {
     len_range: std::ops::RangeInclusive<usize>,
     character_filter: F,
+    forbidden_substrings: Vec<String>,
+    escapes: bool,
 }
 
-impl CreateParserState for StringParser<fn(char) -> bool> {
+impl<F: Fn(char) -> bool + 'static> CreateParserState for StringParser<F> {
     fn create_parser_state(&self) -> <Self as Parser>::PartialState {
         StringParserState::default()
     }
 }
 
 impl StringParser<fn(char) -> bool> {
-    /// Create a new string parser.
+    /// Create a new string parser that accepts strings with a number of characters (not bytes) in the range.
     pub fn new(len_range: std::ops::RangeInclusive<usize>) -> Self {
         Self {
             len_range,
             character_filter: |_| true,
+            forbidden_substrings: Vec::new(),
+            escapes: true,
         }
     }
 }
@@ -34,8 +54,57 @@ impl<F: Fn(char) -> bool + 'static> StringParser<F> {
         StringParser {
             len_range: self.len_range,
             character_filter,
+            forbidden_substrings: self.forbidden_substrings,
+            escapes: self.escapes,
         }
     }
+
+    /// Reject characters that pass the filter in addition to the characters the parser already rejects.
+    pub fn with_disallowed_characters<F2: Fn(char) -> bool + 'static>(
+        self,
+        character_filter: F2,
+    ) -> StringParser<impl Fn(char) -> bool + 'static> {
+        let allowed = self.character_filter;
+        StringParser {
+            len_range: self.len_range,
+            character_filter: move |c| allowed(c) && !character_filter(c),
+            forbidden_substrings: self.forbidden_substrings,
+            escapes: self.escapes,
+        }
+    }
+
+    /// Reject strings that contain any of the substrings.
+    pub fn with_forbidden_substrings(
+        mut self,
+        substrings: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.forbidden_substrings
+            .extend(substrings.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set whether escape sequences are allowed in the string. If they are not allowed, backslashes are rejected. Defaults to true.
+    pub fn with_escapes(mut self, escapes: bool) -> Self {
+        self.escapes = escapes;
+        self
+    }
+
+    /// Add a character to the string if the constraints of the parser allow it.
+    fn push(&self, state: &mut StringParserState, character: char) -> crate::ParseResult<()> {
+        if !(self.character_filter)(character) || state.length == *self.len_range.end() {
+            crate::bail!(ParserError::from(StringParseError).in_parser("string"));
+        }
+        state.string.push(character);
+        state.length += 1;
+        if self
+            .forbidden_substrings
+            .iter()
+            .any(|substring| state.string.ends_with(substring.as_str()))
+        {
+            crate::bail!(ParserError::from(StringParseError).in_parser("string"));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Default, Debug, PartialEq, Eq, Clone)]
@@ -45,16 +114,31 @@ enum StringParserProgress {
     InString,
 }
 
-/// The state of a literal parser.
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+enum StringEscape {
+    #[default]
+    None,
+    Backslash,
+    Unicode {
+        digits: u8,
+        value: u32,
+    },
+}
+
+/// The state of a string parser.
 #[derive(Default, Debug, PartialEq, Eq, Clone)]
 pub struct StringParserState {
     progress: StringParserProgress,
     string: String,
-    next_char_escaped: bool,
+    length: usize,
+    escape: StringEscape,
+    // The first half of a surrogate pair written as two `\u` escapes
+    high_surrogate: Option<u32>,
+    partial_character: Vec<u8>,
 }
 
 impl StringParserState {
-    /// Create a new literal parser state.
+    /// Create a new string parser state.
     pub fn new(string: String) -> Self {
         let progress = if string.starts_with('"') {
             StringParserProgress::InString
@@ -63,10 +147,22 @@ impl StringParserState {
         };
         Self {
             progress,
-            next_char_escaped: string.ends_with('\\'),
+            escape: if string.ends_with('\\') {
+                StringEscape::Backslash
+            } else {
+                StringEscape::None
+            },
+            length: string.chars().count(),
             string,
+            high_surrogate: None,
+            partial_character: Vec::new(),
         }
     }
+
+    /// The decoded text of the string so far.
+    pub(crate) fn text(&self) -> &str {
+        &self.string
+    }
 }
 
 /// An error that can occur while parsing a string literal.
@@ -90,58 +186,120 @@ impl<F: Fn(char) -> bool + 'static> Parser for StringParser<F> {
         state: &StringParserState,
         input: &'a [u8],
     ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
-        let StringParserState {
-            mut progress,
-            mut string,
-            mut next_char_escaped,
-        } = state.clone();
+        let mut state = state.clone();
 
         for (i, byte) in input.iter().enumerate() {
-            match progress {
-                StringParserProgress::BeforeQuote => {
-                    if *byte == b'"' {
-                        progress = StringParserProgress::InString;
-                    } else {
-                        crate::bail!(StringParseError);
-                    }
+            if state.progress == StringParserProgress::BeforeQuote {
+                if *byte == b'"' {
+                    state.progress = StringParserProgress::InString;
+                    continue;
                 }
-                StringParserProgress::InString => {
-                    if (state.next_char_escaped || *byte != b'"')
-                        && !(self.character_filter)(*byte as char)
-                    {
-                        crate::bail!(StringParseError);
-                    }
+                crate::bail!(ParserError::from(StringParseError).in_parser("string"));
+            }
 
-                    if string.len() == *self.len_range.end() && *byte != b'"' {
-                        crate::bail!(StringParseError);
-                    }
+            state.partial_character.push(*byte);
+            let character = match std::str::from_utf8(&state.partial_character) {
+                Ok(text) => text.chars().next().unwrap(),
+                // Wait for the rest of the character
+                Err(err) if err.error_len().is_none() => continue,
+                Err(err) => crate::bail!(ParserError::from(err).in_parser("string")),
+            };
+            state.partial_character.clear();
 
-                    if next_char_escaped {
-                        next_char_escaped = false;
-                        string.push(*byte as char);
-                    } else if *byte == b'"' {
-                        if !self.len_range.contains(&string.len()) {
-                            crate::bail!(StringParseError);
+            // The second half of a surrogate pair must follow the first half
+            if state.high_surrogate.is_some()
+                && !matches!(
+                    (state.escape, character),
+                    (StringEscape::None, '\\')
+                        | (StringEscape::Backslash, 'u')
+                        | (StringEscape::Unicode { .. }, _)
+                )
+            {
+                crate::bail!(ParserError::from(StringParseError).in_parser("string"));
+            }
+
+            match state.escape {
+                StringEscape::None => match character {
+                    '"' => {
+                        if !self.len_range.contains(&state.length) {
+                            crate::bail!(ParserError::from(StringParseError).in_parser("string"));
                         }
                         return Ok(ParseStatus::Finished {
                             remaining: &input[i + 1..],
-                            result: string,
+                            result: state.string,
                         });
-                    } else if *byte == b'\\' {
-                        next_char_escaped = true;
-                    } else {
-                        string.push(*byte as char);
                     }
+                    // Every escape sequence adds a character, so it can't start once the string is full
+                    '\\' if self.escapes && state.length < *self.len_range.end() => {
+                        state.escape = StringEscape::Backslash
+                    }
+                    '\\' => {
+                        crate::bail!(ParserError::from(StringParseError).in_parser("string"))
+                    }
+                    // JSON strings cannot contain raw control characters
+                    character if character < ' ' => {
+                        crate::bail!(ParserError::from(StringParseError).in_parser("string"))
+                    }
+                    character => self.push(&mut state, character)?,
+                },
+                StringEscape::Backslash => {
+                    state.escape = StringEscape::None;
+                    let escaped = match character {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            state.escape = StringEscape::Unicode {
+                                digits: 0,
+                                value: 0,
+                            };
+                            continue;
+                        }
+                        _ => crate::bail!(ParserError::from(StringParseError).in_parser("string")),
+                    };
+                    self.push(&mut state, escaped)?;
+                }
+                StringEscape::Unicode { digits, value } => {
+                    let Some(digit) = character.to_digit(16) else {
+                        crate::bail!(ParserError::from(StringParseError).in_parser("string"));
+                    };
+                    let value = value * 16 + digit;
+                    if digits < 3 {
+                        state.escape = StringEscape::Unicode {
+                            digits: digits + 1,
+                            value,
+                        };
+                        continue;
+                    }
+                    state.escape = StringEscape::None;
+                    let code_point = match state.high_surrogate.take() {
+                        Some(high) if (0xDC00..=0xDFFF).contains(&value) => {
+                            0x10000 + ((high - 0xD800) << 10) + (value - 0xDC00)
+                        }
+                        Some(_) => {
+                            crate::bail!(ParserError::from(StringParseError).in_parser("string"))
+                        }
+                        None if (0xD800..=0xDBFF).contains(&value) => {
+                            state.high_surrogate = Some(value);
+                            continue;
+                        }
+                        None => value,
+                    };
+                    let Some(character) = char::from_u32(code_point) else {
+                        crate::bail!(ParserError::from(StringParseError).in_parser("string"));
+                    };
+                    self.push(&mut state, character)?;
                 }
             }
         }
 
         Ok(ParseStatus::Incomplete {
-            new_state: StringParserState {
-                progress,
-                string,
-                next_char_escaped,
-            },
+            new_state: state,
             required_next: "".into(),
         })
     }
@@ -165,7 +323,8 @@ fn literal_parser() {
             new_state: StringParserState {
                 progress: StringParserProgress::InString,
                 string: "Hello, ".to_string(),
-                next_char_escaped: false,
+                length: 7,
+                ..Default::default()
             },
             required_next: "".into()
         })
@@ -186,3 +345,44 @@ fn literal_parser() {
         })
     );
 }
+
+#[test]
+fn string_parser_decodes_split_characters() {
+    let parser = StringParser::new(1..=3).with_escapes(true);
+    let state = parser.create_parser_state();
+    let text = "\"é\\ud83d\\ude00\"".as_bytes();
+    // Feed the input one byte at a time so multi-byte characters and escapes are split
+    let mut state = state;
+    for byte in &text[..text.len() - 1] {
+        state = parser
+            .parse(&state, std::slice::from_ref(byte))
+            .unwrap()
+            .unwrap_incomplete()
+            .0;
+    }
+    assert_eq!(
+        parser.parse(&state, b"\"").unwrap().unwrap_finished(),
+        "é😀"
+    );
+
+    assert!(parser.parse(&state, b"x\\").is_err());
+    let parser = parser.with_escapes(false);
+    assert!(parser
+        .parse(&parser.create_parser_state(), b"\"\\n")
+        .is_err());
+}
+
+#[test]
+fn string_parser_counts_characters() {
+    // "café" is 4 characters but 5 bytes
+    let parser = StringParser::new(4..=4);
+    let state = parser.create_parser_state();
+    assert_eq!(
+        parser
+            .parse(&state, "\"café\"".as_bytes())
+            .unwrap()
+            .unwrap_finished(),
+        "café"
+    );
+    assert!(parser.parse(&state, "\"cafés".as_bytes()).is_err());
+}
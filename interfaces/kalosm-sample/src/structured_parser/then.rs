@@ -69,14 +69,20 @@ impl<
     ) -> crate::ParseResult<ParseStatus<'a, Self::PartialState, Self::Output>> {
         match state {
             SequenceParserState::FirstParser(p1) => {
-                let result = self.parser1.parse(p1, input)?;
+                let result = self
+                    .parser1
+                    .parse(p1, input)
+                    .map_err(|err| err.in_parser("then"))?;
                 match result {
                     ParseStatus::Finished {
                         result: o1,
                         remaining,
                     } => {
                         let second_parser_state = self.parser2.create_parser_state();
-                        let result = self.parser2.parse(&second_parser_state, remaining)?;
+                        let result = self
+                            .parser2
+                            .parse(&second_parser_state, remaining)
+                            .map_err(|err| err.in_parser("then"))?;
                         match result {
                             ParseStatus::Finished { result, remaining } => {
                                 Ok(ParseStatus::Finished {
@@ -109,7 +115,10 @@ impl<
                 }
             }
             SequenceParserState::SecondParser(p2, o1) => {
-                let result = self.parser2.parse(p2, input)?;
+                let result = self
+                    .parser2
+                    .parse(p2, input)
+                    .map_err(|err| err.in_parser("then"))?;
                 match result {
                     ParseStatus::Finished { result, remaining } => Ok(ParseStatus::Finished {
                         result: (o1.clone(), result),
@@ -1,17 +1,18 @@
 use std::ops::{Deref, DerefMut};
 
-use crate::{CreateParserState, HasParser};
+use crate::{CreateParserState, HasDescription, HasParser};
 use crate::{ParseStatus, Parser, StringParser};
 
 #[derive(Clone, Debug)]
-/// A single word.
+/// A single word. The length limits count characters, not bytes.
 pub struct Word<const MIN_LENGTH: usize = 1, const MAX_LENGTH: usize = 20>(pub String);
 
 impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> Word<MIN_LENGTH, MAX_LENGTH> {
     /// Create a new word.
     pub fn new(word: String) -> Self {
-        assert!(word.len() >= MIN_LENGTH);
-        assert!(word.len() <= MAX_LENGTH);
+        let length = word.chars().count();
+        assert!(length >= MIN_LENGTH);
+        assert!(length <= MAX_LENGTH);
         Self(word)
     }
 }
@@ -69,6 +70,23 @@ impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> Default
     }
 }
 
+impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> WordParser<MIN_LENGTH, MAX_LENGTH> {
+    /// Only allow characters that pass the filter instead of ASCII letters and digits.
+    pub fn with_allowed_characters(mut self, character_filter: fn(char) -> bool) -> Self {
+        self.parser = self.parser.with_allowed_characters(character_filter);
+        self
+    }
+
+    /// Reject words that contain any of the substrings.
+    pub fn with_forbidden_substrings(
+        mut self,
+        substrings: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.parser = self.parser.with_forbidden_substrings(substrings);
+        self
+    }
+}
+
 impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> CreateParserState
     for WordParser<MIN_LENGTH, MAX_LENGTH>
 {
@@ -105,3 +123,11 @@ impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> HasParser for Word<MIN_LE
         Default::default()
     }
 }
+
+impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> HasDescription
+    for Word<MIN_LENGTH, MAX_LENGTH>
+{
+    fn description() -> String {
+        "string".to_string()
+    }
+}
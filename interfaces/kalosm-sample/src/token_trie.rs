@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::{ParseStatus, Parser};
+
+/// A prefix trie of the text of every token in a vocabulary.
+///
+/// Finding the tokens a parser accepts by parsing the text of every token is slow for large vocabularies. The trie lets tokens that share a prefix share the work of parsing it, and skips every token under a prefix the parser rejects.
+///
+/// # Example
+/// ```rust
+/// use kalosm_sample::*;
+///
+/// let trie = TokenTrie::new([(0, "1"), (1, "12"), (2, "a"), (3, "1a")]);
+/// let parser = RegexParser::new(r"\d{2}").unwrap();
+/// let state = parser.create_parser_state();
+/// let mask = trie.allowed_tokens(&parser, &state);
+/// assert_eq!(mask.allowed(), [0, 1]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TokenTrie {
+    id: u64,
+    nodes: Vec<TrieNode>,
+    token_count: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    /// The tokens whose text ends at this node.
+    tokens: Vec<u32>,
+    /// The edges to the children of this node. Chains of nodes with a single child are merged into one edge.
+    children: Vec<(Box<[u8]>, usize)>,
+}
+
+impl TokenTrie {
+    /// Create a new trie from the id and text of each token. Tokens with empty text are skipped.
+    pub fn new(tokens: impl IntoIterator<Item = (u32, impl AsRef<[u8]>)>) -> Self {
+        #[derive(Default)]
+        struct ByteNode {
+            tokens: Vec<u32>,
+            children: Vec<(u8, usize)>,
+        }
+
+        let mut byte_nodes = vec![ByteNode::default()];
+        let mut token_count = 0;
+        for (token, text) in tokens {
+            let text = text.as_ref();
+            if text.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for byte in text {
+                node = match byte_nodes[node]
+                    .children
+                    .iter()
+                    .find(|(child_byte, _)| child_byte == byte)
+                {
+                    Some((_, child)) => *child,
+                    None => {
+                        byte_nodes.push(ByteNode::default());
+                        let child = byte_nodes.len() - 1;
+                        byte_nodes[node].children.push((*byte, child));
+                        child
+                    }
+                };
+            }
+            byte_nodes[node].tokens.push(token);
+            token_count += 1;
+        }
+
+        fn compress(byte_nodes: &[ByteNode], index: usize, nodes: &mut Vec<TrieNode>) -> usize {
+            let id = nodes.len();
+            nodes.push(TrieNode {
+                tokens: byte_nodes[index].tokens.clone(),
+                children: Vec::new(),
+            });
+            for &(byte, mut child) in &byte_nodes[index].children {
+                let mut label = vec![byte];
+                while byte_nodes[child].tokens.is_empty() && byte_nodes[child].children.len() == 1 {
+                    let (byte, next) = byte_nodes[child].children[0];
+                    label.push(byte);
+                    child = next;
+                }
+                let child = compress(byte_nodes, child, nodes);
+                nodes[id].children.push((label.into(), child));
+            }
+            id
+        }
+        let mut nodes = Vec::new();
+        compress(&byte_nodes, 0, &mut nodes);
+
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            nodes,
+            token_count,
+        }
+    }
+
+    /// A unique id for this trie. Parsers that cache token masks use it to keep masks from different tries apart.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The number of tokens in the trie.
+    pub fn len(&self) -> usize {
+        self.token_count
+    }
+
+    /// Check if the trie has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.token_count == 0
+    }
+
+    /// Find every token the parser accepts from the given state.
+    ///
+    /// A token is accepted if the parser accepts its whole text, or finishes partway through it. This always walks the trie. Use [`Parser::allowed_tokens`] to use the cache of parsers that have one.
+    pub fn allowed_tokens<P: Parser + ?Sized>(
+        &self,
+        parser: &P,
+        state: &P::PartialState,
+    ) -> TokenMask {
+        let mut allowed = Vec::new();
+        self.visit(0, parser, state, &mut allowed);
+        allowed.sort_unstable();
+        TokenMask { allowed }
+    }
+
+    fn visit<P: Parser + ?Sized>(
+        &self,
+        node: usize,
+        parser: &P,
+        state: &P::PartialState,
+        allowed: &mut Vec<u32>,
+    ) {
+        for (label, child) in &self.nodes[node].children {
+            match parser.parse(state, label) {
+                Ok(ParseStatus::Incomplete { new_state, .. }) => {
+                    allowed.extend_from_slice(&self.nodes[*child].tokens);
+                    self.visit(*child, parser, &new_state, allowed);
+                }
+                // Once the parser is finished, the rest of every longer token is left for whatever comes next
+                Ok(ParseStatus::Finished { .. }) => self.collect(*child, allowed),
+                // If the prefix is invalid, every token that starts with it is invalid
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn collect(&self, node: usize, allowed: &mut Vec<u32>) {
+        allowed.extend_from_slice(&self.nodes[node].tokens);
+        for (_, child) in &self.nodes[node].children {
+            self.collect(*child, allowed);
+        }
+    }
+}
+
+/// The tokens a parser accepts from a state.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TokenMask {
+    allowed: Vec<u32>,
+}
+
+impl TokenMask {
+    /// Get the sorted ids of the allowed tokens.
+    pub fn allowed(&self) -> &[u32] {
+        &self.allowed
+    }
+
+    /// Check if a token is allowed.
+    pub fn contains(&self, token: u32) -> bool {
+        self.allowed.binary_search(&token).is_ok()
+    }
+
+    /// The number of allowed tokens.
+    pub fn len(&self) -> usize {
+        self.allowed.len()
+    }
+
+    /// Check if no tokens are allowed.
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_empty()
+    }
+}
+
+/// A cache of the token masks a parser computed for its states.
+///
+/// Each mask can hold every token in the vocabulary, so the cache is cleared once it holds [`MaskCache::CAPACITY`] masks instead of keeping every mask the parser ever computed.
+#[derive(Debug)]
+pub(crate) struct MaskCache<K> {
+    masks: RwLock<HashMap<K, Arc<TokenMask>>>,
+}
+
+impl<K> Default for MaskCache<K> {
+    fn default() -> Self {
+        Self {
+            masks: Default::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> MaskCache<K> {
+    /// The maximum number of masks the cache holds.
+    pub(crate) const CAPACITY: usize = 1024;
+
+    /// Get the mask for the key, or compute and cache it if it isn't cached.
+    pub(crate) fn get_or_insert_with(
+        &self,
+        key: K,
+        mask: impl FnOnce() -> TokenMask,
+    ) -> Arc<TokenMask> {
+        if let Some(mask) = self.masks.read().unwrap().get(&key) {
+            return mask.clone();
+        }
+        let mask = Arc::new(mask());
+        let mut masks = self.masks.write().unwrap();
+        if masks.len() >= Self::CAPACITY {
+            masks.clear();
+        }
+        masks.insert(key, mask.clone());
+        mask
+    }
+
+    /// The number of cached masks.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.masks.read().unwrap().len()
+    }
+}
+
+#[test]
+fn token_trie_matches_parsing_every_token() {
+    use crate::{CreateParserState, LiteralParser, ParserExt, RegexParser};
+
+    let vocab = [
+        "1", "12", "123", "1a", "a", " 1", "2, ", ", ", ",", "3, 4", "9", "99, 1",
+    ];
+    let trie = TokenTrie::new(vocab.iter().enumerate().map(|(i, text)| (i as u32, text)));
+    assert_eq!(trie.len(), vocab.len());
+
+    fn check<P: Parser>(parser: &P, state: &P::PartialState, trie: &TokenTrie, vocab: &[&str]) {
+        let expected: Vec<u32> = (0..)
+            .zip(vocab)
+            .filter(|(_, text)| parser.parse(state, text.as_bytes()).is_ok())
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(trie.allowed_tokens(parser, state).allowed(), expected);
+        assert_eq!(parser.allowed_tokens(state, trie).allowed(), expected);
+    }
+
+    let parser = RegexParser::new(r"(\d, ){2}\d").unwrap();
+    let state = parser.create_parser_state();
+    check(&parser, &state, &trie, &vocab);
+    let state = parser.parse(&state, b"1").unwrap().unwrap_incomplete().0;
+    check(&parser, &state, &trie, &vocab);
+    // The second lookup comes from the cache
+    check(&parser, &state, &trie, &vocab);
+
+    let parser = LiteralParser::from("1").then(LiteralParser::from(", ").boxed());
+    let state = parser.create_parser_state();
+    check(&parser, &state, &trie, &vocab);
+}
+
+#[test]
+fn mask_cache_is_bounded() {
+    let cache = MaskCache::default();
+    for key in 0..MaskCache::<usize>::CAPACITY {
+        cache.get_or_insert_with(key, TokenMask::default);
+    }
+    assert_eq!(cache.len(), MaskCache::<usize>::CAPACITY);
+    // Cached masks are reused without computing them again
+    cache.get_or_insert_with(0, || unreachable!());
+    assert_eq!(cache.len(), MaskCache::<usize>::CAPACITY);
+
+    cache.get_or_insert_with(MaskCache::<usize>::CAPACITY, TokenMask::default);
+    assert_eq!(cache.len(), 1);
+}
@@ -0,0 +1,28 @@
+use kalosm::language::*;
+
+#[tokio::main]
+async fn main() {
+    let llm = Llama::new().await.unwrap();
+    let prompt = "An arithmetic expression that equals 10: ";
+
+    println!("# with constraints");
+    print!("{}", prompt);
+
+    let validator = GrammarParser::new(
+        r#"
+        root ::= expr
+        expr ::= term ((" + " | " - ") term)*
+        term ::= [0-9]+ | "(" expr ")"
+        "#,
+    )
+    .unwrap();
+    let stream = llm.stream_structured_text(prompt, validator).await.unwrap();
+
+    stream.split().0.to_std_out().await.unwrap();
+
+    println!("\n\n# without constraints");
+    print!("{}", prompt);
+
+    let stream = llm.stream_text(prompt).with_max_length(100).await.unwrap();
+    stream.to_std_out().await.unwrap();
+}
@@ -407,11 +407,10 @@ where
     let examples_tokens: usize = examples
         .iter()
         .filter_map(|example| {
-            llm.tokenizer()
-                .encode(example.input, true)
-                .ok()
-                .map(|x| x.len())
-                .and_then(|x| Some(x + llm.tokenizer().encode(example.output, true).ok()?.len()))
+            let tokenizer = llm.tokenizer()?;
+            let input = tokenizer.encode(example.input, true).ok()?.len();
+            let output = tokenizer.encode(example.output, true).ok()?.len();
+            Some(input + output)
         })
         .sum();
 
@@ -0,0 +1,666 @@
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use crate::structured::{generate_structured, token_trie, update_state};
+use crate::{GenerationParameters, LogProbabilities, ModelFeedback, Session, SyncModel};
+use crate::{StopOnMatcher, StopOnStatus, StopReason, SyncModelExt, TokenOutputStream};
+use kalosm_sample::{ParseStatus, Parser};
+
+/// Parameters to use when decoding with beam search.
+///
+/// Beam search keeps the `beams` most likely partial completions at every step instead of committing to a single token. Finished completions are ranked by their cumulative log probability divided by `length ^ length_penalty`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BeamSearchParameters {
+    pub(crate) beams: usize,
+    pub(crate) length_penalty: f32,
+    pub(crate) max_length: u32,
+    pub(crate) stop_sequences: Vec<String>,
+    pub(crate) stop_token_ids: Vec<u32>,
+}
+
+impl Default for BeamSearchParameters {
+    fn default() -> Self {
+        Self {
+            beams: 4,
+            length_penalty: 1.,
+            max_length: 128,
+            stop_sequences: Vec::new(),
+            stop_token_ids: Vec::new(),
+        }
+    }
+}
+
+impl BeamSearchParameters {
+    /// Set the number of completions to keep at every step. (default: 4)
+    pub fn with_beams(mut self, beams: usize) -> Self {
+        self.beams = beams.max(1);
+        self
+    }
+
+    /// Set the length penalty used to rank finished completions. A penalty of 0 ranks completions by their total log probability, which favors short completions. (default: 1)
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    /// Set the maximum number of tokens to generate. (default: 128)
+    pub fn with_max_length(mut self, max_length: u32) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Set the strings to stop on. A completion finishes when it generates any of the stop sequences, and the stop sequence is not included in the text.
+    pub fn with_stop_sequences(
+        mut self,
+        stop_sequences: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.stop_sequences = stop_sequences.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the token ids to stop on.
+    pub fn with_stop_token_ids(mut self, stop_token_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_token_ids = stop_token_ids.into_iter().collect();
+        self
+    }
+
+    /// Get the number of completions to keep at every step.
+    pub fn beams(&self) -> usize {
+        self.beams
+    }
+
+    /// Get the length penalty used to rank finished completions.
+    pub fn length_penalty(&self) -> f32 {
+        self.length_penalty
+    }
+
+    /// Get the maximum number of tokens to generate.
+    pub fn max_length(&self) -> u32 {
+        self.max_length
+    }
+
+    /// Get the strings to stop on.
+    pub fn stop_sequences(&self) -> &[String] {
+        &self.stop_sequences
+    }
+
+    /// Get the token ids to stop on.
+    pub fn stop_token_ids(&self) -> &[u32] {
+        &self.stop_token_ids
+    }
+}
+
+/// A text completion along with its log probability and length normalized score.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredText {
+    text: String,
+    tokens: Vec<u32>,
+    log_probability: f32,
+    score: f32,
+    stop_reason: StopReason,
+}
+
+impl ScoredText {
+    /// Get the generated text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Get the generated text as an owned string.
+    pub fn into_text(self) -> String {
+        self.text
+    }
+
+    /// Get the generated tokens.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Get the total log probability of the completion.
+    pub fn log_probability(&self) -> f32 {
+        self.log_probability
+    }
+
+    /// Get the length normalized score used to rank completions. Higher is better.
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    /// Get the reason generation stopped.
+    pub fn stop_reason(&self) -> &StopReason {
+        &self.stop_reason
+    }
+}
+
+/// A structured completion along with its log probability and length normalized score.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredStructure<O> {
+    output: O,
+    text: String,
+    tokens: Vec<u32>,
+    log_probability: f32,
+    score: f32,
+}
+
+impl<O> ScoredStructure<O> {
+    /// Get the parsed output.
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    /// Get the parsed output as an owned value.
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Get the generated text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Get the generated tokens.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Get the total log probability of the completion.
+    pub fn log_probability(&self) -> f32 {
+        self.log_probability
+    }
+
+    /// Get the length normalized score used to rank completions. Higher is better.
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+}
+
+fn length_normalized(log_probability: f32, length: usize, length_penalty: f32) -> f32 {
+    log_probability / (length.max(1) as f32).powf(length_penalty)
+}
+
+fn sort_by_score<T>(candidates: &mut [T], score: impl Fn(&T) -> f32) {
+    candidates.sort_by(|a, b| score(b).total_cmp(&score(a)));
+}
+
+/// Token ids sorted from the most to the least likely.
+fn tokens_by_likelihood(logits: &[f32]) -> Vec<u32> {
+    let mut ids: Vec<u32> = (0..logits.len() as u32).collect();
+    ids.sort_by(|a, b| logits[*b as usize].total_cmp(&logits[*a as usize]));
+    ids
+}
+
+/// A candidate token to extend a beam with.
+struct Expansion<T> {
+    beam: usize,
+    token: u32,
+    log_probability: f32,
+    extra: T,
+}
+
+/// Keep the `count` most likely expansions.
+fn most_likely<T>(mut expansions: Vec<Expansion<T>>, count: usize) -> Vec<Expansion<T>> {
+    sort_by_score(&mut expansions, |expansion| expansion.log_probability);
+    expansions.truncate(count);
+    expansions
+}
+
+struct TextBeam<S> {
+    session: S,
+    token_stream: TokenOutputStream,
+    stop_on: StopOnMatcher,
+    logits: Vec<f32>,
+    tokens: Vec<u32>,
+    text: String,
+    log_probability: f32,
+    length: usize,
+}
+
+impl<S: Session> TextBeam<S> {
+    fn try_clone(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            session: self.session.try_clone()?,
+            token_stream: self.token_stream.clone(),
+            stop_on: self.stop_on.clone(),
+            logits: Vec::new(),
+            tokens: self.tokens.clone(),
+            text: self.text.clone(),
+            log_probability: self.log_probability,
+            length: self.length,
+        })
+    }
+
+    fn finish(mut self, stop_reason: StopReason, length_penalty: f32) -> ScoredText {
+        if !matches!(stop_reason, StopReason::StopSequence { .. }) {
+            self.text += &self.stop_on.flush();
+        }
+        ScoredText {
+            score: length_normalized(self.log_probability, self.length, length_penalty),
+            text: self.text,
+            tokens: self.tokens,
+            log_probability: self.log_probability,
+            stop_reason,
+        }
+    }
+}
+
+/// Generate text with beam search. Returns the finished completions from the best to the worst score.
+pub(crate) fn beam_search<M: ?Sized + SyncModel>(
+    llm: &M,
+    session: &M::Session,
+    prompt: &str,
+    parameters: &BeamSearchParameters,
+) -> anyhow::Result<Vec<ScoredText>> {
+    let tokenizer = llm.tokenizer();
+    let stop_token = llm.stop_token()?;
+    let prompt_tokens = tokenizer.encode(prompt, true)?;
+    let mut token_stream = TokenOutputStream::new(tokenizer);
+    for token in prompt_tokens.iter().copied() {
+        token_stream.next_token(token)?;
+    }
+    let mut session = session.try_clone()?;
+    let logits = llm.feed_tokens(&mut session, &prompt_tokens)?;
+
+    let mut live = vec![TextBeam {
+        session,
+        token_stream,
+        stop_on: StopOnMatcher::from_sequences(&parameters.stop_sequences),
+        logits,
+        tokens: Vec::new(),
+        text: String::new(),
+        log_probability: 0.,
+        length: 0,
+    }];
+    let mut finished = Vec::new();
+
+    for _ in 0..parameters.max_length {
+        // Every finished completion takes up one of the beams
+        let remaining = parameters.beams.saturating_sub(finished.len());
+        if remaining == 0 || live.is_empty() {
+            break;
+        }
+
+        let mut expansions = Vec::new();
+        for (i, beam) in live.iter().enumerate() {
+            let log_probabilities = LogProbabilities::new(&beam.logits);
+            for token in tokens_by_likelihood(&beam.logits)
+                .into_iter()
+                .take(remaining)
+            {
+                let log_probability = log_probabilities.logprob(token);
+                if log_probability.is_finite() {
+                    expansions.push(Expansion {
+                        beam: i,
+                        token,
+                        log_probability: beam.log_probability + log_probability,
+                        extra: (),
+                    });
+                }
+            }
+        }
+
+        let mut next = Vec::new();
+        for expansion in most_likely(expansions, remaining) {
+            let mut beam = live[expansion.beam].try_clone()?;
+            beam.log_probability = expansion.log_probability;
+            beam.length += 1;
+            if expansion.token == stop_token {
+                finished.push(beam.finish(StopReason::EndOfSequence, parameters.length_penalty));
+                continue;
+            }
+            if parameters.stop_token_ids.contains(&expansion.token) {
+                let stop_reason = StopReason::StopToken {
+                    id: expansion.token,
+                };
+                finished.push(beam.finish(stop_reason, parameters.length_penalty));
+                continue;
+            }
+            beam.tokens.push(expansion.token);
+            let new_text = beam
+                .token_stream
+                .next_token(expansion.token)?
+                .unwrap_or_default();
+            match beam.stop_on.feed(&new_text) {
+                StopOnStatus::Continue(text) => beam.text += &text,
+                StopOnStatus::Stop(text) => {
+                    beam.text += &text;
+                    let stop_reason = beam
+                        .stop_on
+                        .stop_reason()
+                        .unwrap_or(StopReason::EndOfSequence);
+                    finished.push(beam.finish(stop_reason, parameters.length_penalty));
+                    continue;
+                }
+            }
+            beam.logits = llm.feed_tokens(&mut beam.session, &[expansion.token])?;
+            next.push(beam);
+        }
+        live = next;
+    }
+
+    // Any beams that are still running ran out of tokens
+    let remaining = parameters.beams.saturating_sub(finished.len());
+    sort_by_score(&mut live, |beam| beam.log_probability);
+    for beam in live.into_iter().take(remaining) {
+        finished.push(beam.finish(StopReason::MaxLength, parameters.length_penalty));
+    }
+    sort_by_score(&mut finished, |completion| completion.score);
+
+    Ok(finished)
+}
+
+struct StructuredBeam<S, PS> {
+    session: S,
+    token_stream: TokenOutputStream,
+    parser_state: PS,
+    unprocessed_token_count: usize,
+    tokens: Vec<u32>,
+    text: String,
+    log_probability: f32,
+}
+
+impl<S: Session, PS: Clone> StructuredBeam<S, PS> {
+    fn try_clone(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            session: self.session.try_clone()?,
+            token_stream: self.token_stream.clone(),
+            parser_state: self.parser_state.clone(),
+            unprocessed_token_count: self.unprocessed_token_count,
+            tokens: self.tokens.clone(),
+            text: self.text.clone(),
+            log_probability: self.log_probability,
+        })
+    }
+}
+
+/// Generate text that conforms to the parser with beam search. Only completions the parser accepts are kept. Returns the finished completions from the best to the worst score.
+pub(crate) fn structured_beam_search<M: ?Sized + SyncModel, P: Parser>(
+    llm: &M,
+    session: &M::Session,
+    prompt: impl Display,
+    parser: P,
+    parser_state: P::PartialState,
+    parameters: &BeamSearchParameters,
+) -> anyhow::Result<Vec<ScoredStructure<P::Output>>>
+where
+    P::PartialState: Clone,
+{
+    let tokenizer = llm.tokenizer();
+    let trie = token_trie(&tokenizer)?;
+    let prompt_tokens = tokenizer.encode(&prompt.to_string(), true)?;
+    let mut token_stream = TokenOutputStream::new(tokenizer.clone());
+    for token in prompt_tokens.iter().copied() {
+        token_stream.next_token(token)?;
+    }
+
+    let mut live = vec![StructuredBeam {
+        session: session.try_clone()?,
+        token_stream,
+        parser_state,
+        unprocessed_token_count: prompt_tokens.len(),
+        tokens: Vec::new(),
+        text: String::new(),
+        log_probability: 0.,
+    }];
+    let mut finished = Vec::new();
+
+    for _ in 0..parameters.max_length {
+        let remaining = parameters.beams.saturating_sub(finished.len());
+        if remaining == 0 || live.is_empty() {
+            break;
+        }
+
+        let mut expansions = Vec::new();
+        for (i, beam) in live.iter_mut().enumerate() {
+            let tokens = beam.token_stream.tokens();
+            let logits = llm.feed_tokens(
+                &mut beam.session,
+                &tokens[tokens.len() - beam.unprocessed_token_count..],
+            )?;
+            beam.unprocessed_token_count = 0;
+            let log_probabilities = LogProbabilities::new(&logits);
+
+            // Find the most likely tokens the parser accepts
+            let mask = parser.allowed_tokens(&beam.parser_state, &trie);
+            let mut candidates = tokens_by_likelihood(&logits);
+            candidates.retain(|token| mask.contains(*token));
+            let next_tokens = beam.token_stream.peek_tokens(candidates.clone())?;
+            let mut valid = 0;
+            for (token, text) in candidates.into_iter().zip(next_tokens) {
+                if valid >= remaining {
+                    break;
+                }
+                let Some(mut text) = text else {
+                    continue;
+                };
+                let log_probability = log_probabilities.logprob(token);
+                if !log_probability.is_finite() {
+                    continue;
+                }
+                if let Ok(result) = parser.parse(&beam.parser_state, text.as_bytes()) {
+                    let parsed_bytes = match result {
+                        ParseStatus::Finished { remaining, .. } => text.len() - remaining.len(),
+                        ParseStatus::Incomplete { .. } => text.len(),
+                    };
+                    let result = result.without_remaining();
+                    text.truncate(parsed_bytes);
+                    valid += 1;
+                    expansions.push(Expansion {
+                        beam: i,
+                        token,
+                        log_probability: beam.log_probability + log_probability,
+                        extra: (text, result),
+                    });
+                }
+            }
+        }
+
+        let mut next = Vec::new();
+        for expansion in most_likely(expansions, remaining) {
+            let (text, result) = expansion.extra;
+            let mut beam = live[expansion.beam].try_clone()?;
+            beam.log_probability = expansion.log_probability;
+            beam.token_stream.next_token(expansion.token)?;
+            beam.unprocessed_token_count = 1;
+            beam.tokens.push(expansion.token);
+            beam.text += &text;
+
+            let StructuredBeam {
+                token_stream,
+                parser_state,
+                unprocessed_token_count,
+                tokens,
+                text,
+                ..
+            } = &mut beam;
+            // Tokens the parser requires next are added without changing the log probability
+            let output = update_state(
+                &parser,
+                parser_state,
+                result,
+                &tokenizer,
+                token_stream,
+                &mut |token| {
+                    tokens.push(token.id());
+                    *text += token.text();
+                    Ok(())
+                },
+                unprocessed_token_count,
+            )?;
+            match output {
+                Some(output) => finished.push(ScoredStructure {
+                    output,
+                    score: length_normalized(
+                        beam.log_probability,
+                        beam.tokens.len(),
+                        parameters.length_penalty,
+                    ),
+                    text: beam.text,
+                    tokens: beam.tokens,
+                    log_probability: beam.log_probability,
+                }),
+                None => next.push(beam),
+            }
+        }
+        live = next;
+    }
+
+    if finished.is_empty() {
+        anyhow::bail!(
+            "No completion that matches the parser was found within {} tokens",
+            parameters.max_length
+        );
+    }
+    sort_by_score(&mut finished, |completion| completion.score);
+
+    Ok(finished)
+}
+
+/// Sample `candidates` independent completions and rank them by their length normalized log probability.
+pub(crate) fn best_of<M: ?Sized + SyncModel>(
+    llm: &M,
+    session: &M::Session,
+    prompt: &str,
+    parameters: &GenerationParameters,
+    candidates: usize,
+    length_penalty: f32,
+) -> anyhow::Result<Vec<ScoredText>> {
+    let mut completions = Vec::with_capacity(candidates);
+    for i in 0..candidates {
+        let mut session = session.try_clone()?;
+        let mut text = String::new();
+        let mut tokens = Vec::new();
+        let mut log_probability = 0.;
+        // Each candidate needs a different seed to sample a different completion
+        let seed = parameters.seed().map(|seed| seed.wrapping_add(i as u64));
+        let stop_reason = llm.stream_tokens_with_sampler(
+            &mut session,
+            prompt,
+            Some(parameters.max_length()),
+            parameters.stop_sequences(),
+            parameters.stop_token_ids(),
+            Arc::new(Mutex::new(parameters.clone().sampler())),
+            seed,
+            Some(0),
+            |token| {
+                text += token.text();
+                tokens.push(token.id());
+                log_probability += token.logprob();
+                Ok(ModelFeedback::Continue)
+            },
+        )?;
+        completions.push(ScoredText {
+            score: length_normalized(log_probability, tokens.len(), length_penalty),
+            text,
+            tokens,
+            log_probability,
+            stop_reason,
+        });
+    }
+    sort_by_score(&mut completions, |completion| completion.score);
+
+    Ok(completions)
+}
+
+/// Sample `candidates` independent completions that conform to the parser and rank them by their length normalized log probability.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn structured_best_of<M: ?Sized + SyncModel, P: Parser>(
+    llm: &M,
+    session: &M::Session,
+    prompt: impl Display,
+    parser: P,
+    parser_state: P::PartialState,
+    parameters: &GenerationParameters,
+    candidates: usize,
+    length_penalty: f32,
+) -> anyhow::Result<Vec<ScoredStructure<P::Output>>>
+where
+    P::PartialState: Clone,
+{
+    let prompt = prompt.to_string();
+    let mut completions = Vec::with_capacity(candidates);
+    for i in 0..candidates {
+        let mut session = session.try_clone()?;
+        let mut text = String::new();
+        let mut tokens = Vec::new();
+        let mut log_probability = 0.;
+        // Each candidate needs a different seed to sample a different completion
+        let seed = parameters.seed().map(|seed| seed.wrapping_add(i as u64));
+        let output = generate_structured(
+            &prompt,
+            llm,
+            &mut session,
+            &parser,
+            parser_state.clone(),
+            Arc::new(Mutex::new(parameters.clone().sampler())),
+            |token| {
+                text += token.text();
+                tokens.push(token.id());
+                log_probability += token.logprob();
+                Ok(())
+            },
+            Some(32),
+            0,
+            seed,
+        )?;
+        completions.push(ScoredStructure {
+            output,
+            score: length_normalized(log_probability, tokens.len(), length_penalty),
+            text,
+            tokens,
+            log_probability,
+        });
+    }
+    sort_by_score(&mut completions, |completion| completion.score);
+
+    Ok(completions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_model::{MockModel, END};
+    use kalosm_sample::{CreateParserState, LiteralParser, ParserExt};
+
+    /// A model where the greedy first token ("a") leads to a much less likely completion than the second most likely token ("b").
+    fn trap_model() -> MockModel {
+        MockModel::new(|tokens| match tokens.last().map(|token| *token as u8) {
+            Some(b'a') => vec![(b'x', 1. / 3.), (b'y', 1. / 3.), (b'z', 1. / 3.)],
+            Some(b'b') => vec![(END as u8, 0.9), (b'c', 0.1)],
+            Some(b'c' | b'x' | b'y' | b'z') => vec![(END as u8, 1.)],
+            _ => vec![(b'a', 0.5), (b'b', 0.4), (b'c', 0.1)],
+        })
+    }
+
+    #[test]
+    fn beam_search_finds_the_most_likely_completion() {
+        let model = trap_model();
+        let session = model.new_session().unwrap();
+        let parameters = BeamSearchParameters::default().with_beams(2);
+        let completions = beam_search(&model, &session, "q", &parameters).unwrap();
+
+        let texts: Vec<_> = completions.iter().map(|c| c.text()).collect();
+        assert_eq!(texts, ["b", "ax"]);
+        assert_eq!(completions[0].stop_reason(), &StopReason::EndOfSequence);
+        assert!((completions[0].log_probability() - (0.4f32 * 0.9).ln()).abs() < 1e-5);
+        // The original session is left untouched
+        assert!(session.0.is_empty());
+    }
+
+    #[test]
+    fn structured_beam_search_finds_the_most_likely_parse() {
+        let model = trap_model();
+        let session = model.new_session().unwrap();
+        let parser = LiteralParser::from("ax").or(LiteralParser::from("b"));
+        let parser_state = parser.create_parser_state();
+        let parameters = BeamSearchParameters::default()
+            .with_beams(2)
+            .with_length_penalty(0.);
+        let completions =
+            structured_beam_search(&model, &session, "q", &parser, parser_state, &parameters)
+                .unwrap();
+
+        assert_eq!(completions[0].text(), "b");
+        assert_eq!(completions[1].text(), "ax");
+    }
+}
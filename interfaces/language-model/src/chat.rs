@@ -0,0 +1,62 @@
+use crate::ChatMarkers;
+
+/// The type of a chat message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MessageType {
+    /// A system prompt.
+    SystemPrompt,
+    /// A user message.
+    UserMessage,
+    /// A model answer.
+    ModelAnswer,
+}
+
+/// A single item in the chat history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChatHistoryItem {
+    ty: MessageType,
+    contents: String,
+}
+
+impl ChatHistoryItem {
+    /// Creates a new chat history item.
+    pub fn new(ty: MessageType, contents: impl Into<String>) -> Self {
+        Self {
+            ty,
+            contents: contents.into(),
+        }
+    }
+
+    /// Returns the type of the item.
+    pub fn ty(&self) -> MessageType {
+        self.ty
+    }
+
+    /// Returns the contents of the item.
+    pub fn contents(&self) -> &str {
+        &self.contents
+    }
+}
+
+impl ChatMarkers {
+    /// Format a chat history into a prompt with these markers. The prompt ends with the assistant marker so the model will continue with the next answer.
+    pub fn format_history(&self, history: &[ChatHistoryItem]) -> String {
+        let mut prompt = String::new();
+        for item in history {
+            let (start, end) = match item.ty() {
+                MessageType::SystemPrompt => {
+                    (self.system_prompt_marker, self.end_system_prompt_marker)
+                }
+                MessageType::UserMessage => (self.user_marker, self.end_user_marker),
+                MessageType::ModelAnswer => (self.assistant_marker, self.end_assistant_marker),
+            };
+            prompt += start;
+            prompt += item.contents();
+            prompt += end;
+        }
+        prompt += self.assistant_marker;
+        prompt
+    }
+}
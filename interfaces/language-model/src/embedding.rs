@@ -5,16 +5,32 @@ use candle_core::{Device, Tensor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 /// An untyped vector space that is not associated with a model. This can be used to erase the vector type from an embedding.
+///
+/// Deserializing an embedding into the unknown vector space will accept an embedding from any vector space.
 pub struct UnknownVectorSpace;
 
-impl VectorSpace for UnknownVectorSpace {}
+impl VectorSpace for UnknownVectorSpace {
+    fn id() -> &'static str {
+        "unknown"
+    }
+}
 
 /// The type of a vector space marks what model the vector space is from. You should only combine vector spaces that come from the same model.
 ///
 /// For example, the Llama model has a different vector space than the Bert model. Comparing these two vector spaces would not make sense because different parts of each vector encode different information. This trait allows you to mark an embedding with the type of vector space it comes from to avoid problems combing vector spaces.
 ///
 /// If you want to cast an embedding from one vector space to another, you can use the [`Embedding::cast`] method. You can cast to the UnknownVectorSpace to erase the vector space type.
-pub trait VectorSpace: Sync + Send + 'static {}
+pub trait VectorSpace: Sync + Send + 'static {
+    /// A unique identifier for this vector space. This is stored alongside serialized embeddings so they cannot be deserialized into a different vector space.
+    ///
+    /// The default implementation uses the type name which may change if the type is moved. Implementations should override this if the embeddings are stored long term.
+    fn id() -> &'static str
+    where
+        Self: Sized,
+    {
+        std::any::type_name::<Self>()
+    }
+}
 
 /// An embedding represents something about the meaning of data. It can be used to compare the meaning of different pieces of data, cluster data, or as input to a machine learning model.
 pub struct Embedding<S: VectorSpace> {
@@ -65,17 +81,58 @@ impl<S: VectorSpace> Clone for Embedding<S> {
     }
 }
 
+/// The serialized form of an embedding.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct SerializedEmbedding<'a> {
+    vector_space: std::borrow::Cow<'a, str>,
+    dimension: usize,
+    vector: Vec<f32>,
+}
+
 #[cfg(feature = "serde")]
 impl<S: VectorSpace> Serialize for Embedding<S> {
-    fn serialize<Ser: Serializer>(&self, _serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
-        todo!()
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let vector = self
+            .embedding
+            .flatten_all()
+            .and_then(|embedding| embedding.to_vec1::<f32>())
+            .map_err(serde::ser::Error::custom)?;
+        SerializedEmbedding {
+            vector_space: S::id().into(),
+            dimension: vector.len(),
+            vector,
+        }
+        .serialize(serializer)
     }
 }
 
 #[cfg(feature = "serde")]
 impl<'de, S: VectorSpace> Deserialize<'de> for Embedding<S> {
-    fn deserialize<Des: Deserializer<'de>>(_deserializer: Des) -> Result<Self, Des::Error> {
-        todo!()
+    fn deserialize<Des: Deserializer<'de>>(deserializer: Des) -> Result<Self, Des::Error> {
+        let SerializedEmbedding {
+            vector_space,
+            dimension,
+            vector,
+        } = SerializedEmbedding::deserialize(deserializer)?;
+        // Any embedding can be deserialized into the unknown vector space, but other vector spaces must match exactly
+        if std::any::TypeId::of::<S>() != std::any::TypeId::of::<UnknownVectorSpace>()
+            && vector_space != S::id()
+        {
+            return Err(serde::de::Error::custom(format!(
+                "expected an embedding from the vector space {}, but found an embedding from {}",
+                S::id(),
+                vector_space
+            )));
+        }
+        if vector.len() != dimension {
+            return Err(serde::de::Error::custom(format!(
+                "expected an embedding with {} dimensions, but found {} values",
+                dimension,
+                vector.len()
+            )));
+        }
+        Ok(Embedding::from(vector))
     }
 }
 
@@ -128,3 +185,34 @@ impl<S: VectorSpace> Embedding<S> {
         }
     }
 }
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_embedding() {
+    struct TestSpace;
+    impl VectorSpace for TestSpace {
+        fn id() -> &'static str {
+            "test"
+        }
+    }
+    struct OtherSpace;
+    impl VectorSpace for OtherSpace {}
+
+    let embedding = Embedding::<TestSpace>::from([1.0f32, 2.0, 3.0]);
+    let json = serde_json::to_string(&embedding).unwrap();
+    assert_eq!(
+        json,
+        r#"{"vector_space":"test","dimension":3,"vector":[1.0,2.0,3.0]}"#
+    );
+
+    let deserialized: Embedding<TestSpace> = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized.to_vec(), vec![1.0, 2.0, 3.0]);
+
+    let unknown: Embedding<UnknownVectorSpace> = serde_json::from_str(&json).unwrap();
+    assert_eq!(unknown.to_vec(), vec![1.0, 2.0, 3.0]);
+
+    assert!(serde_json::from_str::<Embedding<OtherSpace>>(&json).is_err());
+
+    let wrong_dimension = r#"{"vector_space":"test","dimension":2,"vector":[1.0,2.0,3.0]}"#;
+    assert!(serde_json::from_str::<Embedding<TestSpace>>(wrong_dimension).is_err());
+}
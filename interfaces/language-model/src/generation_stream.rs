@@ -0,0 +1,134 @@
+use crate::StopReason;
+use futures_util::Stream;
+use kalosm_streams::text_stream::ChannelTextStream;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A stream of generated text that reports why generation stopped once the stream is finished.
+pub trait GenerationStream: Stream<Item = String> {
+    /// Get the reason generation stopped. This is `None` until the stream has returned all of its text, or if the model does not report stop reasons.
+    fn stop_reason(&self) -> Option<StopReason>;
+}
+
+impl GenerationStream for ChannelTextStream<String> {
+    fn stop_reason(&self) -> Option<StopReason> {
+        None
+    }
+}
+
+/// A stream of text from a tokio channel along with the reason generation stopped.
+pub struct TextGenerationStream {
+    receiver: tokio::sync::mpsc::UnboundedReceiver<String>,
+    stop_reason_receiver: Option<tokio::sync::oneshot::Receiver<StopReason>>,
+    stop_reason: Option<StopReason>,
+}
+
+impl std::fmt::Debug for TextGenerationStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TextGenerationStream")
+            .field("stop_reason", &self.stop_reason)
+            .finish()
+    }
+}
+
+impl TextGenerationStream {
+    /// Create a new stream from a channel of text and a channel that receives the stop reason when generation finishes.
+    pub fn new(
+        receiver: tokio::sync::mpsc::UnboundedReceiver<String>,
+        stop_reason: tokio::sync::oneshot::Receiver<StopReason>,
+    ) -> Self {
+        Self {
+            receiver,
+            stop_reason_receiver: Some(stop_reason),
+            stop_reason: None,
+        }
+    }
+}
+
+impl From<tokio::sync::mpsc::UnboundedReceiver<String>> for TextGenerationStream {
+    fn from(receiver: tokio::sync::mpsc::UnboundedReceiver<String>) -> Self {
+        Self {
+            receiver,
+            stop_reason_receiver: None,
+            stop_reason: None,
+        }
+    }
+}
+
+impl Stream for TextGenerationStream {
+    type Item = String;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(None) => {
+                // Wait for the stop reason before ending the stream so it is always available once the stream is finished
+                if let Some(receiver) = &mut self.stop_reason_receiver {
+                    match Pin::new(receiver).poll(cx) {
+                        Poll::Ready(stop_reason) => {
+                            self.stop_reason = stop_reason.ok();
+                            self.stop_reason_receiver = None;
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                Poll::Ready(None)
+            }
+            other => other,
+        }
+    }
+}
+
+impl GenerationStream for TextGenerationStream {
+    fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason.clone()
+    }
+}
+
+/// Text generated by a model along with the reason generation stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedText {
+    text: String,
+    stop_reason: Option<StopReason>,
+}
+
+impl GeneratedText {
+    /// Create a new generated text.
+    pub fn new(text: String, stop_reason: Option<StopReason>) -> Self {
+        Self { text, stop_reason }
+    }
+
+    /// Get the generated text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Get the generated text as an owned string.
+    pub fn into_text(self) -> String {
+        self.text
+    }
+
+    /// Get the reason generation stopped, if the model reports it.
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+}
+
+impl Display for GeneratedText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+impl AsRef<str> for GeneratedText {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl From<GeneratedText> for String {
+    fn from(text: GeneratedText) -> Self {
+        text.text
+    }
+}
@@ -30,10 +30,31 @@ mod remote;
 #[cfg(feature = "remote")]
 pub use remote::*;
 
+mod beam_search;
+pub use beam_search::*;
+mod generation_stream;
+pub use generation_stream::*;
+#[cfg(test)]
+mod mock_model;
+mod prefix_cache;
+pub use prefix_cache::*;
+mod sampler;
+pub use sampler::*;
+mod speculative;
+pub use speculative::*;
+mod stop_on;
+pub use stop_on::*;
 mod structured;
+pub use structured::*;
+mod structured_retry;
+pub use structured_retry::*;
+mod token_probabilities;
+pub use token_probabilities::*;
 mod token_stream;
 pub use token_stream::*;
 
+mod chat;
+pub use chat::*;
 mod embedding;
 pub use embedding::*;
 mod model;
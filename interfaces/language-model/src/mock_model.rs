@@ -0,0 +1,112 @@
+//! A small model with one token per byte for testing generation without loading real weights.
+
+use crate::{Session, SyncModel};
+use kalosm_sample::Tokenizer;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// The end of sequence token of a [`MockModel`].
+pub(crate) const END: u32 = 0;
+
+/// A tokenizer with one token per byte.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ByteTokenizer {
+    // The bytes the tokenizer can generate, or None for every byte
+    vocabulary: Option<&'static [u8]>,
+}
+
+impl ByteTokenizer {
+    /// Only generate the given bytes.
+    pub(crate) fn with_vocabulary(vocabulary: &'static [u8]) -> Self {
+        Self {
+            vocabulary: Some(vocabulary),
+        }
+    }
+}
+
+impl Tokenizer for ByteTokenizer {
+    fn encode(&self, text: &str, _: bool) -> anyhow::Result<Vec<u32>> {
+        Ok(text.bytes().map(u32::from).collect())
+    }
+
+    fn decode(&self, ids: &[u32]) -> anyhow::Result<Cow<'_, str>> {
+        let bytes: Vec<u8> = ids.iter().map(|id| *id as u8).collect();
+        Ok(String::from_utf8_lossy(&bytes).into_owned().into())
+    }
+
+    fn get_all_tokens(&self) -> anyhow::Result<Cow<'_, [u32]>> {
+        Ok(match self.vocabulary {
+            Some(vocabulary) => vocabulary.iter().map(|byte| u32::from(*byte)).collect(),
+            None => (0..256).collect(),
+        })
+    }
+}
+
+/// The tokens fed into a [`MockModel`].
+pub(crate) struct ByteSession(pub(crate) Vec<u32>);
+
+impl Session for ByteSession {
+    fn try_clone(&self) -> anyhow::Result<Self> {
+        Ok(Self(self.0.clone()))
+    }
+
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()> {
+        self.0.truncate(self.0.len() - count);
+        Ok(())
+    }
+}
+
+/// A model whose next token probabilities only depend on the tokens fed so far. Tokens that are not returned have a probability of zero.
+pub(crate) struct MockModel {
+    next: fn(&[u32]) -> Vec<(u8, f32)>,
+    tokenizer: Arc<dyn Tokenizer + Send + Sync>,
+}
+
+impl MockModel {
+    /// Create a model with a [`ByteTokenizer`] for every byte.
+    pub(crate) fn new(next: fn(&[u32]) -> Vec<(u8, f32)>) -> Self {
+        Self {
+            next,
+            tokenizer: Arc::new(ByteTokenizer::default()),
+        }
+    }
+
+    /// Use a different tokenizer. The tokenizer must encode text one byte per token.
+    pub(crate) fn with_tokenizer(
+        mut self,
+        tokenizer: impl Tokenizer + Send + Sync + 'static,
+    ) -> Self {
+        self.tokenizer = Arc::new(tokenizer);
+        self
+    }
+}
+
+impl SyncModel for MockModel {
+    type Session = ByteSession;
+
+    fn new_session(&self) -> anyhow::Result<Self::Session> {
+        Ok(ByteSession(Vec::new()))
+    }
+
+    fn feed_text(&self, session: &mut Self::Session, prompt: &str) -> anyhow::Result<Vec<f32>> {
+        let tokens = self.tokenizer.encode(prompt, false)?;
+        self.feed_tokens(session, &tokens)
+    }
+
+    fn feed_tokens(&self, session: &mut Self::Session, tokens: &[u32]) -> anyhow::Result<Vec<f32>> {
+        session.0.extend_from_slice(tokens);
+        let mut logits = vec![f32::NEG_INFINITY; 256];
+        for (token, probability) in (self.next)(&session.0) {
+            logits[token as usize] = probability.ln();
+        }
+        Ok(logits)
+    }
+
+    fn stop_token(&self) -> anyhow::Result<u32> {
+        Ok(END)
+    }
+
+    fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync> {
+        self.tokenizer.clone()
+    }
+}
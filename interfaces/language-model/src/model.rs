@@ -1,14 +1,25 @@
+use crate::beam_search::{beam_search, best_of, structured_beam_search, structured_best_of};
 use crate::embedding::{Embedding, VectorSpace};
-use crate::structured::generate_structured;
+use crate::speculative::speculative_stream_tokens;
+use crate::structured::{generate_structured, generate_structured_with_recovery};
+use crate::structured_retry::{generate_structured_with_retries, spawn_structured_with_retries};
+use crate::ChatHistoryItem;
 use crate::TokenOutputStream;
 use crate::UnknownVectorSpace;
+use crate::{sampler_chain, SamplerStage};
+use crate::{
+    BeamSearchParameters, ScoredStructure, ScoredText, StructuredRecovery, StructuredRetry,
+};
+use crate::{GeneratedText, GenerationStream, TextGenerationStream};
+use crate::{GeneratedToken, LogProbabilities};
+use crate::{StopOnMatcher, StopOnStatus, StopReason};
 use futures_util::{Stream, StreamExt};
 use kalosm_common::*;
 use kalosm_sample::{Parser, Tokenizer};
 use kalosm_streams::text_stream::ChannelTextStream;
-use llm_samplers::configure::SamplerChainBuilder;
 use llm_samplers::prelude::*;
 use std::any::Any;
+use std::collections::VecDeque;
 use std::fmt::Display;
 use std::future::IntoFuture;
 use std::marker::PhantomData;
@@ -218,9 +229,24 @@ impl<'a, M: Model> StreamTextBuilder<'a, M> {
         self
     }
 
-    /// Set the string to stop on when generating text.
+    /// Set the string to stop on when generating text. This replaces any stop sequences that were set before.
     pub fn with_stop_on(mut self, stop_on: impl Into<Option<String>>) -> Self {
-        self.parameters.stop_on = stop_on.into();
+        self.parameters = self.parameters.with_stop_on(stop_on);
+        self
+    }
+
+    /// Set the strings to stop on when generating text. Generation stops on whichever stop sequence is generated first.
+    pub fn with_stop_sequences(
+        mut self,
+        stop_sequences: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.parameters = self.parameters.with_stop_sequences(stop_sequences);
+        self
+    }
+
+    /// Set the token ids to stop on when generating text.
+    pub fn with_stop_token_ids(mut self, stop_token_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.parameters = self.parameters.with_stop_token_ids(stop_token_ids);
         self
     }
 }
@@ -250,8 +276,9 @@ pub struct GenerateTextBuilder<'a, M: Model> {
         &'a M,
         &'a str,
         GenerationParameters,
-    )
-        -> Pin<Box<dyn std::future::Future<Output = anyhow::Result<String>> + Send + 'a>>,
+    ) -> Pin<
+        Box<dyn std::future::Future<Output = anyhow::Result<GeneratedText>> + Send + 'a>,
+    >,
 }
 
 impl<'a, M: Model> GenerateTextBuilder<'a, M> {
@@ -265,7 +292,7 @@ impl<'a, M: Model> GenerateTextBuilder<'a, M> {
             &'a str,
             GenerationParameters,
         ) -> Pin<
-            Box<dyn std::future::Future<Output = anyhow::Result<String>> + Send + 'a>,
+            Box<dyn std::future::Future<Output = anyhow::Result<GeneratedText>> + Send + 'a>,
         >,
     ) -> Self {
         Self {
@@ -324,15 +351,30 @@ impl<'a, M: Model> GenerateTextBuilder<'a, M> {
         self
     }
 
-    /// Set the string to stop on when generating text.
+    /// Set the string to stop on when generating text. This replaces any stop sequences that were set before.
     pub fn with_stop_on(mut self, stop_on: impl Into<Option<String>>) -> Self {
-        self.parameters.stop_on = stop_on.into();
+        self.parameters = self.parameters.with_stop_on(stop_on);
+        self
+    }
+
+    /// Set the strings to stop on when generating text. Generation stops on whichever stop sequence is generated first.
+    pub fn with_stop_sequences(
+        mut self,
+        stop_sequences: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.parameters = self.parameters.with_stop_sequences(stop_sequences);
+        self
+    }
+
+    /// Set the token ids to stop on when generating text.
+    pub fn with_stop_token_ids(mut self, stop_token_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.parameters = self.parameters.with_stop_token_ids(stop_token_ids);
         self
     }
 }
 
 impl<'a, M: Model> IntoFuture for GenerateTextBuilder<'a, M> {
-    type Output = anyhow::Result<String>;
+    type Output = anyhow::Result<GeneratedText>;
     type IntoFuture = Pin<Box<dyn std::future::Future<Output = Self::Output> + Send + 'a>>;
 
     fn into_future(self) -> Self::IntoFuture {
@@ -362,6 +404,7 @@ pub trait ModelExt: Model + Send + Sync + 'static {
     ///     let mut result = model.generate_text(prompt).with_max_length(300).await.unwrap();
     ///
     ///     println!("{prompt}{result}");
+    ///     println!("stopped because of {:?}", result.stop_reason());
     /// }
     /// ```
     fn generate_text<'a>(&'a self, prompt: &'a str) -> GenerateTextBuilder<'a, Self>
@@ -394,6 +437,8 @@ pub trait ModelExt: Model + Send + Sync + 'static {
     ///         print!("{token}");
     ///         std::io::stdout().flush().unwrap();
     ///     }
+    ///     // Once the stream is finished, it reports why generation stopped
+    ///     println!("\nstopped because of {:?}", result.stop_reason());
     /// }
     /// ```
     fn stream_text<'a>(&'a self, prompt: &'a str) -> StreamTextBuilder<'a, Self>
@@ -464,6 +509,8 @@ pub trait ModelExt: Model + Send + Sync + 'static {
     }
 
     /// Generate structured text with the given prompt and sampler.
+    ///
+    /// Models that cannot constrain sampling (see [`Model::structured_retry_handle`]) ignore the sampler and retry completions with the default [`GenerationParameters`] and [`StructuredRetry`] settings instead.
     async fn stream_structured_text_with_sampler<P>(
         &self,
         prompt: &str,
@@ -481,6 +528,20 @@ pub trait ModelExt: Model + Send + Sync + 'static {
         let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
 
         let prompt = prompt.to_string();
+        if let Some(model) = self.structured_retry_handle() {
+            spawn_structured_with_retries(
+                model,
+                prompt,
+                parser,
+                parser_state,
+                sender,
+                result_sender,
+            );
+            return Ok(StructureParserResult::new(
+                Self::TextStream::from(receiver),
+                result_receiver,
+            ));
+        }
         self.run_sync(move |llm: &mut Self::SyncModel| {
             let mut session = llm.new_session().unwrap();
             Box::pin(async move {
@@ -510,15 +571,178 @@ pub trait ModelExt: Model + Send + Sync + 'static {
             result_receiver,
         ))
     }
+
+    /// Generate structured text with a model that cannot constrain sampling, like a remote model.
+    ///
+    /// Completions are checked against the parser as they stream in and retried from the last text the parser accepted. See [`StructuredRetry`] for details. Each piece of accepted text is passed to `on_text`.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm_language_model::*;
+    /// use kalosm_sample::*;
+    ///
+    /// // This works with any model, including remote models like `Gpt3_5` that cannot run constrained sampling locally
+    /// async fn answer(llm: &impl Model) -> anyhow::Result<()> {
+    ///     let parser = LiteralParser::from("The answer is ")
+    ///         .then(IntegerParser::new(0..=100))
+    ///         .then(LiteralParser::from("."));
+    ///     let result = llm
+    ///         .generate_structured_with_retries(
+    ///             "What is 2 + 2? ",
+    ///             parser,
+    ///             GenerationParameters::default(),
+    ///             StructuredRetry::new(),
+    ///             |text| {
+    ///                 print!("{text}");
+    ///                 Ok(())
+    ///             },
+    ///         )
+    ///         .await?;
+    ///     println!("\n{result:?}");
+    ///     Ok(())
+    /// }
+    /// ```
+    async fn generate_structured_with_retries<P, F>(
+        &self,
+        prompt: &str,
+        parser: P,
+        parameters: GenerationParameters,
+        retry: StructuredRetry,
+        on_text: F,
+    ) -> anyhow::Result<P::Output>
+    where
+        P: kalosm_sample::CreateParserState + Parser + Send + Sync + 'static,
+        P::PartialState: Send + 'static,
+        P::Output: Send + 'static,
+        F: FnMut(String) -> anyhow::Result<()> + Send + 'static,
+    {
+        let parser_state = parser.create_parser_state();
+        generate_structured_with_retries(
+            self,
+            prompt,
+            &parser,
+            parser_state,
+            parameters,
+            retry,
+            on_text,
+        )
+        .await
+    }
+
+    /// Stream text with the given prompt along with the probability of each generated token and the `top_n` most likely alternatives at each position.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use rphi::prelude::*;
+    /// use kalosm_language_model::*;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut llm = Phi::default();
+    ///     let mut stream = llm
+    ///         .stream_text_with_probabilities("The capital of France is", GenerationParameters::default(), 3)
+    ///         .await
+    ///         .unwrap();
+    ///     while let Some(token) = stream.next().await {
+    ///         println!("{:?} ({:.2})", token.text(), token.probability());
+    ///         for alternative in token.top_alternatives() {
+    ///             println!("    {:?} ({:.2})", alternative.text(), alternative.probability());
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    async fn stream_text_with_probabilities(
+        &self,
+        prompt: &str,
+        parameters: GenerationParameters,
+        top_n: usize,
+    ) -> anyhow::Result<ChannelTextStream<GeneratedToken>> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let prompt = prompt.to_string();
+        self.run_sync(move |llm: &mut Self::SyncModel| {
+            Box::pin(async move {
+                let result = llm.new_session().and_then(|mut session| {
+                    llm.stream_tokens_with_sampler(
+                        &mut session,
+                        &prompt,
+                        Some(parameters.max_length),
+                        parameters.stop_sequences(),
+                        parameters.stop_token_ids(),
+                        Arc::new(Mutex::new(parameters.clone().sampler())),
+                        parameters.seed(),
+                        Some(top_n),
+                        |token| match sender.send(token) {
+                            Ok(()) => Ok(ModelFeedback::Continue),
+                            Err(_) => Ok(ModelFeedback::Stop),
+                        },
+                    )
+                });
+                if let Err(err) = result {
+                    log::error!("Error generating text: {:?}", err);
+                }
+            })
+        })?;
+
+        Ok(receiver.into())
+    }
+
+    /// Generate structured text with the given prompt along with the probability of each generated token and the `top_n` most likely alternatives the parser would have accepted at each position.
+    async fn stream_structured_text_with_probabilities<P>(
+        &self,
+        prompt: &str,
+        parser: P,
+        top_n: usize,
+    ) -> anyhow::Result<StructureParserResult<ChannelTextStream<GeneratedToken>, P::Output>>
+    where
+        P: kalosm_sample::CreateParserState + Parser + Send + 'static,
+        P::PartialState: Send + 'static,
+        P::Output: Clone + Send + 'static,
+    {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+
+        let sampler = Arc::new(Mutex::new(GenerationParameters::default().sampler()));
+        let parser_state = parser.create_parser_state();
+        let prompt = prompt.to_string();
+        self.run_sync(move |llm: &mut Self::SyncModel| {
+            Box::pin(async move {
+                let result = llm.new_session().and_then(|mut session| {
+                    llm.generate_structured_with_probabilities(
+                        &mut session,
+                        prompt,
+                        parser,
+                        parser_state,
+                        sampler,
+                        |token| Ok(sender.send(token)?),
+                        Some(32),
+                        top_n,
+                        None,
+                    )
+                });
+                match result_sender.send(result) {
+                    Ok(()) => {}
+                    Err(Ok(_)) => {
+                        log::error!("Error generating structured text: cancelled");
+                    }
+                    Err(Err(err)) => {
+                        log::error!("Error generating structured text: {:?}", err);
+                    }
+                }
+            })
+        })?;
+
+        Ok(StructureParserResult::new(receiver.into(), result_receiver))
+    }
 }
 
 /// The result of a structured parser stream.
-pub struct StructureParserResult<S: Stream<Item = String> + Send + Unpin + 'static, O> {
+pub struct StructureParserResult<S: Stream + Send + Unpin + 'static, O> {
     stream: S,
     result: tokio::sync::oneshot::Receiver<anyhow::Result<O>>,
 }
 
-impl<S: Stream<Item = String> + Send + Unpin + 'static, O> StructureParserResult<S, O> {
+impl<S: Stream + Send + Unpin + 'static, O> StructureParserResult<S, O> {
     /// Create a new structured parser result from a stream and a result.
     pub fn new(stream: S, result: tokio::sync::oneshot::Receiver<anyhow::Result<O>>) -> Self {
         Self { stream, result }
@@ -530,11 +754,14 @@ impl<S: Stream<Item = String> + Send + Unpin + 'static, O> StructureParserResult
     }
 
     /// Get all the text from the stream.
-    pub async fn text(self) -> String {
+    pub async fn text(self) -> String
+    where
+        S::Item: AsRef<str>,
+    {
         let mut text = String::new();
         let mut stream = self.stream;
         while let Some(new) = stream.next().await {
-            text.push_str(&new);
+            text.push_str(new.as_ref());
         }
         text
     }
@@ -545,8 +772,8 @@ impl<S: Stream<Item = String> + Send + Unpin + 'static, O> StructureParserResult
     }
 }
 
-impl<S: Stream<Item = String> + Send + Unpin + 'static, O> Stream for StructureParserResult<S, O> {
-    type Item = String;
+impl<S: Stream + Send + Unpin + 'static, O> Stream for StructureParserResult<S, O> {
+    type Item = S::Item;
 
     fn poll_next(
         self: Pin<&mut Self>,
@@ -601,11 +828,30 @@ pub trait SyncModel {
     /// Run the model synchronously with a pre-tokenized input. The model implementation may choose to return only the top k logits.
     fn feed_tokens(&self, session: &mut Self::Session, tokens: &[u32]) -> anyhow::Result<Vec<f32>>;
 
+    /// Run the model synchronously with a pre-tokenized input and return the logits after each of the tokens. This lets speculative decoding check several draft tokens with one pass of the model.
+    ///
+    /// The default implementation feeds the tokens one at a time.
+    fn feed_tokens_with_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        tokens
+            .iter()
+            .map(|token| self.feed_tokens(session, &[*token]))
+            .collect()
+    }
+
     /// Get the token ID that represents the end of a sequence.
     fn stop_token(&self) -> anyhow::Result<u32>;
 
     /// Return the tokenizer associated with this model.
     fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync>;
+
+    /// Returns the maximum number of tokens the model can attend to at once, if it is known.
+    fn context_length(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// A session for a model.
@@ -630,6 +876,11 @@ pub trait Session {
     {
         Err(anyhow::Error::msg("Not implemented"))
     }
+
+    /// Remove the last `count` tokens from the session so generation can continue from an earlier point.
+    fn rewind(&mut self, _count: usize) -> anyhow::Result<()> {
+        Err(anyhow::Error::msg("Not implemented"))
+    }
 }
 
 impl Session for () {
@@ -653,8 +904,36 @@ pub trait SyncModelExt: SyncModel {
         parser: P,
         parser_state: P::PartialState,
         sampler: Arc<Mutex<dyn Sampler>>,
-        on_token: impl FnMut(String) -> anyhow::Result<()>,
+        mut on_token: impl FnMut(String) -> anyhow::Result<()>,
         top_k: Option<usize>,
+    ) -> anyhow::Result<P::Output> {
+        generate_structured(
+            prompt,
+            self,
+            session,
+            parser,
+            parser_state,
+            sampler,
+            |token| on_token(token.text().to_string()),
+            top_k,
+            0,
+            None,
+        )
+    }
+
+    /// Generate new text with the given prompt that conforms to the given parser, calling the on_token callback with the probability of every generated token and the `top_n` most likely alternatives the parser would have accepted. If a seed is set, the tokens are sampled deterministically.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_structured_with_probabilities<P: Parser>(
+        &self,
+        session: &mut Self::Session,
+        prompt: impl Display,
+        parser: P,
+        parser_state: P::PartialState,
+        sampler: Arc<Mutex<dyn Sampler>>,
+        on_token: impl FnMut(GeneratedToken) -> anyhow::Result<()>,
+        top_k: Option<usize>,
+        top_n: usize,
+        seed: Option<u64>,
     ) -> anyhow::Result<P::Output> {
         generate_structured(
             prompt,
@@ -665,6 +944,43 @@ pub trait SyncModelExt: SyncModel {
             sampler,
             on_token,
             top_k,
+            top_n,
+            seed,
+        )
+    }
+
+    /// Generate new text with the given prompt that conforms to the given parser. If the parser accepts none of the tokens, generation backtracks and samples different tokens as configured by `recovery` instead of failing.
+    ///
+    /// Tokens are passed to the on_token callback once generation can no longer backtrack past them. If generation still fails, the error is a [`crate::StructuredGenerationError`]. If a seed is set, the tokens are sampled deterministically.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_structured_with_recovery<P: Parser>(
+        &self,
+        session: &mut Self::Session,
+        prompt: impl Display,
+        parser: P,
+        parser_state: P::PartialState,
+        sampler: Arc<Mutex<dyn Sampler>>,
+        on_token: impl FnMut(GeneratedToken) -> anyhow::Result<()>,
+        top_k: Option<usize>,
+        top_n: usize,
+        seed: Option<u64>,
+        recovery: StructuredRecovery,
+    ) -> anyhow::Result<P::Output>
+    where
+        P::PartialState: Clone,
+    {
+        generate_structured_with_recovery(
+            prompt,
+            self,
+            session,
+            parser,
+            parser_state,
+            sampler,
+            on_token,
+            top_k,
+            top_n,
+            seed,
+            recovery,
         )
     }
 
@@ -676,101 +992,273 @@ pub trait SyncModelExt: SyncModel {
         prompt: &str,
         max_tokens: Option<u32>,
         stop_on: Option<&str>,
-        mut sampler: Arc<Mutex<dyn Sampler>>,
+        sampler: Arc<Mutex<dyn Sampler>>,
         mut on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
     ) -> anyhow::Result<()> {
-        let tokens = self.tokenizer().encode(prompt, true)?;
-        let mut text_stream = TokenOutputStream::new(self.tokenizer());
+        let stop_on = StopOnMatcher::new(stop_on);
+        self.stream_tokens_with_sampler(
+            session,
+            prompt,
+            max_tokens,
+            stop_on.stop_sequences(),
+            &[],
+            sampler,
+            None,
+            None,
+            |token| {
+                if token.text().is_empty() {
+                    Ok(ModelFeedback::Continue)
+                } else {
+                    on_token(token.text().to_string())
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    /// Stream tokens, calling the on_token callback with every generated token, its probability and the `top_n` most likely alternatives. If a seed is set, the tokens are sampled deterministically. Returns the reason generation stopped.
+    ///
+    /// If `top_n` is `None`, the probabilities are not computed and the log probability of every token is NaN.
+    ///
+    /// Tokens that could be part of a stop sequence are held back until it is clear whether the stop sequence was generated. The text of the token that starts the stop sequence is trimmed to the text before it. Stop tokens are never passed to the callback.
+    fn stream_tokens_with_sampler(
+        &self,
+        session: &mut Self::Session,
+        prompt: &str,
+        max_tokens: Option<u32>,
+        stop_on: &[String],
+        stop_token_ids: &[u32],
+        mut sampler: Arc<Mutex<dyn Sampler>>,
+        seed: Option<u64>,
+        top_n: Option<usize>,
+        mut on_token: impl FnMut(GeneratedToken) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<StopReason> {
+        let tokenizer = self.tokenizer();
+        let tokens = tokenizer.encode(prompt, true)?;
+        let mut text_stream = TokenOutputStream::new(tokenizer.clone()).with_seed(seed);
         for token in tokens.iter().copied() {
             text_stream.next_token(token)?;
         }
 
-        let logit_probs = self.feed_tokens(session, &tokens)?;
-        let mut logits = Logits::try_from_iter_top_k(logit_probs, 512)?;
+        let mut logit_probs = self.feed_tokens(session, &tokens)?;
         let mut tokens_generated = 0;
-        // This stores a buffer of text that has been generated to check against the stop_on string. It should never be longer than the stop_on string.
-        let mut queued_text_matching_stop_on = String::new();
-        let stop_on_lowercase = stop_on.map(|s| s.to_lowercase());
-        let stop_on_lowercase = stop_on_lowercase.as_deref();
+        // Text that could be the start of a stop sequence is held back by the matcher until we know if it matches
+        let mut stop_on_matcher = StopOnMatcher::from_sequences(stop_on);
+        let mut held_back = HeldBackTokens::default();
         let stop_token = self.stop_token()?;
 
-        'generate: loop {
-            let new_token = text_stream.sample_token(&mut sampler, logits, stop_on)?;
+        let stop_reason = loop {
+            // The full logits are only kept around if the caller asked for the probabilities
+            let probability_logits = top_n.map(|_| logit_probs.clone());
+            let logits = Logits::try_from_iter_top_k(logit_probs, 512)?;
+            let new_token =
+                text_stream.sample_token(&mut sampler, logits, stop_on_matcher.stop_sequences())?;
             if new_token == stop_token {
                 tracing::trace!("Stopping on stop token");
-                break;
+                break StopReason::EndOfSequence;
             }
-            if let Some(mut new_text) = text_stream.next_token(new_token)? {
-                if let Some(stop_on) = stop_on_lowercase {
-                    let lowercase = new_text.to_lowercase();
-
-                    // Check if the string ends with the start of the stop_on string
-                    let mut before_stop_on = None;
-                    let remaining_stop_on = stop_on
-                        .strip_prefix(&queued_text_matching_stop_on)
-                        .unwrap_or(stop_on);
-
-                    // If the remaining stop_on string is empty, we have found a match
-                    if remaining_stop_on.is_empty() {
-                        break;
-                    }
-
-                    for (i, _) in lowercase.char_indices() {
-                        let end_of_new_text = &lowercase[i..];
-                        if end_of_new_text.is_empty() {
-                            break;
-                        }
-
-                        // Check if we have matched all of the stop_on string
-                        if end_of_new_text.starts_with(remaining_stop_on) {
-                            queued_text_matching_stop_on += end_of_new_text;
-                            break 'generate;
-                        }
-
-                        // Check if the string ends with the start of the stop_on string
-                        if remaining_stop_on.starts_with(end_of_new_text) {
-                            before_stop_on = Some(lowercase[..i].to_string());
-                            queued_text_matching_stop_on += end_of_new_text;
-                            break;
+            if stop_token_ids.contains(&new_token) {
+                tracing::trace!("Stopping on stop token {}", new_token);
+                break StopReason::StopToken { id: new_token };
+            }
+            let new_text = text_stream.next_token(new_token)?.unwrap_or_default();
+            let status = stop_on_matcher.feed(&new_text);
+            held_back.push(match &probability_logits {
+                Some(probability_logits) => LogProbabilities::new(probability_logits)
+                    .generated_token(
+                        new_token,
+                        new_text,
+                        top_n.unwrap_or_default(),
+                        0..probability_logits.len() as u32,
+                        &*tokenizer,
+                    )?,
+                None => GeneratedToken::without_probabilities(new_token, new_text),
+            });
+            match status {
+                StopOnStatus::Continue(text) => {
+                    for token in held_back.release(text.len()) {
+                        if let ModelFeedback::Stop = on_token(token)? {
+                            return Ok(StopReason::Cancelled);
                         }
                     }
-
-                    match before_stop_on {
-                        Some(before_stop_on) => {
-                            if let ModelFeedback::Stop = on_token(before_stop_on)? {
-                                break;
-                            }
-                        }
-                        None => {
-                            new_text =
-                                std::mem::take(&mut queued_text_matching_stop_on) + &new_text;
-                            if let ModelFeedback::Stop = on_token(new_text)? {
-                                break;
-                            }
-                        }
+                }
+                StopOnStatus::Stop(text) => {
+                    for token in held_back.release_and_truncate(text.len()) {
+                        on_token(token)?;
                     }
-                } else if let ModelFeedback::Stop = on_token(new_text)? {
-                    break;
+                    return Ok(stop_on_matcher
+                        .stop_reason()
+                        .unwrap_or(StopReason::EndOfSequence));
                 }
             }
             tokens_generated += 1;
             if let Some(max_tokens) = max_tokens {
                 if tokens_generated >= max_tokens {
-                    break;
+                    break StopReason::MaxLength;
                 }
             }
-            let logit_probs = self.feed_tokens(session, &[new_token])?;
-            logits = Logits::try_from_iter_top_k(logit_probs, 512)?;
+            logit_probs = self.feed_tokens(session, &[new_token])?;
+        };
+
+        // Flush the queued tokens
+        stop_on_matcher.flush();
+        for token in held_back.flush() {
+            if let ModelFeedback::Stop = on_token(token)? {
+                return Ok(StopReason::Cancelled);
+            }
         }
 
-        // Flush the queued text
-        if let Some(stop_string) = stop_on_lowercase {
-            if !queued_text_matching_stop_on.starts_with(stop_string) {
-                on_token(queued_text_matching_stop_on)?;
+        Ok(stop_reason)
+    }
+
+    /// Stream tokens with speculative decoding. The draft model proposes up to `draft_tokens` tokens at a time and this model checks all of them in one pass with [`SyncModel::feed_tokens_with_all_logits`]. Rejection sampling keeps the output distribution the same as sampling from this model alone.
+    ///
+    /// Both models must use the same tokenizer, and both sessions must support [`Session::rewind`]. Returns the reason generation stopped.
+    #[allow(clippy::too_many_arguments)]
+    fn stream_tokens_speculative<D: SyncModel>(
+        &self,
+        session: &mut Self::Session,
+        draft: &D,
+        draft_session: &mut D::Session,
+        prompt: &str,
+        parameters: &GenerationParameters,
+        draft_tokens: usize,
+        on_token: impl FnMut(GeneratedToken) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<StopReason> {
+        speculative_stream_tokens(
+            draft,
+            draft_session,
+            self,
+            session,
+            prompt,
+            parameters,
+            draft_tokens,
+            on_token,
+        )
+    }
+
+    /// Generate text with beam search, keeping the `beams` most likely completions at every step. Returns the finished completions ranked from the best to the worst length normalized score.
+    ///
+    /// The session is cloned with [`Session::try_clone`] so every beam can continue independently. The session passed in is not modified.
+    fn generate_text_beam_search(
+        &self,
+        session: &Self::Session,
+        prompt: &str,
+        parameters: &BeamSearchParameters,
+    ) -> anyhow::Result<Vec<ScoredText>> {
+        beam_search(self, session, prompt, parameters)
+    }
+
+    /// Sample `candidates` independent completions with the given parameters and rank them from the best to the worst length normalized score.
+    ///
+    /// If a seed is set, candidate `i` is sampled with the seed `seed + i`. The session passed in is not modified.
+    fn generate_text_best_of(
+        &self,
+        session: &Self::Session,
+        prompt: &str,
+        parameters: &GenerationParameters,
+        candidates: usize,
+        length_penalty: f32,
+    ) -> anyhow::Result<Vec<ScoredText>> {
+        best_of(
+            self,
+            session,
+            prompt,
+            parameters,
+            candidates,
+            length_penalty,
+        )
+    }
+
+    /// Generate text that conforms to the given parser with beam search. Only tokens the parser accepts are considered, so the best result is the most likely valid completion instead of the completion that follows the most likely token at every step.
+    fn generate_structured_beam_search<P: Parser>(
+        &self,
+        session: &Self::Session,
+        prompt: impl Display,
+        parser: P,
+        parser_state: P::PartialState,
+        parameters: &BeamSearchParameters,
+    ) -> anyhow::Result<Vec<ScoredStructure<P::Output>>>
+    where
+        P::PartialState: Clone,
+    {
+        structured_beam_search(self, session, prompt, parser, parser_state, parameters)
+    }
+
+    /// Sample `candidates` independent completions that conform to the given parser and rank them from the best to the worst length normalized score.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_structured_best_of<P: Parser>(
+        &self,
+        session: &Self::Session,
+        prompt: impl Display,
+        parser: P,
+        parser_state: P::PartialState,
+        parameters: &GenerationParameters,
+        candidates: usize,
+        length_penalty: f32,
+    ) -> anyhow::Result<Vec<ScoredStructure<P::Output>>>
+    where
+        P::PartialState: Clone,
+    {
+        structured_best_of(
+            self,
+            session,
+            prompt,
+            parser,
+            parser_state,
+            parameters,
+            candidates,
+            length_penalty,
+        )
+    }
+}
+
+/// Tokens that have been generated, but not sent to the user because some of their text is being held back by a [`StopOnMatcher`].
+#[derive(Default)]
+pub(crate) struct HeldBackTokens {
+    tokens: VecDeque<GeneratedToken>,
+    /// The number of bytes the matcher released that have not been assigned to a token yet.
+    released_bytes: usize,
+}
+
+impl HeldBackTokens {
+    pub(crate) fn push(&mut self, token: GeneratedToken) {
+        self.tokens.push_back(token);
+    }
+
+    /// Release the tokens whose text is fully covered by the text the matcher released.
+    pub(crate) fn release(&mut self, released_bytes: usize) -> Vec<GeneratedToken> {
+        self.released_bytes += released_bytes;
+        let mut released = Vec::new();
+        while let Some(token) = self.tokens.front() {
+            if token.text().len() > self.released_bytes {
+                break;
             }
+            self.released_bytes -= token.text().len();
+            released.extend(self.tokens.pop_front());
         }
+        released
+    }
 
-        Ok(())
+    /// Release the tokens before the stop sequence. The token that starts the stop sequence is truncated to the text before it.
+    pub(crate) fn release_and_truncate(&mut self, released_bytes: usize) -> Vec<GeneratedToken> {
+        let mut released = self.release(released_bytes);
+        if let Some(mut token) = self.tokens.pop_front() {
+            if self.released_bytes > 0 {
+                token.truncate_text(self.released_bytes);
+                released.push(token);
+            }
+        }
+        self.tokens.clear();
+        self.released_bytes = 0;
+        released
+    }
+
+    /// Release every token that is still held back.
+    pub(crate) fn flush(&mut self) -> Vec<GeneratedToken> {
+        self.released_bytes = 0;
+        self.tokens.drain(..).collect()
     }
 }
 
@@ -817,10 +1305,10 @@ impl SyncModel for SyncModelNotSupported {
 #[async_trait::async_trait]
 pub trait Model: Send + Sync + 'static {
     /// The type of stream that this model generates.
-    type TextStream: Stream<Item = String> + Send + Sync + Unpin + 'static;
+    type TextStream: GenerationStream + Send + Sync + Unpin + 'static;
 
-    /// Get the tokenizer associated with this model to use for constrained generation.
-    fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync>;
+    /// Get the tokenizer associated with this model to use for constrained generation. Returns `None` for models that do not expose their tokenizer (like remote models).
+    fn tokenizer(&self) -> Option<Arc<dyn Tokenizer + Send + Sync>>;
 
     /// The raw sync model that backs this model.
     type SyncModel: SyncModel;
@@ -868,14 +1356,14 @@ pub trait Model: Send + Sync + 'static {
         &self,
         prompt: &str,
         parameters: GenerationParameters,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<GeneratedText> {
         let mut text = String::new();
 
         let mut stream = self.stream_text_inner(prompt, parameters).await?;
         while let Some(new) = stream.next().await {
             text.push_str(&new);
         }
-        Ok(text)
+        Ok(GeneratedText::new(text, stream.stop_reason()))
     }
 
     /// Generate text with the given prompt.
@@ -898,16 +1386,54 @@ pub trait Model: Send + Sync + 'static {
         parameters: GenerationParameters,
     ) -> anyhow::Result<Self::TextStream>;
 
+    /// Get an owned handle to this model if it cannot run synchronously with [`Model::run_sync_raw`] (like remote models).
+    ///
+    /// Structured generation cannot constrain the sampling of these models, so [`ModelExt::stream_structured_text`] falls back to retrying completions (see [`StructuredRetry`]) with this handle in a background task. Returns `None` by default.
+    fn structured_retry_handle(&self) -> Option<DynModel> {
+        None
+    }
+
     /// Returns the chat markers to use for the model if this is a chat model.
     fn chat_markers(&self) -> Option<ChatMarkers> {
         None
     }
+
+    /// Returns the maximum number of tokens the model can attend to at once, if it is known.
+    fn context_length(&self) -> Option<usize> {
+        None
+    }
+
+    /// Count the number of tokens the text takes up in the model's context.
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        let tokenizer = self.tokenizer().ok_or_else(|| {
+            anyhow::anyhow!("This model does not expose a tokenizer to count tokens with")
+        })?;
+        Ok(tokenizer.encode(text, false)?.len())
+    }
+
+    /// Generate the next model answer for a chat history.
+    ///
+    /// By default, this formats the history with the model's [`ChatMarkers`] and streams the completion of that prompt. Models with a native chat interface can override this to send the messages directly.
+    async fn stream_chat_inner(
+        &self,
+        history: &[ChatHistoryItem],
+        parameters: GenerationParameters,
+    ) -> anyhow::Result<Self::TextStream> {
+        let markers = self
+            .chat_markers()
+            .ok_or_else(|| anyhow::anyhow!("Model does not support chat"))?;
+        let prompt = markers.format_history(history);
+        let parameters = if !parameters.stop_sequences().is_empty() {
+            parameters
+        } else {
+            parameters.with_stop_on(markers.end_assistant_marker.to_string())
+        };
+        self.stream_text_inner(&prompt, parameters).await
+    }
 }
 
 /// An extension trait for models that can be converted into a trait object.
-pub trait AnyModelExt:
-    Model<TextStream = ChannelTextStream<String>> + Send + Sync + 'static
-{
+pub trait AnyModelExt: Model<TextStream = TextGenerationStream> + Send + Sync + 'static {
     /// Convert this model into a model trait object.
     fn into_any_model(self) -> DynModel
     where
@@ -917,7 +1443,7 @@ pub trait AnyModelExt:
     }
 }
 
-impl<M: Model<TextStream = ChannelTextStream<String>> + Send + Sync + 'static> AnyModelExt for M {}
+impl<M: Model<TextStream = TextGenerationStream> + Send + Sync + 'static> AnyModelExt for M {}
 
 /// The chat markers to use for the model.
 #[derive(Default, Clone, Debug)]
@@ -938,15 +1464,15 @@ pub struct ChatMarkers {
 
 /// A trait object for a model.
 pub type DynModel =
-    Box<dyn Model<TextStream = ChannelTextStream<String>, SyncModel = BoxedSyncModel> + Send>;
+    Box<dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel> + Send>;
 
 #[async_trait::async_trait]
 impl Model for DynModel {
-    type TextStream = ChannelTextStream<String>;
+    type TextStream = TextGenerationStream;
     type SyncModel = BoxedSyncModel;
 
-    fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync> {
-        let self_ref: &(dyn Model<TextStream = ChannelTextStream<String>, SyncModel = BoxedSyncModel>
+    fn tokenizer(&self) -> Option<Arc<dyn Tokenizer + Send + Sync>> {
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
               + Send) = self.as_ref();
         self_ref.tokenizer()
     }
@@ -956,10 +1482,44 @@ impl Model for DynModel {
         prompt: &str,
         parameters: GenerationParameters,
     ) -> anyhow::Result<Self::TextStream> {
-        let self_ref: &(dyn Model<TextStream = ChannelTextStream<String>, SyncModel = BoxedSyncModel>
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
               + Send) = self.as_ref();
         self_ref.stream_text_inner(prompt, parameters).await
     }
+
+    async fn stream_chat_inner(
+        &self,
+        history: &[ChatHistoryItem],
+        parameters: GenerationParameters,
+    ) -> anyhow::Result<Self::TextStream> {
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
+              + Send) = self.as_ref();
+        self_ref.stream_chat_inner(history, parameters).await
+    }
+
+    fn chat_markers(&self) -> Option<ChatMarkers> {
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
+              + Send) = self.as_ref();
+        self_ref.chat_markers()
+    }
+
+    fn structured_retry_handle(&self) -> Option<DynModel> {
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
+              + Send) = self.as_ref();
+        self_ref.structured_retry_handle()
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
+              + Send) = self.as_ref();
+        self_ref.context_length()
+    }
+
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        let self_ref: &(dyn Model<TextStream = TextGenerationStream, SyncModel = BoxedSyncModel>
+              + Send) = self.as_ref();
+        self_ref.count_tokens(text)
+    }
 }
 
 /// A trait object for a sync model.
@@ -968,6 +1528,7 @@ pub type BoxedSyncModel = Box<dyn SyncModel<Session = AnySession>>;
 trait AnySessionTrait {
     fn as_any_mut(&mut self) -> &mut dyn Any;
     fn save_to(&self, path: &Path) -> anyhow::Result<()>;
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()>;
 }
 
 impl<S: Any + Session> AnySessionTrait for S {
@@ -978,6 +1539,10 @@ impl<S: Any + Session> AnySessionTrait for S {
     fn save_to(&self, path: &Path) -> anyhow::Result<()> {
         Session::save_to(self, path)
     }
+
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()> {
+        Session::rewind(self, count)
+    }
 }
 
 /// A type-erased session.
@@ -997,6 +1562,10 @@ impl Session for AnySession {
     fn save_to(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
         self.session.save_to(path.as_ref())
     }
+
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()> {
+        self.session.rewind(count)
+    }
 }
 
 impl SyncModel for BoxedSyncModel {
@@ -1017,6 +1586,15 @@ impl SyncModel for BoxedSyncModel {
         self_ref.feed_tokens(session, tokens)
     }
 
+    fn feed_tokens_with_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let self_ref: &(dyn SyncModel<Session = AnySession>) = self.as_ref();
+        self_ref.feed_tokens_with_all_logits(session, tokens)
+    }
+
     fn stop_token(&self) -> anyhow::Result<u32> {
         let self_ref: &(dyn SyncModel<Session = AnySession>) = self.as_ref();
         self_ref.stop_token()
@@ -1026,6 +1604,11 @@ impl SyncModel for BoxedSyncModel {
         let self_ref: &(dyn SyncModel<Session = AnySession>) = self.as_ref();
         self_ref.tokenizer()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        let self_ref: &(dyn SyncModel<Session = AnySession>) = self.as_ref();
+        self_ref.context_length()
+    }
 }
 
 struct AnySyncModel<M: SyncModel<Session = S>, S: Any>(M, PhantomData<S>);
@@ -1069,6 +1652,25 @@ impl<M: SyncModel<Session = S>, S: Session + Any> SyncModel for AnySyncModel<M,
         )
     }
 
+    fn feed_tokens_with_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.0.feed_tokens_with_all_logits(
+            match session.as_any_mut().downcast_mut() {
+                Some(s) => s,
+                None => {
+                    return Err(anyhow::Error::msg(format!(
+                        "Invalid session type expected {:?}",
+                        std::any::type_name::<S>()
+                    )))
+                }
+            },
+            tokens,
+        )
+    }
+
     fn stop_token(&self) -> anyhow::Result<u32> {
         self.0.stop_token()
     }
@@ -1076,6 +1678,10 @@ impl<M: SyncModel<Session = S>, S: Session + Any> SyncModel for AnySyncModel<M,
     fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync> {
         self.0.tokenizer()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        self.0.context_length()
+    }
 }
 
 struct AnyModel<M>(M);
@@ -1083,12 +1689,12 @@ struct AnyModel<M>(M);
 #[async_trait::async_trait]
 impl<M> Model for AnyModel<M>
 where
-    M: Model<TextStream = ChannelTextStream<String>> + Send + Sync,
+    M: Model<TextStream = TextGenerationStream> + Send + Sync,
 {
-    type TextStream = ChannelTextStream<String>;
+    type TextStream = TextGenerationStream;
     type SyncModel = BoxedSyncModel;
 
-    fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync> {
+    fn tokenizer(&self) -> Option<Arc<dyn Tokenizer + Send + Sync>> {
         self.0.tokenizer()
     }
 
@@ -1111,10 +1717,37 @@ where
             .stream_text_with_sampler(prompt, max_tokens, stop_on, sampler)
             .await
     }
+
+    async fn stream_chat_inner(
+        &self,
+        history: &[ChatHistoryItem],
+        parameters: GenerationParameters,
+    ) -> anyhow::Result<Self::TextStream> {
+        self.0.stream_chat_inner(history, parameters).await
+    }
+
+    fn chat_markers(&self) -> Option<ChatMarkers> {
+        self.0.chat_markers()
+    }
+
+    fn structured_retry_handle(&self) -> Option<DynModel> {
+        self.0.structured_retry_handle()
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        self.0.context_length()
+    }
+
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        self.0.count_tokens(text)
+    }
 }
 
 /// Parameters to use when generating text.
+///
+/// By default, tokens are sampled with a chain of repetition penalties, temperature and mirostat 2 configured by the individual setters. Use [`GenerationParameters::with_sampler_stages`] to pick your own sampler chain.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GenerationParameters {
     pub(crate) temperature: f32,
     pub(crate) tau: f32,
@@ -1123,7 +1756,10 @@ pub struct GenerationParameters {
     pub(crate) repetition_penalty: f32,
     pub(crate) repetition_penalty_range: u32,
     pub(crate) max_length: u32,
-    pub(crate) stop_on: Option<String>,
+    pub(crate) stop_sequences: Vec<String>,
+    pub(crate) stop_token_ids: Vec<u32>,
+    pub(crate) sampler_stages: Option<Vec<SamplerStage>>,
+    pub(crate) seed: Option<u64>,
 }
 
 impl Default for GenerationParameters {
@@ -1136,7 +1772,10 @@ impl Default for GenerationParameters {
             repetition_penalty: 1.3,
             repetition_penalty_range: 64,
             max_length: 128,
-            stop_on: None,
+            stop_sequences: Vec::new(),
+            stop_token_ids: Vec::new(),
+            sampler_stages: None,
+            seed: None,
         }
     }
 }
@@ -1144,50 +1783,7 @@ impl Default for GenerationParameters {
 impl crate::model::GenerationParameters {
     /// Create a sampler chain from the generation parameters.
     pub fn sampler(self) -> SamplerChain {
-        use llm_samplers::configure::SamplerSlot;
-        let GenerationParameters {
-            temperature,
-            tau,
-            eta,
-            mu,
-            repetition_penalty,
-            repetition_penalty_range,
-            max_length: _,
-            stop_on: _,
-        } = self;
-        SamplerChainBuilder::from([
-            (
-                "repetition",
-                SamplerSlot::new_static(move || {
-                    Box::new(
-                        SampleRepetition::default()
-                            .penalty(repetition_penalty)
-                            .last_n(repetition_penalty_range as usize),
-                    )
-                }),
-            ),
-            (
-                "freqpresence",
-                SamplerSlot::new_static(move || Box::new(SampleFreqPresence::default().last_n(64))),
-            ),
-            (
-                "seqrepetition",
-                SamplerSlot::new_static(move || Box::<SampleSeqRepetition>::default()),
-            ),
-            (
-                "temperature",
-                SamplerSlot::new_static(move || {
-                    Box::new(SampleTemperature::default().temperature(temperature))
-                }),
-            ),
-            (
-                "mirostat2",
-                SamplerSlot::new_static(move || {
-                    Box::new(SampleMirostat2::default().tau(tau).eta(eta).mu(mu))
-                }),
-            ),
-        ])
-        .into_chain()
+        sampler_chain(&self.sampler_stages(), true)
     }
 
     /// Get the mirostat2 sampler from the generation parameters.
@@ -1200,40 +1796,69 @@ impl crate::model::GenerationParameters {
 
     /// Create a sampler chain from the generation parameters without removing any tokens. This can be useful in combination with [`ModelExt::stream_structured_text_with_sampler`] which may pick unlikely tokens.
     pub fn bias_only_sampler(self) -> SamplerChain {
-        use llm_samplers::configure::SamplerSlot;
-        let GenerationParameters {
-            temperature,
-            repetition_penalty,
-            repetition_penalty_range,
-            ..
-        } = self;
-        SamplerChainBuilder::from([
-            (
-                "repetition",
-                SamplerSlot::new_static(move || {
-                    Box::new(
-                        SampleRepetition::default()
-                            .penalty(repetition_penalty)
-                            .last_n(repetition_penalty_range as usize),
-                    )
-                }),
-            ),
-            (
-                "freqpresence",
-                SamplerSlot::new_static(move || Box::new(SampleFreqPresence::default().last_n(64))),
-            ),
-            (
-                "seqrepetition",
-                SamplerSlot::new_static(move || Box::<SampleSeqRepetition>::default()),
-            ),
-            (
-                "temperature",
-                SamplerSlot::new_static(move || {
-                    Box::new(SampleTemperature::default().temperature(temperature))
-                }),
-            ),
-        ])
-        .into_chain()
+        sampler_chain(
+            self.sampler_stages()
+                .iter()
+                .filter(|stage| stage.is_bias_only()),
+            false,
+        )
+    }
+
+    /// Get the stages of the sampler chain. If no stages were set with [`GenerationParameters::with_sampler_stages`], this is the default chain built from the other parameters.
+    pub fn sampler_stages(&self) -> Vec<SamplerStage> {
+        if let Some(stages) = &self.sampler_stages {
+            return stages.clone();
+        }
+        vec![
+            SamplerStage::Repetition {
+                penalty: self.repetition_penalty,
+                last_n: self.repetition_penalty_range as usize,
+            },
+            SamplerStage::FrequencyPresence {
+                frequency_penalty: 0.,
+                presence_penalty: 0.,
+                last_n: 64,
+            },
+            SamplerStage::SequenceRepetition,
+            SamplerStage::Temperature {
+                temperature: self.temperature,
+            },
+            SamplerStage::Mirostat2 {
+                tau: self.tau,
+                eta: self.eta,
+                mu: self.mu,
+            },
+        ]
+    }
+
+    /// Set the stages of the sampler chain. This replaces the default chain, so the temperature, mirostat and repetition penalty settings are ignored.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kalosm_language_model::{GenerationParameters, SamplerStage};
+    ///
+    /// let parameters = GenerationParameters::default()
+    ///     .with_sampler_stages([
+    ///         SamplerStage::TopK { k: 40 },
+    ///         SamplerStage::TopP { p: 0.9 },
+    ///         SamplerStage::Temperature { temperature: 0.7 },
+    ///     ])
+    ///     .with_seed(42);
+    /// ```
+    pub fn with_sampler_stages(mut self, stages: impl IntoIterator<Item = SamplerStage>) -> Self {
+        self.sampler_stages = Some(stages.into_iter().collect());
+        self
+    }
+
+    /// Set the seed for the random number generator used when sampling. Generating text with the same seed, parameters and prompt will produce the same text.
+    pub fn with_seed(mut self, seed: impl Into<Option<u64>>) -> Self {
+        self.seed = seed.into();
+        self
+    }
+
+    /// Get the seed for the random number generator used when sampling.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
     }
 
     /// Set the temperature to use when generating text.
@@ -1278,9 +1903,31 @@ impl crate::model::GenerationParameters {
         self
     }
 
-    /// Set the string to stop on when generating text.
+    /// Set the string to stop on when generating text. This replaces any stop sequences that were set before.
     pub fn with_stop_on(mut self, stop_on: impl Into<Option<String>>) -> Self {
-        self.stop_on = stop_on.into();
+        self.stop_sequences = stop_on.into().into_iter().collect();
+        self
+    }
+
+    /// Set the strings to stop on when generating text. Generation stops on whichever stop sequence is generated first, and the stop sequence is not included in the output.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kalosm_language_model::GenerationParameters;
+    ///
+    /// let parameters = GenerationParameters::default().with_stop_sequences(["\n\n", "User:"]);
+    /// ```
+    pub fn with_stop_sequences(
+        mut self,
+        stop_sequences: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.stop_sequences = stop_sequences.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the token ids to stop on when generating text. The stop token is not included in the output.
+    pub fn with_stop_token_ids(mut self, stop_token_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_token_ids = stop_token_ids.into_iter().collect();
         self
     }
 
@@ -1319,8 +1966,18 @@ impl crate::model::GenerationParameters {
         self.max_length
     }
 
-    /// Get the string to stop on when generating text.
+    /// Get the first string to stop on when generating text.
     pub fn stop_on(&self) -> Option<&str> {
-        self.stop_on.as_deref()
+        self.stop_sequences.first().map(|s| s.as_str())
+    }
+
+    /// Get the strings to stop on when generating text.
+    pub fn stop_sequences(&self) -> &[String] {
+        &self.stop_sequences
+    }
+
+    /// Get the token ids to stop on when generating text.
+    pub fn stop_token_ids(&self) -> &[u32] {
+        &self.stop_token_ids
     }
 }
@@ -0,0 +1,264 @@
+/// A model cache (like the key-value cache of a transformer) that can be stored in a [`PrefixCache`].
+pub trait PrefixCacheEntry: Clone {
+    /// Truncate the cache so it only contains the first `len` tokens.
+    fn truncate(&mut self, len: usize) -> anyhow::Result<()>;
+
+    /// The number of bytes this cache uses.
+    fn memory_usage(&self) -> usize;
+}
+
+/// A cache of model states keyed on the tokens that were fed into the model.
+///
+/// When a new request starts, the cached state with the longest common prefix can be reused so shared system prompts and few shot examples only need to be processed once. The least recently used entries are evicted when the cache grows past its memory budget.
+#[derive(Debug, Clone)]
+pub struct PrefixCache<C> {
+    max_memory: usize,
+    memory_usage: usize,
+    entries: Vec<PrefixCacheItem<C>>,
+    clock: u64,
+}
+
+#[derive(Debug, Clone)]
+struct PrefixCacheItem<C> {
+    tokens: Vec<u32>,
+    cache: C,
+    memory_usage: usize,
+    last_used: u64,
+}
+
+impl<C: PrefixCacheEntry> PrefixCache<C> {
+    /// Create a new prefix cache that will use at most `max_memory` bytes. A budget of zero disables the cache.
+    pub fn new(max_memory: usize) -> Self {
+        Self {
+            max_memory,
+            memory_usage: 0,
+            entries: Vec::new(),
+            clock: 0,
+        }
+    }
+
+    /// Check if the cache is disabled.
+    pub fn is_disabled(&self) -> bool {
+        self.max_memory == 0
+    }
+
+    /// The number of bytes the cached entries use.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    /// The number of cached entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove every entry from the cache.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.memory_usage = 0;
+    }
+
+    /// Find the cached state with the longest common prefix with `tokens`. Returns the number of tokens the state covers and a copy of the state truncated to that length.
+    ///
+    /// At least one token is always left out of the match so the model still has a token to feed to get the next logits. If a state can't be truncated, the next best match is used instead.
+    pub fn get(&mut self, tokens: &[u32]) -> Option<(usize, C)> {
+        let max_len = tokens.len().saturating_sub(1);
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i, common_prefix_len(&entry.tokens, tokens).min(max_len)))
+            .filter(|(_, len)| *len > 0)
+            .collect();
+        // Try the longest matches first, preferring entries that don't need to be truncated
+        candidates.sort_by_key(|(i, len)| {
+            (
+                std::cmp::Reverse(*len),
+                self.entries[*i].tokens.len() != *len,
+            )
+        });
+
+        for (index, len) in candidates {
+            let entry = &self.entries[index];
+            let mut cache = entry.cache.clone();
+            if len < entry.tokens.len() {
+                if let Err(err) = cache.truncate(len) {
+                    tracing::error!("Failed to truncate cached prefix: {}", err);
+                    continue;
+                }
+            }
+
+            self.clock += 1;
+            self.entries[index].last_used = self.clock;
+            tracing::trace!("Reusing {} cached prompt tokens", len);
+
+            return Some((len, cache));
+        }
+
+        None
+    }
+
+    /// Add the state after feeding `tokens` into the model to the cache.
+    pub fn insert(&mut self, tokens: Vec<u32>, cache: C) {
+        let memory_usage = cache.memory_usage();
+        if tokens.is_empty() || memory_usage > self.max_memory {
+            return;
+        }
+
+        // Entries that are a prefix of the new entry are no longer useful
+        let mut i = 0;
+        while i < self.entries.len() {
+            let entry = &self.entries[i];
+            if tokens.starts_with(&entry.tokens) {
+                self.memory_usage -= entry.memory_usage;
+                self.entries.swap_remove(i);
+            } else if entry.tokens.starts_with(&tokens) {
+                // The new entry is already covered by an existing entry
+                return;
+            } else {
+                i += 1;
+            }
+        }
+
+        while self.memory_usage + memory_usage > self.max_memory {
+            self.evict_least_recently_used();
+        }
+
+        self.clock += 1;
+        self.memory_usage += memory_usage;
+        self.entries.push(PrefixCacheItem {
+            tokens,
+            cache,
+            memory_usage,
+            last_used: self.clock,
+        });
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some((index, _)) = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| entry.last_used)
+        {
+            let entry = self.entries.swap_remove(index);
+            self.memory_usage -= entry.memory_usage;
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+impl PrefixCacheEntry for Vec<u32> {
+    fn truncate(&mut self, len: usize) -> anyhow::Result<()> {
+        Vec::truncate(self, len);
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.len() * std::mem::size_of::<u32>()
+    }
+}
+
+#[test]
+fn prefix_cache_longest_match() {
+    let mut cache = PrefixCache::new(1024);
+    cache.insert(vec![1, 2, 3], vec![1, 2, 3]);
+    cache.insert(vec![1, 2, 4, 5], vec![1, 2, 4, 5]);
+
+    assert_eq!(cache.get(&[1, 2, 4, 6]), Some((3, vec![1, 2, 4])));
+    assert_eq!(cache.get(&[1, 2, 3, 7]), Some((3, vec![1, 2, 3])));
+    // At least one token is left for the model to feed
+    assert_eq!(cache.get(&[1, 2, 3]), Some((2, vec![1, 2])));
+    assert_eq!(cache.get(&[9]), None);
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+struct FallibleCache {
+    tokens: Vec<u32>,
+    can_truncate: bool,
+}
+
+#[cfg(test)]
+impl PrefixCacheEntry for FallibleCache {
+    fn truncate(&mut self, len: usize) -> anyhow::Result<()> {
+        if !self.can_truncate {
+            anyhow::bail!("Can't truncate the cache to {} tokens", len);
+        }
+        self.tokens.truncate(len);
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.tokens.memory_usage()
+    }
+}
+
+#[test]
+fn prefix_cache_falls_back_when_truncation_fails() {
+    let mut cache = PrefixCache::new(1024);
+    cache.insert(
+        vec![1, 2, 3, 9],
+        FallibleCache {
+            tokens: vec![1, 2, 3, 9],
+            can_truncate: false,
+        },
+    );
+    cache.insert(
+        vec![1, 2, 7],
+        FallibleCache {
+            tokens: vec![1, 2, 7],
+            can_truncate: true,
+        },
+    );
+
+    // The longest match can't be truncated, so the next best match is used
+    assert_eq!(
+        cache.get(&[1, 2, 3, 4]),
+        Some((
+            2,
+            FallibleCache {
+                tokens: vec![1, 2],
+                can_truncate: true,
+            }
+        ))
+    );
+    // The longest match can be used without truncating it
+    assert_eq!(cache.get(&[1, 2, 3, 9, 4]).map(|(len, _)| len), Some(4));
+}
+
+#[test]
+fn prefix_cache_evicts_least_recently_used() {
+    // Room for two entries of three tokens
+    let mut cache = PrefixCache::new(24);
+    cache.insert(vec![1, 1, 1], vec![1, 1, 1]);
+    cache.insert(vec![2, 2, 2], vec![2, 2, 2]);
+    assert!(cache.get(&[1, 1, 1, 1]).is_some());
+    cache.insert(vec![3, 3, 3], vec![3, 3, 3]);
+
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.memory_usage(), 24);
+    assert!(cache.get(&[2, 2, 2, 2]).is_none());
+    assert!(cache.get(&[1, 1, 1, 1]).is_some());
+    assert!(cache.get(&[3, 3, 3, 3]).is_some());
+}
+
+#[test]
+fn prefix_cache_replaces_shorter_prefixes() {
+    let mut cache = PrefixCache::new(1024);
+    cache.insert(vec![1, 2], vec![1, 2]);
+    cache.insert(vec![1, 2, 3], vec![1, 2, 3]);
+    cache.insert(vec![1], vec![1]);
+
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.memory_usage(), 12);
+}
@@ -0,0 +1,93 @@
+//! A local server with an OpenAI compatible API for testing the remote models.
+
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// A request the mock server received.
+#[derive(Debug, Clone)]
+pub(crate) struct MockRequest {
+    /// The path of the request (like `/v1/chat/completions`)
+    pub(crate) path: String,
+    /// The JSON body of the request
+    pub(crate) body: serde_json::Value,
+}
+
+/// Start a server with OpenAI compatible completions and chat completions endpoints that streams each of the responses in order, one response per request. Returns the base URL of the API and the requests the server received.
+pub(crate) async fn mock_server(
+    responses: Vec<Vec<&'static str>>,
+) -> (String, Arc<Mutex<Vec<MockRequest>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let received = requests.clone();
+    tokio::spawn(async move {
+        for chunks in responses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(length) = line.strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).await.unwrap();
+            let body = serde_json::from_slice(&body).unwrap();
+            let chat = path.ends_with("/chat/completions");
+            received.lock().unwrap().push(MockRequest { path, body });
+
+            let mut response = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncache-control: no-cache\r\nconnection: close\r\n\r\n".to_string();
+            for chunk in chunks {
+                let event = if chat {
+                    serde_json::json!({
+                        "id": "chatcmpl-mock",
+                        "object": "chat.completion.chunk",
+                        "created": 0,
+                        "model": "mock",
+                        "choices": [{
+                            "index": 0,
+                            "delta": { "content": chunk },
+                            "finish_reason": null
+                        }]
+                    })
+                } else {
+                    serde_json::json!({
+                        "id": "cmpl-mock",
+                        "object": "text_completion",
+                        "created": 0,
+                        "model": "mock",
+                        "choices": [{
+                            "text": chunk,
+                            "index": 0,
+                            "logprobs": null,
+                            "finish_reason": null
+                        }]
+                    })
+                };
+                response += &format!("data: {event}\n\n");
+            }
+            response += "data: [DONE]\n\n";
+            let stream = stream.get_mut();
+            // The client may close the connection early once it has seen enough of the response
+            _ = stream.write_all(response.as_bytes()).await;
+            _ = stream.shutdown().await;
+        }
+    });
+
+    (format!("http://{address}/v1"), requests)
+}
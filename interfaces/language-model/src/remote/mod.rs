@@ -1,2 +1,318 @@
 mod open_ai;
 pub use open_ai::*;
+mod open_ai_chat;
+pub use open_ai_chat::*;
+#[cfg(test)]
+mod mock_server;
+
+use crate::{GenerationParameters, SamplerStage};
+use crate::{StopOnMatcher, StopOnStatus, StopReason, TextGenerationStream, ToolCall};
+use async_openai::types::Stop;
+use futures_util::{Stream, StreamExt};
+use std::fmt::Display;
+
+/// The sampler settings an OpenAI compatible API supports. Stages without an equivalent (like top-k, min-p, typical and mirostat sampling) are ignored.
+#[derive(Debug, Default, PartialEq)]
+struct OpenAISamplerSettings {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    seed: Option<i64>,
+}
+
+impl From<&GenerationParameters> for OpenAISamplerSettings {
+    fn from(parameters: &GenerationParameters) -> Self {
+        let mut settings = Self {
+            // The API takes a signed seed, so seeds above i64::MAX wrap around
+            seed: parameters.seed().map(|seed| seed as i64),
+            ..Default::default()
+        };
+        let mut repetition_penalty = None;
+        for stage in parameters.sampler_stages() {
+            match stage {
+                SamplerStage::Repetition { penalty, .. } => repetition_penalty = Some(penalty),
+                SamplerStage::FrequencyPresence {
+                    frequency_penalty,
+                    presence_penalty,
+                    ..
+                } => {
+                    if frequency_penalty != 0. {
+                        settings.frequency_penalty = Some(frequency_penalty);
+                    }
+                    if presence_penalty != 0. {
+                        settings.presence_penalty = Some(presence_penalty);
+                    }
+                }
+                SamplerStage::Temperature { temperature } => {
+                    settings.temperature = Some(temperature)
+                }
+                SamplerStage::TopP { p } => settings.top_p = Some(p),
+                SamplerStage::Greedy => settings.temperature = Some(0.),
+                _ => {}
+            }
+        }
+        // There is no repetition penalty in the API, so it is sent as the frequency penalty if no frequency penalty is set
+        if settings.frequency_penalty.is_none() {
+            settings.frequency_penalty = repetition_penalty;
+        }
+        settings
+    }
+}
+
+/// OpenAI's API accepts at most four stop sequences
+const MAX_SERVER_STOP_SEQUENCES: usize = 4;
+
+/// The stop sequences to send to an OpenAI compatible API. Empty sequences are removed and only the first four sequences are sent. Every stop sequence is still matched locally.
+fn server_stop_sequences(stop_sequences: &[String]) -> Option<Stop> {
+    let stop_sequences: Vec<_> = stop_sequences
+        .iter()
+        .filter(|sequence| !sequence.is_empty())
+        .take(MAX_SERVER_STOP_SEQUENCES)
+        .cloned()
+        .collect();
+    (!stop_sequences.is_empty()).then_some(Stop::StringArray(stop_sequences))
+}
+
+/// A chunk of text streamed from an OpenAI compatible API.
+#[derive(Debug, Default)]
+struct RemoteChunk {
+    text: Option<String>,
+    tool_calls: Vec<RemoteToolCallChunk>,
+    finish_reason: Option<RemoteFinishReason>,
+}
+
+/// A piece of a tool call streamed from an OpenAI compatible API. The id and name are sent with the first piece of each call and the arguments are split across the pieces.
+#[derive(Debug, Default)]
+struct RemoteToolCallChunk {
+    /// The index of the call this piece belongs to
+    index: usize,
+    id: Option<String>,
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// Why an OpenAI compatible API finished the response.
+#[derive(Debug)]
+enum RemoteFinishReason {
+    /// The response reached the maximum number of tokens
+    Length,
+    /// The model stopped for any other reason (like generating a stop sequence or an end of sequence token)
+    Stop,
+}
+
+/// Stream the text from an OpenAI compatible API into a [`TextGenerationStream`].
+///
+/// The stop sequences are sent to the server so it stops generating as soon as one is generated. They are also matched locally with the same [`StopOnMatcher`] the local models use, so servers that ignore stop sequences are trimmed the same way and the stream can report which stop sequence was generated if the server returns it. Servers that trim the stop sequence themselves end the stream with [`StopReason::EndOfSequence`].
+fn spawn_remote_stream<E: Display>(
+    stream: impl Stream<Item = Result<RemoteChunk, E>> + Send + Unpin + 'static,
+    stop_sequences: &[String],
+) -> TextGenerationStream {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (stop_reason_tx, stop_reason_rx) = tokio::sync::oneshot::channel();
+    let matcher = StopOnMatcher::from_sequences(stop_sequences);
+
+    tokio::spawn(async move {
+        if let Some(stop_reason) = forward_remote_stream(stream, matcher, tx).await {
+            _ = stop_reason_tx.send(stop_reason);
+        }
+    });
+
+    TextGenerationStream::new(rx, stop_reason_rx)
+}
+
+/// Forward the text from the stream to the sender until the stream ends or a stop sequence is generated. Returns the reason generation stopped, or `None` if the stream failed.
+///
+/// If the model called any tools, the calls are collected and the stream ends with [`StopReason::ToolCalls`].
+async fn forward_remote_stream<E: Display>(
+    mut stream: impl Stream<Item = Result<RemoteChunk, E>> + Unpin,
+    mut matcher: StopOnMatcher,
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+) -> Option<StopReason> {
+    let send = |text: String| text.is_empty() || sender.send(text).is_ok();
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+    // If the model called tools, the calls are reported instead of the finish reason the server sent
+    let finish = |reason: StopReason, tool_calls: Vec<ToolCall>| {
+        if tool_calls.is_empty() {
+            reason
+        } else {
+            StopReason::ToolCalls { calls: tool_calls }
+        }
+    };
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                log::error!("Error in OpenAI stream: {}", e);
+                return None;
+            }
+        };
+        for call in chunk.tool_calls {
+            if tool_calls.len() <= call.index {
+                tool_calls.resize_with(call.index + 1, ToolCall::default);
+            }
+            let tool_call = &mut tool_calls[call.index];
+            if let Some(id) = call.id {
+                tool_call.id = id;
+            }
+            if let Some(name) = call.name {
+                tool_call.name += &name;
+            }
+            if let Some(arguments) = call.arguments {
+                tool_call.arguments += &arguments;
+            }
+        }
+        if let Some(text) = chunk.text {
+            match matcher.feed(&text) {
+                StopOnStatus::Continue(text) => {
+                    if !send(text) {
+                        return Some(StopReason::Cancelled);
+                    }
+                }
+                StopOnStatus::Stop(text) => {
+                    send(text);
+                    return matcher.stop_reason();
+                }
+            }
+        }
+        if let Some(finish_reason) = chunk.finish_reason {
+            send(matcher.flush());
+            let reason = match finish_reason {
+                RemoteFinishReason::Length => StopReason::MaxLength,
+                RemoteFinishReason::Stop => StopReason::EndOfSequence,
+            };
+            return Some(finish(reason, tool_calls));
+        }
+    }
+
+    send(matcher.flush());
+    Some(finish(StopReason::EndOfSequence, tool_calls))
+}
+
+#[test]
+fn openai_sampler_settings() {
+    let settings = OpenAISamplerSettings::from(&GenerationParameters::default());
+    assert_eq!(
+        settings,
+        OpenAISamplerSettings {
+            temperature: Some(0.8),
+            top_p: None,
+            frequency_penalty: Some(1.3),
+            presence_penalty: None,
+            seed: None,
+        }
+    );
+
+    let parameters = GenerationParameters::default()
+        .with_sampler_stages([
+            SamplerStage::TopK { k: 10 },
+            SamplerStage::TopP { p: 0.9 },
+            SamplerStage::Greedy,
+        ])
+        .with_seed(42);
+    assert_eq!(
+        OpenAISamplerSettings::from(&parameters),
+        OpenAISamplerSettings {
+            temperature: Some(0.),
+            top_p: Some(0.9),
+            frequency_penalty: None,
+            presence_penalty: None,
+            seed: Some(42),
+        }
+    );
+}
+
+#[test]
+fn server_stop_sequences_skip_empty_sequences() {
+    assert!(server_stop_sequences(&[]).is_none());
+    assert!(server_stop_sequences(&[String::new()]).is_none());
+    let stop_sequences = ["", "a", "b", "c", "d", "e"].map(String::from);
+    match server_stop_sequences(&stop_sequences) {
+        Some(Stop::StringArray(sequences)) => assert_eq!(sequences, ["a", "b", "c", "d"]),
+        other => panic!("unexpected stop sequences {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn remote_stream_trims_stop_sequences() {
+    let chunks = ["The answer", " is 4.\nUs", "er: thanks", "!"].map(|text| {
+        Ok::<_, String>(RemoteChunk {
+            text: Some(text.to_string()),
+            ..Default::default()
+        })
+    });
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let stop_reason = forward_remote_stream(
+        futures_util::stream::iter(chunks),
+        StopOnMatcher::from_sequences(["\n\n", "\nUser:"]),
+        tx,
+    )
+    .await;
+
+    let mut text = String::new();
+    while let Some(chunk) = rx.recv().await {
+        text += &chunk;
+    }
+    assert_eq!(text, "The answer is 4.");
+    assert_eq!(
+        stop_reason,
+        Some(StopReason::StopSequence {
+            index: 1,
+            sequence: "\nUser:".to_string()
+        })
+    );
+}
+
+#[tokio::test]
+async fn remote_stream_collects_tool_calls() {
+    let call = |index, id: Option<&str>, name: Option<&str>, arguments: &str| RemoteToolCallChunk {
+        index,
+        id: id.map(String::from),
+        name: name.map(String::from),
+        arguments: Some(arguments.to_string()),
+    };
+    let chunks = [
+        RemoteChunk {
+            tool_calls: vec![call(0, Some("call_0"), Some("weather"), "{\"city\":")],
+            ..Default::default()
+        },
+        RemoteChunk {
+            tool_calls: vec![
+                call(0, None, None, " \"Paris\"}"),
+                call(1, Some("call_1"), Some("time"), "{}"),
+            ],
+            ..Default::default()
+        },
+        RemoteChunk {
+            finish_reason: Some(RemoteFinishReason::Stop),
+            ..Default::default()
+        },
+    ]
+    .map(Ok::<_, String>);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let stop_reason = forward_remote_stream(
+        futures_util::stream::iter(chunks),
+        StopOnMatcher::default(),
+        tx,
+    )
+    .await;
+
+    assert_eq!(
+        stop_reason,
+        Some(StopReason::ToolCalls {
+            calls: vec![
+                ToolCall {
+                    id: "call_0".to_string(),
+                    name: "weather".to_string(),
+                    arguments: "{\"city\": \"Paris\"}".to_string(),
+                },
+                ToolCall {
+                    id: "call_1".to_string(),
+                    name: "time".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            ]
+        })
+    );
+}
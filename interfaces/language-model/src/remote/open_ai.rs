@@ -1,16 +1,24 @@
 use async_openai::types::CreateEmbeddingRequestArgs;
-use async_openai::{types::CreateCompletionRequestArgs, Client};
+use async_openai::types::{CompletionFinishReason, CreateCompletionRequestArgs};
+use async_openai::Client;
 use futures_util::StreamExt;
 use kalosm_common::*;
 use kalosm_sample::Tokenizer;
-use kalosm_streams::text_stream::ChannelTextStream;
 use std::sync::Arc;
 
-use crate::{Embedder, Embedding, GenerationParameters, ModelBuilder, VectorSpace};
+use super::{
+    server_stop_sequences, spawn_remote_stream, OpenAISamplerSettings, RemoteChunk,
+    RemoteFinishReason,
+};
+use crate::{
+    AnyModelExt, Embedder, Embedding, GenerationParameters, ModelBuilder, TextGenerationStream,
+    VectorSpace,
+};
 
 macro_rules! openai_model {
     ($ty: ident, $tybuilder: ident, $model: literal) => {
         /// A model that uses OpenAI's API.
+        #[derive(Clone)]
         pub struct $ty {
             client: Client<async_openai::config::OpenAIConfig>,
         }
@@ -77,8 +85,7 @@ macro_rules! openai_model {
                 self,
                 _: impl FnMut(ModelLoadingProgress) + Send + Sync + 'static,
             ) -> anyhow::Result<$ty> {
-                let client = Client::new();
-                Ok($ty { client })
+                Ok(self.build())
             }
 
             fn requires_download(&self) -> bool {
@@ -88,11 +95,16 @@ macro_rules! openai_model {
 
         #[async_trait::async_trait]
         impl crate::model::Model for $ty {
-            type TextStream = ChannelTextStream<String>;
+            type TextStream = TextGenerationStream;
             type SyncModel = crate::SyncModelNotSupported;
 
-            fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync> {
-                panic!("OpenAI does not expose tokenization")
+            /// OpenAI compatible APIs do not expose their tokenizer, so this always returns `None`.
+            fn tokenizer(&self) -> Option<Arc<dyn Tokenizer + Send + Sync>> {
+                None
+            }
+
+            fn structured_retry_handle(&self) -> Option<crate::DynModel> {
+                Some(self.clone().into_any_model())
             }
 
             async fn stream_text_inner(
@@ -100,47 +112,61 @@ macro_rules! openai_model {
                 prompt: &str,
                 generation_parameters: GenerationParameters,
             ) -> anyhow::Result<Self::TextStream> {
-                let request = CreateCompletionRequestArgs::default()
+                // The completions endpoint only generates 16 tokens by default, so limits that don't fit in the request are rejected instead of being left to the server
+                let max_tokens = u16::try_from(generation_parameters.max_length).map_err(|_| {
+                    anyhow::anyhow!(
+                        "The maximum length {} is larger than the completions API supports ({})",
+                        generation_parameters.max_length,
+                        u16::MAX
+                    )
+                })?;
+                let mut request = CreateCompletionRequestArgs::default();
+                request
                     .model($model)
                     .n(1)
                     .prompt(prompt)
                     .stream(true)
-                    .frequency_penalty(generation_parameters.repetition_penalty)
-                    .temperature(generation_parameters.temperature)
-                    .stop(
-                        generation_parameters
-                            .stop_on
-                            .iter()
-                            .cloned()
-                            .collect::<Vec<String>>(),
-                    )
-                    .max_tokens(generation_parameters.max_length as u16)
-                    .build()?;
-
-                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-
-                let mut stream = self.client.completions().create_stream(request).await?;
-
-                tokio::spawn(async move {
-                    while let Some(response) = stream.next().await {
-                        match response {
-                            Ok(response) => {
-                                let text = response.choices[0].text.clone();
-                                if tx.send(text).is_err() {
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                log::error!("Error in OpenAI stream: {}", e);
-                                break;
-                            }
-                        }
-                    }
-
-                    Ok::<(), anyhow::Error>(())
+                    .max_tokens(max_tokens);
+                let sampler_settings = OpenAISamplerSettings::from(&generation_parameters);
+                if let Some(temperature) = sampler_settings.temperature {
+                    request.temperature(temperature);
+                }
+                if let Some(top_p) = sampler_settings.top_p {
+                    request.top_p(top_p);
+                }
+                if let Some(frequency_penalty) = sampler_settings.frequency_penalty {
+                    request.frequency_penalty(frequency_penalty);
+                }
+                if let Some(presence_penalty) = sampler_settings.presence_penalty {
+                    request.presence_penalty(presence_penalty);
+                }
+                if let Some(seed) = sampler_settings.seed {
+                    request.seed(seed);
+                }
+                if let Some(stop) = server_stop_sequences(generation_parameters.stop_sequences()) {
+                    request.stop(stop);
+                }
+                let request = request.build()?;
+
+                let stream = self.client.completions().create_stream(request).await?;
+                let stream = stream.map(|response| {
+                    response.map(|response| match response.choices.into_iter().next() {
+                        Some(choice) => RemoteChunk {
+                            text: Some(choice.text),
+                            finish_reason: choice.finish_reason.map(|reason| match reason {
+                                CompletionFinishReason::Length => RemoteFinishReason::Length,
+                                _ => RemoteFinishReason::Stop,
+                            }),
+                            ..Default::default()
+                        },
+                        None => RemoteChunk::default(),
+                    })
                 });
 
-                Ok(rx.into())
+                Ok(spawn_remote_stream(
+                    stream,
+                    generation_parameters.stop_sequences(),
+                ))
             }
         }
     };
@@ -229,7 +255,11 @@ impl ModelBuilder for AdaEmbedderBuilder {
 /// The embedding space for the Ada embedding model.
 pub struct AdaEmbedding;
 
-impl VectorSpace for AdaEmbedding {}
+impl VectorSpace for AdaEmbedding {
+    fn id() -> &'static str {
+        "text-embedding-ada-002"
+    }
+}
 
 #[async_trait::async_trait]
 impl Embedder for AdaEmbedder {
@@ -249,3 +279,133 @@ impl Embedder for AdaEmbedder {
         Ok(embedding)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::mock_server::mock_server;
+    use crate::{ModelExt, StructuredGenerationError, StructuredRetry};
+    use kalosm_sample::{IntegerParser, LiteralParser, ParserExt};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn structured_generation_against_mock_server() {
+        let (base_url, requests) =
+            mock_server(vec![vec!["forty", " two"], vec!["4", "2! Anything else?"]]).await;
+        let llm = Gpt3_5::builder()
+            .with_api_key("test")
+            .with_base_url(&base_url)
+            .build();
+        let parser = LiteralParser::from("The answer is ")
+            .then(IntegerParser::new(0..=100))
+            .then(LiteralParser::from("!"));
+
+        let text = Arc::new(Mutex::new(String::new()));
+        let result = llm
+            .generate_structured_with_retries(
+                "What is 40 + 2? ",
+                parser,
+                GenerationParameters::default(),
+                StructuredRetry::new(),
+                {
+                    let text = text.clone();
+                    move |new_text| {
+                        text.lock().unwrap().push_str(&new_text);
+                        Ok(())
+                    }
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result, (((), 42), ()));
+        assert_eq!(*text.lock().unwrap(), "The answer is 42!");
+        // The required text is added without a request, and the second request continues after it
+        let prompts: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request.body["prompt"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            prompts,
+            [
+                "What is 40 + 2? The answer is ",
+                "What is 40 + 2? The answer is "
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_structured_text_falls_back_to_retries() {
+        let (base_url, requests) = mock_server(vec![vec!["forty"], vec!["4", "2!"]]).await;
+        let llm = Gpt3_5::builder()
+            .with_api_key("test")
+            .with_base_url(&base_url)
+            .build();
+        let parser = LiteralParser::from("The answer is ")
+            .then(IntegerParser::new(0..=100))
+            .then(LiteralParser::from("!"));
+
+        let (mut stream, result) = llm
+            .stream_structured_text("What is 40 + 2? ", parser)
+            .await
+            .unwrap()
+            .split();
+        let mut text = String::new();
+        while let Some(new_text) = stream.next().await {
+            text.push_str(&new_text);
+        }
+
+        assert_eq!(result.await.unwrap().unwrap(), (((), 42), ()));
+        assert_eq!(text, "The answer is 42!");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn structured_generation_against_mock_server_gives_up() {
+        let (base_url, _) = mock_server(vec![vec!["1", "x"], vec!["y"]]).await;
+        let llm = Gpt3_5::builder()
+            .with_api_key("test")
+            .with_base_url(&base_url)
+            .build();
+        let parser = LiteralParser::from("The answer is ")
+            .then(IntegerParser::new(0..=100))
+            .then(LiteralParser::from("!"));
+
+        let error = llm
+            .generate_structured_with_retries(
+                "What is 40 + 2? ",
+                parser,
+                GenerationParameters::default(),
+                StructuredRetry::new().with_max_attempts(2),
+                |_| Ok(()),
+            )
+            .await
+            .unwrap_err();
+        let error = error.downcast::<StructuredGenerationError>().unwrap();
+        assert_eq!(error.partial_output(), "The answer is 1");
+        assert_eq!(error.rejected_token(), Some("y"));
+        assert!(error.expected().contains(&'!'));
+    }
+
+    #[tokio::test]
+    async fn max_length_larger_than_the_api_supports_is_rejected() {
+        use crate::Model;
+
+        let (base_url, requests) = mock_server(Vec::new()).await;
+        let llm = Gpt3_5::builder()
+            .with_api_key("test")
+            .with_base_url(&base_url)
+            .build();
+
+        let result = llm
+            .stream_text_inner(
+                "Hello",
+                GenerationParameters::default().with_max_length(u32::MAX),
+            )
+            .await;
+        assert!(result.is_err());
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,416 @@
+use async_openai::types::{
+    ChatCompletionMessageToolCallChunk, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionTool, ChatCompletionToolType,
+    CreateChatCompletionRequestArgs, CreateChatCompletionStreamResponse, FinishReason,
+    FunctionObject,
+};
+use async_openai::Client;
+use futures_util::StreamExt;
+use kalosm_common::*;
+use kalosm_sample::Tokenizer;
+use std::sync::Arc;
+
+use super::{
+    server_stop_sequences, spawn_remote_stream, OpenAISamplerSettings, RemoteChunk,
+    RemoteFinishReason, RemoteToolCallChunk,
+};
+use crate::{
+    AnyModelExt, ChatHistoryItem, ChatMarkers, GenerationParameters, MessageType, ModelBuilder,
+    TextGenerationStream,
+};
+
+/// The markers [`OpenAICompatibleChatModel`] uses to format chat prompts. Prompts passed to [`crate::Model::stream_text`] that use these markers are split back into chat messages before they are sent to the server.
+const CHAT_MARKERS: ChatMarkers = ChatMarkers {
+    system_prompt_marker: "<|im_start|>system\n",
+    end_system_prompt_marker: "<|im_end|>\n",
+    user_marker: "<|im_start|>user\n",
+    end_user_marker: "<|im_end|>\n",
+    assistant_marker: "<|im_start|>assistant\n",
+    end_assistant_marker: "<|im_end|>\n",
+};
+
+/// A model that uses the chat completions endpoint of OpenAI's API or any other server with an OpenAI compatible API (like vLLM or the llama.cpp server).
+///
+/// The text content of responses is streamed. If the model was given tools with [`OpenAICompatibleChatModelBuilder::with_tool`] and decides to call them, the stream ends with [`crate::StopReason::ToolCalls`] with the calls the model made. Chat histories can't contain tool calls or tool results, so the results of the calls need to be sent back to the model as a normal message.
+#[derive(Clone)]
+pub struct OpenAICompatibleChatModel {
+    client: Client<async_openai::config::OpenAIConfig>,
+    model: String,
+    tools: Vec<ChatCompletionTool>,
+}
+
+/// A builder for [`OpenAICompatibleChatModel`].
+#[derive(Debug)]
+pub struct OpenAICompatibleChatModelBuilder {
+    config: async_openai::config::OpenAIConfig,
+    model: String,
+    tools: Vec<ChatCompletionTool>,
+}
+
+impl Default for OpenAICompatibleChatModelBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenAICompatibleChatModelBuilder {
+    /// Creates a new builder
+    pub fn new() -> Self {
+        Self {
+            config: Default::default(),
+            model: "gpt-3.5-turbo".to_string(),
+            tools: Vec::new(),
+        }
+    }
+
+    /// Sets the API key for the builder.
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.config = self.config.with_api_key(api_key);
+        self
+    }
+
+    /// Set the base URL of the API.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.config = self.config.with_api_base(base_url);
+        self
+    }
+
+    /// Set the organization ID for the builder.
+    pub fn with_organization_id(mut self, organization_id: &str) -> Self {
+        self.config = self.config.with_org_id(organization_id);
+        self
+    }
+
+    /// Set the name of the model to use. (default: gpt-3.5-turbo)
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Add a tool the model can call. The parameters are a JSON schema that describes the arguments of the tool.
+    pub fn with_tool(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        self.tools.push(ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: name.into(),
+                description: Some(description.into()),
+                parameters: Some(parameters),
+            },
+        });
+        self
+    }
+
+    /// Build the model.
+    pub fn build(self) -> OpenAICompatibleChatModel {
+        OpenAICompatibleChatModel {
+            client: Client::with_config(self.config),
+            model: self.model,
+            tools: self.tools,
+        }
+    }
+}
+
+impl OpenAICompatibleChatModel {
+    /// Creates a new builder
+    pub fn builder() -> OpenAICompatibleChatModelBuilder {
+        OpenAICompatibleChatModelBuilder::new()
+    }
+}
+
+impl Default for OpenAICompatibleChatModel {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelBuilder for OpenAICompatibleChatModelBuilder {
+    type Model = OpenAICompatibleChatModel;
+
+    async fn start_with_loading_handler(
+        self,
+        _: impl FnMut(ModelLoadingProgress) + Send + Sync + 'static,
+    ) -> anyhow::Result<OpenAICompatibleChatModel> {
+        Ok(self.build())
+    }
+
+    fn requires_download(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::model::Model for OpenAICompatibleChatModel {
+    type TextStream = TextGenerationStream;
+    type SyncModel = crate::SyncModelNotSupported;
+
+    /// OpenAI compatible APIs do not expose their tokenizer, so this always returns `None`.
+    fn tokenizer(&self) -> Option<Arc<dyn Tokenizer + Send + Sync>> {
+        None
+    }
+
+    fn structured_retry_handle(&self) -> Option<crate::DynModel> {
+        Some(self.clone().into_any_model())
+    }
+
+    async fn stream_text_inner(
+        &self,
+        prompt: &str,
+        generation_parameters: GenerationParameters,
+    ) -> anyhow::Result<Self::TextStream> {
+        let history = split_prompt_into_messages(prompt);
+        self.stream_chat_inner(&history, generation_parameters)
+            .await
+    }
+
+    async fn stream_chat_inner(
+        &self,
+        history: &[ChatHistoryItem],
+        generation_parameters: GenerationParameters,
+    ) -> anyhow::Result<Self::TextStream> {
+        let messages = history
+            .iter()
+            .map(chat_message)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
+            .model(&self.model)
+            .n(1)
+            .messages(messages)
+            .stream(true);
+        if !self.tools.is_empty() {
+            request.tools(self.tools.clone());
+        }
+        let sampler_settings = OpenAISamplerSettings::from(&generation_parameters);
+        if let Some(temperature) = sampler_settings.temperature {
+            request.temperature(temperature);
+        }
+        if let Some(top_p) = sampler_settings.top_p {
+            request.top_p(top_p);
+        }
+        if let Some(frequency_penalty) = sampler_settings.frequency_penalty {
+            request.frequency_penalty(frequency_penalty);
+        }
+        if let Some(presence_penalty) = sampler_settings.presence_penalty {
+            request.presence_penalty(presence_penalty);
+        }
+        if let Some(seed) = sampler_settings.seed {
+            request.seed(seed);
+        }
+        if let Some(stop) = server_stop_sequences(generation_parameters.stop_sequences()) {
+            request.stop(stop);
+        }
+        // Very large limits are left to the server default instead of being rejected
+        if let Ok(max_tokens) = u16::try_from(generation_parameters.max_length) {
+            request.max_tokens(max_tokens);
+        }
+        let request = request.build()?;
+
+        let stream = self.client.chat().create_stream(request).await?;
+        let stream = stream.map(|response| response.map(chat_chunk));
+
+        Ok(spawn_remote_stream(
+            stream,
+            generation_parameters.stop_sequences(),
+        ))
+    }
+
+    fn chat_markers(&self) -> Option<ChatMarkers> {
+        Some(CHAT_MARKERS)
+    }
+}
+
+/// Convert a chunk of a streamed chat completion into the text and tool calls it contains.
+fn chat_chunk(response: CreateChatCompletionStreamResponse) -> RemoteChunk {
+    let Some(choice) = response.choices.into_iter().next() else {
+        return RemoteChunk::default();
+    };
+    RemoteChunk {
+        text: choice.delta.content,
+        tool_calls: choice
+            .delta
+            .tool_calls
+            .unwrap_or_default()
+            .into_iter()
+            .map(tool_call_chunk)
+            .collect(),
+        finish_reason: choice.finish_reason.map(|reason| match reason {
+            FinishReason::Length => RemoteFinishReason::Length,
+            _ => RemoteFinishReason::Stop,
+        }),
+    }
+}
+
+fn tool_call_chunk(chunk: ChatCompletionMessageToolCallChunk) -> RemoteToolCallChunk {
+    let (name, arguments) = chunk
+        .function
+        .map(|function| (function.name, function.arguments))
+        .unwrap_or_default();
+    RemoteToolCallChunk {
+        index: usize::try_from(chunk.index).unwrap_or_default(),
+        id: chunk.id,
+        name,
+        arguments,
+    }
+}
+
+fn chat_message(item: &ChatHistoryItem) -> anyhow::Result<ChatCompletionRequestMessage> {
+    let contents = item.contents();
+    Ok(match item.ty() {
+        MessageType::SystemPrompt => ChatCompletionRequestSystemMessageArgs::default()
+            .content(contents)
+            .build()?
+            .into(),
+        MessageType::UserMessage => ChatCompletionRequestUserMessageArgs::default()
+            .content(contents)
+            .build()?
+            .into(),
+        MessageType::ModelAnswer => ChatCompletionRequestAssistantMessageArgs::default()
+            .content(contents)
+            .build()?
+            .into(),
+    })
+}
+
+/// Split a prompt formatted with [`CHAT_MARKERS`] back into chat messages. Text outside of any marker is sent as a user message.
+fn split_prompt_into_messages(prompt: &str) -> Vec<ChatHistoryItem> {
+    let mut history = Vec::new();
+    let mut add_message = |ty, contents: &str| {
+        if !contents.is_empty() {
+            history.push(ChatHistoryItem::new(ty, contents));
+        }
+    };
+
+    let mut segments = prompt.split("<|im_start|>");
+    if let Some(before_markers) = segments.next() {
+        add_message(MessageType::UserMessage, before_markers);
+    }
+    for segment in segments {
+        let (role, contents) = segment.split_once('\n').unwrap_or((segment, ""));
+        let ty = match role.trim() {
+            "system" => MessageType::SystemPrompt,
+            "assistant" => MessageType::ModelAnswer,
+            _ => MessageType::UserMessage,
+        };
+        let contents = contents.split("<|im_end|>").next().unwrap_or_default();
+        add_message(ty, contents);
+    }
+
+    history
+}
+
+#[test]
+fn split_chat_prompt() {
+    let history = [
+        ChatHistoryItem::new(MessageType::SystemPrompt, "You are a helpful assistant."),
+        ChatHistoryItem::new(MessageType::UserMessage, "Hello!"),
+        ChatHistoryItem::new(MessageType::ModelAnswer, "Hi, how can I help?"),
+        ChatHistoryItem::new(MessageType::UserMessage, "What is 2 + 2?"),
+    ];
+    let prompt = CHAT_MARKERS.format_history(&history);
+    assert_eq!(split_prompt_into_messages(&prompt), history);
+
+    assert_eq!(
+        split_prompt_into_messages("The capital of France is"),
+        [ChatHistoryItem::new(
+            MessageType::UserMessage,
+            "The capital of France is"
+        )]
+    );
+}
+
+#[tokio::test]
+async fn chat_completions_mock_server() {
+    use crate::Model;
+
+    let (base_url, requests) =
+        super::mock_server::mock_server(vec![vec!["Hello", " world", "!"]]).await;
+    let model = OpenAICompatibleChatModel::builder()
+        .with_base_url(&base_url)
+        .with_api_key("test-key")
+        .with_model("mock")
+        .with_tool(
+            "weather",
+            "Get the weather in a city",
+            serde_json::json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } }
+            }),
+        )
+        .build();
+    let history = [
+        ChatHistoryItem::new(MessageType::SystemPrompt, "Be concise."),
+        ChatHistoryItem::new(MessageType::UserMessage, "Say hello"),
+    ];
+    let parameters = GenerationParameters::default()
+        .with_stop_sequences(["", "\nUser:"])
+        .with_seed(7);
+    let mut stream = model.stream_chat_inner(&history, parameters).await.unwrap();
+    let mut text = String::new();
+    while let Some(token) = stream.next().await {
+        text += &token;
+    }
+    assert_eq!(text, "Hello world!");
+    // Remote models can't count tokens without a tokenizer
+    assert!(model.count_tokens("Say hello").is_err());
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/v1/chat/completions");
+    let body = &requests[0].body;
+    assert_eq!(body["model"], "mock");
+    assert_eq!(body["stream"], true);
+    assert_eq!(body["stop"], serde_json::json!(["\nUser:"]));
+    assert_eq!(body["seed"], 7);
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["role"], "system");
+    assert_eq!(messages[0]["content"], "Be concise.");
+    assert_eq!(messages[1]["role"], "user");
+    assert_eq!(messages[1]["content"], "Say hello");
+    let tools = body["tools"].as_array().unwrap();
+    assert_eq!(tools.len(), 1);
+    assert_eq!(tools[0]["type"], "function");
+    assert_eq!(tools[0]["function"]["name"], "weather");
+}
+
+#[test]
+fn chat_chunk_with_tool_calls() {
+    let response = serde_json::from_value(serde_json::json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "mock",
+        "choices": [{
+            "index": 0,
+            "delta": {
+                "tool_calls": [{
+                    "index": 1,
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "weather", "arguments": "{\"ci" }
+                }]
+            },
+            "finish_reason": "tool_calls"
+        }]
+    }))
+    .unwrap();
+    let chunk = chat_chunk(response);
+    assert_eq!(chunk.text, None);
+    assert_eq!(chunk.tool_calls.len(), 1);
+    let call = &chunk.tool_calls[0];
+    assert_eq!(call.index, 1);
+    assert_eq!(call.id.as_deref(), Some("call_1"));
+    assert_eq!(call.name.as_deref(), Some("weather"));
+    assert_eq!(call.arguments.as_deref(), Some("{\"ci"));
+    assert!(matches!(
+        chunk.finish_reason,
+        Some(RemoteFinishReason::Stop)
+    ));
+}
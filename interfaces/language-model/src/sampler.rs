@@ -0,0 +1,166 @@
+use llm_samplers::prelude::*;
+
+/// A single stage of a sampler chain. Stages run in order: stages that adjust or remove tokens come first, and the chain ends with a stage that picks a token.
+///
+/// If none of the stages pick a token, a token is picked at random from the remaining distribution.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum SamplerStage {
+    /// Penalize tokens that appeared in the last `last_n` tokens.
+    Repetition {
+        /// The penalty to apply to repeated tokens.
+        penalty: f32,
+        /// The number of previous tokens to consider.
+        last_n: usize,
+    },
+    /// Penalize tokens based on how often they appeared in the last `last_n` tokens.
+    FrequencyPresence {
+        /// The penalty to apply for every time a token appeared.
+        frequency_penalty: f32,
+        /// The penalty to apply if a token appeared at all.
+        presence_penalty: f32,
+        /// The number of previous tokens to consider.
+        last_n: usize,
+    },
+    /// Penalize tokens that would continue a sequence that was already generated.
+    SequenceRepetition,
+    /// Scale the logits by the temperature. Higher temperatures make unlikely tokens more likely.
+    Temperature {
+        /// The temperature.
+        temperature: f32,
+    },
+    /// Keep only the `k` most likely tokens.
+    TopK {
+        /// The number of tokens to keep.
+        k: usize,
+    },
+    /// Keep the most likely tokens whose probabilities add up to at least `p`.
+    TopP {
+        /// The cumulative probability to keep.
+        p: f32,
+    },
+    /// Remove tokens with a probability less than `p` times the probability of the most likely token.
+    MinP {
+        /// The minimum probability relative to the most likely token.
+        p: f32,
+    },
+    /// Keep the tokens closest to the expected information content of the distribution.
+    Typical {
+        /// The cumulative probability to keep.
+        p: f32,
+    },
+    /// Pick a token with mirostat 2, which targets a constant level of surprise.
+    Mirostat2 {
+        /// The target surprise.
+        tau: f32,
+        /// The learning rate.
+        eta: f32,
+        /// The initial maximum surprise.
+        mu: f32,
+    },
+    /// Pick the most likely token.
+    Greedy,
+}
+
+impl SamplerStage {
+    /// Check if this stage picks a token (instead of only adjusting the distribution).
+    pub fn picks_token(&self) -> bool {
+        matches!(self, Self::Mirostat2 { .. } | Self::Greedy)
+    }
+
+    /// Check if this stage only changes the probabilities of tokens without removing or picking any.
+    pub fn is_bias_only(&self) -> bool {
+        matches!(
+            self,
+            Self::Repetition { .. }
+                | Self::FrequencyPresence { .. }
+                | Self::SequenceRepetition
+                | Self::Temperature { .. }
+        )
+    }
+
+    fn push_to(&self, chain: &mut SamplerChain) {
+        match *self {
+            Self::Repetition { penalty, last_n } => {
+                chain.push_sampler(SampleRepetition::default().penalty(penalty).last_n(last_n));
+            }
+            Self::FrequencyPresence {
+                frequency_penalty,
+                presence_penalty,
+                last_n,
+            } => {
+                chain.push_sampler(
+                    SampleFreqPresence::default()
+                        .frequency_penalty(frequency_penalty)
+                        .presence_penalty(presence_penalty)
+                        .last_n(last_n),
+                );
+            }
+            Self::SequenceRepetition => {
+                chain.push_sampler(SampleSeqRepetition::default());
+            }
+            Self::Temperature { temperature } => {
+                chain.push_sampler(SampleTemperature::default().temperature(temperature));
+            }
+            Self::TopK { k } => {
+                chain.push_sampler(SampleTopK::default().k(k));
+            }
+            Self::TopP { p } => {
+                chain.push_sampler(SampleTopP::default().p(p));
+            }
+            Self::MinP { p } => {
+                chain.push_sampler(SampleMinP::default().p(p));
+            }
+            Self::Typical { p } => {
+                chain.push_sampler(SampleLocallyTypical::default().p(p));
+            }
+            Self::Mirostat2 { tau, eta, mu } => {
+                chain.push_sampler(SampleMirostat2::default().tau(tau).eta(eta).mu(mu));
+            }
+            Self::Greedy => {
+                chain.push_sampler(SampleGreedy::default());
+            }
+        }
+    }
+}
+
+/// Create a sampler chain from a list of stages. If `pick_token` is true and none of the stages pick a token, a token is picked from the remaining distribution at random.
+pub(crate) fn sampler_chain<'a>(
+    stages: impl IntoIterator<Item = &'a SamplerStage>,
+    pick_token: bool,
+) -> SamplerChain {
+    let mut chain = SamplerChain::new();
+    let mut picks_token = false;
+    for stage in stages {
+        stage.push_to(&mut chain);
+        picks_token |= stage.picks_token();
+        // Nothing after a stage that picks a token can change the result
+        if picks_token {
+            break;
+        }
+    }
+    if pick_token && !picks_token {
+        chain.push_sampler(SampleRandDistrib::default());
+    }
+    chain
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_sampler_stages() {
+    let parameters = crate::GenerationParameters::default()
+        .with_sampler_stages([
+            SamplerStage::TopK { k: 40 },
+            SamplerStage::MinP { p: 0.05 },
+            SamplerStage::Greedy,
+        ])
+        .with_seed(42);
+    let json = serde_json::to_string(&parameters).unwrap();
+    assert!(json.contains(r#"{"type":"top_k","k":40}"#));
+    let deserialized: crate::GenerationParameters = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, parameters);
+}
@@ -0,0 +1,718 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::model::HeldBackTokens;
+use crate::{ChatMarkers, GeneratedToken, GenerationParameters, LogProbabilities};
+use crate::{Model, ModelExt};
+use crate::{ModelFeedback, SamplerStage, Session, StopOnMatcher, StopOnStatus, StopReason};
+use crate::{SyncModel, TextGenerationStream, TokenOutputStream};
+use kalosm_sample::Tokenizer;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// A model that generates text with speculative decoding.
+///
+/// A small draft model proposes several tokens at a time, and the larger target model checks all of them with a single pass. Every proposed token the target model agrees with saves a full pass of the target model. Rejection sampling keeps the generated text distributed exactly as if it were sampled from the target model alone.
+///
+/// Both models must use the same tokenizer and support [`Session::rewind`]. The draft and target models each run on their own thread, so a request blocks both models until it finishes.
+///
+/// Sampler stages that depend on the token that was sampled before ([`SamplerStage::SequenceRepetition`] and [`SamplerStage::Mirostat2`]) don't describe a fixed distribution, so they are not supported with speculative decoding. The default sampler chain uses both of them, so set a chain without them with [`GenerationParameters::with_sampler_stages`]. Generation stops with [`StopReason::Error`] if the chain contains an unsupported stage.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::*;
+/// use kalosm_llama::prelude::*;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let draft = Llama::builder()
+///         .with_source(LlamaSource::tiny_llama_1_1b())
+///         .build()
+///         .await
+///         .unwrap();
+///     let target = Llama::new().await.unwrap();
+///     let model = SpeculativeModel::new(draft, target).unwrap().with_draft_tokens(5);
+///
+///     let mut stream = model
+///         .stream_text("The capital of France is")
+///         .with_generation_parameters(GenerationParameters::default().with_sampler_stages([
+///             SamplerStage::TopK { k: 40 },
+///             SamplerStage::Temperature { temperature: 0.7 },
+///         ]))
+///         .await
+///         .unwrap();
+///     stream.to_std_out().await.unwrap();
+/// }
+/// ```
+pub struct SpeculativeModel<D, T> {
+    draft: D,
+    target: T,
+    draft_tokens: usize,
+}
+
+impl<D: Model, T: Model> SpeculativeModel<D, T> {
+    /// Create a new speculative model from a draft model and a target model that share a tokenizer.
+    pub fn new(draft: D, target: T) -> anyhow::Result<Self> {
+        let sample = "The quick brown fox jumps over the lazy dog. 0123456789 {\"a\": [1, 2]}";
+        let (Some(draft_tokenizer), Some(target_tokenizer)) =
+            (draft.tokenizer(), target.tokenizer())
+        else {
+            anyhow::bail!("Speculative decoding requires models that expose their tokenizer");
+        };
+        if draft_tokenizer.encode(sample, false)? != target_tokenizer.encode(sample, false)? {
+            anyhow::bail!("The draft and target models must use the same tokenizer");
+        }
+        Ok(Self {
+            draft,
+            target,
+            draft_tokens: 4,
+        })
+    }
+
+    /// Set the number of tokens the draft model proposes before the target model checks them. (default: 4)
+    pub fn with_draft_tokens(mut self, draft_tokens: usize) -> Self {
+        self.draft_tokens = draft_tokens.max(1);
+        self
+    }
+
+    /// Get the draft model.
+    pub fn draft(&self) -> &D {
+        &self.draft
+    }
+
+    /// Get the target model.
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Model, T: Model> Model for SpeculativeModel<D, T>
+where
+    D::SyncModel: 'static,
+    <D::SyncModel as SyncModel>::Session: 'static,
+{
+    type TextStream = TextGenerationStream;
+    type SyncModel = T::SyncModel;
+
+    fn tokenizer(&self) -> Option<Arc<dyn Tokenizer + Send + Sync>> {
+        self.target.tokenizer()
+    }
+
+    fn run_sync_raw(
+        &self,
+        f: Box<
+            dyn for<'a> FnOnce(
+                    &'a mut Self::SyncModel,
+                )
+                    -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + 'a>>
+                + Send,
+        >,
+    ) -> anyhow::Result<()> {
+        self.target.run_sync_raw(f)
+    }
+
+    async fn stream_text_inner(
+        &self,
+        prompt: &str,
+        parameters: GenerationParameters,
+    ) -> anyhow::Result<Self::TextStream> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (stop_reason_sender, stop_reason_receiver) = tokio::sync::oneshot::channel();
+        let draft = DraftWorker::start(&self.draft)?;
+        let prompt = prompt.to_string();
+        let draft_tokens = self.draft_tokens;
+
+        self.target.run_sync(move |target: &mut T::SyncModel| {
+            Box::pin(async move {
+                let result = (|| -> anyhow::Result<StopReason> {
+                    let mut draft_session = draft.new_session()?;
+                    let mut target_session = target.new_session()?;
+                    speculative_stream_tokens(
+                        &draft,
+                        &mut draft_session,
+                        &*target,
+                        &mut target_session,
+                        &prompt,
+                        &parameters,
+                        draft_tokens,
+                        |token| {
+                            if token.text().is_empty()
+                                || sender.send(token.text().to_string()).is_ok()
+                            {
+                                Ok(ModelFeedback::Continue)
+                            } else {
+                                Ok(ModelFeedback::Stop)
+                            }
+                        },
+                    )
+                })();
+                match result {
+                    Ok(stop_reason) => {
+                        _ = stop_reason_sender.send(stop_reason);
+                    }
+                    Err(err) => {
+                        tracing::error!("Error in speculative decoding: {}", err);
+                        _ = stop_reason_sender.send(StopReason::Error {
+                            message: err.to_string(),
+                        });
+                    }
+                }
+            })
+        })?;
+
+        Ok(TextGenerationStream::new(receiver, stop_reason_receiver))
+    }
+
+    fn chat_markers(&self) -> Option<ChatMarkers> {
+        self.target.chat_markers()
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        self.target.context_length()
+    }
+
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        self.target.count_tokens(text)
+    }
+}
+
+type DraftTask<M> = Box<dyn FnOnce(&mut M, &mut <M as SyncModel>::Session) + Send>;
+
+/// A handle to a draft model running on its own thread.
+///
+/// The draft model keeps a single session on its thread for the lifetime of the worker. The worker stops once every handle to it is dropped.
+struct DraftWorker<M: SyncModel> {
+    tasks: std::sync::mpsc::Sender<DraftTask<M>>,
+    tokenizer: Arc<dyn Tokenizer + Send + Sync>,
+}
+
+impl<M: SyncModel + 'static> DraftWorker<M>
+where
+    M::Session: 'static,
+{
+    fn start<D: Model<SyncModel = M>>(model: &D) -> anyhow::Result<Self> {
+        let tokenizer = model
+            .tokenizer()
+            .ok_or_else(|| anyhow::anyhow!("The draft model does not expose a tokenizer"))?;
+        let (tasks, receiver) = std::sync::mpsc::channel::<DraftTask<M>>();
+        model.run_sync(move |draft: &mut M| {
+            Box::pin(async move {
+                let mut session = match draft.new_session() {
+                    Ok(session) => session,
+                    Err(err) => {
+                        tracing::error!("Failed to create a draft session: {}", err);
+                        return;
+                    }
+                };
+                // Serve requests until the generation finishes and the handles are dropped
+                while let Ok(task) = receiver.recv() {
+                    task(draft, &mut session);
+                }
+            })
+        })?;
+        Ok(Self { tasks, tokenizer })
+    }
+}
+
+/// Run a task on the draft model's thread and wait for the result.
+fn run_draft_task<M: SyncModel, R: Send + 'static>(
+    tasks: &std::sync::mpsc::Sender<DraftTask<M>>,
+    task: impl FnOnce(&mut M, &mut M::Session) -> anyhow::Result<R> + Send + 'static,
+) -> anyhow::Result<R> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    tasks
+        .send(Box::new(move |model: &mut M, session: &mut M::Session| {
+            _ = sender.send(task(model, session));
+        }))
+        .map_err(|_| anyhow::anyhow!("The draft model stopped"))?;
+    receiver
+        .recv()
+        .map_err(|_| anyhow::anyhow!("The draft model stopped"))?
+}
+
+/// The session of a [`DraftWorker`]. The state of the session lives on the draft model's thread.
+struct DraftWorkerSession<M: SyncModel> {
+    tasks: std::sync::mpsc::Sender<DraftTask<M>>,
+}
+
+impl<M: SyncModel + 'static> Session for DraftWorkerSession<M>
+where
+    M::Session: 'static,
+{
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()> {
+        run_draft_task(&self.tasks, move |_, session| session.rewind(count))
+    }
+}
+
+impl<M: SyncModel + 'static> SyncModel for DraftWorker<M>
+where
+    M::Session: 'static,
+{
+    type Session = DraftWorkerSession<M>;
+
+    fn new_session(&self) -> anyhow::Result<Self::Session> {
+        Ok(DraftWorkerSession {
+            tasks: self.tasks.clone(),
+        })
+    }
+
+    fn feed_text(&self, session: &mut Self::Session, prompt: &str) -> anyhow::Result<Vec<f32>> {
+        let tokens = self.tokenizer.encode(prompt, false)?;
+        self.feed_tokens(session, &tokens)
+    }
+
+    fn feed_tokens(&self, session: &mut Self::Session, tokens: &[u32]) -> anyhow::Result<Vec<f32>> {
+        let tokens = tokens.to_vec();
+        run_draft_task(&session.tasks, move |model, session| {
+            model.feed_tokens(session, &tokens)
+        })
+    }
+
+    fn stop_token(&self) -> anyhow::Result<u32> {
+        run_draft_task(&self.tasks, |model, _| model.stop_token())
+    }
+
+    fn tokenizer(&self) -> Arc<dyn Tokenizer + Send + Sync> {
+        self.tokenizer.clone()
+    }
+}
+
+/// Stream tokens from the target model with speculative decoding. See [`crate::SyncModelExt::stream_tokens_speculative`].
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn speculative_stream_tokens<D: ?Sized + SyncModel, T: ?Sized + SyncModel>(
+    draft: &D,
+    draft_session: &mut D::Session,
+    target: &T,
+    target_session: &mut T::Session,
+    prompt: &str,
+    parameters: &GenerationParameters,
+    draft_tokens: usize,
+    mut on_token: impl FnMut(GeneratedToken) -> anyhow::Result<ModelFeedback>,
+) -> anyhow::Result<StopReason> {
+    let tokenizer = target.tokenizer();
+    let stop_token = target.stop_token()?;
+    let stop_token_ids = parameters.stop_token_ids();
+    let is_stop_token = |token: u32| token == stop_token || stop_token_ids.contains(&token);
+    let stages = parameters.sampler_stages();
+    let max_tokens = parameters.max_length() as usize;
+    let draft_tokens = draft_tokens.max(1);
+    let mut rng = match parameters.seed() {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let prompt_tokens = tokenizer.encode(prompt, true)?;
+    let Some((&last_prompt_token, prompt_prefix)) = prompt_tokens.split_last() else {
+        anyhow::bail!("Cannot generate text from an empty prompt");
+    };
+    // The last token of the prompt is fed in with the first tokens the draft model proposes
+    if !prompt_prefix.is_empty() {
+        draft.feed_tokens(draft_session, prompt_prefix)?;
+        target.feed_tokens(target_session, prompt_prefix)?;
+    }
+    let mut draft_pending = vec![last_prompt_token];
+    let mut target_pending = vec![last_prompt_token];
+
+    let mut text_stream = TokenOutputStream::new(tokenizer.clone());
+    for token in prompt_tokens.iter().copied() {
+        text_stream.next_token(token)?;
+    }
+    // Every token that was fed into the models so far. This is used for repetition penalties
+    let mut context = prompt_tokens;
+    let mut stop_on_matcher = StopOnMatcher::from_sequences(parameters.stop_sequences());
+    let mut held_back = HeldBackTokens::default();
+    let mut tokens_generated = 0;
+
+    let stop_reason = 'generation: loop {
+        if tokens_generated >= max_tokens {
+            break StopReason::MaxLength;
+        }
+        let committed_len = context.len();
+
+        // The draft model proposes tokens one at a time
+        let proposal_len = draft_tokens.min(max_tokens - tokens_generated);
+        let mut draft_distributions = Vec::with_capacity(proposal_len);
+        let mut draft_logits = draft.feed_tokens(draft_session, &draft_pending)?;
+        let mut draft_fed = 0;
+        loop {
+            let distribution = token_distribution(&draft_logits, &stages, &context)?;
+            let token = sample_from(&distribution, &mut rng);
+            draft_distributions.push(distribution);
+            context.push(token);
+            if draft_distributions.len() == proposal_len || is_stop_token(token) {
+                break;
+            }
+            draft_logits = draft.feed_tokens(draft_session, &[token])?;
+            draft_fed += 1;
+        }
+        let proposed = context.split_off(committed_len);
+
+        // The target model checks every proposed token in one pass
+        let pending_len = target_pending.len();
+        let mut verify = std::mem::take(&mut target_pending);
+        verify.extend_from_slice(&proposed);
+        let target_logits = target.feed_tokens_with_all_logits(target_session, &verify)?;
+        if target_logits.len() != verify.len() {
+            anyhow::bail!(
+                "Expected logits for {} tokens, but the model returned {}",
+                verify.len(),
+                target_logits.len()
+            );
+        }
+        // The logits for the position of each proposed token, followed by the logits after the last proposed token
+        let target_logits = &target_logits[pending_len - 1..];
+
+        // Accept each proposed token with probability min(1, p / q), and sample a replacement from the leftover distribution at the first rejection
+        let mut committed = Vec::with_capacity(proposed.len() + 1);
+        let mut rejected = false;
+        for (i, (&token, draft_distribution)) in
+            proposed.iter().zip(&draft_distributions).enumerate()
+        {
+            let previous_tokens = [&context[..], &proposed[..i]].concat();
+            let target_distribution =
+                token_distribution(&target_logits[i], &stages, &previous_tokens)?;
+            let p = target_distribution[token as usize];
+            let q = draft_distribution[token as usize];
+            if rng.gen::<f32>() * q < p {
+                committed.push(token);
+                continue;
+            }
+            let residual: Vec<f32> = target_distribution
+                .iter()
+                .zip(draft_distribution)
+                .map(|(p, q)| (p - q).max(0.))
+                .collect();
+            let replacement = if residual.iter().sum::<f32>() > 0. {
+                sample_from(&residual, &mut rng)
+            } else {
+                sample_from(&target_distribution, &mut rng)
+            };
+            committed.push(replacement);
+            rejected = true;
+            break;
+        }
+        // If every token was accepted, the target model's logits after the last token give one extra token for free
+        let accepted = committed.len() - rejected as usize;
+        let last_proposed = proposed[proposed.len() - 1];
+        if !rejected && !is_stop_token(last_proposed) {
+            let previous_tokens = [&context[..], &proposed[..]].concat();
+            let target_distribution =
+                token_distribution(&target_logits[proposed.len()], &stages, &previous_tokens)?;
+            committed.push(sample_from(&target_distribution, &mut rng));
+        }
+
+        // Rewind both models to the accepted tokens. The last committed token is fed in the next round
+        if proposed.len() > accepted {
+            target_session.rewind(proposed.len() - accepted)?;
+        }
+        if draft_fed > accepted {
+            draft_session.rewind(draft_fed - accepted)?;
+        }
+        draft_pending = proposed[draft_fed.min(accepted)..accepted].to_vec();
+        if let Some(&last) = committed.last() {
+            if committed.len() > accepted {
+                draft_pending.push(last);
+                target_pending.push(last);
+            }
+        }
+
+        for (i, token) in committed.into_iter().enumerate() {
+            if token == stop_token {
+                tracing::trace!("Stopping on stop token");
+                break 'generation StopReason::EndOfSequence;
+            }
+            if stop_token_ids.contains(&token) {
+                tracing::trace!("Stopping on stop token {}", token);
+                break 'generation StopReason::StopToken { id: token };
+            }
+            let logits = &target_logits[i];
+            let new_text = text_stream.next_token(token)?.unwrap_or_default();
+            let status = stop_on_matcher.feed(&new_text);
+            held_back.push(LogProbabilities::new(logits).generated_token(
+                token,
+                new_text,
+                0,
+                0..logits.len() as u32,
+                &*tokenizer,
+            )?);
+            match status {
+                StopOnStatus::Continue(text) => {
+                    for token in held_back.release(text.len()) {
+                        if let ModelFeedback::Stop = on_token(token)? {
+                            return Ok(StopReason::Cancelled);
+                        }
+                    }
+                }
+                StopOnStatus::Stop(text) => {
+                    for token in held_back.release_and_truncate(text.len()) {
+                        on_token(token)?;
+                    }
+                    return Ok(stop_on_matcher
+                        .stop_reason()
+                        .unwrap_or(StopReason::EndOfSequence));
+                }
+            }
+            context.push(token);
+            tokens_generated += 1;
+            if tokens_generated >= max_tokens {
+                break 'generation StopReason::MaxLength;
+            }
+        }
+    };
+
+    // Flush the queued tokens
+    stop_on_matcher.flush();
+    for token in held_back.flush() {
+        if let ModelFeedback::Stop = on_token(token)? {
+            return Ok(StopReason::Cancelled);
+        }
+    }
+
+    Ok(stop_reason)
+}
+
+/// The probability of every token after applying the sampler stages to the logits.
+///
+/// Speculative decoding needs the full distribution of the draft and target models to decide which tokens to accept, so the stages are applied here instead of with a sampler chain that only returns the sampled token.
+fn token_distribution(
+    logits: &[f32],
+    stages: &[SamplerStage],
+    previous_tokens: &[u32],
+) -> anyhow::Result<Vec<f32>> {
+    let mut logits = logits.to_vec();
+    for stage in stages {
+        match *stage {
+            SamplerStage::Repetition { penalty, last_n } => {
+                let mut seen = Vec::new();
+                for &token in last_tokens(previous_tokens, last_n) {
+                    if seen.contains(&token) {
+                        continue;
+                    }
+                    seen.push(token);
+                    if let Some(logit) = logits.get_mut(token as usize) {
+                        *logit = if *logit >= 0. {
+                            *logit / penalty
+                        } else {
+                            *logit * penalty
+                        };
+                    }
+                }
+            }
+            SamplerStage::FrequencyPresence {
+                frequency_penalty,
+                presence_penalty,
+                last_n,
+            } => {
+                let mut counts = HashMap::new();
+                for &token in last_tokens(previous_tokens, last_n) {
+                    *counts.entry(token).or_insert(0usize) += 1;
+                }
+                for (token, count) in counts {
+                    if let Some(logit) = logits.get_mut(token as usize) {
+                        *logit -= count as f32 * frequency_penalty + presence_penalty;
+                    }
+                }
+            }
+            SamplerStage::Temperature { temperature } => {
+                if temperature <= 0. {
+                    return Ok(most_likely(&logits));
+                }
+                for logit in &mut logits {
+                    *logit /= temperature;
+                }
+            }
+            SamplerStage::TopK { k } => {
+                let by_likelihood = tokens_by_likelihood(&logits);
+                for &token in by_likelihood.iter().skip(k.max(1)) {
+                    logits[token] = f32::NEG_INFINITY;
+                }
+            }
+            SamplerStage::TopP { p } => {
+                let probabilities = softmax(&logits);
+                let mut cumulative = 0.;
+                for token in tokens_by_likelihood(&logits) {
+                    if cumulative >= p {
+                        logits[token] = f32::NEG_INFINITY;
+                    }
+                    cumulative += probabilities[token];
+                }
+            }
+            SamplerStage::MinP { p } => {
+                let probabilities = softmax(&logits);
+                let max = probabilities.iter().copied().fold(0., f32::max);
+                for (logit, probability) in logits.iter_mut().zip(probabilities) {
+                    if probability < p * max {
+                        *logit = f32::NEG_INFINITY;
+                    }
+                }
+            }
+            SamplerStage::Typical { p } => {
+                let probabilities = softmax(&logits);
+                let entropy: f32 = probabilities
+                    .iter()
+                    .filter(|probability| **probability > 0.)
+                    .map(|probability| -probability * probability.ln())
+                    .sum();
+                let mut by_typicality: Vec<usize> = (0..logits.len())
+                    .filter(|token| probabilities[*token] > 0.)
+                    .collect();
+                let distance = |token: usize| (-probabilities[token].ln() - entropy).abs();
+                by_typicality.sort_by(|a, b| distance(*a).total_cmp(&distance(*b)));
+                let mut cumulative = 0.;
+                for token in by_typicality {
+                    if cumulative >= p {
+                        logits[token] = f32::NEG_INFINITY;
+                    }
+                    cumulative += probabilities[token];
+                }
+            }
+            SamplerStage::Greedy => return Ok(most_likely(&logits)),
+            SamplerStage::SequenceRepetition | SamplerStage::Mirostat2 { .. } => {
+                anyhow::bail!(
+                    "The {stage:?} sampler stage depends on the previously sampled token and is not supported with speculative decoding"
+                );
+            }
+        }
+    }
+    Ok(softmax(&logits))
+}
+
+fn last_tokens(tokens: &[u32], last_n: usize) -> &[u32] {
+    &tokens[tokens.len().saturating_sub(last_n)..]
+}
+
+fn tokens_by_likelihood(logits: &[f32]) -> Vec<usize> {
+    let mut tokens: Vec<usize> = (0..logits.len()).collect();
+    tokens.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+    tokens
+}
+
+fn most_likely(logits: &[f32]) -> Vec<f32> {
+    let mut distribution = vec![0.; logits.len()];
+    if let Some(&token) = tokens_by_likelihood(logits).first() {
+        distribution[token] = 1.;
+    }
+    distribution
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return vec![0.; logits.len()];
+    }
+    let exp: Vec<f32> = logits.iter().map(|logit| (logit - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|exp| exp / sum).collect()
+}
+
+/// Sample a token from a distribution. The weights don't need to add up to one.
+fn sample_from(weights: &[f32], rng: &mut impl Rng) -> u32 {
+    let total: f32 = weights.iter().sum();
+    let mut target = rng.gen::<f32>() * total;
+    let mut last_possible = 0;
+    for (token, weight) in weights.iter().enumerate() {
+        if *weight <= 0. {
+            continue;
+        }
+        if target < *weight {
+            return token as u32;
+        }
+        target -= weight;
+        last_possible = token;
+    }
+    // Rounding errors can leave a tiny amount of weight at the end
+    last_possible as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_model::{MockModel, END};
+
+    /// Follow the text one byte at a time after the one byte prompt, then end the sequence.
+    fn spell(text: &[u8], tokens: &[u32]) -> Vec<(u8, f32)> {
+        match text.get(tokens.len() - 1) {
+            Some(byte) => vec![(*byte, 0.9), (b'?', 0.1)],
+            None => vec![(END as u8, 1.)],
+        }
+    }
+
+    fn generate(
+        draft: &MockModel,
+        target: &MockModel,
+        parameters: &GenerationParameters,
+    ) -> (String, StopReason) {
+        let mut text = String::new();
+        let stop_reason = speculative_stream_tokens(
+            draft,
+            &mut draft.new_session().unwrap(),
+            target,
+            &mut target.new_session().unwrap(),
+            "q",
+            parameters,
+            3,
+            |token| {
+                text += token.text();
+                Ok(ModelFeedback::Continue)
+            },
+        )
+        .unwrap();
+        (text, stop_reason)
+    }
+
+    #[test]
+    fn speculative_decoding_matches_the_target_model() {
+        let draft = MockModel::new(|tokens| spell(b"help me", tokens));
+        let target = MockModel::new(|tokens| spell(b"hello world", tokens));
+        let parameters =
+            GenerationParameters::default().with_sampler_stages([SamplerStage::Greedy]);
+
+        let (text, stop_reason) = generate(&draft, &target, &parameters);
+        assert_eq!(text, "hello world");
+        assert_eq!(stop_reason, StopReason::EndOfSequence);
+    }
+
+    #[test]
+    fn speculative_sampling_keeps_the_target_distribution() {
+        let draft = MockModel::new(|tokens| match tokens.len() {
+            1 => vec![(b'a', 0.2), (b'b', 0.8)],
+            _ => vec![(END as u8, 1.)],
+        });
+        let target = MockModel::new(|tokens| match tokens.len() {
+            1 => vec![(b'a', 0.7), (b'b', 0.3)],
+            _ => vec![(END as u8, 1.)],
+        });
+
+        let runs = 2000;
+        let mut a = 0;
+        for seed in 0..runs {
+            let parameters = GenerationParameters::default()
+                .with_sampler_stages(Vec::new())
+                .with_max_length(1)
+                .with_seed(seed);
+            if generate(&draft, &target, &parameters).0 == "a" {
+                a += 1;
+            }
+        }
+        let frequency = a as f32 / runs as f32;
+        assert!((frequency - 0.7).abs() < 0.04, "{frequency}");
+    }
+
+    #[test]
+    fn unsupported_sampler_stages_are_rejected() {
+        let draft = MockModel::new(|tokens| spell(b"hello", tokens));
+        let target = MockModel::new(|tokens| spell(b"hello", tokens));
+        let result = speculative_stream_tokens(
+            &draft,
+            &mut draft.new_session().unwrap(),
+            &target,
+            &mut target.new_session().unwrap(),
+            "q",
+            &GenerationParameters::default(),
+            3,
+            |_| Ok(ModelFeedback::Continue),
+        );
+        assert!(result.is_err());
+    }
+}
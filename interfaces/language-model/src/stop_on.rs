@@ -0,0 +1,246 @@
+/// Why text generation stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum StopReason {
+    /// The maximum number of tokens was generated.
+    MaxLength,
+    /// One of the stop sequences was generated. The stop sequence is trimmed from the output.
+    StopSequence {
+        /// The index of the stop sequence in the list of stop sequences.
+        index: usize,
+        /// The stop sequence that was generated.
+        sequence: String,
+    },
+    /// One of the stop tokens was generated. The stop token is not included in the output.
+    StopToken {
+        /// The id of the stop token.
+        id: u32,
+    },
+    /// The model generated the end of sequence token.
+    EndOfSequence,
+    /// The generation was cancelled before it finished, for example because the stream was dropped.
+    Cancelled,
+    /// The model failed while generating text.
+    Error {
+        /// The error the model returned.
+        message: String,
+    },
+    /// The model called one or more tools instead of finishing its answer. This is only reported by remote chat models that were given tools.
+    ToolCalls {
+        /// The tools the model called, in the order the model called them.
+        calls: Vec<ToolCall>,
+    },
+}
+
+/// A call to a tool a model requested.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ToolCall {
+    /// The id the server assigned to the call.
+    pub id: String,
+    /// The name of the tool that was called.
+    pub name: String,
+    /// The arguments of the call as a JSON string. Models may generate invalid JSON, so check the arguments before using them.
+    pub arguments: String,
+}
+
+/// Incrementally matches generated text against a list of stop sequences.
+///
+/// Text that could be the start of a stop sequence is held back until it is clear whether the stop sequence was generated. The stop sequence itself is never returned. Matching is case insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct StopOnMatcher {
+    stop_on: Vec<String>,
+    // The index of each stop sequence in the list the matcher was created from, before empty sequences were removed
+    indices: Vec<usize>,
+    queued: String,
+    matched: Option<usize>,
+}
+
+/// The result of feeding new text into a [`StopOnMatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopOnStatus {
+    /// No stop sequence has been found yet. The text is safe to send to the user (it may be empty if all of the new text could be part of a stop sequence).
+    Continue(String),
+    /// A stop sequence was found. The text is everything that was generated before the stop sequence that has not been returned yet.
+    Stop(String),
+}
+
+impl StopOnMatcher {
+    /// Create a new matcher for the given stop sequence. If the stop sequence is `None`, all text is passed through unchanged.
+    pub fn new(stop_on: Option<&str>) -> Self {
+        Self::from_sequences(stop_on)
+    }
+
+    /// Create a new matcher that stops on any of the given stop sequences. Empty stop sequences are ignored.
+    pub fn from_sequences(stop_on: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        let (indices, stop_on) = stop_on
+            .into_iter()
+            .enumerate()
+            .filter(|(_, s)| !s.as_ref().is_empty())
+            .map(|(index, s)| (index, s.as_ref().to_string()))
+            .unzip();
+        Self {
+            stop_on,
+            indices,
+            queued: String::new(),
+            matched: None,
+        }
+    }
+
+    /// Get the first stop sequence this matcher is looking for.
+    pub fn stop_on(&self) -> Option<&str> {
+        self.stop_on.first().map(|s| s.as_str())
+    }
+
+    /// Get all of the stop sequences this matcher is looking for.
+    pub fn stop_sequences(&self) -> &[String] {
+        &self.stop_on
+    }
+
+    /// Get the index and text of the stop sequence that was matched, if any. The index refers to the list of stop sequences the matcher was created from.
+    pub fn matched_sequence(&self) -> Option<(usize, &str)> {
+        self.matched
+            .map(|index| (self.indices[index], self.stop_on[index].as_str()))
+    }
+
+    /// Get the [`StopReason`] for the stop sequence that was matched, if any.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.matched_sequence()
+            .map(|(index, sequence)| StopReason::StopSequence {
+                index,
+                sequence: sequence.to_string(),
+            })
+    }
+
+    /// Feed newly generated text into the matcher.
+    pub fn feed(&mut self, new_text: &str) -> StopOnStatus {
+        if self.stop_on.is_empty() {
+            return StopOnStatus::Continue(new_text.to_string());
+        }
+        self.queued += new_text;
+
+        // If any stop sequence was generated, stop at the earliest one even if an earlier position could still be the start of a longer stop sequence
+        for (i, _) in self.queued.char_indices() {
+            let end_of_text = &self.queued[i..];
+            if let Some(index) = self
+                .stop_on
+                .iter()
+                .position(|stop_on| starts_with_ignore_case(end_of_text, stop_on))
+            {
+                let before = self.queued[..i].to_string();
+                self.queued.clear();
+                self.matched = Some(index);
+                return StopOnStatus::Stop(before);
+            }
+        }
+
+        for (i, _) in self.queued.char_indices() {
+            let end_of_text = &self.queued[i..];
+            // The text ends with the start of a stop sequence. Hold it back until we know if it matches
+            if self
+                .stop_on
+                .iter()
+                .any(|stop_on| starts_with_ignore_case(stop_on, end_of_text))
+            {
+                let before = self.queued[..i].to_string();
+                self.queued.drain(..i);
+                return StopOnStatus::Continue(before);
+            }
+        }
+
+        StopOnStatus::Continue(std::mem::take(&mut self.queued))
+    }
+
+    /// Take any text that is being held back because it could be the start of a stop sequence. This should be called when generation ends for another reason.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.queued)
+    }
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    prefix
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|c| text.next() == Some(c))
+}
+
+#[test]
+fn stop_on_across_tokens() {
+    let mut matcher = StopOnMatcher::new(Some("\nUser:"));
+    assert_eq!(
+        matcher.feed("Hello"),
+        StopOnStatus::Continue("Hello".to_string())
+    );
+    assert_eq!(
+        matcher.feed(" world\n"),
+        StopOnStatus::Continue(" world".to_string())
+    );
+    assert_eq!(matcher.feed("us"), StopOnStatus::Continue(String::new()));
+    assert_eq!(matcher.feed("er: hi"), StopOnStatus::Stop(String::new()));
+}
+
+#[test]
+fn stop_on_false_start() {
+    let mut matcher = StopOnMatcher::new(Some("stop"));
+    assert_eq!(matcher.feed("st"), StopOnStatus::Continue(String::new()));
+    assert_eq!(
+        matcher.feed("art"),
+        StopOnStatus::Continue("start".to_string())
+    );
+    assert_eq!(matcher.feed("s"), StopOnStatus::Continue(String::new()));
+    assert_eq!(matcher.flush(), "s");
+}
+
+#[test]
+fn stop_on_ignores_case() {
+    let mut matcher = StopOnMatcher::new(Some("END"));
+    assert_eq!(
+        matcher.feed("the end."),
+        StopOnStatus::Stop("the ".to_string())
+    );
+}
+
+#[test]
+fn stop_on_none_passes_through() {
+    let mut matcher = StopOnMatcher::new(None);
+    assert_eq!(
+        matcher.feed("anything"),
+        StopOnStatus::Continue("anything".to_string())
+    );
+}
+
+#[test]
+fn stop_on_multiple_sequences() {
+    let mut matcher = StopOnMatcher::from_sequences(["</answer>", "\n\n"]);
+    assert_eq!(
+        matcher.feed("Paris\n"),
+        StopOnStatus::Continue("Paris".to_string())
+    );
+    assert_eq!(matcher.matched_sequence(), None);
+    assert_eq!(matcher.feed("\n</ans"), StopOnStatus::Stop(String::new()));
+    assert_eq!(
+        matcher.stop_reason(),
+        Some(StopReason::StopSequence {
+            index: 1,
+            sequence: "\n\n".to_string()
+        })
+    );
+
+    // A complete stop sequence wins over an earlier partial match of a longer one
+    let mut matcher = StopOnMatcher::from_sequences(["abcd", "b"]);
+    assert_eq!(matcher.feed("xab"), StopOnStatus::Stop("xa".to_string()));
+    assert_eq!(matcher.matched_sequence(), Some((1, "b")));
+
+    // Indices refer to the original list even if it contains empty sequences
+    let mut matcher = StopOnMatcher::from_sequences(["", "stop"]);
+    assert_eq!(
+        matcher.feed("go stop"),
+        StopOnStatus::Stop("go ".to_string())
+    );
+    assert_eq!(matcher.matched_sequence(), Some((1, "stop")));
+}
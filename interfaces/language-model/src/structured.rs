@@ -1,122 +1,414 @@
 use std::{
+    collections::VecDeque,
     fmt::{Debug, Display, Formatter},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, Weak},
 };
 
 use crate::SyncModel;
 use crate::TokenOutputStream;
-use kalosm_sample::{ParseStatus, Parser, Tokenizer};
+use crate::{GeneratedToken, LogProbabilities};
+use kalosm_sample::{ParseDiagnostic, ParseStatus, Parser, TokenTrie, Tokenizer};
 use llm_samplers::prelude::{Logit, Logits};
 use llm_samplers::types::{HasSamplerResources, Sampler, SamplerError};
-use rustc_hash::FxHashMap;
+use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Settings for recovering when structured generation reaches a point where the parser accepts none of the tokens.
+///
+/// Instead of failing, generation backtracks to the last token it sampled, bans that token and samples again. Generation can backtrack at most `max_backtrack` tokens from the end of the text and gives up after `max_attempts` backtracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StructuredRecovery {
+    max_backtrack: usize,
+    max_attempts: usize,
+}
+
+impl Default for StructuredRecovery {
+    fn default() -> Self {
+        Self {
+            max_backtrack: 4,
+            max_attempts: 16,
+        }
+    }
+}
+
+impl StructuredRecovery {
+    /// Create new recovery settings with the default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of tokens generation can backtrack.
+    pub fn with_max_backtrack(mut self, max_backtrack: usize) -> Self {
+        self.max_backtrack = max_backtrack;
+        self
+    }
+
+    /// Set the maximum number of times generation can backtrack before it fails.
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Get the maximum number of tokens generation can backtrack.
+    pub fn max_backtrack(&self) -> usize {
+        self.max_backtrack
+    }
+
+    /// Get the maximum number of times generation can backtrack before it fails.
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+}
+
+/// The error returned when structured generation reaches a point where the parser accepts none of the tokens.
+#[derive(Debug, Clone)]
+pub struct StructuredGenerationError {
+    partial_output: String,
+    rejected_token: Option<String>,
+    diagnostic: ParseDiagnostic,
+}
+
+impl StructuredGenerationError {
+    pub(crate) fn new(
+        partial_output: String,
+        rejected_token: Option<String>,
+        diagnostic: ParseDiagnostic,
+    ) -> Self {
+        Self {
+            partial_output,
+            rejected_token,
+            diagnostic,
+        }
+    }
+
+    /// The text that was generated before the parser got stuck.
+    pub fn partial_output(&self) -> &str {
+        &self.partial_output
+    }
+
+    /// The text of the most likely token, which the parser rejected.
+    pub fn rejected_token(&self) -> Option<&str> {
+        self.rejected_token.as_deref()
+    }
+
+    /// Where and why the parser rejected the most likely token. The offset of the diagnostic is relative to the start of the rejected token.
+    pub fn diagnostic(&self) -> &ParseDiagnostic {
+        &self.diagnostic
+    }
+
+    /// The byte offset in the generated text where the parser rejected the most likely token.
+    pub fn offset(&self) -> usize {
+        self.partial_output.len() + self.diagnostic.offset()
+    }
+
+    /// The names of the parsers the error passed through, from the outermost parser to the parser that rejected the most likely token.
+    pub fn path(&self) -> &[&'static str] {
+        self.diagnostic.path()
+    }
+
+    /// The ASCII characters the parser would have accepted next.
+    pub fn expected(&self) -> &[char] {
+        self.diagnostic.expected()
+    }
+}
+
+impl Display for StructuredGenerationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No valid tokens found after {:?}. ", self.partial_output)?;
+        if let Some(token) = &self.rejected_token {
+            write!(f, "The most likely token {:?} was rejected. ", token)?;
+        }
+        self.diagnostic.fmt(f)
+    }
+}
+
+impl std::error::Error for StructuredGenerationError {}
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
+    prompt: impl Display,
+    llm: &M,
+    session: &mut M::Session,
+    parser: P,
+    parser_state: P::PartialState,
+    sampler: Arc<Mutex<dyn Sampler>>,
+    on_token: impl FnMut(GeneratedToken) -> anyhow::Result<()>,
+    top_k: Option<usize>,
+    top_n: usize,
+    seed: Option<u64>,
+) -> anyhow::Result<P::Output> {
+    generate_structured_inner(
+        prompt,
+        llm,
+        session,
+        parser,
+        parser_state,
+        sampler,
+        on_token,
+        top_k,
+        top_n,
+        seed,
+        None,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn generate_structured_with_recovery<M: ?Sized + SyncModel, P: Parser>(
+    prompt: impl Display,
+    llm: &M,
+    session: &mut M::Session,
+    parser: P,
+    parser_state: P::PartialState,
+    sampler: Arc<Mutex<dyn Sampler>>,
+    on_token: impl FnMut(GeneratedToken) -> anyhow::Result<()>,
+    top_k: Option<usize>,
+    top_n: usize,
+    seed: Option<u64>,
+    recovery: StructuredRecovery,
+) -> anyhow::Result<P::Output>
+where
+    P::PartialState: Clone,
+{
+    generate_structured_inner(
+        prompt,
+        llm,
+        session,
+        parser,
+        parser_state,
+        sampler,
+        on_token,
+        top_k,
+        top_n,
+        seed,
+        Some((
+            recovery,
+            <P::PartialState as Clone>::clone as fn(&P::PartialState) -> P::PartialState,
+        )),
+    )
+}
+
+/// The state of generation right after a token was sampled, so generation can backtrack and sample a different token.
+struct Checkpoint<S> {
+    parser_state: S,
+    token_stream: TokenOutputStream,
+    logits: Arc<Vec<f32>>,
+    banned: Vec<u32>,
+    sampled: u32,
+    pending: usize,
+}
+
+/// Holds back generated tokens while generation can still backtrack past them.
+struct TokenBuffer<F> {
+    on_token: F,
+    pending: Vec<GeneratedToken>,
+    hold: bool,
+}
+
+impl<F: FnMut(GeneratedToken) -> anyhow::Result<()>> TokenBuffer<F> {
+    fn push(&mut self, token: GeneratedToken) -> anyhow::Result<()> {
+        if self.hold {
+            self.pending.push(token);
+            Ok(())
+        } else {
+            (self.on_token)(token)
+        }
+    }
+
+    fn release(&mut self, count: usize) -> anyhow::Result<()> {
+        for token in self.pending.drain(..count) {
+            (self.on_token)(token)?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_structured_inner<M: ?Sized + SyncModel, P: Parser>(
     prompt: impl Display,
     llm: &M,
     session: &mut M::Session,
     parser: P,
     mut parser_state: P::PartialState,
     mut sampler: Arc<Mutex<dyn Sampler>>,
-    mut on_token: impl FnMut(String) -> anyhow::Result<()>,
+    on_token: impl FnMut(GeneratedToken) -> anyhow::Result<()>,
     top_k: Option<usize>,
+    top_n: usize,
+    seed: Option<u64>,
+    recovery: Option<(StructuredRecovery, fn(&P::PartialState) -> P::PartialState)>,
 ) -> anyhow::Result<P::Output> {
     let tokenizer = llm.tokenizer();
 
     let prompt_text = prompt.to_string();
     let prompt_tokens = tokenizer.encode(&prompt_text, true)?;
+    let prompt_token_count = prompt_tokens.len();
     let mut unprocessed_token_count = prompt_tokens.len();
     let mut token_stream = TokenOutputStream::new(tokenizer.clone());
     for token in prompt_tokens {
         token_stream.next_token(token)?;
     }
-    let mut rng = rand::thread_rng();
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let trie = token_trie(&tokenizer)?;
+
+    let mut buffer = TokenBuffer {
+        on_token,
+        pending: Vec::new(),
+        hold: recovery.is_some(),
+    };
+    let mut checkpoints = VecDeque::<Checkpoint<P::PartialState>>::new();
+    let mut restored = None;
+    let mut attempts = 0;
 
     loop {
-        let tokens = token_stream.tokens();
-        let logit_probs =
-            llm.feed_tokens(session, &tokens[tokens.len() - unprocessed_token_count..])?;
-        let resources = &mut SamplerResources {
-            previous_tokens: tokens,
-            rng: &mut rng,
+        // After backtracking, the logits at the checkpoint are reused instead of running the model again
+        let (logit_probs, banned) = match restored.take() {
+            Some(restored) => restored,
+            None => {
+                let tokens = token_stream.tokens();
+                let logits =
+                    llm.feed_tokens(session, &tokens[tokens.len() - unprocessed_token_count..])?;
+                (Arc::new(logits), Vec::new())
+            }
         };
-        let mut state_map = FxHashMap::default();
+        let log_probabilities = LogProbabilities::new(&logit_probs);
 
-        let mut logits = Logits::default();
-
-        let next_tokens = token_stream.peek_tokens((0..logit_probs.len() as u32).collect())?;
-
-        let mut logits_indexed = (0..)
-            .zip(logit_probs.iter().copied())
-            .map(|(id, prob)| Logit {
-                token_id: id as u32,
-                logit: prob,
-                prob: 0f32,
+        let mask = parser.allowed_tokens(&parser_state, &trie);
+        let mut allowed = mask
+            .allowed()
+            .iter()
+            .filter(|token_id| !banned.contains(token_id))
+            .filter_map(|&token_id| {
+                Some(Logit {
+                    token_id,
+                    logit: *logit_probs.get(token_id as usize)?,
+                    prob: 0f32,
+                })
             })
             .collect::<Vec<_>>();
 
-        // We can partition the logits into two groups: the top k and the rest
-        if let Some(top_k) = top_k {
-            if top_k < logits_indexed.len() {
-                logits_indexed
-                    .select_nth_unstable_by(top_k, |a, b| b.logit.partial_cmp(&a.logit).unwrap());
+        if allowed.is_empty() {
+            // Go back to the last sampled token and sample a different one
+            if let Some((recovery, _)) = &recovery {
+                if attempts < recovery.max_attempts {
+                    if let Some(checkpoint) = checkpoints.pop_back() {
+                        // Every token in the stream has been fed to the model at this point
+                        let count =
+                            token_stream.tokens().len() - checkpoint.token_stream.tokens().len();
+                        match session.rewind(count) {
+                            Ok(()) => {
+                                tracing::trace!("Backtracking {} tokens", count);
+                                attempts += 1;
+                                token_stream = checkpoint.token_stream;
+                                parser_state = checkpoint.parser_state;
+                                unprocessed_token_count = 0;
+                                buffer.pending.truncate(checkpoint.pending);
+                                let mut banned = checkpoint.banned;
+                                banned.push(checkpoint.sampled);
+                                restored = Some((checkpoint.logits, banned));
+                                continue;
+                            }
+                            Err(err) => tracing::warn!("Failed to backtrack: {}", err),
+                        }
+                    }
+                }
             }
+
+            return Err(structured_generation_error(
+                &parser,
+                &parser_state,
+                &token_stream,
+                prompt_token_count,
+                &logit_probs,
+                &banned,
+            )?
+            .into());
         }
 
-        for i in 0..logits_indexed.len() {
-            let Logit {
-                token_id, logit, ..
-            } = logits_indexed[i];
-            let Some(text) = next_tokens[token_id as usize].as_ref() else {
-                continue;
-            };
-            if let Ok(result) = parser.parse(&parser_state, text.as_bytes()) {
-                let parsed_bytes = match result {
-                    ParseStatus::Finished { remaining, .. } => text.len() - remaining.len(),
-                    ParseStatus::Incomplete { .. } => text.len(),
-                };
-                let result = result.without_remaining();
-                state_map.insert(token_id, (result, parsed_bytes));
-                logits.push(Logit {
-                    token_id,
-                    logit,
-                    prob: 0f32,
-                });
-                // If we only need to keep the top k logits, then we can quit early once we have enough
-                if let Some(top_k) = top_k {
-                    if logits.len() >= top_k {
-                        break;
-                    }
-                }
-            } else if let Some(top_k) = top_k {
-                // If the remaining logits are less than the top k, no need to partition
-                let remaining_needed = (top_k - logits.len()).min(logits_indexed.len() - i);
-                let remaining_possible = logits_indexed.len() - i;
-                if remaining_possible < remaining_needed {
-                    // If we eliminated a logit, our partitioning of the logits is no longer valid
-                    logits_indexed[i..].select_nth_unstable_by(remaining_needed, |a, b| {
-                        b.logit.partial_cmp(&a.logit).unwrap()
-                    });
-                }
+        // The alternatives are taken from every allowed token, not just the ones that are sampled from
+        let allowed_ids = allowed
+            .iter()
+            .map(|logit| logit.token_id)
+            .collect::<Vec<_>>();
+        // Only keep the top k allowed logits
+        if let Some(top_k) = top_k {
+            if top_k < allowed.len() {
+                allowed
+                    .select_nth_unstable_by(top_k, |a, b| b.logit.partial_cmp(&a.logit).unwrap());
+                allowed.truncate(top_k);
             }
         }
 
-        // If there are no valid tokens, return an error
-        if state_map.is_empty() {
-            return Err(anyhow::anyhow!("No valid tokens found"));
+        let mut logits = Logits::default();
+        for logit in allowed {
+            logits.push(logit);
         }
+        let resources = &mut SamplerResources {
+            previous_tokens: token_stream.tokens(),
+            rng: &mut rng,
+        };
         let token_id = sampler
             .sample_token(resources, &mut logits)?
             .ok_or(anyhow::anyhow!("Failed to sample constrained tokens"))?;
 
+        // The token trie decodes each token after a fixed prefix. If the text of the token after the current output doesn't parse, fall back to the next best token
+        let token_text = token_stream.peek_tokens(vec![token_id])?.pop().flatten();
+        let parsed = token_text.and_then(|token_text| {
+            let result = parser.parse(&parser_state, token_text.as_bytes()).ok()?;
+            let parsed_bytes = match &result {
+                ParseStatus::Finished { remaining, .. } => token_text.len() - remaining.len(),
+                ParseStatus::Incomplete { .. } => token_text.len(),
+            };
+            Some((parsed_bytes, result.without_remaining()))
+        });
+        let Some((parsed_bytes, result)) = parsed else {
+            tracing::trace!(
+                "Token {} is not valid after the current output, sampling another token",
+                token_id
+            );
+            let mut banned = banned;
+            banned.push(token_id);
+            restored = Some((logit_probs, banned));
+            continue;
+        };
+
+        if let Some((recovery, clone_state)) = &recovery {
+            checkpoints.push_back(Checkpoint {
+                parser_state: clone_state(&parser_state),
+                token_stream: token_stream.clone(),
+                logits: logit_probs.clone(),
+                banned,
+                sampled: token_id,
+                pending: buffer.pending.len(),
+            });
+            // Tokens before the oldest checkpoint can no longer be backtracked
+            while checkpoints.len() > recovery.max_backtrack {
+                checkpoints.pop_front();
+                let released = checkpoints
+                    .front()
+                    .map_or(buffer.pending.len(), |checkpoint| checkpoint.pending);
+                buffer.release(released)?;
+                for checkpoint in &mut checkpoints {
+                    checkpoint.pending -= released;
+                }
+            }
+        }
+
         unprocessed_token_count = 1;
-        let (result, parsed_bytes) = state_map
-            .remove(&token_id)
-            .ok_or(anyhow::anyhow!("Token {} not found in state map", token_id))?;
-        let mut token = token_stream.next_token(token_id)?.unwrap();
-        token.truncate(parsed_bytes);
-        tracing::trace!("Adding token {} to parser", token);
-        on_token(token)?;
+        let text = token_stream.next_token(token_id)?.unwrap_or_default();
+        // Alternatives are limited to the tokens the parser accepts
+        let mut token = log_probabilities.generated_token(
+            token_id,
+            text,
+            top_n,
+            allowed_ids.iter().copied(),
+            &*tokenizer,
+        )?;
+        token.truncate_text(parsed_bytes);
+        tracing::trace!("Adding token {} to parser", token.text());
+        buffer.push(token)?;
 
         if let Some(result) = update_state(
             &parser,
@@ -124,22 +416,109 @@ pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
             result,
             &tokenizer,
             &mut token_stream,
-            &mut on_token,
+            &mut |token| buffer.push(token),
             &mut unprocessed_token_count,
         )? {
+            buffer.release(buffer.pending.len())?;
             return Ok(result);
         }
     }
 }
 
+/// Describe why the parser accepts none of the tokens from the current state with the most likely token the parser rejected.
+fn structured_generation_error<P: Parser>(
+    parser: &P,
+    parser_state: &P::PartialState,
+    token_stream: &TokenOutputStream,
+    prompt_token_count: usize,
+    logits: &[f32],
+    banned: &[u32],
+) -> anyhow::Result<StructuredGenerationError> {
+    let partial_output = token_stream
+        .tokenizer()
+        .decode(&token_stream.tokens()[prompt_token_count..])?
+        .into_owned();
+    let most_likely = (0..)
+        .zip(logits)
+        .filter(|(token_id, _)| !banned.contains(token_id))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(token_id, _)| token_id);
+    let most_likely_text = match most_likely {
+        Some(token_id) => token_stream.peek_tokens(vec![token_id])?.pop().flatten(),
+        None => None,
+    };
+    // The most likely token may be missing from the token trie instead of rejected by the parser
+    let rejected = most_likely_text.and_then(|token| {
+        let diagnostic = ParseDiagnostic::new(parser, parser_state, token.as_bytes())?;
+        Some((token, diagnostic))
+    });
+    let (rejected_token, diagnostic) = match rejected {
+        Some((token, diagnostic)) => (Some(token), diagnostic),
+        None => (None, ParseDiagnostic::at_state(parser, parser_state)),
+    };
+
+    Ok(StructuredGenerationError::new(
+        partial_output,
+        rejected_token,
+        diagnostic,
+    ))
+}
+
+/// The token tries that have been built for each tokenizer.
+static TOKEN_TRIES: Lazy<Mutex<Vec<(Weak<dyn Tokenizer + Send + Sync>, Arc<TokenTrie>)>>> =
+    Lazy::new(Default::default);
+
+/// Get the [`TokenTrie`] for a tokenizer. The trie is built the first time it is needed and shared between generations that use the same tokenizer.
+pub(crate) fn token_trie(
+    tokenizer: &Arc<dyn Tokenizer + Send + Sync>,
+) -> anyhow::Result<Arc<TokenTrie>> {
+    let mut tries = TOKEN_TRIES.lock().unwrap();
+    tries.retain(|(tokenizer, _)| tokenizer.strong_count() > 0);
+    if let Some((_, trie)) = tries.iter().find(|(cached, _)| {
+        cached
+            .upgrade()
+            .is_some_and(|cached| Arc::ptr_eq(&cached, tokenizer))
+    }) {
+        return Ok(trie.clone());
+    }
+
+    let trie = Arc::new(build_token_trie(&**tokenizer)?);
+    tries.push((Arc::downgrade(tokenizer), trie.clone()));
+    Ok(trie)
+}
+
+/// Build a [`TokenTrie`] with the text each token adds when it follows other text.
+fn build_token_trie(tokenizer: &(dyn Tokenizer + Send + Sync)) -> anyhow::Result<TokenTrie> {
+    // Some tokenizers decode the first token differently, so each token is decoded after a prefix
+    let prefix = tokenizer.encode("a", false)?;
+    let prefix_text = tokenizer.decode(&prefix)?.into_owned();
+    let all_tokens = tokenizer.get_all_tokens()?;
+    let mut tokens = Vec::with_capacity(all_tokens.len());
+    let mut ids = prefix.clone();
+    for &token in all_tokens.iter() {
+        ids.truncate(prefix.len());
+        ids.push(token);
+        let text = tokenizer.decode(&ids)?;
+        let Some(text) = text.strip_prefix(&prefix_text) else {
+            continue;
+        };
+        // Like TokenOutputStream::peek_tokens, skip text that may end with part of a character
+        if !text.chars().last().is_some_and(|c| c.is_ascii()) {
+            continue;
+        }
+        tokens.push((token, text.to_string()));
+    }
+    Ok(TokenTrie::new(tokens))
+}
+
 #[allow(unused, clippy::all)]
-fn update_state<P: Parser>(
+pub(crate) fn update_state<P: Parser>(
     parser: &P,
     parser_state: &mut P::PartialState,
     result: ParseStatus<P::PartialState, P::Output>,
     tokenizer: &Arc<dyn Tokenizer + Send + Sync>,
     token_stream: &mut TokenOutputStream,
-    on_token: &mut impl FnMut(String) -> anyhow::Result<()>,
+    on_token: &mut impl FnMut(GeneratedToken) -> anyhow::Result<()>,
     unprocessed_token_count: &mut usize,
 ) -> anyhow::Result<Option<P::Output>> {
     match result {
@@ -168,9 +547,9 @@ fn update_state<P: Parser>(
                         unreachable!("Required next should always be valid attempted to add {} but got error", required_next)
                 });
                 for token in extra_tokens {
-                    if let Some(token) = token_stream.next_token(token)? {
-                        on_token(token)?;
-                    }
+                    // Tokens required by the parser are not sampled, so they don't have any alternatives
+                    let text = token_stream.next_token(token)?.unwrap_or_default();
+                    on_token(GeneratedToken::forced(token, text))?;
 
                     *unprocessed_token_count += 1;
                 }
@@ -222,3 +601,118 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_model::{ByteTokenizer, MockModel};
+    use crate::{GenerationParameters, SamplerStage, SyncModelExt};
+    use kalosm_sample::{CreateParserState, LiteralParser, ParserExt};
+    use std::borrow::Cow;
+
+    /// A model that always prefers "a" over "b" with a tokenizer that can only generate "a" and "b".
+    fn prefer_a_model() -> MockModel {
+        MockModel::new(|_| vec![(b'a', 0.6), (b'b', 0.4)])
+            .with_tokenizer(ByteTokenizer::with_vocabulary(b"ab"))
+    }
+
+    /// A tokenizer like [`ByteTokenizer`] that can only generate "a" and "b", and decodes "a" as "c" after "q".
+    struct ContextTokenizer;
+
+    impl Tokenizer for ContextTokenizer {
+        fn encode(&self, text: &str, add_special_tokens: bool) -> anyhow::Result<Vec<u32>> {
+            ByteTokenizer::default().encode(text, add_special_tokens)
+        }
+
+        fn decode(&self, ids: &[u32]) -> anyhow::Result<Cow<'_, str>> {
+            Ok(ByteTokenizer::default()
+                .decode(ids)?
+                .replace("qa", "qc")
+                .into())
+        }
+
+        fn get_all_tokens(&self) -> anyhow::Result<Cow<'_, [u32]>> {
+            Ok(vec![u32::from(b'a'), u32::from(b'b')].into())
+        }
+    }
+
+    #[test]
+    fn structured_generation_falls_back_when_context_changes_a_token() {
+        // The token trie allows "a", but after the prompt it decodes as "c"
+        let parser = LiteralParser::from("a").or(LiteralParser::from("b"));
+        let parameters =
+            GenerationParameters::default().with_sampler_stages([SamplerStage::Greedy]);
+        let model = prefer_a_model().with_tokenizer(ContextTokenizer);
+        let mut session = model.new_session().unwrap();
+        let mut text = String::new();
+        model
+            .generate_structured(
+                &mut session,
+                "q",
+                &parser,
+                parser.create_parser_state(),
+                Arc::new(Mutex::new(parameters.sampler())),
+                |token| {
+                    text += token.text();
+                    Ok(())
+                },
+                None,
+            )
+            .unwrap();
+        assert_eq!(text, "b");
+    }
+
+    #[test]
+    fn structured_generation_recovers_from_dead_ends() {
+        // No token can continue "a" because the tokenizer cannot generate "c"
+        let parser = LiteralParser::from("ac").or(LiteralParser::from("b"));
+        let sampler = || {
+            let parameters =
+                GenerationParameters::default().with_sampler_stages([SamplerStage::Greedy]);
+            Arc::new(Mutex::new(parameters.sampler()))
+        };
+
+        let model = prefer_a_model();
+        let mut session = model.new_session().unwrap();
+        let error = model
+            .generate_structured(
+                &mut session,
+                "q",
+                &parser,
+                parser.create_parser_state(),
+                sampler(),
+                |_| Ok(()),
+                None,
+            )
+            .unwrap_err();
+        let error = error.downcast_ref::<StructuredGenerationError>().unwrap();
+        assert_eq!(error.partial_output(), "a");
+        assert_eq!(error.rejected_token(), Some("a"));
+        assert_eq!(error.offset(), 1);
+        assert_eq!(error.path(), ["or", "literal"]);
+        assert_eq!(error.expected(), ['c']);
+
+        let mut session = model.new_session().unwrap();
+        let mut text = String::new();
+        model
+            .generate_structured_with_recovery(
+                &mut session,
+                "q",
+                &parser,
+                parser.create_parser_state(),
+                sampler(),
+                |token| {
+                    text += token.text();
+                    Ok(())
+                },
+                None,
+                0,
+                None,
+                StructuredRecovery::new().with_max_backtrack(1),
+            )
+            .unwrap();
+        // The backtracked token is never passed to the callback or left in the session
+        assert_eq!(text, "b");
+        assert_eq!(session.0, [u32::from(b'q')]);
+    }
+}
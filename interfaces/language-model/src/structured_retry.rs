@@ -0,0 +1,222 @@
+use futures_util::StreamExt;
+use kalosm_sample::{ParseDiagnostic, ParseStatus, Parser};
+
+use crate::{DynModel, GenerationParameters, Model, StructuredGenerationError};
+
+/// Settings for generating structured text with models that cannot constrain sampling, like remote models.
+///
+/// Remote APIs do not expose the tokenizer or the logits of the model, so the parser cannot mask the tokens the model samples. Instead, short completions are requested and checked against the parser as they stream in. The text the parser accepts is kept, the rest of the completion is thrown away and the next completion continues from the end of the accepted text. Text the parser requires next is added without asking the model.
+///
+/// Generation fails with a [`StructuredGenerationError`] after `max_attempts` completions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StructuredRetry {
+    max_attempts: usize,
+    max_tokens_per_attempt: u32,
+}
+
+impl Default for StructuredRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 16,
+            max_tokens_per_attempt: 32,
+        }
+    }
+}
+
+impl StructuredRetry {
+    /// Create new retry settings with the default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of completions to request before generation fails.
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the maximum number of tokens to request in each completion. Shorter completions waste less text when the parser rejects the completion early, but need more requests.
+    pub fn with_max_tokens_per_attempt(mut self, max_tokens_per_attempt: u32) -> Self {
+        self.max_tokens_per_attempt = max_tokens_per_attempt;
+        self
+    }
+
+    /// Get the maximum number of completions to request before generation fails.
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    /// Get the maximum number of tokens to request in each completion.
+    pub fn max_tokens_per_attempt(&self) -> u32 {
+        self.max_tokens_per_attempt
+    }
+}
+
+/// How much of some text the parser accepted.
+enum Fed<O> {
+    /// The parser accepted all of the text and needs more.
+    Accepted,
+    /// The parser finished after the first `consumed` bytes of the text.
+    Finished { consumed: usize, output: O },
+    /// The parser rejected the text after the first `accepted` bytes.
+    Rejected { accepted: usize },
+}
+
+/// Feed as much of the text into the parser as it accepts.
+fn feed<P: Parser>(parser: &P, state: &mut P::PartialState, text: &str) -> Fed<P::Output> {
+    match parser.parse(state, text.as_bytes()) {
+        Ok(ParseStatus::Incomplete { new_state, .. }) => {
+            *state = new_state;
+            return Fed::Accepted;
+        }
+        Ok(ParseStatus::Finished { result, remaining }) => {
+            return Fed::Finished {
+                consumed: text.len() - remaining.len(),
+                output: result,
+            }
+        }
+        Err(_) => {}
+    }
+
+    // Find the longest prefix the parser accepts one character at a time
+    for (index, character) in text.char_indices() {
+        let bytes = &text.as_bytes()[index..index + character.len_utf8()];
+        match parser.parse(state, bytes) {
+            Ok(ParseStatus::Incomplete { new_state, .. }) => *state = new_state,
+            Ok(ParseStatus::Finished { result, remaining }) => {
+                return Fed::Finished {
+                    consumed: index + bytes.len() - remaining.len(),
+                    output: result,
+                }
+            }
+            Err(_) => return Fed::Rejected { accepted: index },
+        }
+    }
+    Fed::Accepted
+}
+
+fn push_text(
+    generated: &mut String,
+    on_text: &mut impl FnMut(String) -> anyhow::Result<()>,
+    text: &str,
+) -> anyhow::Result<()> {
+    if !text.is_empty() {
+        generated.push_str(text);
+        on_text(text.to_string())?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn generate_structured_with_retries<M: Model + ?Sized, P: Parser>(
+    model: &M,
+    prompt: &str,
+    parser: &P,
+    mut parser_state: P::PartialState,
+    parameters: GenerationParameters,
+    retry: StructuredRetry,
+    mut on_text: impl FnMut(String) -> anyhow::Result<()>,
+) -> anyhow::Result<P::Output> {
+    let parameters = parameters.with_max_length(retry.max_tokens_per_attempt);
+    let mut generated = String::new();
+    let mut rejected = None;
+    let mut attempts = 0;
+
+    loop {
+        let required_next = match parser
+            .parse(&parser_state, &[])
+            .map_err(|err| anyhow::anyhow!("Parser rejected empty input: {}", &*err))?
+        {
+            ParseStatus::Finished { result, .. } => return Ok(result),
+            ParseStatus::Incomplete {
+                new_state,
+                required_next,
+            } => {
+                parser_state = new_state;
+                required_next
+            }
+        };
+
+        // Add the text the parser requires next without asking the model
+        if !required_next.is_empty() {
+            match feed(parser, &mut parser_state, &required_next) {
+                Fed::Accepted => push_text(&mut generated, &mut on_text, &required_next)?,
+                Fed::Finished { consumed, output } => {
+                    push_text(&mut generated, &mut on_text, &required_next[..consumed])?;
+                    return Ok(output);
+                }
+                Fed::Rejected { .. } => {
+                    anyhow::bail!("Parser rejected the text it requires next: {required_next:?}")
+                }
+            }
+            continue;
+        }
+
+        if attempts == retry.max_attempts {
+            let diagnostic = rejected
+                .as_ref()
+                .and_then(|text: &String| {
+                    ParseDiagnostic::new(parser, &parser_state, text.as_bytes())
+                })
+                .unwrap_or_else(|| ParseDiagnostic::at_state(parser, &parser_state));
+            return Err(StructuredGenerationError::new(generated, rejected, diagnostic).into());
+        }
+        attempts += 1;
+        rejected = None;
+
+        let mut stream = model
+            .stream_text_inner(&format!("{prompt}{generated}"), parameters.clone())
+            .await?;
+        while let Some(text) = stream.next().await {
+            match feed(parser, &mut parser_state, &text) {
+                Fed::Accepted => push_text(&mut generated, &mut on_text, &text)?,
+                Fed::Finished { consumed, output } => {
+                    push_text(&mut generated, &mut on_text, &text[..consumed])?;
+                    return Ok(output);
+                }
+                Fed::Rejected { accepted } => {
+                    push_text(&mut generated, &mut on_text, &text[..accepted])?;
+                    // Dropping the stream cancels the rest of the completion
+                    rejected = Some(text[accepted..].to_string());
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Generate structured text with the default retry settings in the background with a handle from [`Model::structured_retry_handle`]. Accepted text is sent to `sender` and the result to `result_sender`.
+pub(crate) fn spawn_structured_with_retries<P>(
+    model: DynModel,
+    prompt: String,
+    parser: P,
+    parser_state: P::PartialState,
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+    result_sender: tokio::sync::oneshot::Sender<anyhow::Result<P::Output>>,
+) where
+    P: Parser + Send + 'static,
+    P::PartialState: Send + 'static,
+    P::Output: Send + 'static,
+{
+    // The parser is only `Send`, so the generation future is driven on a blocking thread instead of being spawned as a task
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let result = runtime.block_on(generate_structured_with_retries(
+            &model,
+            &prompt,
+            &parser,
+            parser_state,
+            GenerationParameters::default(),
+            StructuredRetry::default(),
+            |text| Ok(sender.send(text)?),
+        ));
+        match result_sender.send(result) {
+            Ok(()) => {}
+            Err(Ok(_)) => {
+                log::error!("Error generating structured text: cancelled");
+            }
+            Err(Err(err)) => {
+                log::error!("Error generating structured text: {:?}", err);
+            }
+        }
+    });
+}
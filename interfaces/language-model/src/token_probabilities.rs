@@ -0,0 +1,191 @@
+use kalosm_sample::Tokenizer;
+
+/// The log probability of a single token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenProbability {
+    id: u32,
+    text: String,
+    logprob: f32,
+}
+
+impl TokenProbability {
+    /// The id of the token.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The text of the token on its own.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The natural log of the probability the model assigned to the token. This is NaN if the token was generated without asking for probabilities.
+    pub fn logprob(&self) -> f32 {
+        self.logprob
+    }
+
+    /// The probability the model assigned to the token.
+    pub fn probability(&self) -> f32 {
+        self.logprob.exp()
+    }
+}
+
+/// A token generated by a model along with the probability the model assigned to it and the most likely alternatives at that position.
+///
+/// The probabilities come from the model's distribution before any sampler adjustments (like temperature or repetition penalties) are applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedToken {
+    id: u32,
+    text: String,
+    logprob: f32,
+    top_alternatives: Vec<TokenProbability>,
+}
+
+impl GeneratedToken {
+    pub(crate) fn new(
+        id: u32,
+        text: String,
+        logprob: f32,
+        top_alternatives: Vec<TokenProbability>,
+    ) -> Self {
+        Self {
+            id,
+            text,
+            logprob,
+            top_alternatives,
+        }
+    }
+
+    /// Create a token that was forced into the output (for example by a parser) instead of being sampled. Forced tokens have a probability of 1 and no alternatives.
+    pub(crate) fn forced(id: u32, text: String) -> Self {
+        Self::new(id, text, 0.0, Vec::new())
+    }
+
+    /// Create a token for generation that did not ask for any probabilities. The log probability of the token is NaN.
+    pub(crate) fn without_probabilities(id: u32, text: String) -> Self {
+        Self::new(id, text, f32::NAN, Vec::new())
+    }
+
+    /// The id of the token.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The text this token added to the output. This may be empty if the token is only part of a character, or if the text was trimmed by a stop sequence or parser.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The natural log of the probability the model assigned to the token. This is NaN if the token was generated without asking for probabilities.
+    pub fn logprob(&self) -> f32 {
+        self.logprob
+    }
+
+    /// The probability the model assigned to the token.
+    pub fn probability(&self) -> f32 {
+        self.logprob.exp()
+    }
+
+    /// The most likely tokens at this position sorted from most to least likely. This includes the generated token if it is one of the most likely tokens.
+    ///
+    /// For constrained generation, only tokens that were valid for the parser are included.
+    pub fn top_alternatives(&self) -> &[TokenProbability] {
+        &self.top_alternatives
+    }
+
+    pub(crate) fn truncate_text(&mut self, len: usize) {
+        self.text.truncate(len);
+    }
+}
+
+impl AsRef<str> for GeneratedToken {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Log probabilities for one set of logits from a model.
+pub(crate) struct LogProbabilities<'a> {
+    logits: &'a [f32],
+    log_sum_exp: f32,
+}
+
+impl<'a> LogProbabilities<'a> {
+    pub(crate) fn new(logits: &'a [f32]) -> Self {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+        Self {
+            logits,
+            log_sum_exp: max + sum.ln(),
+        }
+    }
+
+    /// Get the log probability of a token.
+    pub(crate) fn logprob(&self, token: u32) -> f32 {
+        self.logits
+            .get(token as usize)
+            .map(|logit| logit - self.log_sum_exp)
+            .unwrap_or(f32::NEG_INFINITY)
+    }
+
+    /// Get the `top_n` most likely tokens out of the candidates.
+    pub(crate) fn top_n(
+        &self,
+        top_n: usize,
+        candidates: impl IntoIterator<Item = u32>,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
+    ) -> anyhow::Result<Vec<TokenProbability>> {
+        if top_n == 0 {
+            return Ok(Vec::new());
+        }
+        let mut candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|&id| (id as usize) < self.logits.len())
+            .collect();
+        let by_logit =
+            |a: &u32, b: &u32| self.logits[*b as usize].total_cmp(&self.logits[*a as usize]);
+        if top_n < candidates.len() {
+            candidates.select_nth_unstable_by(top_n, by_logit);
+            candidates.truncate(top_n);
+        }
+        candidates.sort_unstable_by(by_logit);
+
+        candidates
+            .into_iter()
+            .map(|id| {
+                Ok(TokenProbability {
+                    id,
+                    text: tokenizer.decode(&[id])?.to_string(),
+                    logprob: self.logprob(id),
+                })
+            })
+            .collect()
+    }
+
+    /// Create a [`GeneratedToken`] for a sampled token.
+    pub(crate) fn generated_token(
+        &self,
+        id: u32,
+        text: String,
+        top_n: usize,
+        candidates: impl IntoIterator<Item = u32>,
+        tokenizer: &(dyn Tokenizer + Send + Sync),
+    ) -> anyhow::Result<GeneratedToken> {
+        Ok(GeneratedToken::new(
+            id,
+            text,
+            self.logprob(id),
+            self.top_n(top_n, candidates, tokenizer)?,
+        ))
+    }
+}
+
+#[test]
+fn log_probabilities() {
+    let logits = [1.0f32, 2.0, 3.0];
+    let probabilities = LogProbabilities::new(&logits);
+    let total: f32 = (0..3).map(|id| probabilities.logprob(id).exp()).sum();
+    assert!((total - 1.0).abs() < 1e-5);
+    assert!(probabilities.logprob(2) > probabilities.logprob(1));
+    assert_eq!(probabilities.logprob(3), f32::NEG_INFINITY);
+}
@@ -1,17 +1,21 @@
 use anyhow::Result;
 use kalosm_sample::Tokenizer;
 use llm_samplers::types::{HasSamplerResources, Logits, Sampler, SamplerError};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 use std::sync::Arc;
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
+#[derive(Clone)]
 pub struct TokenOutputStream {
     tokenizer: Arc<dyn Tokenizer + Send + Sync>,
     tokens: Vec<u32>,
     prev_index: usize,
     current_index: usize,
+    rng: StdRng,
 }
 
 impl TokenOutputStream {
@@ -22,9 +26,19 @@ impl TokenOutputStream {
             prev_index: 0,
             current_index: 0,
             tokens: Vec::new(),
+            rng: StdRng::from_entropy(),
         }
     }
 
+    /// Seed the random number generator used to sample tokens. If the seed is `None`, the generator is seeded from the operating system.
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        self
+    }
+
     fn decode(&self, tokens: &[u32]) -> Result<String> {
         match self.tokenizer.decode(tokens) {
             Ok(str) => Ok(str.to_string()),
@@ -32,12 +46,12 @@ impl TokenOutputStream {
         }
     }
 
-    /// Samples a token from the logits.
+    /// Samples a token from the logits. Tokens that would generate text past one of the stop sequences are never sampled.
     pub fn sample_token(
-        &self,
+        &mut self,
         sampler: &mut impl Sampler,
         mut logits: Logits,
-        stop_on: Option<&str>,
+        stop_on: &[String],
     ) -> anyhow::Result<u32> {
         struct SamplerResources<'a, 'b, R: rand::Rng> {
             rng: &'a mut R,
@@ -72,37 +86,37 @@ impl TokenOutputStream {
                 Ok(())
             }
         }
-        let mut rng = rand::thread_rng();
+        let rng = &mut self.rng;
         let tokenizer = self.tokenizer.as_ref();
         let previous_tokens = &self.tokens;
 
         let mut end_tokens = String::new();
-        // grab as many characters as the stop_on string has from the end of the previous tokens
-        if let Some(stop_on) = stop_on {
-            let required_len = stop_on.len();
-            let mut previous_token_iter = previous_tokens.iter().rev();
-            while end_tokens.len() < required_len {
-                match previous_token_iter.next() {
-                    Some(token) => {
-                        end_tokens = tokenizer
-                            .decode(&[*token])
-                            .map_err(anyhow::Error::msg)?
-                            .to_string()
-                            + &end_tokens;
-                    }
-                    None => {
-                        break;
-                    }
+        // grab as many characters as the longest stop sequence has from the end of the previous tokens
+        let required_len = stop_on.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut previous_token_iter = previous_tokens.iter().rev();
+        while end_tokens.len() < required_len {
+            match previous_token_iter.next() {
+                Some(token) => {
+                    end_tokens = tokenizer
+                        .decode(&[*token])
+                        .map_err(anyhow::Error::msg)?
+                        .to_string()
+                        + &end_tokens;
+                }
+                None => {
+                    break;
                 }
             }
         }
-        for logit in logits.iter_mut() {
-            let tid = logit.token_id;
-            if let Some(stop_on) = stop_on {
+        if !stop_on.is_empty() {
+            for logit in logits.iter_mut() {
+                let tid = logit.token_id;
                 let token = tokenizer.decode(&[tid]).unwrap();
                 let combined = end_tokens.clone() + &token;
-                if combined.contains(stop_on) && !combined.ends_with(stop_on) {
-                    // if the token contains a stop_on token, but not the end of the string, set the probability to 0
+                if stop_on.iter().any(|stop_on| {
+                    combined.contains(stop_on.as_str()) && !combined.ends_with(stop_on.as_str())
+                }) {
+                    // if the token contains a stop sequence, but not at the end of the string, set the probability to 0
                     logit.prob = 0.0;
                 }
             }
@@ -111,7 +125,7 @@ impl TokenOutputStream {
             .sample_token(
                 &mut SamplerResources {
                     previous_tokens,
-                    rng: &mut rng,
+                    rng,
                 },
                 sampler,
             )?
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use kalosm_language_model::ChatMarkers;
+use minijinja::{context, Environment, Error, ErrorKind};
+
+const SYSTEM_PROMPT: &str = "kalosm-system-prompt";
+const FIRST_USER_MESSAGE: &str = "kalosm-first-user-message";
+const ASSISTANT_MESSAGE: &str = "kalosm-assistant-message";
+const SECOND_USER_MESSAGE: &str = "kalosm-second-user-message";
+
+struct ChatTemplate<'a> {
+    environment: Environment<'a>,
+    eos_token: &'a str,
+}
+
+impl<'a> ChatTemplate<'a> {
+    fn new(template: &'a str, eos_token: &'a str) -> anyhow::Result<Self> {
+        let mut environment = Environment::new();
+        environment
+            .set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        environment.add_function(
+            "raise_exception",
+            |message: String| -> Result<String, Error> {
+                Err(Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+        environment.add_template("chat", template)?;
+        Ok(Self {
+            environment,
+            eos_token,
+        })
+    }
+
+    fn render(
+        &self,
+        messages: &[(&str, &str)],
+        add_generation_prompt: bool,
+    ) -> anyhow::Result<String> {
+        let messages: Vec<_> = messages
+            .iter()
+            .map(|(role, content)| context! { role => role, content => content })
+            .collect();
+        // The tokenizer adds the BOS token when the prompt is encoded, so the markers leave it out
+        Ok(self.environment.get_template("chat")?.render(context! {
+            messages => messages,
+            add_generation_prompt => add_generation_prompt,
+            bos_token => "",
+            eos_token => self.eos_token,
+        })?)
+    }
+}
+
+/// Split the text around each of the messages in order. Returns the text before, between and after the messages.
+fn split_around<'a>(text: &'a str, messages: &[&str]) -> Option<Vec<&'a str>> {
+    let mut pieces = Vec::with_capacity(messages.len() + 1);
+    let mut rest = text;
+    for message in messages {
+        let (before, after) = rest.split_once(message)?;
+        pieces.push(before);
+        rest = after;
+    }
+    pieces.push(rest);
+    Some(pieces)
+}
+
+fn leak(text: &str) -> &'static str {
+    // Markers are cached for each template, so this only leaks the first time a template is rendered
+    Box::leak(text.to_string().into_boxed_str())
+}
+
+/// The markers rendered from each chat template and end of sequence token.
+static CHAT_MARKERS: OnceLock<Mutex<HashMap<(String, String), ChatMarkers>>> = OnceLock::new();
+
+/// Render a Jinja chat template (the `tokenizer.chat_template` in a GGUF file) into [`ChatMarkers`].
+///
+/// The template is rendered with placeholder messages and the markers are the text the template adds around them. Templates that do not allow system prompts use the user markers for system prompts.
+pub(crate) fn chat_markers_from_template(
+    template: &str,
+    eos_token: &str,
+) -> anyhow::Result<ChatMarkers> {
+    let mut cache = CHAT_MARKERS.get_or_init(Default::default).lock().unwrap();
+    let key = (template.to_string(), eos_token.to_string());
+    if let Some(markers) = cache.get(&key) {
+        return Ok(markers.clone());
+    }
+    let markers = render_chat_markers(template, eos_token)?;
+    cache.insert(key, markers.clone());
+    Ok(markers)
+}
+
+fn render_chat_markers(template: &str, eos_token: &str) -> anyhow::Result<ChatMarkers> {
+    let template = ChatTemplate::new(template, eos_token)?;
+    let missing_message =
+        || anyhow::anyhow!("The chat template did not include the content of every message");
+
+    let user_only = template.render(&[("user", FIRST_USER_MESSAGE)], false)?;
+    let user_only = split_around(&user_only, &[FIRST_USER_MESSAGE]).ok_or_else(missing_message)?;
+    let (before_user, end_user) = (user_only[0], user_only[1]);
+
+    let user_and_assistant = template.render(
+        &[
+            ("user", FIRST_USER_MESSAGE),
+            ("assistant", ASSISTANT_MESSAGE),
+        ],
+        false,
+    )?;
+    let user_and_assistant = split_around(
+        &user_and_assistant,
+        &[FIRST_USER_MESSAGE, ASSISTANT_MESSAGE],
+    )
+    .ok_or_else(missing_message)?;
+    let end_assistant = user_and_assistant[2];
+
+    let conversation = template.render(
+        &[
+            ("user", FIRST_USER_MESSAGE),
+            ("assistant", ASSISTANT_MESSAGE),
+            ("user", SECOND_USER_MESSAGE),
+        ],
+        true,
+    )?;
+    let conversation = split_around(
+        &conversation,
+        &[FIRST_USER_MESSAGE, ASSISTANT_MESSAGE, SECOND_USER_MESSAGE],
+    )
+    .ok_or_else(missing_message)?;
+    let (between_user_and_assistant, between_assistant_and_user) =
+        (conversation[1], conversation[2]);
+
+    let assistant_marker = between_user_and_assistant
+        .strip_prefix(end_user)
+        .unwrap_or(between_user_and_assistant);
+    let user_marker = between_assistant_and_user
+        .strip_prefix(end_assistant)
+        .unwrap_or(between_assistant_and_user);
+    // Text the template adds once at the start of the conversation
+    let prefix = before_user.strip_suffix(user_marker).unwrap_or_default();
+
+    let system_and_user = template
+        .render(
+            &[("system", SYSTEM_PROMPT), ("user", FIRST_USER_MESSAGE)],
+            false,
+        )
+        .ok();
+    let (system_prompt_marker, end_system_prompt_marker) = match system_and_user
+        .as_deref()
+        .and_then(|text| split_around(text, &[SYSTEM_PROMPT, FIRST_USER_MESSAGE]))
+        .as_deref()
+    {
+        Some(&[before_system, between_system_and_user, _]) => (
+            before_system.strip_prefix(prefix).unwrap_or(before_system),
+            between_system_and_user
+                .strip_suffix(user_marker)
+                .unwrap_or(between_system_and_user),
+        ),
+        _ => (user_marker, end_user),
+    };
+
+    Ok(ChatMarkers {
+        system_prompt_marker: leak(system_prompt_marker),
+        end_system_prompt_marker: leak(end_system_prompt_marker),
+        user_marker: leak(user_marker),
+        end_user_marker: leak(end_user),
+        assistant_marker: leak(assistant_marker),
+        end_assistant_marker: leak(end_assistant),
+    })
+}
+
+#[test]
+fn chat_markers_from_chat_templates() {
+    let chatml = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+    let markers = chat_markers_from_template(chatml, "<|im_end|>").unwrap();
+    assert_eq!(markers.system_prompt_marker, "<|im_start|>system\n");
+    assert_eq!(markers.end_system_prompt_marker, "<|im_end|>\n");
+    assert_eq!(markers.user_marker, "<|im_start|>user\n");
+    assert_eq!(markers.end_user_marker, "<|im_end|>\n");
+    assert_eq!(markers.assistant_marker, "<|im_start|>assistant\n");
+    assert_eq!(markers.end_assistant_marker, "<|im_end|>\n");
+
+    // Mistral templates only allow alternating user and assistant messages
+    let mistral = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token}}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}";
+    let markers = chat_markers_from_template(mistral, "</s>").unwrap();
+    assert_eq!(markers.system_prompt_marker, "[INST] ");
+    assert_eq!(markers.end_system_prompt_marker, " [/INST]");
+    assert_eq!(markers.user_marker, "[INST] ");
+    assert_eq!(markers.end_user_marker, " [/INST]");
+    assert_eq!(markers.assistant_marker, "");
+    assert_eq!(markers.end_assistant_marker, "</s>");
+
+    // Rendering the same template again reuses the markers instead of leaking new ones
+    let again = chat_markers_from_template(mistral, "</s>").unwrap();
+    assert!(std::ptr::eq(markers.user_marker, again.user_marker));
+}
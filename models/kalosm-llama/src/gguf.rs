@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use anyhow::Error as E;
+use candle_core::quantized::gguf_file::Content;
+use kalosm_language_model::ChatMarkers;
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence as DecoderSequence,
+        strip::Strip, DecoderWrapper,
+    },
+    models::bpe::BPE,
+    normalizers::{NormalizerWrapper, Prepend, Replace, Sequence as NormalizerSequence},
+    pre_tokenizers::{
+        byte_level::ByteLevel,
+        sequence::Sequence as PreTokenizerSequence,
+        split::{Split, SplitPattern},
+        PreTokenizerWrapper,
+    },
+    processors::template::TemplateProcessing,
+    AddedToken, SplitDelimiterBehavior, Tokenizer,
+};
+
+use crate::chat_template::chat_markers_from_template;
+
+// The token types GGUF files store in `tokenizer.ggml.token_type`
+const NORMAL_TOKEN: i32 = 1;
+const UNKNOWN_TOKEN: i32 = 2;
+const CONTROL_TOKEN: i32 = 3;
+const USER_DEFINED_TOKEN: i32 = 4;
+
+// The regexes byte level BPE tokenizers split text with before the text is split into tokens, named by `tokenizer.ggml.pre`
+const LLAMA3_PRE_TOKENIZER: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const QWEN2_PRE_TOKENIZER: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+fn string_array<'a>(ct: &'a Content, key: &str) -> anyhow::Result<Vec<&'a str>> {
+    let Some(value) = ct.metadata.get(key) else {
+        anyhow::bail!("cannot find {key} in metadata")
+    };
+    value
+        .to_vec()?
+        .iter()
+        .map(|value| anyhow::Ok(value.to_string()?.as_str()))
+        .collect()
+}
+
+fn token_id(ct: &Content, key: &str) -> Option<u32> {
+    ct.metadata.get(key).and_then(|value| value.to_u32().ok())
+}
+
+fn token_types(ct: &Content) -> Option<Vec<i32>> {
+    let types = ct
+        .metadata
+        .get("tokenizer.ggml.token_type")?
+        .to_vec()
+        .ok()?;
+    types.iter().map(|ty| ty.to_i32().ok()).collect()
+}
+
+/// Build the tokenizer embedded in the metadata of a GGUF file.
+///
+/// SentencePiece (`llama`) and byte level BPE (`gpt2`) tokenizers are supported. Byte level BPE tokenizers support the GPT-2, Llama 3 (`llama-bpe`) and Qwen 2 pre-tokenizers.
+pub(crate) fn tokenizer_from_gguf(ct: &Content) -> anyhow::Result<Tokenizer> {
+    let model = match ct.metadata.get("tokenizer.ggml.model") {
+        Some(model) => model.to_string()?.as_str(),
+        None => anyhow::bail!("cannot find tokenizer.ggml.model in metadata"),
+    };
+    let tokens = string_array(ct, "tokenizer.ggml.tokens")?;
+    let types = token_types(ct).filter(|types| types.len() == tokens.len());
+    let token_type = |id: usize| types.as_ref().map_or(NORMAL_TOKEN, |types| types[id]);
+    let vocab: HashMap<String, u32> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+
+    let mut tokenizer = match model {
+        "llama" => {
+            let scores = match ct.metadata.get("tokenizer.ggml.scores") {
+                Some(scores) => scores
+                    .to_vec()?
+                    .iter()
+                    .map(|score| score.to_f32())
+                    .collect::<candle_core::Result<Vec<_>>>()?,
+                None => vec![0.; tokens.len()],
+            };
+            let mergeable: Vec<_> = (0..tokens.len())
+                .map(|id| token_type(id) == NORMAL_TOKEN)
+                .collect();
+            let merges = sentencepiece_merges(&tokens, &scores, &mergeable, &vocab);
+            let mut bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .byte_fallback(true)
+                .fuse_unk(true);
+            if let Some(unknown) = token_id(ct, "tokenizer.ggml.unknown_token_id")
+                .and_then(|id| tokens.get(id as usize))
+            {
+                bpe = bpe.unk_token(unknown.to_string());
+            }
+            let mut tokenizer = Tokenizer::new(bpe.build().map_err(E::msg)?);
+            tokenizer.with_normalizer(NormalizerSequence::new(vec![
+                NormalizerWrapper::from(Prepend::new("▁".to_string())),
+                NormalizerWrapper::from(Replace::new(" ", "▁").map_err(E::msg)?),
+            ]));
+            tokenizer.with_decoder(DecoderSequence::new(vec![
+                DecoderWrapper::from(Replace::new("▁", " ").map_err(E::msg)?),
+                DecoderWrapper::from(ByteFallback::new()),
+                DecoderWrapper::from(Fuse::new()),
+                DecoderWrapper::from(Strip::new(' ', 1, 0)),
+            ]));
+            tokenizer
+        }
+        "gpt2" => {
+            let merges = string_array(ct, "tokenizer.ggml.merges")?
+                .into_iter()
+                .map(|merge| {
+                    merge
+                        .split_once(' ')
+                        .map(|(first, second)| (first.to_string(), second.to_string()))
+                        .ok_or_else(|| anyhow::anyhow!("invalid merge {merge:?}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .build()
+                .map_err(E::msg)?;
+            let mut tokenizer = Tokenizer::new(bpe);
+            tokenizer.with_pre_tokenizer(byte_level_pre_tokenizer(ct)?);
+            tokenizer.with_decoder(ByteLevel::default());
+            tokenizer
+        }
+        _ => anyhow::bail!(
+            "unsupported tokenizer model {model} in GGUF file. Set the tokenizer with LlamaSource::with_tokenizer instead"
+        ),
+    };
+
+    let added_tokens = |ty: &[i32], special: bool| {
+        tokens
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| ty.contains(&token_type(*id)))
+            .map(|(_, token)| AddedToken::from(token.to_string(), special))
+            .collect::<Vec<_>>()
+    };
+    tokenizer.add_special_tokens(&added_tokens(&[UNKNOWN_TOKEN, CONTROL_TOKEN], true));
+    tokenizer.add_tokens(&added_tokens(&[USER_DEFINED_TOKEN], false));
+
+    let add_bos_token = ct
+        .metadata
+        .get("tokenizer.ggml.add_bos_token")
+        .and_then(|add_bos_token| add_bos_token.to_bool().ok())
+        .unwrap_or(model == "llama");
+    let bos_token = token_id(ct, "tokenizer.ggml.bos_token_id")
+        .and_then(|id| Some((id, *tokens.get(id as usize)?)));
+    if let Some((bos_id, bos_token)) = bos_token.filter(|_| add_bos_token) {
+        tokenizer.with_post_processor(
+            TemplateProcessing::builder()
+                .try_single(format!("{bos_token} $A"))
+                .map_err(E::msg)?
+                .try_pair(format!("{bos_token} $A {bos_token}:1 $B:1"))
+                .map_err(E::msg)?
+                .special_tokens(vec![(bos_token.to_string(), bos_id)])
+                .build()
+                .map_err(E::msg)?,
+        );
+    }
+
+    Ok(tokenizer)
+}
+
+/// Build the pre-tokenizer named by `tokenizer.ggml.pre` for a byte level BPE (`gpt2`) tokenizer. Files without the key use the GPT-2 pre-tokenizer.
+fn byte_level_pre_tokenizer(ct: &Content) -> anyhow::Result<PreTokenizerWrapper> {
+    let pre = match ct.metadata.get("tokenizer.ggml.pre") {
+        Some(pre) => pre.to_string()?.as_str(),
+        None => "default",
+    };
+    let regex = match pre {
+        // The GPT-2 regex is built into the byte level pre-tokenizer
+        "default" | "gpt-2" | "gpt2" => {
+            return Ok(ByteLevel::default().add_prefix_space(false).into())
+        }
+        "llama-bpe" | "llama3" | "llama-v3" => LLAMA3_PRE_TOKENIZER,
+        "qwen2" => QWEN2_PRE_TOKENIZER,
+        _ => anyhow::bail!(
+            "unsupported pre-tokenizer {pre} in GGUF file. Set the tokenizer with LlamaSource::with_tokenizer instead"
+        ),
+    };
+    let split = Split::new(
+        SplitPattern::Regex(regex.to_string()),
+        SplitDelimiterBehavior::Isolated,
+        false,
+    )
+    .map_err(E::msg)?;
+    Ok(PreTokenizerSequence::new(vec![
+        PreTokenizerWrapper::from(split),
+        PreTokenizerWrapper::from(
+            ByteLevel::default()
+                .add_prefix_space(false)
+                .use_regex(false),
+        ),
+    ])
+    .into())
+}
+
+/// SentencePiece models store a score for each token instead of a list of merges. Merging the pieces of the highest scoring tokens first gives the same result as SentencePiece.
+fn sentencepiece_merges(
+    tokens: &[&str],
+    scores: &[f32],
+    mergeable: &[bool],
+    vocab: &HashMap<String, u32>,
+) -> Vec<(String, String)> {
+    let mut merges = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        if !mergeable[id] {
+            continue;
+        }
+        let score = scores.get(id).copied().unwrap_or_default();
+        for (split, _) in token.char_indices().skip(1) {
+            let (first, second) = token.split_at(split);
+            if let (Some(&first_id), Some(&second_id)) = (vocab.get(first), vocab.get(second)) {
+                merges.push((score, first_id, second_id));
+            }
+        }
+    }
+    merges.sort_by(|first, second| {
+        second
+            .0
+            .total_cmp(&first.0)
+            .then((first.1, first.2).cmp(&(second.1, second.2)))
+    });
+    merges
+        .into_iter()
+        .map(|(_, first, second)| {
+            (
+                tokens[first as usize].to_string(),
+                tokens[second as usize].to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Get the id of the end of sequence token from the metadata of a GGUF file.
+pub(crate) fn eos_token_from_gguf(ct: &Content) -> Option<u32> {
+    token_id(ct, "tokenizer.ggml.eos_token_id")
+}
+
+/// Render the chat template in the metadata of a GGUF file into [`ChatMarkers`]. Returns `None` if the file does not have a chat template.
+pub(crate) fn chat_markers_from_gguf(ct: &Content) -> anyhow::Result<Option<ChatMarkers>> {
+    let Some(template) = ct.metadata.get("tokenizer.chat_template") else {
+        return Ok(None);
+    };
+    let tokens = string_array(ct, "tokenizer.ggml.tokens")?;
+    let eos_token = eos_token_from_gguf(ct)
+        .and_then(|id| tokens.get(id as usize).copied())
+        .unwrap_or_default();
+    chat_markers_from_template(template.to_string()?, eos_token).map(Some)
+}
+
+#[test]
+fn sentencepiece_merges_follow_scores() {
+    let tokens = ["<s>", "a", "b", "c", "bc", "ab", "abc"];
+    let scores = [0., -1., -1., -1., -2., -3., -4.];
+    let mergeable = [false, true, true, true, true, true, true];
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+    let merges = sentencepiece_merges(&tokens, &scores, &mergeable, &vocab);
+    assert_eq!(
+        merges,
+        [
+            ("b".to_string(), "c".to_string()),
+            ("a".to_string(), "b".to_string()),
+            ("a".to_string(), "bc".to_string()),
+            ("ab".to_string(), "c".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn pre_tokenizer_from_gguf() {
+    use candle_core::quantized::gguf_file::{Value, VersionedMagic};
+    use tokenizers::{OffsetReferential, OffsetType, PreTokenizedString, PreTokenizer};
+
+    let content = |pre: Option<&str>| Content {
+        magic: VersionedMagic::GgufV3,
+        metadata: pre
+            .map(|pre| {
+                (
+                    "tokenizer.ggml.pre".to_string(),
+                    Value::String(pre.to_string()),
+                )
+            })
+            .into_iter()
+            .collect(),
+        tensor_infos: HashMap::new(),
+        tensor_data_offset: 0,
+    };
+    let pre_tokenize = |pre: Option<&str>| {
+        let pre_tokenizer = byte_level_pre_tokenizer(&content(pre)).unwrap();
+        let mut text = PreTokenizedString::from("12345 words");
+        pre_tokenizer.pre_tokenize(&mut text).unwrap();
+        text.get_splits(OffsetReferential::Original, OffsetType::Byte)
+            .into_iter()
+            .map(|(split, _, _)| split.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(pre_tokenize(None), ["12345", "Ġwords"]);
+    // Llama 3 splits numbers into groups of up to three digits
+    assert_eq!(pre_tokenize(Some("llama-bpe")), ["123", "45", "Ġwords"]);
+    assert!(byte_level_pre_tokenizer(&content(Some("unknown"))).is_err());
+}
@@ -6,8 +6,7 @@ use crate::{InferenceSettings, Task};
 use crate::{LlamaBuilder, LlamaModel};
 use kalosm_common::ModelLoadingProgress;
 use kalosm_language_model::ChatMarkers;
-use kalosm_language_model::{GenerationParameters, Model, ModelBuilder};
-use kalosm_streams::text_stream::ChannelTextStream;
+use kalosm_language_model::{GenerationParameters, Model, ModelBuilder, TextGenerationStream};
 
 #[async_trait::async_trait]
 impl ModelBuilder for LlamaBuilder {
@@ -21,17 +20,22 @@ impl ModelBuilder for LlamaBuilder {
     }
 
     fn requires_download(&self) -> bool {
-        !self.source.model.downloaded() || !self.source.tokenizer.downloaded()
+        !self.source.model.downloaded()
+            || self
+                .source
+                .tokenizer
+                .as_ref()
+                .is_some_and(|tokenizer| !tokenizer.downloaded())
     }
 }
 
 #[async_trait::async_trait]
 impl Model for Llama {
-    type TextStream = ChannelTextStream<String>;
+    type TextStream = TextGenerationStream;
     type SyncModel = LlamaModel;
 
-    fn tokenizer(&self) -> Arc<dyn kalosm_sample::Tokenizer + Send + Sync> {
-        self.get_tokenizer() as Arc<dyn kalosm_sample::Tokenizer + Send + Sync>
+    fn tokenizer(&self) -> Option<Arc<dyn kalosm_sample::Tokenizer + Send + Sync>> {
+        Some(self.get_tokenizer() as Arc<dyn kalosm_sample::Tokenizer + Send + Sync>)
     }
 
     fn run_sync_raw(
@@ -59,10 +63,11 @@ impl Model for Llama {
         self.run(
             InferenceSettings::new(prompt)
                 .with_sample_len(max_length as usize)
-                .with_stop_on(generation_parameters.stop_on().map(|s| s.to_string())),
+                .with_stop_on(generation_parameters.stop_sequences().to_vec())
+                .with_stop_token_ids(generation_parameters.stop_token_ids().to_vec())
+                .with_seed(generation_parameters.seed()),
             Arc::new(Mutex::new(generation_parameters.sampler())),
         )
-        .map(Into::into)
     }
 
     async fn stream_text_with_sampler(
@@ -79,10 +84,13 @@ impl Model for Llama {
                 .with_stop_on(stop_on.map(|s| s.to_string())),
             sampler,
         )
-        .map(Into::into)
     }
 
     fn chat_markers(&self) -> Option<ChatMarkers> {
         self.chat_markers.deref().clone()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        Some(self.context_length)
+    }
 }
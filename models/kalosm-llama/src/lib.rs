@@ -32,26 +32,31 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
+mod chat_template;
+mod gguf;
 mod language_model;
 mod model;
 mod raw;
+mod scheduler;
 mod session;
 mod source;
 
 pub use crate::model::LlamaModel;
 pub use crate::raw::cache::*;
-use crate::raw::Model;
+pub use crate::raw::RopeScaling;
+use crate::raw::{LlamaConfigOverrides, Model};
+use crate::scheduler::InferenceScheduler;
 pub use crate::session::LlamaSession;
-use candle_core::{
-    quantized::{ggml_file, gguf_file},
-    Device,
-};
+use candle_core::Device;
 pub use kalosm_common::*;
-use kalosm_language_model::ChatMarkers;
+use kalosm_language_model::{ChatMarkers, StopReason, TextGenerationStream};
 use llm_samplers::types::Sampler;
+use source::LoadedModel;
 pub use source::*;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tokenizers::Tokenizer;
+use tokio::sync::mpsc::error::TryRecvError;
 
 /// A prelude of commonly used items in kalosm-llama.
 pub mod prelude {
@@ -65,6 +70,7 @@ enum Task {
     Infer {
         settings: InferenceSettings,
         sender: tokio::sync::mpsc::UnboundedSender<String>,
+        stop_reason: tokio::sync::oneshot::Sender<StopReason>,
         sampler: Arc<Mutex<dyn Sampler>>,
     },
     RunSync {
@@ -85,6 +91,7 @@ pub struct Llama {
     task_sender: tokio::sync::mpsc::UnboundedSender<Task>,
     tokenizer: Arc<Tokenizer>,
     chat_markers: Arc<Option<ChatMarkers>>,
+    context_length: usize,
 }
 
 impl Drop for Llama {
@@ -124,34 +131,62 @@ impl Llama {
         device: Device,
         cache: LlamaCache,
         chat_markers: Option<ChatMarkers>,
+        stop_token: Option<u32>,
+        max_batch_size: usize,
+        prefix_cache_size: usize,
     ) -> Self {
         let (task_sender, mut task_receiver) = tokio::sync::mpsc::unbounded_channel();
         let arc_tokenizer = Arc::new(tokenizer);
+        let context_length = model.config.context_length();
 
         std::thread::spawn({
             let arc_tokenizer = arc_tokenizer.clone();
             move || {
-                let mut inner = LlamaModel::new(model, arc_tokenizer, device, cache);
+                let mut inner = LlamaModel::new(
+                    model,
+                    arc_tokenizer,
+                    device,
+                    cache,
+                    stop_token,
+                    prefix_cache_size,
+                );
                 tokio::runtime::Builder::new_current_thread()
                     .enable_all()
                     .build()
                     .unwrap()
                     .block_on(async move {
-                        while let Some(task) = task_receiver.recv().await {
+                        let mut scheduler = InferenceScheduler::new(max_batch_size);
+                        loop {
+                            let task = if scheduler.is_empty() {
+                                task_receiver.recv().await
+                            } else {
+                                // While requests are generating, only take tasks that are already waiting
+                                match task_receiver.try_recv() {
+                                    Ok(task) => Some(task),
+                                    Err(TryRecvError::Empty) => {
+                                        scheduler.step(&inner);
+                                        continue;
+                                    }
+                                    Err(TryRecvError::Disconnected) => None,
+                                }
+                            };
                             match task {
-                                Task::Kill => break,
-                                Task::Infer {
+                                Some(Task::Infer {
                                     settings,
                                     sender,
+                                    stop_reason,
                                     sampler,
-                                } => {
-                                    if let Err(err) = inner._infer(settings, sampler, sender) {
-                                        eprintln!("Error: {}", err);
-                                    }
+                                }) => {
+                                    scheduler.add(settings, sampler, sender, stop_reason);
                                 }
-                                Task::RunSync { callback } => {
+                                Some(Task::RunSync { callback }) => {
                                     callback(&mut inner).await;
                                 }
+                                Some(Task::Kill) | None => {
+                                    // Finish any streams that are still being read before shutting down
+                                    scheduler.run_to_completion(&inner);
+                                    break;
+                                }
                             }
                         }
                     })
@@ -161,9 +196,41 @@ impl Llama {
             task_sender,
             tokenizer: arc_tokenizer,
             chat_markers: chat_markers.into(),
+            context_length,
         }
     }
 
+    /// Replace the LoRA adapters applied to the model with the given adapters and the scale of each adapter. Pass an empty list to run the base model again.
+    ///
+    /// The quantized weights of the model are shared between all adapters, so switching adapters is cheap. Requests that are already generating text continue with the new adapters.
+    ///
+    /// ```rust, no_run
+    /// use kalosm_llama::prelude::*;
+    /// use kalosm_llama::{accelerated_device_if_available, LoraAdapter};
+    /// use std::sync::Arc;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let model = Llama::new_chat().await?;
+    ///     let device = accelerated_device_if_available()?;
+    ///     let adapter = Arc::new(LoraAdapter::load("adapter_model.safetensors", &device)?);
+    ///     model.set_lora_adapters([(adapter, 1.0)]).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn set_lora_adapters(
+        &self,
+        adapters: impl IntoIterator<Item = (Arc<LoraAdapter>, f32)>,
+    ) -> anyhow::Result<()> {
+        let adapters: Vec<_> = adapters.into_iter().collect();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        kalosm_language_model::Model::run_sync(self, move |model: &mut LlamaModel| {
+            let _ = sender.send(model.set_lora_adapters(&adapters));
+            Box::pin(async {})
+        })?;
+        receiver.await?
+    }
+
     /// Get a reference to the tokenizer.
     pub(crate) fn get_tokenizer(&self) -> Arc<Tokenizer> {
         self.tokenizer.clone()
@@ -173,25 +240,59 @@ impl Llama {
         &self,
         settings: InferenceSettings,
         sampler: Arc<Mutex<dyn Sampler>>,
-    ) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<String>> {
+    ) -> anyhow::Result<TextGenerationStream> {
         let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (stop_reason_sender, stop_reason_receiver) = tokio::sync::oneshot::channel();
         self.task_sender
             .send(Task::Infer {
                 settings,
                 sender,
+                stop_reason: stop_reason_sender,
                 sampler,
             })
             .unwrap();
-        Ok(receiver)
+        Ok(TextGenerationStream::new(receiver, stop_reason_receiver))
     }
 }
 
 /// A builder with configuration for a Llama model.
-#[derive(Default)]
 pub struct LlamaBuilder {
     source: source::LlamaSource,
 
     flash_attn: bool,
+
+    max_batch_size: usize,
+
+    prefix_cache_size: usize,
+
+    rope_scaling: Option<RopeScaling>,
+
+    sliding_window: Option<usize>,
+
+    context_length: Option<usize>,
+
+    kv_cache_dtype: KvCacheDType,
+
+    max_cache_tokens: Option<(usize, CacheTruncation)>,
+
+    lora_adapters: Vec<(PathBuf, f32)>,
+}
+
+impl Default for LlamaBuilder {
+    fn default() -> Self {
+        Self {
+            source: Default::default(),
+            flash_attn: false,
+            max_batch_size: 8,
+            prefix_cache_size: 0,
+            rope_scaling: None,
+            sliding_window: None,
+            context_length: None,
+            kv_cache_dtype: KvCacheDType::F32,
+            max_cache_tokens: None,
+            lora_adapters: Vec::new(),
+        }
+    }
 }
 
 impl LlamaBuilder {
@@ -207,6 +308,90 @@ impl LlamaBuilder {
         self
     }
 
+    /// Set the maximum number of requests that will generate text at the same time. Requests beyond this limit wait until a running request finishes. (default: 8)
+    ///
+    /// Concurrent requests are run together in batched forward passes, so they share throughput instead of queueing behind each other.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Set the number of bytes the model can use to cache prompts. (default: 0, disabled)
+    ///
+    /// When a new request shares a prefix with an earlier prompt (like a system prompt or few shot examples), the cached state for that prefix is reused instead of processing those tokens again. The least recently used prompts are evicted when the cache grows past this size.
+    pub fn with_prefix_cache_size(mut self, prefix_cache_size: usize) -> Self {
+        self.prefix_cache_size = prefix_cache_size;
+        self
+    }
+
+    /// Set how the rotary position embeddings are scaled to run the model past the context length it was trained with. (default: read from the model file)
+    ///
+    /// The context length of the model is extended by the scaling factor unless it is set with [`LlamaBuilder::with_context_length`].
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScaling) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
+    /// Set the number of tokens each token can attend to for models trained with sliding window attention like Mistral. (default: read from the model file)
+    ///
+    /// Tokens outside of the window are evicted from the cache, so the memory used by the cache stops growing once the window is full.
+    pub fn with_sliding_window(mut self, sliding_window: usize) -> Self {
+        self.sliding_window = Some(sliding_window);
+        self
+    }
+
+    /// Set the maximum number of tokens the model can attend to at once. (default: read from the model file, or 4096 for GGML files)
+    pub fn with_context_length(mut self, context_length: usize) -> Self {
+        self.context_length = Some(context_length);
+        self
+    }
+
+    /// Set how the keys and values in the cache are stored. (default: [`KvCacheDType::F32`])
+    ///
+    /// Storing the cache with lower precision lets long sessions use much less memory at a small cost to the quality of the output.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KvCacheDType) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
+
+    /// Set the maximum number of tokens each session can keep in its cache and what to do when the cache is full. (default: the context length of the model)
+    ///
+    /// Without a maximum, a session that grows past the context length of the model is cleared and the most recent tokens are run through the model again.
+    pub fn with_max_cache_tokens(mut self, max_tokens: usize, truncation: CacheTruncation) -> Self {
+        self.max_cache_tokens = Some((max_tokens, truncation));
+        self
+    }
+
+    /// Add a LoRA adapter from a `.safetensors` or `.gguf` file with a scale for the output of the adapter. Adapters are added on top of the quantized weights without dequantizing the model.
+    ///
+    /// Safetensors adapters trained with PEFT read `lora_alpha` from the `adapter_config.json` next to the adapter. The adapters can be swapped after the model is built with [`Llama::set_lora_adapters`].
+    pub fn with_lora(mut self, path: impl Into<PathBuf>, scale: f32) -> Self {
+        self.lora_adapters.push((path.into(), scale));
+        self
+    }
+
+    /// Load the LoRA adapters added to the builder onto the device.
+    pub(crate) fn load_lora_adapters(
+        &self,
+        device: &Device,
+    ) -> anyhow::Result<Vec<(Arc<LoraAdapter>, f32)>> {
+        self.lora_adapters
+            .iter()
+            .map(|(path, scale)| Ok((Arc::new(LoraAdapter::load(path, device)?), *scale)))
+            .collect()
+    }
+
+    /// The settings on the builder that override the hyperparameters in the model file or change how the cache is stored.
+    pub(crate) fn config_overrides(&self) -> LlamaConfigOverrides {
+        LlamaConfigOverrides {
+            rope_scaling: self.rope_scaling,
+            sliding_window: self.sliding_window,
+            context_length: self.context_length,
+            kv_cache_dtype: self.kv_cache_dtype,
+            max_cache_tokens: self.max_cache_tokens,
+        }
+    }
+
     /// Build the model with a handler for progress as the download and loading progresses.
     pub async fn build_with_loading_handler(
         self,
@@ -225,28 +410,28 @@ impl LlamaBuilder {
                     .await
             }
         });
-        let tokenizer = {
-            let source = format!("Tokenizer ({})", self.source.tokenizer);
-            let mut create_progress = ModelLoadingProgress::downloading_progress(source);
-            self.source
-                .tokenizer(|progress| (handler.lock().unwrap())(create_progress(progress)))
-                .await?
+        let tokenizer = match &self.source.tokenizer {
+            Some(tokenizer) => {
+                let source = format!("Tokenizer ({})", tokenizer);
+                let mut create_progress = ModelLoadingProgress::downloading_progress(source);
+                self.source
+                    .tokenizer(|progress| (handler.lock().unwrap())(create_progress(progress)))
+                    .await?
+            }
+            None => None,
         };
         let filename = filename.await??;
 
         let device = accelerated_device_if_available()?;
-        let mut file = std::fs::File::open(&filename)?;
-        let model = match filename.extension().and_then(|v| v.to_str()) {
-            Some("gguf") => {
-                let model = gguf_file::Content::read(&mut file)?;
-                Model::from_gguf(model, &mut file, &device)?
-            }
-            Some("ggml" | "bin") | Some(_) | None => {
-                let model = ggml_file::Content::read(&mut file, &device)?;
-                let gqa = self.source.group_query_attention;
-                Model::from_ggml(model, gqa as usize, &device)?
-            }
-        };
+        let LoadedModel {
+            mut model,
+            tokenizer,
+            markers,
+            stop_token,
+        } = self
+            .source
+            .load(&filename, tokenizer, &self.config_overrides(), &device)?;
+        model.set_lora_adapters(&self.load_lora_adapters(&device)?)?;
 
         let cache = LlamaCache::new(model.config.n_layer);
 
@@ -255,7 +440,10 @@ impl LlamaBuilder {
             tokenizer,
             device,
             cache,
-            self.source.markers,
+            markers,
+            stop_token,
+            self.max_batch_size,
+            self.prefix_cache_size,
         ))
     }
 
@@ -273,8 +461,14 @@ pub(crate) struct InferenceSettings {
     /// The length of the sample to generate (in tokens).
     sample_len: usize,
 
-    /// The token to stop on.
-    stop_on: Option<String>,
+    /// The strings to stop on.
+    stop_on: Vec<String>,
+
+    /// The token ids to stop on.
+    stop_token_ids: Vec<u32>,
+
+    /// The seed to sample with.
+    seed: Option<u64>,
 }
 
 impl InferenceSettings {
@@ -282,7 +476,9 @@ impl InferenceSettings {
         Self {
             prompt: prompt.into(),
             sample_len: 100,
-            stop_on: None,
+            stop_on: Vec::new(),
+            stop_token_ids: Vec::new(),
+            seed: None,
         }
     }
 
@@ -291,8 +487,18 @@ impl InferenceSettings {
         self
     }
 
-    pub fn with_stop_on(mut self, stop_on: impl Into<Option<String>>) -> Self {
-        self.stop_on = stop_on.into();
+    pub fn with_stop_on(mut self, stop_on: impl IntoIterator<Item = String>) -> Self {
+        self.stop_on = stop_on.into_iter().collect();
+        self
+    }
+
+    pub fn with_stop_token_ids(mut self, stop_token_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_token_ids = stop_token_ids.into_iter().collect();
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
         self
     }
 }
@@ -1,18 +1,12 @@
 use crate::raw::cache::LlamaCache;
-use crate::{raw::Model, session::LlamaSession};
-use anyhow::{Error as E, Result};
+use crate::{raw::Model, session::LlamaSession, source::LoadedModel};
+use anyhow::Error as E;
 use kalosm_common::*;
-use kalosm_language_model::SyncModelExt;
-use std::sync::Arc;
-
-use candle_core::{
-    quantized::{ggml_file, gguf_file},
-    DType, Device,
-};
-use kalosm_language_model::SyncModel;
-use tokenizers::Tokenizer;
+use std::sync::{Arc, Mutex};
 
-use crate::InferenceSettings;
+use candle_core::{DType, Device};
+use kalosm_language_model::{PrefixCache, SyncModel};
+use tokenizers::Tokenizer;
 
 /// The inner, synchronous Llama model.
 pub struct LlamaModel {
@@ -20,6 +14,8 @@ pub struct LlamaModel {
     device: Device,
     tokenizer: Arc<Tokenizer>,
     cache: LlamaCache,
+    stop_token: Option<u32>,
+    prefix_cache: Mutex<PrefixCache<LlamaCache>>,
 }
 
 impl SyncModel for LlamaModel {
@@ -37,10 +33,39 @@ impl SyncModel for LlamaModel {
     }
 
     fn feed_tokens(&self, session: &mut Self::Session, tokens: &[u32]) -> anyhow::Result<Vec<f32>> {
-        Self::forward(&self.model, &self.device, tokens, Some(&mut session.cache))
+        // The first tokens fed into a new session can reuse the cache from an earlier prompt
+        let new_session = session.cache.tokens.is_empty();
+        let tokens = if new_session {
+            self.resume_from_prefix(&mut session.cache, tokens)
+        } else {
+            tokens
+        };
+        let logits = Self::forward(&self.model, &self.device, tokens, Some(&mut session.cache))?;
+        if new_session {
+            self.cache_prefix(&session.cache);
+        }
+        Ok(logits)
+    }
+
+    fn feed_tokens_with_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("Cannot run model on empty input"));
+        }
+        let logits = self
+            .model
+            .forward_all(tokens, &self.device, Some(&mut session.cache))?;
+        let logits = logits.to_dtype(DType::F32)?;
+        Ok(logits.to_vec2()?)
     }
 
     fn stop_token(&self) -> anyhow::Result<u32> {
+        if let Some(stop_token) = self.stop_token {
+            return Ok(stop_token);
+        }
         let vocab = self.tokenizer.get_vocab(true);
         let eos_token = match vocab.get("</s>").or(vocab.get("<|end_of_text|>")) {
             Some(token) => *token,
@@ -52,6 +77,10 @@ impl SyncModel for LlamaModel {
     fn tokenizer(&self) -> std::sync::Arc<dyn kalosm_sample::Tokenizer + Send + Sync> {
         self.tokenizer.clone() as std::sync::Arc<dyn kalosm_sample::Tokenizer + Send + Sync>
     }
+
+    fn context_length(&self) -> Option<usize> {
+        Some(self.model.config.context_length())
+    }
 }
 
 impl LlamaModel {
@@ -72,17 +101,78 @@ impl LlamaModel {
         Ok(logits)
     }
 
+    /// Run the model on a batch of independent sequences and return the logits for the last token of each sequence.
+    pub(crate) fn forward_batch(
+        &self,
+        batch: &mut [(&[u32], &mut LlamaCache)],
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        if batch.iter().any(|(tokens, _)| tokens.is_empty()) {
+            return Err(anyhow::anyhow!("Cannot run model on empty input"));
+        }
+
+        let logits = self.model.forward_batch(batch, &self.device)?;
+
+        let logits = logits.to_dtype(DType::F32)?;
+        let logits: Vec<Vec<f32>> = logits.to_vec2()?;
+        Ok(logits)
+    }
+
+    /// Restore the longest cached prefix of the tokens into an empty cache. Returns the tokens that still need to be fed into the model.
+    pub(crate) fn resume_from_prefix<'a>(
+        &self,
+        cache: &mut LlamaCache,
+        tokens: &'a [u32],
+    ) -> &'a [u32] {
+        let mut prefix_cache = self.prefix_cache.lock().unwrap();
+        if prefix_cache.is_disabled() {
+            return tokens;
+        }
+        match prefix_cache.get(tokens) {
+            Some((len, cached)) => {
+                *cache = cached;
+                &tokens[len..]
+            }
+            None => tokens,
+        }
+    }
+
+    /// Save the cache after a prompt is processed so later prompts with the same prefix can reuse it. Compacted caches no longer match a prefix of the prompt, so they are not saved.
+    pub(crate) fn cache_prefix(&self, cache: &LlamaCache) {
+        if cache.is_compacted() {
+            return;
+        }
+        let mut prefix_cache = self.prefix_cache.lock().unwrap();
+        if !prefix_cache.is_disabled() {
+            prefix_cache.insert(cache.tokens.clone(), cache.clone());
+        }
+    }
+
+    /// Replace the LoRA adapters applied to the model with the given adapters and the scale of each adapter. Cached prefixes were computed with the old adapters, so they are cleared.
+    pub fn set_lora_adapters(
+        &mut self,
+        adapters: &[(Arc<LoraAdapter>, f32)],
+    ) -> anyhow::Result<()> {
+        self.model.set_lora_adapters(adapters)?;
+        self.prefix_cache.lock().unwrap().clear();
+        Ok(())
+    }
+
     /// Create a new sync Llama model from a builder.
     pub async fn from_builder(
         builder: crate::LlamaBuilder,
         mut handler: impl FnMut(ModelLoadingProgress) + Send + Sync + 'static,
     ) -> anyhow::Result<Self> {
-        let tokenizer_source = format!("Tokenizer ({})", builder.source.tokenizer);
-        let mut create_progress = ModelLoadingProgress::downloading_progress(tokenizer_source);
-        let tokenizer = builder
-            .source
-            .tokenizer(|progress| handler(create_progress(progress)))
-            .await?;
+        let tokenizer = match &builder.source.tokenizer {
+            Some(tokenizer) => {
+                let source = format!("Tokenizer ({})", tokenizer);
+                let mut create_progress = ModelLoadingProgress::downloading_progress(source);
+                builder
+                    .source
+                    .tokenizer(|progress| handler(create_progress(progress)))
+                    .await?
+            }
+            None => None,
+        };
 
         let device = accelerated_device_if_available()?;
         let source = format!("Model ({})", builder.source.model);
@@ -91,26 +181,25 @@ impl LlamaModel {
             .source
             .model(|progress| handler(create_progress(progress)))
             .await?;
-        let mut file = std::fs::File::open(&filename)?;
-        let model = match filename.extension().and_then(|v| v.to_str()) {
-            Some("gguf") => {
-                let model = gguf_file::Content::read(&mut file)?;
-                Model::from_gguf(model, &mut file, &device)?
-            }
-            Some("ggml" | "bin") | Some(_) | None => {
-                let model = ggml_file::Content::read(&mut file, &device)?;
-                let gqa = builder.source.group_query_attention;
-                Model::from_ggml(model, gqa as usize, &device)?
-            }
-        };
+        let LoadedModel {
+            mut model,
+            tokenizer,
+            stop_token,
+            ..
+        } = builder
+            .source
+            .load(&filename, tokenizer, &builder.config_overrides(), &device)?;
+        model.set_lora_adapters(&builder.load_lora_adapters(&device)?)?;
 
         let cache = LlamaCache::new(model.config.n_layer);
-        Ok(Self {
+        Ok(Self::new(
             model,
-            tokenizer: Arc::new(tokenizer),
+            Arc::new(tokenizer),
             device,
             cache,
-        })
+            stop_token,
+            builder.prefix_cache_size,
+        ))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -119,42 +208,78 @@ impl LlamaModel {
         tokenizer: Arc<Tokenizer>,
         device: Device,
         cache: LlamaCache,
+        stop_token: Option<u32>,
+        prefix_cache_size: usize,
     ) -> Self {
         Self {
             cache,
             model,
             device,
             tokenizer,
+            stop_token,
+            prefix_cache: Mutex::new(PrefixCache::new(prefix_cache_size)),
         }
     }
+}
 
-    pub(crate) fn _infer(
-        &mut self,
-        settings: InferenceSettings,
-        sampler: std::sync::Arc<std::sync::Mutex<dyn llm_samplers::prelude::Sampler>>,
-        out: tokio::sync::mpsc::UnboundedSender<String>,
-    ) -> Result<()> {
-        let InferenceSettings {
-            prompt,
-            sample_len,
-            stop_on,
-        } = settings;
-
-        let mut session = self.new_session()?;
-
-        self.stream_text_with_sampler(
-            &mut session,
-            prompt.as_str(),
-            Some(sample_len as u32),
-            stop_on.as_deref(),
-            sampler,
-            |token| {
-                out.send(token)
-                    .map_err(|_| anyhow::anyhow!("Failed to send token to output channel"))
-                    .map(|_| kalosm_language_model::ModelFeedback::Continue)
-            },
-        )?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::tests::{dense_tensors, load_synthetic_model};
+    use tokenizers::models::bpe::BPE;
 
-        Ok(())
+    fn synthetic_model(
+        tensors: &[(String, candle_core::Tensor)],
+        prefix_cache_size: usize,
+    ) -> LlamaModel {
+        let model = load_synthetic_model(Vec::new(), tensors, &Default::default());
+        let cache = LlamaCache::new(model.config.n_layer);
+        LlamaModel::new(
+            model,
+            Arc::new(Tokenizer::new(BPE::default())),
+            Device::Cpu,
+            cache,
+            None,
+            prefix_cache_size,
+        )
+    }
+
+    fn max_error(first: &[f32], second: &[f32]) -> f32 {
+        first
+            .iter()
+            .zip(second)
+            .map(|(first, second)| (first - second).abs())
+            .fold(0., f32::max)
+    }
+
+    #[test]
+    fn resumed_session_matches_fresh_session() {
+        let tensors = dense_tensors();
+        let cached = synthetic_model(&tensors, 1 << 20);
+        let uncached = synthetic_model(&tensors, 0);
+
+        let mut session = cached.new_session().unwrap();
+        cached.feed_tokens(&mut session, &[1, 2, 3, 4, 5]).unwrap();
+
+        // The new prompt shares the first four tokens with the cached prompt
+        let prompt = [1, 2, 3, 4, 6, 7];
+        let remaining = cached.resume_from_prefix(&mut LlamaCache::new(1), &prompt);
+        assert_eq!(remaining, &prompt[4..]);
+
+        let mut resumed = cached.new_session().unwrap();
+        let mut fresh = uncached.new_session().unwrap();
+        let resumed_logits = cached.feed_tokens(&mut resumed, &prompt).unwrap();
+        let fresh_logits = uncached.feed_tokens(&mut fresh, &prompt).unwrap();
+        let error = max_error(&resumed_logits, &fresh_logits);
+        assert!(error < 1e-5, "error {error}");
+
+        // Generating more tokens from the resumed session matches the fresh session
+        for token in [2, 0, 5] {
+            let resumed_logits = cached.feed_tokens(&mut resumed, &[token]).unwrap();
+            let fresh_logits = uncached.feed_tokens(&mut fresh, &[token]).unwrap();
+            let error = max_error(&resumed_logits, &fresh_logits);
+            assert!(error < 1e-5, "error {error}");
+        }
+        assert_eq!(resumed.cache.tokens, fresh.cache.tokens);
     }
 }
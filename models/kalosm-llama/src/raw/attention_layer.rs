@@ -1,25 +1,27 @@
-use super::cache::{AttentionCache, AttentionCacheValue};
+use super::cache::{AttentionCache, AttentionCacheValue, CachedTensor, KvCacheDType};
+use super::feed_forward::FeedForward;
 use super::mask::AttentionMask;
 use super::rope::RopeCache;
 use candle_core::Device;
-use candle_core::{quantized::QMatMul, Module, Tensor};
+use candle_core::{Module, Tensor};
 use candle_transformers::quantized_nn::RmsNorm;
+use kalosm_common::LoraLinear;
 
 pub struct LlamaAttention {
-    pub attention_wq: QMatMul,
-    pub attention_wk: QMatMul,
-    pub attention_wv: QMatMul,
-    pub attention_wo: QMatMul,
+    pub attention_wq: LoraLinear,
+    pub attention_wk: LoraLinear,
+    pub attention_wv: LoraLinear,
+    pub attention_wo: LoraLinear,
     pub attention_norm: RmsNorm,
-    pub feed_forward_w1: QMatMul,
-    pub feed_forward_w2: QMatMul,
-    pub feed_forward_w3: QMatMul,
+    pub feed_forward: FeedForward,
     pub ffn_norm: RmsNorm,
     pub n_head: usize,
     pub n_kv_head: usize,
     pub head_dim: usize,
     pub hidden_size: usize,
     pub rope_cache: RopeCache,
+    pub sliding_window: Option<usize>,
+    pub kv_cache_dtype: KvCacheDType,
 }
 
 impl LlamaAttention {
@@ -32,11 +34,9 @@ impl LlamaAttention {
     ) -> candle_core::Result<Tensor> {
         let bsz = hidden_states.dims()[0];
         let q_len = hidden_states.dims()[1];
-        let hidden_size = self.hidden_size;
         let num_heads = self.n_head;
         let head_dim = self.head_dim;
         let num_key_value_heads = self.n_kv_head;
-        let num_key_value_groups = num_heads / num_key_value_heads;
         let device = hidden_states.device();
 
         let (query_states, key_states, value_states) = if matches!(device, Device::Cpu) {
@@ -100,6 +100,83 @@ impl LlamaAttention {
             (query_states, key_states, value_states)
         };
 
+        let attn_output = self.attend(
+            query_states,
+            key_states,
+            value_states,
+            attention_mask,
+            cache,
+        )?;
+
+        self.attention_wo.forward(&attn_output)
+    }
+
+    /// Run attention for several independent sequences that are packed together along the sequence dimension.
+    /// The projections run once over the packed hidden states, but each sequence is attended to separately with its own cache and mask.
+    pub(crate) fn forward_batch(
+        &self,
+        hidden_states: &Tensor,
+        segments: &mut [AttentionSegment<'_>],
+    ) -> candle_core::Result<Tensor> {
+        let bsz = hidden_states.dims()[0];
+        let num_heads = self.n_head;
+        let head_dim = self.head_dim;
+        let num_key_value_heads = self.n_kv_head;
+
+        let query_states = self.attention_wq.forward(hidden_states)?;
+        let key_states = self.attention_wk.forward(hidden_states)?;
+        let value_states = self.attention_wv.forward(hidden_states)?;
+
+        let mut outputs = Vec::with_capacity(segments.len());
+        for segment in segments.iter_mut() {
+            let q_len = segment.len;
+            let query_states = query_states
+                .narrow(1, segment.start, q_len)?
+                .reshape((bsz, q_len, num_heads, head_dim))?
+                .transpose(1, 2)?;
+            let key_states = key_states
+                .narrow(1, segment.start, q_len)?
+                .reshape((bsz, q_len, num_key_value_heads, head_dim))?
+                .transpose(1, 2)?;
+            let value_states = value_states
+                .narrow(1, segment.start, q_len)?
+                .reshape((bsz, q_len, num_key_value_heads, head_dim))?
+                .transpose(1, 2)?;
+
+            let (query_states, key_states) =
+                self.rope_cache
+                    .forward(&query_states, &key_states, segment.start_pos)?;
+
+            outputs.push(self.attend(
+                query_states,
+                key_states,
+                value_states,
+                Some(segment.mask),
+                Some(&mut *segment.cache),
+            )?);
+        }
+
+        let attn_output = Tensor::cat(&outputs, 1)?;
+
+        self.attention_wo.forward(&attn_output)
+    }
+
+    /// Attend to the (already rotated) query, key and value states, updating the cache if one is provided.
+    /// Returns the attention output before the output projection.
+    fn attend(
+        &self,
+        query_states: Tensor,
+        key_states: Tensor,
+        value_states: Tensor,
+        attention_mask: Option<&AttentionMask>,
+        cache: Option<&mut AttentionCache>,
+    ) -> candle_core::Result<Tensor> {
+        let (bsz, _, q_len, _) = query_states.dims4()?;
+        let hidden_size = self.hidden_size;
+        let num_heads = self.n_head;
+        let head_dim = self.head_dim;
+        let num_key_value_groups = num_heads / self.n_kv_head;
+
         let key_states = repeat_kv(key_states.clone(), num_key_value_groups)?;
         let value_states = repeat_kv(value_states, num_key_value_groups)?;
 
@@ -107,26 +184,19 @@ impl LlamaAttention {
             None => (key_states, value_states),
             Some(cache) => match &mut cache.0 {
                 Some(AttentionCacheValue { key, value }) => {
-                    let kv_seq_len = key_states.dim(candle_core::D::Minus2)?;
-                    let (k, v) = if kv_seq_len == 0 {
-                        (key_states, value_states)
-                    } else {
-                        let key_states = Tensor::cat(&[&*key, &key_states], 2)?.contiguous()?;
-                        let value_states =
-                            Tensor::cat(&[&*value, &value_states], 2)?.contiguous()?;
-                        (key_states, value_states)
-                    };
-
-                    *key = k.clone();
-                    *value = v.clone();
+                    let k = key.extend(&key_states)?;
+                    let v = value.extend(&value_states)?;
+                    self.evict_outside_window(key)?;
+                    self.evict_outside_window(value)?;
 
                     (k, v)
                 }
                 None => {
-                    cache.0 = Some(AttentionCacheValue {
-                        key: key_states.clone(),
-                        value: value_states.clone(),
-                    });
+                    let mut key = CachedTensor::new(&key_states, self.kv_cache_dtype)?;
+                    let mut value = CachedTensor::new(&value_states, self.kv_cache_dtype)?;
+                    self.evict_outside_window(&mut key)?;
+                    self.evict_outside_window(&mut value)?;
+                    cache.0 = Some(AttentionCacheValue { key, value });
                     (key_states, value_states)
                 }
             },
@@ -154,10 +224,38 @@ impl LlamaAttention {
 
         attn_output = attn_output.reshape(&[bsz, q_len, hidden_size])?;
 
-        attn_output = self.attention_wo.forward(&attn_output)?;
-
         Ok(attn_output)
     }
+
+    /// Drop the cached keys or values that the next token cannot attend to because they are outside of the sliding window.
+    fn evict_outside_window(&self, cached: &mut CachedTensor) -> candle_core::Result<()> {
+        let Some(window) = self.sliding_window else {
+            return Ok(());
+        };
+        let seq_len = cached.seq_len()?;
+        let keep = window - 1;
+        if seq_len > keep {
+            *cached = cached.narrow(seq_len - keep, keep)?;
+        }
+        Ok(())
+    }
+
+    /// Run the feed forward network for this layer.
+    pub(crate) fn feed_forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        self.feed_forward.forward(x)
+    }
+}
+
+/// One sequence in a packed batch passed to [`LlamaAttention::forward_batch`].
+pub(crate) struct AttentionSegment<'a> {
+    /// The offset of the sequence in the packed hidden states.
+    pub(crate) start: usize,
+    /// The number of new tokens in the sequence.
+    pub(crate) len: usize,
+    /// The position of the first new token in the sequence.
+    pub(crate) start_pos: usize,
+    pub(crate) mask: &'a AttentionMask,
+    pub(crate) cache: &'a mut AttentionCache,
 }
 
 fn repeat_kv(x: Tensor, num_key_value_groups: usize) -> candle_core::Result<Tensor> {
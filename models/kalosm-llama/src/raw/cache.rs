@@ -1,11 +1,43 @@
-use candle_core::{Device, Tensor};
+use super::rope::RopeCache;
+use candle_core::{DType, Device, Tensor, D};
+use kalosm_language_model::PrefixCacheEntry;
 use std::collections::HashMap;
 
+/// How the keys and values in a [`LlamaCache`] are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KvCacheDType {
+    /// Store the keys and values with full precision.
+    #[default]
+    F32,
+    /// Store the keys and values as 16 bit floats. This uses half of the memory of [`KvCacheDType::F32`].
+    F16,
+    /// Store the keys and values as 8 bit integers with a scale for each token in each attention head. This uses a little over a quarter of the memory of [`KvCacheDType::F32`] for most models.
+    Q8,
+}
+
+/// What to do when the number of tokens in a [`LlamaCache`] would grow past the maximum set with [`crate::LlamaBuilder::with_max_cache_tokens`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheTruncation {
+    /// Keep the first `sink_tokens` tokens and drop the oldest tokens after them to make room for new tokens.
+    ///
+    /// Models put a lot of attention on the first few tokens regardless of what they are, so keeping them ([attention sinks](https://arxiv.org/abs/2309.17453)) lets the model keep generating text after the middle of the conversation is dropped.
+    ///
+    /// Once tokens are dropped, the cache no longer matches a prefix of the text, so it cannot be rewound or reused for other prompts. With [`KvCacheDType::Q8`], the kept keys are rotated to their new positions and quantized again every time tokens are dropped, so the rounding error of the oldest keys grows a little with each drop.
+    AttentionSinks {
+        /// The number of tokens at the start of the cache to keep.
+        sink_tokens: usize,
+    },
+    /// Return an error when the cache is full.
+    Error,
+}
+
 /// A cache for Llama inference. This cache will speed up generation of sequential text significantly.
 #[derive(Debug, Clone)]
 pub struct LlamaCache {
     pub(crate) tokens: Vec<u32>,
     pub(crate) blocks: Vec<AttentionCache>,
+    // Whether tokens were dropped from the middle of the cache to make room for new tokens
+    pub(crate) compacted: bool,
 }
 
 impl LlamaCache {
@@ -18,6 +50,7 @@ impl LlamaCache {
         Self {
             tokens: Vec::new(),
             blocks,
+            compacted: false,
         }
     }
 
@@ -26,6 +59,21 @@ impl LlamaCache {
         for block in &mut self.blocks {
             *block = AttentionCache(None)
         }
+        self.compacted = false;
+    }
+
+    /// Check if tokens were dropped from the middle of the cache with [`CacheTruncation::AttentionSinks`]. Compacted caches cannot be truncated or reused for other prompts.
+    pub fn is_compacted(&self) -> bool {
+        self.compacted
+    }
+
+    /// Get the number of bytes the keys and values in the cache use.
+    pub fn memory_usage(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter_map(|block| block.0.as_ref())
+            .map(|AttentionCacheValue { key, value }| key.memory_usage() + value.memory_usage())
+            .sum()
     }
 
     /// Get the tensor map for this cache. This can be used to save the cache to disk.
@@ -33,14 +81,32 @@ impl LlamaCache {
         let mut map = HashMap::with_capacity(self.blocks.len());
         for (i, block) in self.blocks.iter().enumerate() {
             if let AttentionCache(Some(AttentionCacheValue { key, value })) = block {
-                map.insert(format!("Llama.cache.blocks.{}.key", i), key.clone());
-                map.insert(format!("Llama.cache.blocks.{}.value", i), value.clone());
+                for (name, cached) in [("key", key), ("value", value)] {
+                    match cached {
+                        CachedTensor::Float(tensor) => {
+                            map.insert(format!("Llama.cache.blocks.{i}.{name}"), tensor.clone());
+                        }
+                        CachedTensor::Q8 { quantized, scale } => {
+                            map.insert(format!("Llama.cache.blocks.{i}.{name}"), quantized.clone());
+                            map.insert(
+                                format!("Llama.cache.blocks.{i}.{name}_scale"),
+                                scale.clone(),
+                            );
+                        }
+                    }
+                }
             }
         }
         map.insert(
             "Llama.cache.tokens".to_string(),
             Tensor::from_iter(self.tokens.iter().copied(), device).unwrap(),
         );
+        if self.compacted {
+            map.insert(
+                "Llama.cache.compacted".to_string(),
+                Tensor::new(1u8, device).unwrap(),
+            );
+        }
         map
     }
 
@@ -50,52 +116,243 @@ impl LlamaCache {
             .get("Llama.cache.tokens")
             .and_then(|tokens| tokens.to_vec1().ok())
             .unwrap_or_default();
-        let mut blocks = Vec::with_capacity(24);
-        for (k, v) in map {
-            if let Some(i) = k.strip_prefix("Llama.cache.blocks.") {
-                let i = i
-                    .strip_suffix(".key")
-                    .unwrap_or_else(|| i.strip_suffix(".value").unwrap());
-                let i = i.parse::<usize>().unwrap_or(0);
-                if i >= blocks.len() {
-                    blocks.resize(i + 1, AttentionCache(None));
+        let layers = map
+            .keys()
+            .filter_map(|k| {
+                let i = k.strip_prefix("Llama.cache.blocks.")?.split('.').next()?;
+                i.parse::<usize>().ok()
+            })
+            .max()
+            .map_or(0, |i| i + 1);
+        let cached = |i: usize, name: &str| {
+            let tensor = map.get(&format!("Llama.cache.blocks.{i}.{name}"))?.clone();
+            Some(
+                match map.get(&format!("Llama.cache.blocks.{i}.{name}_scale")) {
+                    Some(scale) => CachedTensor::Q8 {
+                        quantized: tensor,
+                        scale: scale.clone(),
+                    },
+                    None => CachedTensor::Float(tensor),
+                },
+            )
+        };
+        let blocks = (0..layers)
+            .map(|i| match (cached(i, "key"), cached(i, "value")) {
+                (Some(key), Some(value)) => {
+                    AttentionCache(Some(AttentionCacheValue { key, value }))
                 }
-                if k.ends_with(".key") {
-                    match blocks.get_mut(i) {
-                        Some(AttentionCache(Some(AttentionCacheValue { key, value: _ }))) => {
-                            *key = v;
-                        }
-                        _ => {
-                            blocks[i] = AttentionCache(Some(AttentionCacheValue {
-                                key: v.clone(),
-                                value: v,
-                            }));
-                        }
-                    }
-                } else if k.ends_with(".value") {
-                    match blocks.get_mut(i) {
-                        Some(AttentionCache(Some(AttentionCacheValue { key: _, value }))) => {
-                            *value = v;
-                        }
-                        _ => {
-                            blocks[i] = AttentionCache(Some(AttentionCacheValue {
-                                key: v.clone(),
-                                value: v,
-                            }));
-                        }
-                    }
+                _ => AttentionCache(None),
+            })
+            .collect();
+        Self {
+            tokens,
+            blocks,
+            compacted: map.contains_key("Llama.cache.compacted"),
+        }
+    }
+}
+
+impl PrefixCacheEntry for LlamaCache {
+    fn truncate(&mut self, len: usize) -> anyhow::Result<()> {
+        // The tokens after the attention sinks were moved back, so the cache does not match any shorter prefix of the text
+        if self.compacted && len < self.tokens.len() {
+            anyhow::bail!("cannot truncate a cache that dropped tokens after the attention sinks");
+        }
+        for block in &mut self.blocks {
+            if let AttentionCache(Some(AttentionCacheValue { key, value })) = block {
+                let cached = key.seq_len()?;
+                // Models with a sliding window evict the oldest tokens, which would need to be run again to truncate the cache
+                if cached < self.tokens.len() && len < self.tokens.len() {
+                    anyhow::bail!(
+                        "cannot truncate a cache that evicted tokens outside of the sliding window"
+                    );
                 }
+                let len = len.min(cached);
+                *key = key.narrow(0, len)?;
+                *value = value.narrow(0, len)?;
             }
         }
-        Self { tokens, blocks }
+        self.tokens.truncate(len);
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        LlamaCache::memory_usage(self)
     }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct AttentionCache(pub(crate) Option<AttentionCacheValue>);
 
+impl AttentionCache {
+    /// Drop `len` tokens starting at `start` and move the tokens after them back by `len` positions.
+    pub(crate) fn drop_tokens(
+        &mut self,
+        start: usize,
+        len: usize,
+        rope: &RopeCache,
+    ) -> candle_core::Result<()> {
+        if let Some(AttentionCacheValue { key, value }) = &mut self.0 {
+            let rest = key.seq_len()? - start - len;
+            // The keys were rotated by their old position, so they need to be rotated back to their new position
+            let recent_keys = key.narrow(start + len, rest)?.to_tensor(DType::F32)?;
+            let recent_keys = rope.shift_back(&recent_keys, len)?;
+            let recent_values = value.narrow(start + len, rest)?.to_tensor(DType::F32)?;
+            for (cached, recent) in [(key, recent_keys), (value, recent_values)] {
+                *cached = if start == 0 {
+                    CachedTensor::new(&recent, cached.dtype())?
+                } else {
+                    let mut sinks = cached.narrow(0, start)?;
+                    sinks.append(&recent)?;
+                    sinks
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct AttentionCacheValue {
-    pub(crate) key: Tensor,
-    pub(crate) value: Tensor,
+    pub(crate) key: CachedTensor,
+    pub(crate) value: CachedTensor,
+}
+
+/// The keys or values of one layer in the cache with the shape (batch, heads, seq_len, head_dim).
+#[derive(Debug, Clone)]
+pub(crate) enum CachedTensor {
+    /// A f32 or f16 tensor
+    Float(Tensor),
+    /// The values offset by 128 and stored as u8 with one f32 scale for each token in each head
+    Q8 { quantized: Tensor, scale: Tensor },
+}
+
+impl CachedTensor {
+    pub(crate) fn new(tensor: &Tensor, dtype: KvCacheDType) -> candle_core::Result<Self> {
+        match dtype {
+            KvCacheDType::F32 => Ok(Self::Float(tensor.to_dtype(DType::F32)?)),
+            KvCacheDType::F16 => Ok(Self::Float(tensor.to_dtype(DType::F16)?)),
+            KvCacheDType::Q8 => {
+                let (quantized, scale) = quantize_q8(tensor)?;
+                Ok(Self::Q8 { quantized, scale })
+            }
+        }
+    }
+
+    pub(crate) fn dtype(&self) -> KvCacheDType {
+        match self {
+            Self::Float(tensor) if tensor.dtype() == DType::F16 => KvCacheDType::F16,
+            Self::Float(_) => KvCacheDType::F32,
+            Self::Q8 { .. } => KvCacheDType::Q8,
+        }
+    }
+
+    /// The number of tokens in the cache.
+    pub(crate) fn seq_len(&self) -> candle_core::Result<usize> {
+        match self {
+            Self::Float(tensor) => tensor.dim(2),
+            Self::Q8 { quantized, .. } => quantized.dim(2),
+        }
+    }
+
+    /// Convert the cached tokens back into a tensor with the given dtype.
+    pub(crate) fn to_tensor(&self, dtype: DType) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Float(tensor) => tensor.to_dtype(dtype),
+            Self::Q8 { quantized, scale } => (quantized.to_dtype(DType::F32)? - 128.)?
+                .broadcast_mul(scale)?
+                .to_dtype(dtype),
+        }
+    }
+
+    /// Add new tokens to the end of the cache.
+    pub(crate) fn append(&mut self, tensor: &Tensor) -> candle_core::Result<()> {
+        match self {
+            Self::Float(cached) => {
+                *cached =
+                    Tensor::cat(&[&*cached, &tensor.to_dtype(cached.dtype())?], 2)?.contiguous()?;
+            }
+            Self::Q8 { quantized, scale } => {
+                let (new_quantized, new_scale) = quantize_q8(tensor)?;
+                *quantized = Tensor::cat(&[&*quantized, &new_quantized], 2)?.contiguous()?;
+                *scale = Tensor::cat(&[&*scale, &new_scale], 2)?.contiguous()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add new tokens to the end of the cache and return every token in the cache with the dtype of the new tokens.
+    pub(crate) fn extend(&mut self, tensor: &Tensor) -> candle_core::Result<Tensor> {
+        if let Self::Float(_) = self {
+            self.append(tensor)?;
+            return self.to_tensor(tensor.dtype());
+        }
+        // Attend to the new tokens with full precision
+        let all = Tensor::cat(&[&self.to_tensor(tensor.dtype())?, tensor], 2)?.contiguous()?;
+        self.append(tensor)?;
+        Ok(all)
+    }
+
+    /// Keep `len` tokens starting at `start`.
+    pub(crate) fn narrow(&self, start: usize, len: usize) -> candle_core::Result<Self> {
+        match self {
+            Self::Float(tensor) => Ok(Self::Float(tensor.narrow(2, start, len)?.contiguous()?)),
+            Self::Q8 { quantized, scale } => Ok(Self::Q8 {
+                quantized: quantized.narrow(2, start, len)?.contiguous()?,
+                scale: scale.narrow(2, start, len)?.contiguous()?,
+            }),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let bytes = |tensor: &Tensor| tensor.elem_count() * tensor.dtype().size_in_bytes();
+        match self {
+            Self::Float(tensor) => bytes(tensor),
+            Self::Q8 { quantized, scale } => bytes(quantized) + bytes(scale),
+        }
+    }
+}
+
+/// Quantize each row of the last dimension to u8 with the absolute max of the row as the scale.
+fn quantize_q8(tensor: &Tensor) -> candle_core::Result<(Tensor, Tensor)> {
+    let tensor = tensor.to_dtype(DType::F32)?;
+    // Rows of zeros still need a scale we can divide by
+    let scale = (tensor.abs()?.max_keepdim(D::Minus1)? / 127.)?.maximum(f32::MIN_POSITIVE)?;
+    let quantized = (tensor.broadcast_div(&scale)? + 128.)?
+        .round()?
+        .to_dtype(DType::U8)?;
+    Ok((quantized, scale))
+}
+
+#[test]
+fn quantized_cache_round_trip() {
+    let device = Device::Cpu;
+    let tensor = Tensor::randn(0f32, 1., (1, 2, 5, 32), &device).unwrap();
+    let mut full = CachedTensor::new(&tensor, KvCacheDType::F32).unwrap();
+    let mut half = CachedTensor::new(&tensor, KvCacheDType::F16).unwrap();
+    let mut quantized = CachedTensor::new(&tensor, KvCacheDType::Q8).unwrap();
+    assert_eq!(half.memory_usage() * 2, full.memory_usage());
+    // One byte for each value and four bytes for the scale of every 32 values
+    assert_eq!(quantized.memory_usage() * 32, full.memory_usage() * 9);
+
+    let new = Tensor::randn(0f32, 1., (1, 2, 3, 32), &device).unwrap();
+    let expected = Tensor::cat(&[&tensor, &new], 2).unwrap();
+    for cached in [&mut full, &mut half, &mut quantized] {
+        let all = cached.extend(&new).unwrap();
+        assert_eq!(cached.seq_len().unwrap(), 8);
+        for all in [all, cached.to_tensor(DType::F32).unwrap()] {
+            let error: f32 = (all - &expected)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar()
+                .unwrap();
+            // Each value is rounded to the nearest of 255 steps between -max and max
+            assert!(error < 0.05, "{:?}: error {error}", cached.dtype());
+        }
+    }
 }
@@ -0,0 +1,129 @@
+use super::silu::fast_cpu_silu;
+use candle_core::Device;
+use candle_core::{quantized::QMatMul, DType, Module, Tensor};
+use kalosm_common::LoraLinear;
+
+/// A SwiGLU feed forward network.
+pub struct Mlp {
+    pub feed_forward_w1: LoraLinear,
+    pub feed_forward_w2: LoraLinear,
+    pub feed_forward_w3: LoraLinear,
+}
+
+impl Mlp {
+    pub(crate) fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let device = x.device();
+        if matches!(device, Device::Cpu) {
+            std::thread::scope(|scope| {
+                let w1 = scope.spawn(|| {
+                    let w1 = self.feed_forward_w1.forward(x)?;
+                    fast_cpu_silu(&w1, device)
+                });
+
+                let w3 = self.feed_forward_w3.forward(x)?;
+                let w1 = w1
+                    .join()
+                    .map_err(|_| candle_core::Error::Msg("Failed to join thread".to_string()))??;
+
+                self.feed_forward_w2.forward(&(&w1 * w3)?)
+            })
+        } else {
+            let w1 = self.feed_forward_w1.forward(x)?;
+            let w1 = fast_cpu_silu(&w1, device)?;
+
+            let w3 = self.feed_forward_w3.forward(x)?;
+
+            self.feed_forward_w2.forward(&(&w1 * w3)?)
+        }
+    }
+}
+
+/// The feed forward network of a layer.
+pub enum FeedForward {
+    /// A single feed forward network that every token runs through.
+    Dense(Mlp),
+    /// A mixture of experts (like Mixtral). Each token runs through the `n_expert_used` experts the router picks for it.
+    MixtureOfExperts {
+        router: QMatMul,
+        experts: Vec<Mlp>,
+        n_expert_used: usize,
+    },
+}
+
+impl FeedForward {
+    pub(crate) fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Dense(mlp) => mlp.forward(x),
+            Self::MixtureOfExperts {
+                router,
+                experts,
+                n_expert_used,
+            } => {
+                let (bsz, seq_len, hidden_size) = x.dims3()?;
+                let x = x.reshape(((), hidden_size))?;
+                let router_logits = router.forward(&x)?;
+                let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?
+                    .to_dtype(DType::F32)?
+                    .to_vec2::<f32>()?;
+                let routes = route_tokens(&routing_weights, experts.len(), *n_expert_used);
+
+                let mut output = x.zeros_like()?;
+                for (expert, route) in experts.iter().zip(routes) {
+                    if route.tokens.is_empty() {
+                        continue;
+                    }
+                    let tokens = Tensor::new(route.tokens.as_slice(), x.device())?;
+                    let weights = Tensor::new(route.weights.as_slice(), x.device())?
+                        .reshape(((), 1))?
+                        .to_dtype(x.dtype())?;
+                    let expert_input = x.index_select(&tokens, 0)?;
+                    let expert_output = expert.forward(&expert_input)?.broadcast_mul(&weights)?;
+                    output = output.index_add(&tokens, &expert_output, 0)?;
+                }
+                output.reshape((bsz, seq_len, hidden_size))
+            }
+        }
+    }
+}
+
+/// The tokens routed to one expert along with the weight of the expert for each token.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct ExpertRoute {
+    tokens: Vec<u32>,
+    weights: Vec<f32>,
+}
+
+/// Route each token to the `n_expert_used` experts with the highest routing weights. The weights of the chosen experts are renormalized to sum to one for each token.
+fn route_tokens(
+    routing_weights: &[Vec<f32>],
+    n_experts: usize,
+    n_expert_used: usize,
+) -> Vec<ExpertRoute> {
+    let mut routes = vec![ExpertRoute::default(); n_experts];
+    for (token, weights) in routing_weights.iter().enumerate() {
+        let mut experts: Vec<usize> = (0..weights.len()).collect();
+        experts.sort_by(|&first, &second| weights[second].total_cmp(&weights[first]));
+        let chosen = &experts[..n_expert_used.min(experts.len())];
+        let total: f32 = chosen.iter().map(|&expert| weights[expert]).sum();
+        for &expert in chosen {
+            routes[expert].tokens.push(token as u32);
+            routes[expert].weights.push(weights[expert] / total);
+        }
+    }
+    routes
+}
+
+#[test]
+fn routes_tokens_to_top_experts() {
+    let routes = route_tokens(&[vec![0.1, 0.6, 0.2, 0.1], vec![0.5, 0.1, 0.1, 0.3]], 4, 2);
+    assert_eq!(routes[0].tokens, [1]);
+    assert_eq!(routes[1].tokens, [0]);
+    assert_eq!(routes[2].tokens, [0]);
+    assert_eq!(routes[3].tokens, [1]);
+
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+    assert!(close(routes[1].weights[0], 0.75));
+    assert!(close(routes[2].weights[0], 0.25));
+    assert!(close(routes[0].weights[0], 0.625));
+    assert!(close(routes[3].weights[0], 0.375));
+}
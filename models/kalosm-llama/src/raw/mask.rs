@@ -6,15 +6,34 @@ use std::sync::RwLock;
 #[derive(Default)]
 pub struct MaskCache {
     masks: RwLock<HashMap<usize, AttentionMask>>,
+    sliding_window: Option<usize>,
 }
 
 impl MaskCache {
+    /// Create a mask cache. If there is a sliding window, tokens only attend to the tokens inside the window.
+    pub fn new(sliding_window: Option<usize>) -> Self {
+        Self {
+            masks: Default::default(),
+            sliding_window,
+        }
+    }
+
     pub fn get_mask(
         &self,
         seq_len: usize,
         seqlen_offset: usize,
         device: &Device,
     ) -> Result<AttentionMask> {
+        if let Some(window) = self.sliding_window {
+            let keys = seqlen_offset.min(window - 1) + seq_len;
+            let mask = sliding_window_mask(seq_len, seqlen_offset, window);
+            let mask = Tensor::from_slice(&mask, (seq_len, keys), device)?;
+            return Ok(AttentionMask {
+                mask: mask.unsqueeze(0)?.unsqueeze(0)?,
+                on_true: OnceCell::new(),
+            });
+        }
+
         let mask = if let Some(mask) = {
             let masks = self.masks.read().unwrap();
             masks.get(&seq_len).cloned()
@@ -74,3 +93,43 @@ impl AttentionMask {
         Ok(())
     }
 }
+
+/// Build the mask for a sliding window. The cache only keeps the last `window - 1` tokens, so the keys are the cached tokens followed by the new tokens.
+/// A key is masked if it is after the query or outside of the window of the query.
+fn sliding_window_mask(seq_len: usize, seqlen_offset: usize, window: usize) -> Vec<u8> {
+    let cached = seqlen_offset.min(window - 1);
+    let first_key = seqlen_offset - cached;
+    (0..seq_len)
+        .flat_map(|i| {
+            let query = seqlen_offset + i;
+            (0..cached + seq_len).map(move |j| {
+                let key = first_key + j;
+                u8::from(key > query || query - key >= window)
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn sliding_window_masks() {
+    // Without any cached tokens, each token attends to itself and the two tokens before it
+    assert_eq!(
+        sliding_window_mask(4, 0, 3),
+        [
+            0, 1, 1, 1, //
+            0, 0, 1, 1, //
+            0, 0, 0, 1, //
+            1, 0, 0, 0, //
+        ]
+    );
+    // The cache holds the last two tokens before the new tokens
+    assert_eq!(
+        sliding_window_mask(2, 5, 3),
+        [
+            0, 0, 0, 1, //
+            1, 0, 0, 0, //
+        ]
+    );
+    // Before the window fills up, every cached token is attended to
+    assert_eq!(sliding_window_mask(1, 1, 3), [0, 0]);
+}
@@ -1,21 +1,26 @@
 use crate::raw::attention_layer::LlamaAttention;
 use crate::raw::rope::RopeCache;
-use crate::raw::silu::fast_cpu_silu;
+use attention_layer::AttentionSegment;
 use candle_core::quantized::*;
 use candle_core::IndexOp;
 use candle_core::Module;
 use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::Embedding;
 use candle_transformers::quantized_nn::RmsNorm;
-use mask::MaskCache;
+use feed_forward::{FeedForward, Mlp};
+use kalosm_common::{LoraAdapter, LoraLinear};
+use mask::{AttentionMask, MaskCache};
+pub use rope::RopeScaling;
+use std::sync::Arc;
 
 mod attention_layer;
 pub mod cache;
+mod feed_forward;
 mod mask;
 mod rope;
 mod silu;
 
-use cache::LlamaCache;
+use cache::{CacheTruncation, KvCacheDType, LlamaCache};
 
 fn decode_norm(tensor: QTensor, eps: f64) -> candle_core::Result<RmsNorm> {
     RmsNorm::from_qtensor(tensor, eps)
@@ -24,18 +29,88 @@ fn decode_norm(tensor: QTensor, eps: f64) -> candle_core::Result<RmsNorm> {
 #[allow(unused)]
 pub(crate) struct LlamaConfig {
     rope_theta: f32,
+    rope_scaling: Option<RopeScaling>,
     context_length: usize,
     head_dimension: usize,
     rope_dimension: usize,
     n_head: usize,
     n_kv_head: usize,
     pub(crate) n_layer: usize,
+    /// The number of tokens each token can attend to (including itself) for models with sliding window attention like Mistral.
+    sliding_window: Option<usize>,
+    kv_cache_dtype: KvCacheDType,
+    /// The maximum number of tokens a cache can hold and what to do when it is full.
+    max_cache_tokens: Option<(usize, CacheTruncation)>,
 }
 
 impl LlamaConfig {
     fn hidden_size(&self) -> usize {
         self.head_dimension * self.n_head
     }
+
+    /// The maximum number of tokens the model can attend to at once.
+    pub(crate) fn context_length(&self) -> usize {
+        self.context_length
+    }
+}
+
+/// Settings from the [`crate::LlamaBuilder`] that override the hyperparameters in the model file or change how the cache is stored.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LlamaConfigOverrides {
+    pub(crate) rope_scaling: Option<RopeScaling>,
+    pub(crate) sliding_window: Option<usize>,
+    pub(crate) context_length: Option<usize>,
+    pub(crate) kv_cache_dtype: KvCacheDType,
+    pub(crate) max_cache_tokens: Option<(usize, CacheTruncation)>,
+}
+
+impl LlamaConfigOverrides {
+    fn apply(&self, config: &mut LlamaConfig) -> Result<()> {
+        if let Some(rope_scaling) = self.rope_scaling {
+            // Scaling the rope extends the context length the model can handle
+            config.context_length = match rope_scaling {
+                RopeScaling::Yarn {
+                    factor,
+                    original_context_length,
+                    ..
+                } => (original_context_length as f32 * factor) as usize,
+                _ => (config.context_length as f32 * rope_scaling.factor()) as usize,
+            };
+            config.rope_scaling = Some(rope_scaling);
+        }
+        if let Some(sliding_window) = self.sliding_window {
+            config.sliding_window = Some(sliding_window);
+        }
+        if let Some(context_length) = self.context_length {
+            config.context_length = context_length;
+        }
+        config.kv_cache_dtype = self.kv_cache_dtype;
+        config.max_cache_tokens = self.max_cache_tokens;
+        if let Some(sliding_window) = config.sliding_window {
+            if sliding_window < 2 {
+                candle_core::bail!(
+                    "the sliding window must be at least 2 tokens, but is {sliding_window}"
+                );
+            }
+        }
+        if let Some((max_tokens, truncation)) = config.max_cache_tokens {
+            if max_tokens > config.context_length {
+                candle_core::bail!(
+                    "the cache can hold at most the context length of {} tokens, but the maximum is {max_tokens} tokens",
+                    config.context_length
+                );
+            }
+            if let CacheTruncation::AttentionSinks { sink_tokens } = truncation {
+                if sink_tokens >= max_tokens {
+                    candle_core::bail!("the cache must hold more than the {sink_tokens} attention sink tokens, but the maximum is {max_tokens} tokens");
+                }
+                if config.sliding_window.is_some() {
+                    candle_core::bail!("attention sinks are not supported for models with a sliding window. The sliding window already limits the size of the cache");
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Model {
@@ -51,19 +126,26 @@ impl Model {
     pub fn from_ggml(
         mut ct: ggml_file::Content,
         gqa: usize,
+        overrides: &LlamaConfigOverrides,
         device: &Device,
     ) -> anyhow::Result<Self> {
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
         let n_layer = ct.hparams.n_layer as usize;
-        let config = LlamaConfig {
+        // GGML files do not store the context length, so it can only be changed with the builder
+        let mut config = LlamaConfig {
             rope_theta: 10000.,
+            rope_scaling: None,
             head_dimension: head_dim,
             rope_dimension: head_dim,
             n_head: ct.hparams.n_head as usize,
             n_kv_head: ct.hparams.n_head as usize / gqa,
             n_layer,
             context_length: 4096,
+            sliding_window: None,
+            kv_cache_dtype: Default::default(),
+            max_cache_tokens: None,
         };
+        overrides.apply(&mut config)?;
         let rope = RopeCache::new(&config, DType::F32, device)?;
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(device)?;
@@ -71,6 +153,8 @@ impl Model {
         let mut layers = Vec::with_capacity(n_layer);
         for layer_idx in 0..ct.hparams.n_layer {
             let prefix = format!("layers.{layer_idx}");
+            // Name the layers like GGUF models so the same adapters work with both formats
+            let lora_prefix = format!("blk.{layer_idx}");
             let attention_wq = ct.remove(&format!("{prefix}.attention.wq.weight"))?;
             let attention_wk = ct.remove(&format!("{prefix}.attention.wk.weight"))?;
             let attention_wv = ct.remove(&format!("{prefix}.attention.wv.weight"))?;
@@ -81,35 +165,61 @@ impl Model {
             let attention_norm = ct.remove(&format!("{prefix}.attention_norm.weight"))?;
             let ffn_norm = ct.remove(&format!("{prefix}.ffn_norm.weight"))?;
             layers.push(LlamaAttention {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_wq: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wq)?,
+                    format!("{lora_prefix}.attn_q"),
+                ),
+                attention_wk: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wk)?,
+                    format!("{lora_prefix}.attn_k"),
+                ),
+                attention_wv: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wv)?,
+                    format!("{lora_prefix}.attn_v"),
+                ),
+                attention_wo: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wo)?,
+                    format!("{lora_prefix}.attn_output"),
+                ),
                 attention_norm: decode_norm(attention_norm, 1e-5)?,
-                feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                feed_forward: FeedForward::Dense(Mlp {
+                    feed_forward_w1: LoraLinear::new(
+                        QMatMul::from_qtensor(feed_forward_w1)?,
+                        format!("{lora_prefix}.ffn_gate"),
+                    ),
+                    feed_forward_w2: LoraLinear::new(
+                        QMatMul::from_qtensor(feed_forward_w2)?,
+                        format!("{lora_prefix}.ffn_down"),
+                    ),
+                    feed_forward_w3: LoraLinear::new(
+                        QMatMul::from_qtensor(feed_forward_w3)?,
+                        format!("{lora_prefix}.ffn_up"),
+                    ),
+                }),
                 ffn_norm: decode_norm(ffn_norm, 1e-5)?,
                 n_head: ct.hparams.n_head as usize,
                 n_kv_head: ct.hparams.n_head as usize / gqa,
                 head_dim: (ct.hparams.n_embd / ct.hparams.n_head) as usize,
                 hidden_size: config.hidden_size(),
                 rope_cache: rope.clone(),
+                sliding_window: config.sliding_window,
+                kv_cache_dtype: config.kv_cache_dtype,
             })
         }
         Ok(Self {
+            masks: MaskCache::new(config.sliding_window),
             config,
             tok_embeddings: Embedding::new(tok_embeddings, ct.hparams.n_embd as usize),
             layers,
             norm: decode_norm(ct.remove("norm.weight")?, 1e-5)?,
             output: QMatMul::from_qtensor(output)?,
-            masks: Default::default(),
         })
     }
 
     pub fn from_gguf<R: std::io::Seek + std::io::Read>(
         ct: gguf_file::Content,
         reader: &mut R,
+        overrides: &LlamaConfigOverrides,
         device: &Device,
     ) -> Result<Self> {
         let md_get = |s: &str| match ct.metadata.get(s) {
@@ -117,30 +227,84 @@ impl Model {
             Some(v) => Ok(v),
         };
 
+        // The hyperparameters are stored under the name of the architecture. Mistral and many fine tunes use the llama architecture
+        let architecture = match ct.metadata.get("general.architecture") {
+            Some(architecture) => architecture.to_string()?.clone(),
+            None => "llama".to_string(),
+        };
+        let hparam = |s: &str| md_get(&format!("{architecture}.{s}"));
+
         // Parameter extraction from metadata.
-        let head_count = md_get("llama.attention.head_count")?.to_u32()? as usize;
-        let head_count_kv = md_get("llama.attention.head_count_kv")?.to_u32()? as usize;
-        let block_count = md_get("llama.block_count")?.to_u32()? as usize;
-        let embedding_length = md_get("llama.embedding_length")?.to_u32()? as usize;
-        let rope_dim = md_get("llama.rope.dimension_count")?.to_u32()? as usize;
+        let head_count = hparam("attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = hparam("attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = hparam("block_count")?.to_u32()? as usize;
+        let embedding_length = hparam("embedding_length")?.to_u32()? as usize;
+        let rope_dim = hparam("rope.dimension_count")
+            .and_then(|m| m.to_u32())
+            .map(|rope_dim| rope_dim as usize)
+            .unwrap_or(embedding_length / head_count);
         // Strangely this value is generally 1e-6 in GGUF file but used to be 1e-5 by default.
-        let rms_norm_eps = md_get("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rms_norm_eps = hparam("attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
 
-        let rope_freq_base = md_get("llama.rope.freq_base")
+        let rope_freq_base = hparam("rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
 
-        let context_length = md_get("llama.context_length")?.to_u32()? as usize;
+        let context_length = hparam("context_length")?.to_u32()? as usize;
+
+        // Long context models store how the rope was scaled past the context length they were trained with
+        let rope_scaling_factor = hparam("rope.scaling.factor")
+            .and_then(|m| m.to_f32())
+            .or_else(|_| hparam("rope.scale_linear").and_then(|m| m.to_f32()))
+            .ok()
+            .filter(|factor| *factor > 1.);
+        let rope_scaling_type = match hparam("rope.scaling.type") {
+            Ok(ty) => ty.to_string()?.as_str(),
+            Err(_) => "linear",
+        };
+        let rope_scaling = match (rope_scaling_type, rope_scaling_factor) {
+            (_, None) | ("none", _) => None,
+            ("linear", Some(factor)) => Some(RopeScaling::Linear { factor }),
+            ("yarn", Some(factor)) => {
+                let original_context_length = hparam("rope.scaling.original_context_length")
+                    .and_then(|m| m.to_u32())
+                    .map(|length| length as usize)
+                    .unwrap_or((context_length as f32 / factor) as usize);
+                Some(RopeScaling::yarn(factor, original_context_length))
+            }
+            (ty, _) => candle_core::bail!("unsupported rope scaling type {ty}"),
+        };
 
-        let config = LlamaConfig {
+        let sliding_window = hparam("attention.sliding_window")
+            .and_then(|m| m.to_u32())
+            .ok()
+            .map(|window| window as usize);
+
+        // Mixture of experts models like Mixtral store the number of experts and how many experts each token uses
+        let n_expert = hparam("expert_count").and_then(|m| m.to_u32()).unwrap_or(0) as usize;
+        let n_expert_used = hparam("expert_used_count")
+            .and_then(|m| m.to_u32())
+            .unwrap_or(0) as usize;
+        if n_expert > 1 && !(1..=n_expert).contains(&n_expert_used) {
+            candle_core::bail!(
+                "the model has {n_expert} experts but uses {n_expert_used} experts for each token"
+            );
+        }
+
+        let mut config = LlamaConfig {
             rope_theta: rope_freq_base,
+            rope_scaling,
             context_length,
             head_dimension: embedding_length / head_count,
             rope_dimension: rope_dim,
             n_head: head_count,
             n_kv_head: head_count_kv,
             n_layer: block_count,
+            sliding_window,
+            kv_cache_dtype: Default::default(),
+            max_cache_tokens: None,
         };
+        overrides.apply(&mut config)?;
 
         let rope = RopeCache::new(&config, DType::F32, device)?;
 
@@ -150,7 +314,11 @@ impl Model {
             ct.tensor(reader, "output_norm.weight", device)?,
             rms_norm_eps,
         )?;
-        let output = ct.tensor(reader, "output.weight", device)?;
+        // Models with tied embeddings reuse the token embeddings as the output layer
+        let output = match ct.tensor(reader, "output.weight", device) {
+            Ok(output) => output,
+            Err(_) => ct.tensor(reader, "token_embd.weight", device)?,
+        };
         let mut layers = Vec::with_capacity(block_count);
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
@@ -159,73 +327,194 @@ impl Model {
             let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
             let attention_wo =
                 ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
-            let feed_forward_w1 =
-                ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
-            let feed_forward_w2 =
-                ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
-            let feed_forward_w3 = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+            // Newer files merge the experts of each layer into one tensor
+            let merged_experts = if n_expert > 1
+                && ct
+                    .tensor_infos
+                    .contains_key(&format!("{prefix}.ffn_gate_exps.weight"))
+            {
+                Some(merged_expert_mlps(&ct, reader, &prefix, n_expert, device)?)
+            } else {
+                None
+            };
+            let mut mlp = |suffix: &str| -> Result<Mlp> {
+                let mut tensor = |name: &str| -> Result<LoraLinear> {
+                    let name = format!("{prefix}.{name}{suffix}");
+                    let tensor = ct.tensor(reader, &format!("{name}.weight"), device)?;
+                    Ok(LoraLinear::new(QMatMul::from_qtensor(tensor)?, name))
+                };
+                Ok(Mlp {
+                    feed_forward_w1: tensor("ffn_gate")?,
+                    feed_forward_w2: tensor("ffn_down")?,
+                    feed_forward_w3: tensor("ffn_up")?,
+                })
+            };
+            let feed_forward = if n_expert > 1 {
+                let experts = match merged_experts {
+                    Some(experts) => experts,
+                    None => (0..n_expert)
+                        .map(|expert| mlp(&format!(".{expert}")))
+                        .collect::<Result<Vec<_>>>()?,
+                };
+                let router = ct.tensor(reader, &format!("{prefix}.ffn_gate_inp.weight"), device)?;
+                FeedForward::MixtureOfExperts {
+                    router: QMatMul::from_qtensor(router)?,
+                    experts,
+                    n_expert_used,
+                }
+            } else {
+                FeedForward::Dense(mlp("")?)
+            };
             let attention_norm =
                 ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
             let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
             layers.push(LlamaAttention {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_wq: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wq)?,
+                    format!("{prefix}.attn_q"),
+                ),
+                attention_wk: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wk)?,
+                    format!("{prefix}.attn_k"),
+                ),
+                attention_wv: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wv)?,
+                    format!("{prefix}.attn_v"),
+                ),
+                attention_wo: LoraLinear::new(
+                    QMatMul::from_qtensor(attention_wo)?,
+                    format!("{prefix}.attn_output"),
+                ),
                 attention_norm: decode_norm(attention_norm, rms_norm_eps)?,
-                feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                feed_forward,
                 ffn_norm: decode_norm(ffn_norm, rms_norm_eps)?,
                 n_head: head_count,
                 n_kv_head: head_count_kv,
                 head_dim: embedding_length / head_count,
                 hidden_size: config.hidden_size(),
                 rope_cache: rope.clone(),
+                sliding_window: config.sliding_window,
+                kv_cache_dtype: config.kv_cache_dtype,
             })
         }
         Ok(Self {
+            masks: MaskCache::new(config.sliding_window),
             config,
             tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
-            masks: Default::default(),
         })
     }
 
+    /// Replace the LoRA adapters applied to the model with the given adapters and the scale of each adapter.
+    ///
+    /// Adapters can use either the GGUF names of the layers (`blk.0.attn_q`) or the names of the transformers model (`model.layers.0.self_attn.q_proj`). Every adapter must adapt at least one layer of the model.
+    pub fn set_lora_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) -> Result<()> {
+        let mut matched = vec![false; adapters.len()];
+        let mut updates = Vec::new();
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
+            let hf_name = |name: &str| format!("model.layers.{layer_idx}.{name}");
+            let mut projections = vec![
+                (
+                    &mut layer.attention_wq,
+                    hf_name("self_attn.q_proj"),
+                    Some(layer.n_head),
+                ),
+                (
+                    &mut layer.attention_wk,
+                    hf_name("self_attn.k_proj"),
+                    Some(layer.n_kv_head),
+                ),
+                (&mut layer.attention_wv, hf_name("self_attn.v_proj"), None),
+                (&mut layer.attention_wo, hf_name("self_attn.o_proj"), None),
+            ];
+            match &mut layer.feed_forward {
+                FeedForward::Dense(mlp) => projections.extend([
+                    (&mut mlp.feed_forward_w1, hf_name("mlp.gate_proj"), None),
+                    (&mut mlp.feed_forward_w2, hf_name("mlp.down_proj"), None),
+                    (&mut mlp.feed_forward_w3, hf_name("mlp.up_proj"), None),
+                ]),
+                FeedForward::MixtureOfExperts { experts, .. } => {
+                    for (expert, mlp) in experts.iter_mut().enumerate() {
+                        let expert_name = |name: &str| {
+                            hf_name(&format!("block_sparse_moe.experts.{expert}.{name}"))
+                        };
+                        projections.extend([
+                            (&mut mlp.feed_forward_w1, expert_name("w1"), None),
+                            (&mut mlp.feed_forward_w2, expert_name("w2"), None),
+                            (&mut mlp.feed_forward_w3, expert_name("w3"), None),
+                        ]);
+                    }
+                }
+            }
+
+            for (projection, hf_name, permute_heads) in projections {
+                let mut weights = Vec::new();
+                for ((adapter, scale), matched) in adapters.iter().zip(&mut matched) {
+                    if let Some(weight) = adapter.weight(projection.name()) {
+                        weights.push(weight.scaled(*scale));
+                    } else if let Some(weight) = adapter.weight(&hf_name) {
+                        let mut weight = weight.scaled(*scale);
+                        // GGUF models interleave the rotary halves of the query and key heads, so the output rows of transformers adapters need to be permuted the same way
+                        if let Some(heads) = permute_heads {
+                            weight.b = permute_rotary_rows(&weight.b, heads)?;
+                        }
+                        weights.push(weight);
+                    } else {
+                        continue;
+                    }
+                    *matched = true;
+                }
+                updates.push((projection, weights));
+            }
+        }
+
+        if let Some(unmatched) = matched.iter().position(|matched| !matched) {
+            candle_core::bail!("LoRA adapter {unmatched} does not adapt any layer of the model");
+        }
+        for (projection, weights) in updates {
+            projection.set_adapter_weights(weights);
+        }
+        Ok(())
+    }
+
     pub fn forward(
+        &self,
+        tokens: &[u32],
+        device: &Device,
+        cache: Option<&mut LlamaCache>,
+    ) -> Result<Tensor> {
+        let x = self.forward_hidden(tokens, device, cache)?;
+        let seq_len = x.dim(1)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        self.output.forward(&x)
+    }
+
+    /// Run the model and return the logits after each of the new tokens with the shape `(tokens, vocab)`.
+    pub fn forward_all(
+        &self,
+        tokens: &[u32],
+        device: &Device,
+        cache: Option<&mut LlamaCache>,
+    ) -> Result<Tensor> {
+        let x = self.forward_hidden(tokens, device, cache)?;
+        // If the context overflowed, earlier tokens are run again before the new tokens
+        let seq_len = x.dim(1)?;
+        let x = x.i((0, seq_len - tokens.len().min(seq_len).., ..))?;
+        self.output.forward(&x)
+    }
+
+    /// Run every layer of the model and return the normalized hidden states with the shape `(1, seq_len, hidden_size)`.
+    fn forward_hidden(
         &self,
         tokens: &[u32],
         device: &Device,
         mut cache: Option<&mut LlamaCache>,
     ) -> Result<Tensor> {
+        let (tokens, index_pos) = self.prepare_tokens(tokens, cache.as_deref_mut())?;
         let seq_len = tokens.len();
-        let cached_tokens = cache.as_ref().map(|c| c.tokens.len()).unwrap_or_default();
-        // We use a lower cutoff than the context length to avoid recomputing the attention every single token
-        let cutoff_len: usize = self.config.context_length - 32;
-        let (x, index_pos) = if seq_len + cached_tokens > self.config.context_length {
-            let all_tokens = if let Some(cache) = cache.as_mut() {
-                cache.clear();
-                let mut all_tokens = cache.tokens.clone();
-                all_tokens.extend(tokens);
-                all_tokens
-            } else {
-                tokens.to_vec()
-            };
-            let all_tokens = &all_tokens[all_tokens.len() - cutoff_len..];
-            if let Some(cache) = cache.as_mut() {
-                cache.tokens = all_tokens.to_vec();
-            }
-            assert!(all_tokens.len() <= self.config.context_length);
-            (Tensor::new(all_tokens, device)?.unsqueeze(0)?, 0)
-        } else {
-            let index_pos = cache.as_ref().map(|c| c.tokens.len()).unwrap_or_default();
-            if let Some(cache) = cache.as_mut() {
-                cache.tokens.extend_from_slice(tokens);
-            }
-            (Tensor::new(tokens, device)?.unsqueeze(0)?, index_pos)
-        };
+        let x = Tensor::new(tokens, device)?.unsqueeze(0)?;
         let mask = self.masks.get_mask(seq_len, index_pos, device)?;
 
         let mut layer_in = self.tok_embeddings.forward(&x)?;
@@ -244,36 +533,655 @@ impl Model {
             // MLP
             let residual = &x;
             let x = layer.ffn_norm.forward(&x)?;
+            layer_in = (layer.feed_forward(&x)? + residual)?;
+        }
+        self.norm.forward(&layer_in)
+    }
 
-            layer_in = if matches!(device, Device::Cpu) {
-                std::thread::scope(|scope| {
-                    let w1 = scope.spawn(|| {
-                        let w1 = layer.feed_forward_w1.forward(&x)?;
-                        fast_cpu_silu(&w1, device)
-                    });
+    /// Run the model on a batch of independent sequences, each with its own cache.
+    ///
+    /// The new tokens for every sequence are packed into a single sequence so the embedding, projection and feed forward layers
+    /// run once for the whole batch. Attention is computed for each sequence separately against its own cache.
+    ///
+    /// Returns the logits for the last token of each sequence with the shape `(batch, vocab)`.
+    pub fn forward_batch(
+        &self,
+        batch: &mut [(&[u32], &mut LlamaCache)],
+        device: &Device,
+    ) -> Result<Tensor> {
+        struct Segment {
+            start: usize,
+            len: usize,
+            start_pos: usize,
+            mask: AttentionMask,
+        }
 
-                    let w3 = layer.feed_forward_w3.forward(&x)?;
-                    let w1 = w1.join().map_err(|_| {
-                        candle_core::Error::Msg("Failed to join thread".to_string())
-                    })??;
+        let mut packed_tokens = Vec::new();
+        let mut segments = Vec::with_capacity(batch.len());
+        for (tokens, cache) in batch.iter_mut() {
+            let (tokens, start_pos) = self.prepare_tokens(tokens, Some(&mut **cache))?;
+            let mask = self.masks.get_mask(tokens.len(), start_pos, device)?;
+            segments.push(Segment {
+                start: packed_tokens.len(),
+                len: tokens.len(),
+                start_pos,
+                mask,
+            });
+            packed_tokens.extend(tokens);
+        }
+        if packed_tokens.is_empty() {
+            candle_core::bail!("Cannot run model on empty input");
+        }
 
-                    let mlp = layer.feed_forward_w2.forward(&(&w1 * w3)?)?;
+        let x = Tensor::new(packed_tokens, device)?.unsqueeze(0)?;
+        let mut layer_in = self.tok_embeddings.forward(&x)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let mut attention_segments: Vec<_> = segments
+                .iter()
+                .zip(batch.iter_mut())
+                .map(|(segment, (_, cache))| AttentionSegment {
+                    start: segment.start,
+                    len: segment.len,
+                    start_pos: segment.start_pos,
+                    mask: &segment.mask,
+                    cache: &mut cache.blocks[i],
+                })
+                .collect();
+            let attn = layer.forward_batch(&x, &mut attention_segments)?;
+            let x = (attn + residual)?;
 
-                    mlp + residual
-                })?
-            } else {
-                let w1 = layer.feed_forward_w1.forward(&x)?;
-                let w1 = fast_cpu_silu(&w1, device)?;
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            layer_in = (layer.feed_forward(&x)? + residual)?;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let last_tokens = segments
+            .iter()
+            .map(|segment| x.i((.., segment.start + segment.len - 1, ..)))
+            .collect::<Result<Vec<_>>>()?;
+        let x = Tensor::cat(&last_tokens, 0)?;
+        self.output.forward(&x)
+    }
 
-                let w3 = layer.feed_forward_w3.forward(&x)?;
+    /// Add the new tokens to the cache and return the tokens that need to be run through the model along with the position of the first token.
+    ///
+    /// If the tokens would overflow the maximum number of cache tokens, the cache is truncated with the [`CacheTruncation`] of the model.
+    /// If the tokens would overflow the context length, the cache is cleared and the most recent tokens are run from the start of the sequence.
+    fn prepare_tokens(
+        &self,
+        tokens: &[u32],
+        mut cache: Option<&mut LlamaCache>,
+    ) -> Result<(Vec<u32>, usize)> {
+        if let (Some(cache), Some((max_tokens, truncation))) =
+            (cache.as_deref_mut(), self.config.max_cache_tokens)
+        {
+            if cache.tokens.len() + tokens.len() > max_tokens {
+                match truncation {
+                    CacheTruncation::AttentionSinks { sink_tokens } => {
+                        self.drop_middle_tokens(cache, tokens.len(), max_tokens, sink_tokens)?
+                    }
+                    CacheTruncation::Error => candle_core::bail!(
+                        "adding {} tokens to a cache with {} tokens would exceed the maximum of {max_tokens} cache tokens",
+                        tokens.len(),
+                        cache.tokens.len()
+                    ),
+                }
+            }
+        }
 
-                let mlp = layer.feed_forward_w2.forward(&(&w1 * w3)?)?;
+        let cached_tokens = cache.as_ref().map(|c| c.tokens.len()).unwrap_or_default();
+        // We use a lower cutoff than the context length to avoid recomputing the attention every single token
+        let cutoff_len: usize = self.config.context_length - 32;
+        if tokens.len() + cached_tokens > self.config.context_length {
+            let all_tokens = match cache {
+                Some(cache) => {
+                    cache.clear();
+                    let mut all_tokens = std::mem::take(&mut cache.tokens);
+                    all_tokens.extend(tokens);
+                    let all_tokens =
+                        all_tokens[all_tokens.len().saturating_sub(cutoff_len)..].to_vec();
+                    cache.tokens = all_tokens.clone();
+                    all_tokens
+                }
+                None => tokens[tokens.len().saturating_sub(cutoff_len)..].to_vec(),
+            };
+            assert!(all_tokens.len() <= self.config.context_length);
+            Ok((all_tokens, 0))
+        } else {
+            let index_pos = cached_tokens;
+            if let Some(cache) = cache {
+                cache.tokens.extend_from_slice(tokens);
+            }
+            Ok((tokens.to_vec(), index_pos))
+        }
+    }
 
-                (mlp + residual)?
+    /// Drop the oldest tokens after the attention sinks to make room for the new tokens. The tokens after the dropped tokens are moved back so the positions stay inside the context length.
+    fn drop_middle_tokens(
+        &self,
+        cache: &mut LlamaCache,
+        new_tokens: usize,
+        max_tokens: usize,
+        sink_tokens: usize,
+    ) -> Result<()> {
+        if sink_tokens + new_tokens > max_tokens {
+            candle_core::bail!(
+                "{new_tokens} tokens do not fit in a cache with {sink_tokens} attention sink tokens and a maximum of {max_tokens} tokens"
+            );
+        }
+        // We drop more tokens than we need to avoid moving the cache every single token
+        let target_len = max_tokens.saturating_sub(32).max(sink_tokens + new_tokens);
+        let drop = cache.tokens.len() + new_tokens - target_len;
+        for (block, layer) in cache.blocks.iter_mut().zip(&self.layers) {
+            block.drop_tokens(sink_tokens, drop, &layer.rope_cache)?;
+        }
+        cache.tokens.drain(sink_tokens..sink_tokens + drop);
+        cache.compacted = true;
+        Ok(())
+    }
+}
+
+/// Read the feed forward networks of every expert in a layer from the merged expert tensors (like `blk.0.ffn_gate_exps.weight`).
+///
+/// The experts are stored one after another along the outermost dimension, so each expert is a contiguous range of the quantized data that can be loaded as a separate tensor. The layers use the same names as files with a separate tensor for each expert (like `blk.0.ffn_gate.3`) for LoRA adapters.
+fn merged_expert_mlps<R: std::io::Seek + std::io::Read>(
+    ct: &gguf_file::Content,
+    reader: &mut R,
+    prefix: &str,
+    n_expert: usize,
+    device: &Device,
+) -> Result<Vec<Mlp>> {
+    let mut experts = |name: &str| -> Result<Vec<LoraLinear>> {
+        let tensor_name = format!("{prefix}.{name}_exps.weight");
+        let Some(info) = ct.tensor_infos.get(&tensor_name) else {
+            candle_core::bail!("cannot find tensor info for {tensor_name}")
+        };
+        let (experts, expert_dims) = match info.shape.dims() {
+            [experts, expert_dims @ ..] if expert_dims.len() == 2 => (*experts, expert_dims),
+            dims => {
+                candle_core::bail!("expected {tensor_name} to have 3 dimensions, found {dims:?}")
             }
+        };
+        if experts != n_expert {
+            candle_core::bail!("{tensor_name} has {experts} experts, expected {n_expert}");
         }
-        let x = self.norm.forward(&layer_in)?;
-        let x = x.i((.., seq_len - 1, ..))?;
-        self.output.forward(&x)
+        let dtype = info.ggml_dtype;
+        let expert_bytes =
+            expert_dims.iter().product::<usize>() / dtype.block_size() * dtype.type_size();
+        let mut data = vec![0u8; expert_bytes * experts];
+        reader.seek(std::io::SeekFrom::Start(
+            ct.tensor_data_offset + info.offset,
+        ))?;
+        reader.read_exact(&mut data)?;
+        data.chunks_exact(expert_bytes)
+            .enumerate()
+            .map(|(expert, data)| {
+                let tensor =
+                    ggml_file::qtensor_from_ggml(dtype, data, expert_dims.to_vec(), device)?;
+                Ok(LoraLinear::new(
+                    QMatMul::from_qtensor(tensor)?,
+                    format!("{prefix}.{name}.{expert}"),
+                ))
+            })
+            .collect()
+    };
+    let gates = experts("ffn_gate")?;
+    let downs = experts("ffn_down")?;
+    let ups = experts("ffn_up")?;
+    Ok(gates
+        .into_iter()
+        .zip(downs)
+        .zip(ups)
+        .map(
+            |((feed_forward_w1, feed_forward_w2), feed_forward_w3)| Mlp {
+                feed_forward_w1,
+                feed_forward_w2,
+                feed_forward_w3,
+            },
+        )
+        .collect())
+}
+
+/// Permute the rows of a query or key projection from the layout of transformers models to the layout of GGUF models (the same permutation llama.cpp applies when converting models).
+fn permute_rotary_rows(weight: &Tensor, heads: usize) -> Result<Tensor> {
+    let (rows, columns) = weight.dims2()?;
+    weight
+        .reshape((heads, 2, rows / heads / 2, columns))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((rows, columns))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use gguf_file::Value;
+    use std::io::Cursor;
+
+    const VOCAB: usize = 8;
+    const HIDDEN: usize = 8;
+    const HEADS: usize = 2;
+    const FEED_FORWARD: usize = 16;
+
+    fn random(shape: &[usize]) -> Tensor {
+        Tensor::randn(0f32, 0.5, shape, &Device::Cpu).unwrap()
+    }
+
+    /// Write a single layer model to an in memory GGUF file and load it.
+    pub(crate) fn load_synthetic_model(
+        mut metadata: Vec<(&str, Value)>,
+        tensors: &[(String, Tensor)],
+        overrides: &LlamaConfigOverrides,
+    ) -> Model {
+        metadata.extend([
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.attention.head_count", Value::U32(HEADS as u32)),
+            ("llama.attention.head_count_kv", Value::U32(HEADS as u32)),
+            ("llama.block_count", Value::U32(1)),
+            ("llama.embedding_length", Value::U32(HIDDEN as u32)),
+            (
+                "llama.rope.dimension_count",
+                Value::U32((HIDDEN / HEADS) as u32),
+            ),
+            ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+            ("llama.context_length", Value::U32(64)),
+        ]);
+        let metadata: Vec<_> = metadata.iter().map(|(key, value)| (*key, value)).collect();
+        let tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, tensor)| (name, QTensor::quantize(tensor, GgmlDType::F32).unwrap()))
+            .collect();
+        let tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, tensor)| (name.as_str(), tensor))
+            .collect();
+
+        let mut file = Cursor::new(Vec::new());
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+        file.set_position(0);
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        Model::from_gguf(content, &mut file, overrides, &Device::Cpu).unwrap()
+    }
+
+    /// The tensors every layer has besides the feed forward network.
+    fn attention_tensors() -> Vec<(String, Tensor)> {
+        vec![
+            ("token_embd.weight".to_string(), random(&[VOCAB, HIDDEN])),
+            ("output_norm.weight".to_string(), random(&[HIDDEN])),
+            ("output.weight".to_string(), random(&[VOCAB, HIDDEN])),
+            ("blk.0.attn_q.weight".to_string(), random(&[HIDDEN, HIDDEN])),
+            ("blk.0.attn_k.weight".to_string(), random(&[HIDDEN, HIDDEN])),
+            ("blk.0.attn_v.weight".to_string(), random(&[HIDDEN, HIDDEN])),
+            (
+                "blk.0.attn_output.weight".to_string(),
+                random(&[HIDDEN, HIDDEN]),
+            ),
+            ("blk.0.attn_norm.weight".to_string(), random(&[HIDDEN])),
+            ("blk.0.ffn_norm.weight".to_string(), random(&[HIDDEN])),
+        ]
+    }
+
+    /// The tensors of a model with a dense feed forward network.
+    pub(crate) fn dense_tensors() -> Vec<(String, Tensor)> {
+        let mut tensors = attention_tensors();
+        tensors.extend([
+            (
+                "blk.0.ffn_gate.weight".to_string(),
+                random(&[FEED_FORWARD, HIDDEN]),
+            ),
+            (
+                "blk.0.ffn_down.weight".to_string(),
+                random(&[HIDDEN, FEED_FORWARD]),
+            ),
+            (
+                "blk.0.ffn_up.weight".to_string(),
+                random(&[FEED_FORWARD, HIDDEN]),
+            ),
+        ]);
+        tensors
+    }
+
+    fn max_error(first: &Tensor, second: &Tensor) -> f32 {
+        (first - second)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap()
+    }
+
+    #[test]
+    fn mixture_of_identical_experts_matches_dense_model() {
+        let shared = attention_tensors();
+        let gate = random(&[FEED_FORWARD, HIDDEN]);
+        let down = random(&[HIDDEN, FEED_FORWARD]);
+        let up = random(&[FEED_FORWARD, HIDDEN]);
+
+        let mut dense_tensors = shared.clone();
+        dense_tensors.extend([
+            ("blk.0.ffn_gate.weight".to_string(), gate.clone()),
+            ("blk.0.ffn_down.weight".to_string(), down.clone()),
+            ("blk.0.ffn_up.weight".to_string(), up.clone()),
+        ]);
+        let dense = load_synthetic_model(Vec::new(), &dense_tensors, &Default::default());
+
+        let tokens = [1, 5, 2, 7];
+        let expected = dense.forward_all(&tokens, &Device::Cpu, None).unwrap();
+
+        // Every expert is the same as the dense feed forward network, so the renormalized mixture of the experts must match the dense model for any routing
+        let experts = 4;
+        for experts_used in [1, 2, experts] {
+            let mut tensors = shared.clone();
+            tensors.push((
+                "blk.0.ffn_gate_inp.weight".to_string(),
+                random(&[experts, HIDDEN]),
+            ));
+            for expert in 0..experts {
+                tensors.extend([
+                    (format!("blk.0.ffn_gate.{expert}.weight"), gate.clone()),
+                    (format!("blk.0.ffn_down.{expert}.weight"), down.clone()),
+                    (format!("blk.0.ffn_up.{expert}.weight"), up.clone()),
+                ]);
+            }
+            let mixture = load_synthetic_model(
+                vec![
+                    ("llama.expert_count", Value::U32(experts as u32)),
+                    ("llama.expert_used_count", Value::U32(experts_used as u32)),
+                ],
+                &tensors,
+                &Default::default(),
+            );
+            assert!(matches!(
+                mixture.layers[0].feed_forward,
+                FeedForward::MixtureOfExperts { .. }
+            ));
+
+            let logits = mixture.forward_all(&tokens, &Device::Cpu, None).unwrap();
+            let error = max_error(&logits, &expected);
+            assert!(error < 1e-4, "{experts_used} experts: error {error}");
+        }
+    }
+
+    #[test]
+    fn merged_expert_tensors_match_separate_expert_tensors() {
+        let experts = 4;
+        let gates: Vec<_> = (0..experts)
+            .map(|_| random(&[FEED_FORWARD, HIDDEN]))
+            .collect();
+        let downs: Vec<_> = (0..experts)
+            .map(|_| random(&[HIDDEN, FEED_FORWARD]))
+            .collect();
+        let ups: Vec<_> = (0..experts)
+            .map(|_| random(&[FEED_FORWARD, HIDDEN]))
+            .collect();
+        let mut shared = attention_tensors();
+        shared.push((
+            "blk.0.ffn_gate_inp.weight".to_string(),
+            random(&[experts, HIDDEN]),
+        ));
+        let metadata = || {
+            vec![
+                ("llama.expert_count", Value::U32(experts as u32)),
+                ("llama.expert_used_count", Value::U32(2)),
+            ]
+        };
+
+        let mut separate_tensors = shared.clone();
+        for expert in 0..experts {
+            separate_tensors.extend([
+                (
+                    format!("blk.0.ffn_gate.{expert}.weight"),
+                    gates[expert].clone(),
+                ),
+                (
+                    format!("blk.0.ffn_down.{expert}.weight"),
+                    downs[expert].clone(),
+                ),
+                (format!("blk.0.ffn_up.{expert}.weight"), ups[expert].clone()),
+            ]);
+        }
+        let separate = load_synthetic_model(metadata(), &separate_tensors, &Default::default());
+
+        let mut merged_tensors = shared;
+        merged_tensors.extend([
+            (
+                "blk.0.ffn_gate_exps.weight".to_string(),
+                Tensor::stack(&gates, 0).unwrap(),
+            ),
+            (
+                "blk.0.ffn_down_exps.weight".to_string(),
+                Tensor::stack(&downs, 0).unwrap(),
+            ),
+            (
+                "blk.0.ffn_up_exps.weight".to_string(),
+                Tensor::stack(&ups, 0).unwrap(),
+            ),
+        ]);
+        let merged = load_synthetic_model(metadata(), &merged_tensors, &Default::default());
+
+        let tokens = [1, 5, 2, 7];
+        let expected = separate.forward_all(&tokens, &Device::Cpu, None).unwrap();
+        let logits = merged.forward_all(&tokens, &Device::Cpu, None).unwrap();
+        assert!(max_error(&logits, &expected) < 1e-5);
+    }
+
+    #[test]
+    fn sliding_window_cache_matches_full_attention() {
+        let window = 4;
+        let model = load_synthetic_model(
+            vec![("llama.attention.sliding_window", Value::U32(window))],
+            &dense_tensors(),
+            &Default::default(),
+        );
+
+        let tokens = [3, 1, 4, 1, 5, 2, 6, 5, 3, 5, 7, 2];
+        let expected = model.forward_all(&tokens, &Device::Cpu, None).unwrap();
+
+        // Feed a prompt longer than the window, then decode one token at a time with the cache
+        let mut cache = LlamaCache::new(1);
+        let prompt = 6;
+        let mut logits = vec![model
+            .forward_all(&tokens[..prompt], &Device::Cpu, Some(&mut cache))
+            .unwrap()];
+        for token in &tokens[prompt..] {
+            logits.push(
+                model
+                    .forward_all(&[*token], &Device::Cpu, Some(&mut cache))
+                    .unwrap(),
+            );
+        }
+        let logits = Tensor::cat(&logits, 0).unwrap();
+
+        let error = max_error(&logits, &expected);
+        assert!(error < 1e-4, "error {error}");
+
+        // Only the tokens the next token can attend to are kept in the cache
+        let cached = cache.blocks[0].0.as_ref().unwrap().key.seq_len().unwrap();
+        assert_eq!(cached, window as usize - 1);
+        assert_eq!(cache.tokens.len(), tokens.len());
+    }
+
+    #[test]
+    fn attention_sinks_bound_the_cache() {
+        let max_tokens = 40;
+        let sink_tokens = 4;
+        let model = load_synthetic_model(
+            Vec::new(),
+            &dense_tensors(),
+            &LlamaConfigOverrides {
+                kv_cache_dtype: KvCacheDType::Q8,
+                max_cache_tokens: Some((
+                    max_tokens,
+                    CacheTruncation::AttentionSinks { sink_tokens },
+                )),
+                ..Default::default()
+            },
+        );
+
+        let prompt = [1, 2, 3, 4, 5, 6, 7, 1, 2, 3];
+        let mut cache = LlamaCache::new(1);
+        model
+            .forward(&prompt, &Device::Cpu, Some(&mut cache))
+            .unwrap();
+        // Generate past the context length of the model
+        for i in 0..100 {
+            model
+                .forward(&[i % VOCAB as u32], &Device::Cpu, Some(&mut cache))
+                .unwrap();
+            assert!(cache.tokens.len() <= max_tokens);
+            let cached = cache.blocks[0].0.as_ref().unwrap().key.seq_len().unwrap();
+            assert_eq!(cached, cache.tokens.len());
+        }
+        assert_eq!(cache.tokens[..sink_tokens], prompt[..sink_tokens]);
+
+        let model = load_synthetic_model(
+            Vec::new(),
+            &dense_tensors(),
+            &LlamaConfigOverrides {
+                max_cache_tokens: Some((max_tokens, CacheTruncation::Error)),
+                ..Default::default()
+            },
+        );
+        let mut cache = LlamaCache::new(1);
+        model
+            .forward(&[1; 40], &Device::Cpu, Some(&mut cache))
+            .unwrap();
+        assert!(model.forward(&[1], &Device::Cpu, Some(&mut cache)).is_err());
+    }
+
+    #[test]
+    fn quantized_cache_drift_over_repeated_drops() {
+        use cache::{AttentionCache, AttentionCacheValue, CachedTensor};
+
+        let model = load_synthetic_model(Vec::new(), &dense_tensors(), &Default::default());
+        let rope = &model.layers[0].rope_cache;
+        let values = |tokens: usize| {
+            random(&[1, HEADS, tokens, HIDDEN / HEADS])
+                .clamp(-0.3f32, 0.3f32)
+                .unwrap()
+        };
+        let initial = values(16);
+        let cache = |dtype| {
+            AttentionCache(Some(AttentionCacheValue {
+                key: CachedTensor::new(&initial, dtype).unwrap(),
+                value: CachedTensor::new(&initial, dtype).unwrap(),
+            }))
+        };
+        let mut full = cache(KvCacheDType::F32);
+        let mut quantized = cache(KvCacheDType::Q8);
+
+        for _ in 0..20 {
+            let new = values(1);
+            for cache in [&mut full, &mut quantized] {
+                cache.drop_tokens(2, 1, rope).unwrap();
+                let AttentionCacheValue { key, value } = cache.0.as_mut().unwrap();
+                key.append(&new).unwrap();
+                value.append(&new).unwrap();
+            }
+        }
+
+        // Each drop quantizes the rotated keys again, which adds at most half a quantization step (1/254 of the largest value in the row) of error to the oldest keys
+        let keys =
+            |cache: &AttentionCache| cache.0.as_ref().unwrap().key.to_tensor(DType::F32).unwrap();
+        let error = max_error(&keys(&full), &keys(&quantized));
+        assert!(error < 0.05, "error {error}");
+    }
+
+    #[test]
+    fn compacted_cache_cannot_be_truncated() {
+        use kalosm_language_model::PrefixCacheEntry;
+
+        let model = load_synthetic_model(
+            Vec::new(),
+            &dense_tensors(),
+            &LlamaConfigOverrides {
+                max_cache_tokens: Some((60, CacheTruncation::AttentionSinks { sink_tokens: 4 })),
+                ..Default::default()
+            },
+        );
+        let mut cache = LlamaCache::new(1);
+        model
+            .forward(&[1; 55], &Device::Cpu, Some(&mut cache))
+            .unwrap();
+        assert!(!cache.is_compacted());
+        cache.truncate(52).unwrap();
+
+        model
+            .forward(&[2; 10], &Device::Cpu, Some(&mut cache))
+            .unwrap();
+        assert!(cache.is_compacted());
+        assert!(cache.truncate(10).is_err());
+        // Saving and loading the cache keeps it compacted
+        let loaded = LlamaCache::from_tensor_map(cache.get_tensor_map(&Device::Cpu));
+        assert!(loaded.is_compacted());
+    }
+
+    #[test]
+    fn lora_adapters_match_merged_weights() {
+        let tensors = dense_tensors();
+        let mut model = load_synthetic_model(Vec::new(), &tensors, &Default::default());
+        let tokens = [1, 5, 2, 7];
+        let base = model.forward_all(&tokens, &Device::Cpu, None).unwrap();
+
+        let rank = 2;
+        let a = random(&[rank, FEED_FORWARD]);
+        let b = random(&[HIDDEN, rank]);
+        let adapter = Arc::new(LoraAdapter::new(
+            [(
+                "blk.0.ffn_down".to_string(),
+                kalosm_common::LoraWeight {
+                    a: a.clone(),
+                    b: b.clone(),
+                    scale: 0.5,
+                },
+            )]
+            .into(),
+        ));
+        model.set_lora_adapters(&[(adapter.clone(), 2.)]).unwrap();
+        let adapted = model.forward_all(&tokens, &Device::Cpu, None).unwrap();
+
+        // Merge the adapter into the quantized weights to get the expected output
+        let merged_tensors: Vec<_> = tensors
+            .into_iter()
+            .map(|(name, tensor)| {
+                let tensor = if name == "blk.0.ffn_down.weight" {
+                    (tensor + b.matmul(&a).unwrap()).unwrap()
+                } else {
+                    tensor
+                };
+                (name, tensor)
+            })
+            .collect();
+        let merged = load_synthetic_model(Vec::new(), &merged_tensors, &Default::default());
+        let expected = merged.forward_all(&tokens, &Device::Cpu, None).unwrap();
+        let error = max_error(&adapted, &expected);
+        assert!(error < 1e-4, "error {error}");
+
+        // Removing the adapter restores the base model
+        model.set_lora_adapters(&[]).unwrap();
+        let restored = model.forward_all(&tokens, &Device::Cpu, None).unwrap();
+        assert_eq!(max_error(&restored, &base), 0.);
+
+        // Adapters that do not match any layer are rejected without changing the model
+        let unmatched = Arc::new(LoraAdapter::new(
+            [(
+                "blk.7.attn_q".to_string(),
+                adapter.weight("blk.0.ffn_down").unwrap().clone(),
+            )]
+            .into(),
+        ));
+        assert!(model
+            .set_lora_adapters(&[(adapter, 1.), (unmatched, 1.)])
+            .is_err());
+        let unchanged = model.forward_all(&tokens, &Device::Cpu, None).unwrap();
+        assert_eq!(max_error(&unchanged, &base), 0.);
     }
 }
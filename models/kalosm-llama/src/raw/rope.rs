@@ -1,22 +1,128 @@
 use super::LlamaConfig;
 use candle_core::{DType, Device, Tensor};
 
+/// How the rotary position embeddings are scaled to extend the context length of a model past the context length it was trained with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RopeScaling {
+    /// Divide each position by the factor (position interpolation). Every frequency is interpolated evenly.
+    Linear {
+        /// The factor to extend the context length by.
+        factor: f32,
+    },
+    /// Increase the rope base (NTK-aware scaling). Low frequencies are interpolated while high frequencies are mostly kept, which works better than linear scaling without fine tuning.
+    Ntk {
+        /// The factor to extend the context length by.
+        factor: f32,
+    },
+    /// Interpolate low frequencies, keep high frequencies, blend between the two and scale the attention logits ([YaRN](https://arxiv.org/abs/2309.00071)).
+    Yarn {
+        /// The factor to extend the context length by.
+        factor: f32,
+        /// The context length the model was trained with before scaling.
+        original_context_length: usize,
+        /// Frequencies that rotate more than this many times over the original context length are kept.
+        beta_fast: f32,
+        /// Frequencies that rotate fewer than this many times over the original context length are interpolated.
+        beta_slow: f32,
+    },
+}
+
+impl RopeScaling {
+    /// Create YaRN scaling with the default `beta_fast` of 32 and `beta_slow` of 1.
+    pub fn yarn(factor: f32, original_context_length: usize) -> Self {
+        Self::Yarn {
+            factor,
+            original_context_length,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        }
+    }
+
+    /// Get the factor the context length is extended by.
+    pub fn factor(&self) -> f32 {
+        match self {
+            Self::Linear { factor } | Self::Ntk { factor } | Self::Yarn { factor, .. } => *factor,
+        }
+    }
+}
+
+/// Get the inverse frequency of each pair of dimensions along with the factor to scale the embeddings by.
+fn rope_frequencies(base: f32, dimension: usize, scaling: Option<RopeScaling>) -> (Vec<f32>, f32) {
+    let frequencies = |base: f32| {
+        (0..dimension)
+            .step_by(2)
+            .map(|i| 1. / base.powf(i as f32 / dimension as f32))
+            .collect::<Vec<_>>()
+    };
+    match scaling {
+        None => (frequencies(base), 1.),
+        Some(RopeScaling::Linear { factor }) => (
+            frequencies(base)
+                .into_iter()
+                .map(|frequency| frequency / factor)
+                .collect(),
+            1.,
+        ),
+        Some(RopeScaling::Ntk { factor }) => {
+            let dimension = dimension as f32;
+            (
+                frequencies(base * factor.powf(dimension / (dimension - 2.))),
+                1.,
+            )
+        }
+        Some(RopeScaling::Yarn {
+            factor,
+            original_context_length,
+            beta_fast,
+            beta_slow,
+        }) => {
+            // The dimension that rotates the given number of times over the original context length
+            let correction_dimension = |rotations: f32| {
+                dimension as f32
+                    * (original_context_length as f32 / (rotations * 2. * std::f32::consts::PI))
+                        .ln()
+                    / (2. * base.ln())
+            };
+            let low = correction_dimension(beta_fast).floor().max(0.);
+            let mut high = correction_dimension(beta_slow)
+                .ceil()
+                .min(dimension as f32 - 1.);
+            if low == high {
+                high += 0.001;
+            }
+            let frequencies = frequencies(base)
+                .into_iter()
+                .enumerate()
+                .map(|(i, frequency)| {
+                    // 1 for the high frequencies that are kept and 0 for the low frequencies that are interpolated
+                    let keep = 1. - ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                    frequency / factor * (1. - keep) + frequency * keep
+                })
+                .collect();
+            let attention_factor = if factor <= 1. {
+                1.
+            } else {
+                0.1 * factor.ln() + 1.
+            };
+            (frequencies, attention_factor)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RopeCache {
     sin: Tensor,
     cos: Tensor,
+    inverse_frequency: Tensor,
 }
 
 impl RopeCache {
     pub fn new(config: &LlamaConfig, dtype: DType, device: &Device) -> candle_core::Result<Self> {
-        let inverse_frequency = (0..config.head_dimension)
-            .step_by(2)
-            .map(|i| {
-                1. / (config
-                    .rope_theta
-                    .powf(i as f32 / config.head_dimension as f32))
-            })
-            .collect::<Vec<_>>();
+        let (inverse_frequency, attention_factor) = rope_frequencies(
+            config.rope_theta,
+            config.head_dimension,
+            config.rope_scaling,
+        );
         let inverse_frequency_len = inverse_frequency.len();
         let inverse_frequency =
             Tensor::from_vec(inverse_frequency, (1, inverse_frequency_len), device)?
@@ -29,10 +135,31 @@ impl RopeCache {
 
         let outer_product = llama_context_length_indices.matmul(&inverse_frequency)?;
 
-        let sin = outer_product.sin()?;
-        let cos = outer_product.cos()?;
+        let sin = (outer_product.sin()? * attention_factor as f64)?;
+        let cos = (outer_product.cos()? * attention_factor as f64)?;
+
+        Ok(Self {
+            sin,
+            cos,
+            inverse_frequency,
+        })
+    }
 
-        Ok(Self { sin, cos })
+    /// Rotate keys that were embedded at their position back by `distance` positions. This is used to move tokens back after tokens before them are dropped from the cache.
+    pub fn shift_back(&self, x: &Tensor, distance: usize) -> candle_core::Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let (_, frequencies) = self.inverse_frequency.dims2()?;
+        let angles = (&self.inverse_frequency * distance as f64)?;
+        let cos = angles
+            .cos()?
+            .broadcast_as((seq_len, frequencies))?
+            .contiguous()?;
+        let sin = angles
+            .sin()?
+            .neg()?
+            .broadcast_as((seq_len, frequencies))?
+            .contiguous()?;
+        candle_nn::rotary_emb::rope_i(&x.contiguous()?.to_dtype(cos.dtype())?, &cos, &sin)
     }
 
     pub fn forward(
@@ -78,12 +205,16 @@ impl RopeCache {
 fn test_rope_cache() {
     let config = LlamaConfig {
         rope_theta: 5000.,
+        rope_scaling: None,
         context_length: 6,
         rope_dimension: 2,
         head_dimension: 2,
         n_head: 0,
         n_kv_head: 0,
         n_layer: 0,
+        sliding_window: None,
+        kv_cache_dtype: Default::default(),
+        max_cache_tokens: None,
     };
     let device = Device::cuda_if_available(0).unwrap();
     let cache = RopeCache::new(&config, DType::F32, &device).unwrap();
@@ -132,3 +263,68 @@ fn test_rope_cache() {
         .unwrap();
     assert!(sin_error < 1e-2);
 }
+
+#[test]
+fn test_rope_shift_back() {
+    let config = LlamaConfig {
+        rope_theta: 10000.,
+        rope_scaling: None,
+        context_length: 16,
+        rope_dimension: 8,
+        head_dimension: 8,
+        n_head: 0,
+        n_kv_head: 0,
+        n_layer: 0,
+        sliding_window: None,
+        kv_cache_dtype: Default::default(),
+        max_cache_tokens: None,
+    };
+    let device = Device::Cpu;
+    let cache = RopeCache::new(&config, DType::F32, &device).unwrap();
+    let x = Tensor::randn(0f32, 1., (1, 1, 3, 8), &device).unwrap();
+    // Keys embedded at positions 7..10 and moved back by 5 are the same as keys embedded at positions 2..5
+    let (_, late) = cache.forward(&x, &x, 7).unwrap();
+    let (_, expected) = cache.forward(&x, &x, 2).unwrap();
+    let shifted = cache.shift_back(&late, 5).unwrap();
+    let error: f32 = (shifted - expected)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap()
+        .to_scalar()
+        .unwrap();
+    assert!(error < 1e-4, "error {error}");
+}
+
+#[test]
+fn test_rope_scaling() {
+    let base = 10000.;
+    let dimension = 128;
+    let (unscaled, attention_factor) = rope_frequencies(base, dimension, None);
+    assert_eq!(attention_factor, 1.);
+
+    let (linear, _) = rope_frequencies(base, dimension, Some(RopeScaling::Linear { factor: 4. }));
+    for (linear, unscaled) in linear.iter().zip(&unscaled) {
+        assert!((linear * 4. - unscaled).abs() < 1e-6);
+    }
+
+    // NTK scaling keeps the highest frequency and interpolates the lowest frequency by the full factor
+    let (ntk, _) = rope_frequencies(base, dimension, Some(RopeScaling::Ntk { factor: 4. }));
+    assert_eq!(ntk[0], unscaled[0]);
+    let last = unscaled.len() - 1;
+    assert!((ntk[last] * 4. / unscaled[last] - 1.).abs() < 1e-3);
+
+    // YaRN keeps the high frequencies, interpolates the low frequencies and scales the attention
+    let (yarn, attention_factor) =
+        rope_frequencies(base, dimension, Some(RopeScaling::yarn(4., 4096)));
+    assert!((attention_factor - (0.1 * 4f32.ln() + 1.)).abs() < 1e-6);
+    assert_eq!(yarn[0], unscaled[0]);
+    assert!((yarn[last] * 4. - unscaled[last]).abs() < 1e-9);
+    assert!(yarn
+        .iter()
+        .zip(&unscaled)
+        .all(|(yarn, unscaled)| yarn <= unscaled && yarn * 4. >= unscaled * (1. - 1e-6)));
+}
@@ -0,0 +1,432 @@
+use crate::{InferenceSettings, LlamaModel, LlamaSession};
+use kalosm_language_model::{
+    StopOnMatcher, StopOnStatus, StopReason, SyncModel, TokenOutputStream,
+};
+use llm_samplers::prelude::{Logits, Sampler};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// A scheduler that interleaves several inference requests on the same model.
+///
+/// Every call to [`InferenceScheduler::step`] runs one batched forward pass over all active requests: new requests
+/// feed their prompt while running requests feed the last token they sampled. Each request keeps its own cache,
+/// sampler and stop conditions, so requests can join and leave the batch at any step. When a request finishes, the
+/// reason it stopped is sent before its text stream is closed. If the model fails, every request the error affects
+/// stops with [`StopReason::Error`].
+pub(crate) struct InferenceScheduler {
+    max_batch_size: usize,
+    active: Vec<ActiveRequest>,
+    queued: VecDeque<QueuedRequest>,
+}
+
+struct QueuedRequest {
+    settings: InferenceSettings,
+    sampler: Arc<Mutex<dyn Sampler>>,
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+    stop_reason: tokio::sync::oneshot::Sender<StopReason>,
+}
+
+struct ActiveRequest {
+    session: LlamaSession,
+    text_stream: TokenOutputStream,
+    sampler: Arc<Mutex<dyn Sampler>>,
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+    stop_reason: Option<tokio::sync::oneshot::Sender<StopReason>>,
+    stop_on: StopOnMatcher,
+    stop_token: u32,
+    stop_token_ids: Vec<u32>,
+    /// The tokens that need to be fed into the model on the next step.
+    next_tokens: Vec<u32>,
+    /// If the prompt is being fed on this step, the cache is saved for later prompts with the same prefix.
+    cache_prompt: bool,
+    tokens_generated: usize,
+    sample_len: usize,
+}
+
+impl InferenceScheduler {
+    pub(crate) fn new(max_batch_size: usize) -> Self {
+        Self {
+            max_batch_size: max_batch_size.max(1),
+            active: Vec::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Returns true if there are no requests waiting for tokens.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.active.is_empty() && self.queued.is_empty()
+    }
+
+    /// Add a new request. It will start generating on the next step with room in the batch.
+    pub(crate) fn add(
+        &mut self,
+        settings: InferenceSettings,
+        sampler: Arc<Mutex<dyn Sampler>>,
+        sender: tokio::sync::mpsc::UnboundedSender<String>,
+        stop_reason: tokio::sync::oneshot::Sender<StopReason>,
+    ) {
+        self.queued.push_back(QueuedRequest {
+            settings,
+            sampler,
+            sender,
+            stop_reason,
+        });
+    }
+
+    /// Run one forward pass over every active request and send the newly generated text to each request.
+    pub(crate) fn step(&mut self, model: &LlamaModel) {
+        self.start_queued(model);
+        if self.active.is_empty() {
+            return;
+        }
+
+        let mut batch: Vec<_> = self
+            .active
+            .iter_mut()
+            .map(|request| (request.next_tokens.as_slice(), &mut request.session.cache))
+            .collect();
+        let logits = match model.forward_batch(&mut batch) {
+            Ok(logits) => logits,
+            Err(err) => {
+                // We don't know which request caused the error, so we stop all of them
+                tracing::error!("Error running batched forward pass: {err}");
+                for mut request in self.active.drain(..) {
+                    request.finish(StopReason::Error {
+                        message: err.to_string(),
+                    });
+                }
+                return;
+            }
+        };
+
+        let mut finished = Vec::new();
+        for (i, (request, logits)) in self.active.iter_mut().zip(logits).enumerate() {
+            if std::mem::take(&mut request.cache_prompt) {
+                model.cache_prefix(&request.session.cache);
+            }
+            match request.sample(logits) {
+                Ok(None) => {}
+                Ok(Some(stop_reason)) => {
+                    request.finish(stop_reason);
+                    finished.push(i);
+                }
+                Err(err) => {
+                    tracing::error!("Error sampling token: {err}");
+                    request.finish(StopReason::Error {
+                        message: err.to_string(),
+                    });
+                    finished.push(i);
+                }
+            }
+        }
+        for i in finished.into_iter().rev() {
+            self.active.swap_remove(i);
+        }
+    }
+
+    /// Run every request (including queued requests) until it finishes.
+    pub(crate) fn run_to_completion(&mut self, model: &LlamaModel) {
+        while !self.is_empty() {
+            self.step(model);
+        }
+    }
+
+    fn start_queued(&mut self, model: &LlamaModel) {
+        while self.active.len() < self.max_batch_size {
+            let Some(request) = self.queued.pop_front() else {
+                break;
+            };
+            // If the receiver was dropped before the request started, there is no reason to run it
+            if request.sender.is_closed() {
+                continue;
+            }
+            let QueuedRequest {
+                settings,
+                sampler,
+                sender,
+                stop_reason,
+            } = request;
+            match ActiveRequest::new(model, settings, sampler, sender) {
+                Ok(request) => self.active.push(ActiveRequest {
+                    stop_reason: Some(stop_reason),
+                    ..request
+                }),
+                Err(err) => {
+                    tracing::error!("Error starting request: {err}");
+                    _ = stop_reason.send(StopReason::Error {
+                        message: err.to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl ActiveRequest {
+    fn new(
+        model: &LlamaModel,
+        settings: InferenceSettings,
+        sampler: Arc<Mutex<dyn Sampler>>,
+        sender: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> anyhow::Result<Self> {
+        let InferenceSettings {
+            prompt,
+            sample_len,
+            stop_on,
+            stop_token_ids,
+            seed,
+        } = settings;
+
+        let mut session = model.new_session()?;
+        let tokenizer = model.tokenizer();
+        let prompt_tokens = tokenizer.encode(&prompt, true)?;
+        if prompt_tokens.is_empty() {
+            anyhow::bail!("Cannot run model on empty input");
+        }
+        let next_tokens = model
+            .resume_from_prefix(&mut session.cache, &prompt_tokens)
+            .to_vec();
+        let mut text_stream = TokenOutputStream::new(tokenizer).with_seed(seed);
+        for token in prompt_tokens.iter().copied() {
+            text_stream.next_token(token)?;
+        }
+
+        Ok(Self {
+            session,
+            text_stream,
+            sampler,
+            sender,
+            stop_reason: None,
+            stop_on: StopOnMatcher::from_sequences(stop_on),
+            stop_token: model.stop_token()?,
+            stop_token_ids,
+            next_tokens,
+            cache_prompt: true,
+            tokens_generated: 0,
+            sample_len,
+        })
+    }
+
+    /// Sample the next token from the logits of the last forward pass. Returns the reason the request stopped if it is finished.
+    fn sample(&mut self, logits: Vec<f32>) -> anyhow::Result<Option<StopReason>> {
+        // If the stream was dropped, stop generating
+        if self.sender.is_closed() {
+            return Ok(Some(StopReason::Cancelled));
+        }
+
+        let logits = Logits::try_from_iter_top_k(logits, 512)?;
+        let new_token = self.text_stream.sample_token(
+            &mut self.sampler,
+            logits,
+            self.stop_on.stop_sequences(),
+        )?;
+        if new_token == self.stop_token {
+            tracing::trace!("Stopping on stop token");
+            return Ok(Some(StopReason::EndOfSequence));
+        }
+        if self.stop_token_ids.contains(&new_token) {
+            tracing::trace!("Stopping on stop token {}", new_token);
+            return Ok(Some(StopReason::StopToken { id: new_token }));
+        }
+        if let Some(new_text) = self.text_stream.next_token(new_token)? {
+            match self.stop_on.feed(&new_text) {
+                StopOnStatus::Continue(text) => self.send(text),
+                StopOnStatus::Stop(text) => {
+                    self.send(text);
+                    return Ok(self.stop_on.stop_reason());
+                }
+            }
+        }
+        self.tokens_generated += 1;
+        if self.tokens_generated >= self.sample_len {
+            return Ok(Some(StopReason::MaxLength));
+        }
+        self.next_tokens = vec![new_token];
+
+        Ok(None)
+    }
+
+    /// Send any text that is still held back and the reason the request stopped.
+    fn finish(&mut self, stop_reason: StopReason) {
+        let queued = self.stop_on.flush();
+        self.send(queued);
+        if let Some(sender) = self.stop_reason.take() {
+            _ = sender.send(stop_reason);
+        }
+    }
+
+    fn send(&self, text: String) {
+        if !text.is_empty() {
+            // If the receiver was dropped, the request will be removed on the next step
+            _ = self.sender.send(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::cache::{CacheTruncation, LlamaCache};
+    use crate::raw::tests::{dense_tensors, load_synthetic_model};
+    use crate::raw::LlamaConfigOverrides;
+    use candle_core::Device;
+    use llm_samplers::prelude::SampleGreedy;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::Tokenizer;
+
+    const WORDS: [&str; 8] = ["<unk>", "a", "b", "c", "d", "e", "f", "g"];
+
+    fn synthetic_model(overrides: &LlamaConfigOverrides) -> LlamaModel {
+        let model = load_synthetic_model(Vec::new(), &dense_tensors(), overrides);
+        let vocab: HashMap<String, u32> = WORDS
+            .iter()
+            .enumerate()
+            .map(|(id, word)| (word.to_string(), id as u32))
+            .collect();
+        let mut tokenizer = Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("<unk>".to_string())
+                .build()
+                .unwrap(),
+        );
+        tokenizer.with_pre_tokenizer(Whitespace::default());
+        // The stop token is outside of the vocabulary, so requests only stop when they reach their sample length
+        LlamaModel::new(
+            model,
+            Arc::new(tokenizer),
+            Device::Cpu,
+            LlamaCache::new(1),
+            Some(u32::MAX),
+            0,
+        )
+    }
+
+    struct Request {
+        text: tokio::sync::mpsc::UnboundedReceiver<String>,
+        stop_reason: tokio::sync::oneshot::Receiver<StopReason>,
+    }
+
+    impl Request {
+        fn text(&mut self) -> String {
+            let mut text = String::new();
+            while let Ok(new_text) = self.text.try_recv() {
+                text += &new_text;
+            }
+            text
+        }
+    }
+
+    fn add_request(scheduler: &mut InferenceScheduler, prompt: &str, sample_len: usize) -> Request {
+        let (sender, text) = tokio::sync::mpsc::unbounded_channel();
+        let (stop_reason_sender, stop_reason) = tokio::sync::oneshot::channel();
+        scheduler.add(
+            InferenceSettings::new(prompt).with_sample_len(sample_len),
+            Arc::new(Mutex::new(SampleGreedy::default())),
+            sender,
+            stop_reason_sender,
+        );
+        Request { text, stop_reason }
+    }
+
+    #[test]
+    fn batched_requests_match_individual_requests() {
+        let model = synthetic_model(&Default::default());
+        let prompts = ["a b c", "d e", "f g a b"];
+        let expected: Vec<_> = prompts
+            .iter()
+            .map(|prompt| {
+                let mut scheduler = InferenceScheduler::new(1);
+                let mut request = add_request(&mut scheduler, prompt, 6);
+                scheduler.run_to_completion(&model);
+                request.text()
+            })
+            .collect();
+
+        let mut scheduler = InferenceScheduler::new(prompts.len());
+        let mut requests: Vec<_> = prompts
+            .iter()
+            .map(|prompt| add_request(&mut scheduler, prompt, 6))
+            .collect();
+        scheduler.step(&model);
+        // Every request fits in the batch, so they all start in the first forward pass
+        assert_eq!(scheduler.active.len(), prompts.len());
+        assert!(scheduler.queued.is_empty());
+        scheduler.run_to_completion(&model);
+
+        for (request, expected) in requests.iter_mut().zip(expected) {
+            assert_eq!(request.text(), expected);
+            assert_eq!(request.stop_reason.try_recv(), Ok(StopReason::MaxLength));
+        }
+    }
+
+    #[test]
+    fn requests_beyond_the_batch_size_wait_for_a_slot() {
+        let model = synthetic_model(&Default::default());
+        let mut scheduler = InferenceScheduler::new(2);
+        let mut short = add_request(&mut scheduler, "a b", 1);
+        let mut long = add_request(&mut scheduler, "c d", 4);
+        let mut queued = add_request(&mut scheduler, "e f", 2);
+
+        scheduler.step(&model);
+        // The short request finishes after one token and frees its slot
+        assert_eq!(short.stop_reason.try_recv(), Ok(StopReason::MaxLength));
+        assert_eq!(scheduler.active.len(), 1);
+        assert_eq!(scheduler.queued.len(), 1);
+
+        scheduler.step(&model);
+        // The queued request joins the batch on the next step
+        assert_eq!(scheduler.active.len(), 2);
+        assert!(scheduler.queued.is_empty());
+
+        // Each request finishes when it reaches its own sample length
+        scheduler.step(&model);
+        assert_eq!(queued.stop_reason.try_recv(), Ok(StopReason::MaxLength));
+        assert!(long.stop_reason.try_recv().is_err());
+        scheduler.step(&model);
+        assert_eq!(long.stop_reason.try_recv(), Ok(StopReason::MaxLength));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn dropping_a_stream_only_cancels_that_request() {
+        let model = synthetic_model(&Default::default());
+        let mut scheduler = InferenceScheduler::new(2);
+        let Request {
+            text,
+            stop_reason: mut cancelled,
+        } = add_request(&mut scheduler, "a b", 8);
+        let mut running = add_request(&mut scheduler, "c d", 8);
+
+        scheduler.step(&model);
+        drop(text);
+        scheduler.step(&model);
+        assert_eq!(cancelled.try_recv(), Ok(StopReason::Cancelled));
+        assert_eq!(scheduler.active.len(), 1);
+
+        scheduler.run_to_completion(&model);
+        assert_eq!(running.stop_reason.try_recv(), Ok(StopReason::MaxLength));
+    }
+
+    #[test]
+    fn model_errors_are_sent_to_every_active_request() {
+        // The cache fills up after a few tokens, which makes the next forward pass fail
+        let model = synthetic_model(&LlamaConfigOverrides {
+            max_cache_tokens: Some((4, CacheTruncation::Error)),
+            ..Default::default()
+        });
+        let mut scheduler = InferenceScheduler::new(2);
+        let mut first = add_request(&mut scheduler, "a b", 8);
+        let mut second = add_request(&mut scheduler, "c d e", 8);
+
+        scheduler.run_to_completion(&model);
+        for request in [&mut first, &mut second] {
+            assert!(matches!(
+                request.stop_reason.try_recv(),
+                Ok(StopReason::Error { .. })
+            ));
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::accelerated_device_if_available;
 use crate::raw::cache::LlamaCache;
 use candle_core::{Device, Tensor};
-use kalosm_language_model::Session;
+use kalosm_language_model::{PrefixCacheEntry, Session};
 use std::collections::HashMap;
 
 /// A Llama-1.5 session.
@@ -33,6 +33,11 @@ impl Session for LlamaSession {
     {
         Ok(self.clone())
     }
+
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()> {
+        let len = self.cache.tokens.len().saturating_sub(count);
+        self.cache.truncate(len)
+    }
 }
 
 impl LlamaSession {
@@ -1,7 +1,25 @@
+use std::path::Path;
+
+use candle_core::{
+    quantized::{ggml_file, gguf_file},
+    Device,
+};
 use kalosm_common::FileSource;
 use kalosm_language_model::ChatMarkers;
 use tokenizers::Tokenizer;
 
+use crate::gguf::{chat_markers_from_gguf, eos_token_from_gguf, tokenizer_from_gguf};
+use crate::raw::{LlamaConfigOverrides, Model};
+
+/// A model loaded from a [`LlamaSource`].
+pub(crate) struct LoadedModel {
+    pub(crate) model: Model,
+    pub(crate) tokenizer: Tokenizer,
+    pub(crate) markers: Option<ChatMarkers>,
+    /// The end of sequence token from the model file, if it has one
+    pub(crate) stop_token: Option<u32>,
+}
+
 fn llama_tokenizer() -> FileSource {
     FileSource::huggingface(
         "hf-internal-testing/llama-tokenizer".to_string(),
@@ -27,6 +45,8 @@ fn mistral_tokenizer() -> FileSource {
 }
 
 /// A source for the Llama model.
+///
+/// GGUF files contain the tokenizer, chat template and hyperparameters of the model, so a GGUF file is all [`LlamaSource::new`] needs. Older GGML files also need a tokenizer set with [`LlamaSource::with_tokenizer`].
 #[derive(Clone, Debug)]
 pub struct LlamaSource {
     /// The model to use, check out available models: <https://huggingface.co/models?library=sentence-transformers&sort=trending>
@@ -36,22 +56,43 @@ pub struct LlamaSource {
     // pub(crate) tokenizer_repo: String,
     // pub(crate) tokenizer_file: String,
     pub(crate) model: FileSource,
-    pub(crate) tokenizer: FileSource,
+    pub(crate) tokenizer: Option<FileSource>,
     pub(crate) group_query_attention: u8,
     pub(crate) markers: Option<ChatMarkers>,
 }
 
 impl LlamaSource {
-    /// Create a new source for the Llama model.
-    pub fn new(model: FileSource, tokenizer: FileSource) -> Self {
+    /// Create a new source for the Llama model. The tokenizer and chat markers are read from the GGUF file unless they are set with [`LlamaSource::with_tokenizer`] and [`LlamaSource::with_chat_markers`].
+    ///
+    /// ```rust, no_run
+    /// use kalosm_llama::prelude::*;
+    /// use kalosm_llama::FileSource;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> anyhow::Result<()> {
+    /// let model = Llama::builder()
+    ///     .with_source(LlamaSource::new(FileSource::local("./model.gguf".into())))
+    ///     .build()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new(model: FileSource) -> Self {
         Self {
             model,
-            tokenizer,
+            tokenizer: None,
             group_query_attention: 1,
             markers: Default::default(),
         }
     }
 
+    /// Set the Hugging Face tokenizer to use instead of the tokenizer in the GGUF file. This is required for GGML files
+    pub fn with_tokenizer(mut self, tokenizer: FileSource) -> Self {
+        self.tokenizer = Some(tokenizer);
+
+        self
+    }
+
     /// Set the marker text for a user message
     pub fn with_chat_markers(mut self, markers: ChatMarkers) -> Self {
         self.markers = Some(markers);
@@ -62,15 +103,25 @@ impl LlamaSource {
     /// Set the group query attention for the model
     /// For the llama family of models, this is typically 1
     /// For the mistral family of models, this is typically 8
+    ///
+    /// GGUF files store the group query attention in their metadata, so this is only used for GGML files
     pub fn with_group_query_attention(mut self, group_query_attention: u8) -> Self {
         self.group_query_attention = group_query_attention;
 
         self
     }
 
-    pub(crate) async fn tokenizer(&self, progress: impl FnMut(f32)) -> anyhow::Result<Tokenizer> {
-        let tokenizer_path = self.tokenizer.download(progress).await?;
-        Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)
+    pub(crate) async fn tokenizer(
+        &self,
+        progress: impl FnMut(f32),
+    ) -> anyhow::Result<Option<Tokenizer>> {
+        let Some(tokenizer) = &self.tokenizer else {
+            return Ok(None);
+        };
+        let tokenizer_path = tokenizer.download(progress).await?;
+        Tokenizer::from_file(tokenizer_path)
+            .map(Some)
+            .map_err(anyhow::Error::msg)
     }
 
     pub(crate) async fn model(
@@ -80,6 +131,55 @@ impl LlamaSource {
         self.model.download(progress).await
     }
 
+    /// Load the downloaded model file. The tokenizer, chat markers and stop token are read from the GGUF metadata when the source does not set them.
+    pub(crate) fn load(
+        &self,
+        filename: &Path,
+        tokenizer: Option<Tokenizer>,
+        overrides: &LlamaConfigOverrides,
+        device: &Device,
+    ) -> anyhow::Result<LoadedModel> {
+        let mut file = std::fs::File::open(filename)?;
+        match filename.extension().and_then(|v| v.to_str()) {
+            Some("gguf") => {
+                let content = gguf_file::Content::read(&mut file)?;
+                let tokenizer = match tokenizer {
+                    Some(tokenizer) => tokenizer,
+                    None => tokenizer_from_gguf(&content)?,
+                };
+                let markers = self.markers.clone().or_else(|| {
+                    chat_markers_from_gguf(&content).unwrap_or_else(|err| {
+                        tracing::warn!(
+                            "Failed to read the chat template from the GGUF file: {err}"
+                        );
+                        None
+                    })
+                });
+                let stop_token = eos_token_from_gguf(&content);
+                let model = Model::from_gguf(content, &mut file, overrides, device)?;
+                Ok(LoadedModel {
+                    model,
+                    tokenizer,
+                    markers,
+                    stop_token,
+                })
+            }
+            Some("ggml" | "bin") | Some(_) | None => {
+                let Some(tokenizer) = tokenizer else {
+                    anyhow::bail!("GGML models do not contain a tokenizer. Set the tokenizer with LlamaSource::with_tokenizer")
+                };
+                let content = ggml_file::Content::read(&mut file, device)?;
+                let gqa = self.group_query_attention;
+                Ok(LoadedModel {
+                    model: Model::from_ggml(content, gqa as usize, overrides, device)?,
+                    tokenizer,
+                    markers: self.markers.clone(),
+                    stop_token: None,
+                })
+            }
+        }
+    }
+
     /// A preset for Mistral7b
     pub fn mistral_7b() -> Self {
         Self {
@@ -88,7 +188,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "mistral-7b-v0.1.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             ..Default::default()
         }
@@ -102,7 +202,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<s>[INST] ",
@@ -123,7 +223,42 @@ impl LlamaSource {
                 "main".to_string(),
                 "mistral-7b-instruct-v0.2.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
+            group_query_attention: 8,
+            markers: Some(ChatMarkers {
+                system_prompt_marker: "<s>[INST] ",
+                end_system_prompt_marker: " [/INST]",
+                user_marker: "[INST] ",
+                end_user_marker: " [/INST]",
+                assistant_marker: "",
+                end_assistant_marker: "</s>",
+            }),
+        }
+    }
+
+    /// A preset for Mixtral 8x7b, a mixture of experts model
+    pub fn mixtral_8x7b() -> Self {
+        Self {
+            model: FileSource::huggingface(
+                "TheBloke/Mixtral-8x7B-v0.1-GGUF".to_string(),
+                "main".to_string(),
+                "mixtral-8x7b-v0.1.Q4_K_M.gguf".to_string(),
+            ),
+            tokenizer: Some(mistral_tokenizer()),
+            group_query_attention: 8,
+            ..Default::default()
+        }
+    }
+
+    /// A preset for Mixtral 8x7b Instruct, a mixture of experts model
+    pub fn mixtral_8x7b_instruct() -> Self {
+        Self {
+            model: FileSource::huggingface(
+                "TheBloke/Mixtral-8x7B-Instruct-v0.1-GGUF".to_string(),
+                "main".to_string(),
+                "mixtral-8x7b-instruct-v0.1.Q4_K_M.gguf".to_string(),
+            ),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<s>[INST] ",
@@ -144,7 +279,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "neuralhermes-2.5-mistral-7b.Q4_0.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|im_start|>system\n",
@@ -165,11 +300,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "neural-chat-7b-v3-3.Q4_0.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "Intel/neural-chat-7b-v3-3".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "### System:\n",
@@ -190,7 +325,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "zephyr-7b-alpha.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|system|>",
@@ -211,7 +346,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "zephyr-7b-beta.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|system|>",
@@ -232,11 +367,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "openchat-3.5-0106.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "openchat/openchat-3.5-0106".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "",
@@ -257,11 +392,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "starling-lm-7b-alpha.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "berkeley-nest/Starling-LM-7B-alpha".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "",
@@ -282,11 +417,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "Starling-LM-7B-beta-Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "Nexusflow/Starling-LM-7B-beta".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "",
@@ -307,7 +442,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "WizardLM-2-7B-Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: mistral_tokenizer(),
+            tokenizer: Some(mistral_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "",
@@ -328,11 +463,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "TinyLlama/TinyLlama-1.1B-Chat-v1.0".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 4,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|system|>\n",
@@ -353,11 +488,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "tinyllama-1.1b-intermediate-step-1431k-3t.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "TinyLlama/TinyLlama-1.1B-intermediate-step-1431k-3T".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 4,
             ..Default::default()
         }
@@ -371,11 +506,11 @@ impl LlamaSource {
                 "5eef2ce24766d31909c0b269fe90c817a8f263fb".to_string(),
                 "Phi-3-mini-4k-instruct-q4.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "microsoft/Phi-3-mini-4k-instruct".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             group_query_attention: 1,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|system|>\n",
@@ -396,7 +531,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "llama-2-7b.ggmlv3.q4_0.bin".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             ..Default::default()
         }
@@ -410,7 +545,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "Meta-Llama-3-8B-Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: llama_v3_tokenizer(),
+            tokenizer: Some(llama_v3_tokenizer()),
             group_query_attention: 1,
             ..Default::default()
         }
@@ -424,7 +559,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "Meta-Llama-3-8B-Instruct-Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: llama_v3_tokenizer(),
+            tokenizer: Some(llama_v3_tokenizer()),
             group_query_attention: 1,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|begin_of_text|><|start_header_id|>system<|end_header_id|>",
@@ -445,7 +580,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "Meta-Llama-3-8B-Instruct-Q8_0.gguf".to_string(),
             ),
-            tokenizer: llama_v3_tokenizer(),
+            tokenizer: Some(llama_v3_tokenizer()),
             group_query_attention: 1,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<|begin_of_text|><|start_header_id|>system<|end_header_id|>",
@@ -466,7 +601,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "llama-2-13b.ggmlv3.q4_0.bin".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             markers: Default::default(),
         }
@@ -480,7 +615,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "llama-2-70b.ggmlv3.q4_0.bin".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 8,
             ..Default::default()
         }
@@ -494,7 +629,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "llama-2-7b-chat.ggmlv3.q4_0.bin".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<<SYS>>\n",
@@ -515,7 +650,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "llama-2-13b-chat.ggmlv3.q4_0.bin".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<<SYS>>\n",
@@ -536,7 +671,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "llama-2-70b-chat.ggmlv3.q4_0.bin".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 8,
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<<SYS>>\n",
@@ -557,7 +692,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "codellama-7b.Q8_0.gguf".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             ..Default::default()
         }
@@ -571,7 +706,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "codellama-13b.Q8_0.gguf".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             ..Default::default()
         }
@@ -585,7 +720,7 @@ impl LlamaSource {
                 "main".to_string(),
                 "codellama-34b.Q8_0.gguf".to_string(),
             ),
-            tokenizer: llama_tokenizer(),
+            tokenizer: Some(llama_tokenizer()),
             group_query_attention: 1,
             ..Default::default()
         }
@@ -599,11 +734,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "solar-10.7b-v1.0.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "upstage/SOLAR-10.7B-v1.0".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             ..Default::default()
         }
     }
@@ -616,11 +751,11 @@ impl LlamaSource {
                 "main".to_string(),
                 "solar-10.7b-instruct-v1.0.Q4_K_M.gguf".to_string(),
             ),
-            tokenizer: FileSource::huggingface(
+            tokenizer: Some(FileSource::huggingface(
                 "upstage/SOLAR-10.7B-Instruct-v1.0".to_string(),
                 "main".to_string(),
                 "tokenizer.json".to_string(),
-            ),
+            )),
             markers: Some(ChatMarkers {
                 system_prompt_marker: "<s>### System:\n",
                 end_system_prompt_marker: "",
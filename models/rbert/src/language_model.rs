@@ -45,4 +45,8 @@ impl Embedder for Bert {
 /// A vector space for BERT sentence embeddings.
 pub struct BertSpace;
 
-impl VectorSpace for BertSpace {}
+impl VectorSpace for BertSpace {
+    fn id() -> &'static str {
+        "bert"
+    }
+}
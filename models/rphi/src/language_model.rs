@@ -5,7 +5,6 @@ use crate::PhiBuilder;
 use crate::Task;
 use kalosm_common::ModelLoadingProgress;
 use kalosm_language_model::*;
-use kalosm_streams::text_stream::ChannelTextStream;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -28,11 +27,11 @@ impl ModelBuilder for PhiBuilder {
 
 #[async_trait::async_trait]
 impl Model for Phi {
-    type TextStream = ChannelTextStream<String>;
+    type TextStream = TextGenerationStream;
     type SyncModel = PhiModel;
 
-    fn tokenizer(&self) -> Arc<dyn kalosm_sample::Tokenizer + Send + Sync> {
-        self.get_tokenizer() as Arc<dyn kalosm_sample::Tokenizer + Send + Sync>
+    fn tokenizer(&self) -> Option<Arc<dyn kalosm_sample::Tokenizer + Send + Sync>> {
+        Some(self.get_tokenizer() as Arc<dyn kalosm_sample::Tokenizer + Send + Sync>)
     }
 
     fn run_sync_raw(
@@ -60,10 +59,11 @@ impl Model for Phi {
         self.run(
             InferenceSettings::new(prompt)
                 .with_sample_len(max_length as usize)
-                .with_stop_on(generation_parameters.stop_on().map(|s| s.to_string())),
+                .with_stop_on(generation_parameters.stop_sequences().to_vec())
+                .with_stop_token_ids(generation_parameters.stop_token_ids().to_vec())
+                .with_seed(generation_parameters.seed()),
             Arc::new(Mutex::new(generation_parameters.sampler())),
         )
-        .map(Into::into)
     }
 
     async fn stream_text_with_sampler(
@@ -80,10 +80,13 @@ impl Model for Phi {
                 .with_stop_on(stop_on.map(|s| s.to_string())),
             sampler,
         )
-        .map(Into::into)
     }
 
     fn chat_markers(&self) -> Option<ChatMarkers> {
         self.chat_markers.deref().clone()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        Some(self.context_length)
+    }
 }
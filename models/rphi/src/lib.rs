@@ -38,8 +38,9 @@ mod source;
 
 use kalosm_common::accelerated_device_if_available;
 use kalosm_common::ModelLoadingProgress;
+pub use kalosm_common::{LoraAdapter, LoraWeight};
 pub use kalosm_language_model;
-use kalosm_language_model::ChatMarkers;
+use kalosm_language_model::{ChatMarkers, StopReason, TextGenerationStream};
 use raw::PhiCache;
 pub use source::*;
 
@@ -56,6 +57,7 @@ use crate::raw::MixFormerSequentialForCausalLM as QMixFormer;
 use candle_core::Device;
 use llm_samplers::prelude::Sampler;
 use model::PhiModel;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokenizers::Tokenizer;
@@ -65,6 +67,7 @@ enum Task {
     Infer {
         settings: InferenceSettings,
         sender: tokio::sync::mpsc::UnboundedSender<String>,
+        stop_reason: tokio::sync::oneshot::Sender<StopReason>,
         sampler: Arc<Mutex<dyn Sampler>>,
     },
     RunSync {
@@ -85,6 +88,7 @@ pub struct Phi {
     task_sender: tokio::sync::mpsc::UnboundedSender<Task>,
     tokenizer: Arc<Tokenizer>,
     chat_markers: Arc<Option<ChatMarkers>>,
+    context_length: usize,
 }
 
 impl Drop for Phi {
@@ -121,6 +125,8 @@ impl Phi {
         device: Device,
         cache: PhiCache,
         chat_markers: Option<ChatMarkers>,
+        prefix_cache_size: usize,
+        context_length: usize,
     ) -> Self {
         let (task_sender, mut task_receiver) = tokio::sync::mpsc::unbounded_channel();
         let arc_tokenizer = Arc::new(tokenizer);
@@ -128,7 +134,14 @@ impl Phi {
         std::thread::spawn({
             let arc_tokenizer = arc_tokenizer.clone();
             move || {
-                let mut inner = PhiModel::new(model, arc_tokenizer, device, cache);
+                let mut inner = PhiModel::new(
+                    model,
+                    arc_tokenizer,
+                    device,
+                    cache,
+                    prefix_cache_size,
+                    context_length,
+                );
                 tokio::runtime::Builder::new_current_thread()
                     .enable_all()
                     .build()
@@ -140,12 +153,19 @@ impl Phi {
                                 Task::Infer {
                                     settings,
                                     sender,
+                                    stop_reason,
                                     sampler,
-                                } => {
-                                    if let Err(err) = inner._infer(settings, sampler, sender) {
+                                } => match inner._infer(settings, sampler, sender) {
+                                    Ok(reason) => {
+                                        _ = stop_reason.send(reason);
+                                    }
+                                    Err(err) => {
                                         tracing::error!("Error in PhiModel::_infer: {}", err);
+                                        _ = stop_reason.send(StopReason::Error {
+                                            message: err.to_string(),
+                                        });
                                     }
-                                }
+                                },
                                 Task::RunSync { callback } => {
                                     callback(&mut inner).await;
                                 }
@@ -159,9 +179,26 @@ impl Phi {
             task_sender,
             tokenizer: arc_tokenizer,
             chat_markers: chat_markers.into(),
+            context_length,
         }
     }
 
+    /// Replace the LoRA adapters applied to the model with the given adapters and the scale of each adapter. Pass an empty list to run the base model again.
+    ///
+    /// The quantized weights of the model are shared between all adapters, so switching adapters is cheap.
+    pub async fn set_lora_adapters(
+        &self,
+        adapters: impl IntoIterator<Item = (Arc<LoraAdapter>, f32)>,
+    ) -> anyhow::Result<()> {
+        let adapters: Vec<_> = adapters.into_iter().collect();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        kalosm_language_model::Model::run_sync(self, move |model: &mut PhiModel| {
+            let _ = sender.send(model.set_lora_adapters(&adapters));
+            Box::pin(async {})
+        })?;
+        receiver.await?
+    }
+
     /// Get the tokenizer used by this model.
     pub(crate) fn get_tokenizer(&self) -> Arc<Tokenizer> {
         self.tokenizer.clone()
@@ -171,16 +208,18 @@ impl Phi {
         &self,
         settings: InferenceSettings,
         sampler: Arc<Mutex<dyn Sampler>>,
-    ) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<String>> {
+    ) -> anyhow::Result<TextGenerationStream> {
         let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (stop_reason_sender, stop_reason_receiver) = tokio::sync::oneshot::channel();
         self.task_sender
             .send(Task::Infer {
                 settings,
                 sender,
+                stop_reason: stop_reason_sender,
                 sampler,
             })
             .unwrap();
-        Ok(receiver)
+        Ok(TextGenerationStream::new(receiver, stop_reason_receiver))
     }
 }
 
@@ -189,6 +228,12 @@ impl Phi {
 pub struct PhiBuilder {
     /// The source to use for the model.
     source: source::PhiSource,
+
+    /// The number of bytes the model can use to cache prompts.
+    prefix_cache_size: usize,
+
+    /// The LoRA adapters to apply to the model and the scale of each adapter.
+    lora_adapters: Vec<(PathBuf, f32)>,
 }
 
 impl PhiBuilder {
@@ -198,6 +243,22 @@ impl PhiBuilder {
         self
     }
 
+    /// Set the number of bytes the model can use to cache prompts. (default: 0, disabled)
+    ///
+    /// When a new request shares a prefix with an earlier prompt, the cached state for that prefix is reused instead of processing those tokens again. The least recently used prompts are evicted when the cache grows past this size.
+    pub fn with_prefix_cache_size(mut self, prefix_cache_size: usize) -> Self {
+        self.prefix_cache_size = prefix_cache_size;
+        self
+    }
+
+    /// Add a LoRA adapter from a `.safetensors` or `.gguf` file with a scale for the output of the adapter. Adapters are added on top of the quantized weights without dequantizing the model.
+    ///
+    /// The adapters can be swapped after the model is built with [`Phi::set_lora_adapters`].
+    pub fn with_lora(mut self, path: impl Into<PathBuf>, scale: f32) -> Self {
+        self.lora_adapters.push((path.into(), scale));
+        self
+    }
+
     /// Build the model (this will download the model if it is not already downloaded)
     pub async fn build(self) -> anyhow::Result<Phi> {
         self.build_with_loading_handler(ModelLoadingProgress::multi_bar_loading_indicator())
@@ -228,11 +289,17 @@ impl PhiBuilder {
         let device = accelerated_device_if_available()?;
         let vb =
             candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename, &device)?;
-        let model = if self.source.phi2 {
+        let mut model = if self.source.phi2 {
             QMixFormer::new_v2(&config, vb)?
         } else {
             QMixFormer::new(&config, vb)?
         };
+        let adapters = self
+            .lora_adapters
+            .iter()
+            .map(|(path, scale)| Ok((Arc::new(LoraAdapter::load(path, &device)?), *scale)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        model.set_lora_adapters(&adapters)?;
 
         let cache = PhiCache::new(&config);
 
@@ -242,6 +309,8 @@ impl PhiBuilder {
             device,
             cache,
             self.source.chat_markers,
+            self.prefix_cache_size,
+            config.n_positions,
         ))
     }
 }
@@ -253,8 +322,14 @@ pub(crate) struct InferenceSettings {
     /// The length of the sample to generate (in tokens).
     sample_len: usize,
 
-    /// The token to stop on.
-    stop_on: Option<String>,
+    /// The strings to stop on.
+    stop_on: Vec<String>,
+
+    /// The token ids to stop on.
+    stop_token_ids: Vec<u32>,
+
+    /// The seed to sample with.
+    seed: Option<u64>,
 }
 
 impl InferenceSettings {
@@ -262,7 +337,9 @@ impl InferenceSettings {
         Self {
             prompt: prompt.into(),
             sample_len: 100,
-            stop_on: None,
+            stop_on: Vec::new(),
+            stop_token_ids: Vec::new(),
+            seed: None,
         }
     }
 
@@ -271,8 +348,18 @@ impl InferenceSettings {
         self
     }
 
-    pub fn with_stop_on(mut self, stop_on: impl Into<Option<String>>) -> Self {
-        self.stop_on = stop_on.into();
+    pub fn with_stop_on(mut self, stop_on: impl IntoIterator<Item = String>) -> Self {
+        self.stop_on = stop_on.into_iter().collect();
+        self
+    }
+
+    pub fn with_stop_token_ids(mut self, stop_token_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_token_ids = stop_token_ids.into_iter().collect();
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
         self
     }
 }
@@ -1,10 +1,13 @@
 use anyhow::{Error as E, Result};
+use kalosm_common::LoraAdapter;
 use kalosm_language_model::Session;
+use kalosm_language_model::StopReason;
 use kalosm_language_model::SyncModel;
 use kalosm_language_model::SyncModelExt;
+use kalosm_language_model::{PrefixCache, PrefixCacheEntry};
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::raw::MixFormerSequentialForCausalLM as QMixFormer;
 use crate::raw::PhiCache;
@@ -43,6 +46,13 @@ impl Session for PhiSession {
     {
         Ok(self.clone())
     }
+
+    fn rewind(&mut self, count: usize) -> anyhow::Result<()> {
+        let len = self.current_tokens.len().saturating_sub(count);
+        self.cache.truncate(len)?;
+        self.current_tokens.truncate(len);
+        Ok(())
+    }
 }
 
 impl PhiSession {
@@ -83,9 +93,11 @@ impl PhiSession {
 /// The inner, synchronous Phi-1.5 model.
 pub struct PhiModel {
     cache: PhiCache,
+    prefix_cache: Mutex<PrefixCache<PhiCache>>,
     model: QMixFormer,
     device: Device,
     tokenizer: Arc<Tokenizer>,
+    context_length: usize,
 }
 
 impl SyncModel for PhiModel {
@@ -111,9 +123,23 @@ impl SyncModel for PhiModel {
     }
 
     fn feed_tokens(&self, session: &mut Self::Session, tokens: &[u32]) -> anyhow::Result<Vec<f32>> {
+        // The first tokens fed into a new session can reuse the cache from an earlier prompt
+        let new_session = session.current_tokens.is_empty();
+        let tokens = if new_session {
+            self.resume_from_prefix(session, tokens)
+        } else {
+            tokens
+        };
         session.current_tokens.extend(tokens.iter().copied());
 
-        Self::forward(&self.model, &self.device, tokens, Some(&mut session.cache))
+        let logits = Self::forward(&self.model, &self.device, tokens, Some(&mut session.cache))?;
+        if new_session {
+            let mut prefix_cache = self.prefix_cache.lock().unwrap();
+            if !prefix_cache.is_disabled() {
+                prefix_cache.insert(session.current_tokens.clone(), session.cache.clone());
+            }
+        }
+        Ok(logits)
     }
 
     fn stop_token(&self) -> anyhow::Result<u32> {
@@ -127,6 +153,10 @@ impl SyncModel for PhiModel {
     fn tokenizer(&self) -> Arc<dyn kalosm_sample::Tokenizer + Send + Sync> {
         self.tokenizer.clone()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        Some(self.context_length)
+    }
 }
 
 impl PhiModel {
@@ -151,18 +181,45 @@ impl PhiModel {
         Ok(logits)
     }
 
+    /// Restore the longest cached prefix of the tokens into an empty session. Returns the tokens that still need to be fed into the model.
+    fn resume_from_prefix<'a>(&self, session: &mut PhiSession, tokens: &'a [u32]) -> &'a [u32] {
+        let mut prefix_cache = self.prefix_cache.lock().unwrap();
+        if prefix_cache.is_disabled() {
+            return tokens;
+        }
+        match prefix_cache.get(tokens) {
+            Some((len, cache)) => {
+                session.cache = cache;
+                session.current_tokens = tokens[..len].to_vec();
+                &tokens[len..]
+            }
+            None => tokens,
+        }
+    }
+
+    /// Replace the LoRA adapters applied to the model with the given adapters and the scale of each adapter. Cached prefixes were computed with the old adapters, so they are cleared.
+    pub fn set_lora_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) -> Result<()> {
+        self.model.set_lora_adapters(adapters)?;
+        self.prefix_cache.lock().unwrap().clear();
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         model: QMixFormer,
         tokenizer: Arc<Tokenizer>,
         device: Device,
         cache: PhiCache,
+        prefix_cache_size: usize,
+        context_length: usize,
     ) -> Self {
         Self {
             model,
             device,
             tokenizer,
             cache,
+            prefix_cache: Mutex::new(PrefixCache::new(prefix_cache_size)),
+            context_length,
         }
     }
 
@@ -171,28 +228,36 @@ impl PhiModel {
         settings: InferenceSettings,
         sampler: std::sync::Arc<std::sync::Mutex<dyn llm_samplers::prelude::Sampler>>,
         out: tokio::sync::mpsc::UnboundedSender<String>,
-    ) -> Result<()> {
+    ) -> Result<StopReason> {
         let InferenceSettings {
             prompt,
             sample_len,
             stop_on,
+            stop_token_ids,
+            seed,
         } = settings;
 
         let mut session = self.new_session()?;
 
-        self.stream_text_with_sampler(
+        self.stream_tokens_with_sampler(
             &mut session,
             prompt.as_str(),
             Some(sample_len as u32),
-            stop_on.as_deref(),
+            &stop_on,
+            &stop_token_ids,
             sampler,
+            seed,
+            None,
             |token| {
-                out.send(token)
-                    .map_err(|_| anyhow::anyhow!("Failed to send token to output channel"))
-                    .map(|_| kalosm_language_model::ModelFeedback::Continue)
+                if token.text().is_empty() {
+                    return Ok(kalosm_language_model::ModelFeedback::Continue);
+                }
+                // If the stream was dropped, stop generating
+                match out.send(token.text().to_string()) {
+                    Ok(()) => Ok(kalosm_language_model::ModelFeedback::Continue),
+                    Err(_) => Ok(kalosm_language_model::ModelFeedback::Stop),
+                }
             },
-        )?;
-
-        Ok(())
+        )
     }
 }
@@ -1,11 +1,13 @@
 // Modified from https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/mixformer.rs to separate the model from the cache
 
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
 use candle_nn::Activation;
 use candle_transformers::quantized_nn;
 pub use candle_transformers::quantized_var_builder::VarBuilder;
+use kalosm_common::{LoraAdapter, LoraLinear, LoraWeight};
+use kalosm_language_model::PrefixCacheEntry;
 use quantized_nn::{layer_norm, linear, Linear};
 
 const MAX_SEQ_LEN: usize = 4096;
@@ -111,11 +113,12 @@ impl Module for Embedding {
     }
 }
 
-fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
+/// Get the causal mask for `size` new tokens that follow `offset` cached tokens.
+fn get_mask(size: usize, offset: usize, device: &Device) -> Result<Tensor> {
     let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
+        .flat_map(|i| (0..size + offset).map(move |j| u8::from(j > i + offset)))
         .collect();
-    Tensor::from_slice(&mask, (size, size), device)
+    Tensor::from_slice(&mask, (size, size + offset), device)
 }
 
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
@@ -194,16 +197,22 @@ impl RotaryEmbedding {
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 struct MLP {
-    fc1: Linear,
-    fc2: Linear,
+    fc1: LoraLinear<Linear>,
+    fc2: LoraLinear<Linear>,
     act: Activation,
 }
 
 impl MLP {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(cfg: &Config, vb: VarBuilder, prefix: &str) -> Result<Self> {
         let n_inner = cfg.n_inner.unwrap_or(4 * cfg.n_embd);
-        let fc1 = linear(cfg.n_embd, n_inner, vb.pp("fc1"))?;
-        let fc2 = linear(n_inner, cfg.n_embd, vb.pp("fc2"))?;
+        let fc1 = LoraLinear::new(
+            linear(cfg.n_embd, n_inner, vb.pp("fc1"))?,
+            format!("{prefix}.fc1"),
+        );
+        let fc2 = LoraLinear::new(
+            linear(n_inner, cfg.n_embd, vb.pp("fc2"))?,
+            format!("{prefix}.fc2"),
+        );
         Ok(Self {
             fc1,
             fc2,
@@ -243,8 +252,8 @@ impl Module for CausalLMHead {
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 struct MHA {
-    wqkv: Linear,
-    out_proj: Linear,
+    wqkv: LoraLinear<Linear>,
+    out_proj: LoraLinear<Linear>,
     rotary_emb: RotaryEmbedding,
     head_dim: usize,
     n_head: usize,
@@ -253,11 +262,17 @@ struct MHA {
 }
 
 impl MHA {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(cfg: &Config, vb: VarBuilder, prefix: &str) -> Result<Self> {
         let head_dim = cfg.n_embd / cfg.n_head;
         let op_size = cfg.n_embd;
-        let wqkv = linear(cfg.n_embd, 3 * op_size, vb.pp("Wqkv"))?;
-        let out_proj = linear(op_size, cfg.n_embd, vb.pp("out_proj"))?;
+        let wqkv = LoraLinear::new(
+            linear(cfg.n_embd, 3 * op_size, vb.pp("Wqkv"))?,
+            format!("{prefix}.Wqkv"),
+        );
+        let out_proj = LoraLinear::new(
+            linear(op_size, cfg.n_embd, vb.pp("out_proj"))?,
+            format!("{prefix}.out_proj"),
+        );
         let rotary_emb = RotaryEmbedding::new(cfg.rotary_dim, MAX_SEQ_LEN, vb.device())?;
         let softmax_scale = 1f64 / (head_dim as f64).sqrt();
         Ok(Self {
@@ -349,10 +364,11 @@ struct ParallelBlock {
 }
 
 impl ParallelBlock {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    /// Create a block with the prefix adapters use to refer to the layers in the block.
+    fn new(cfg: &Config, vb: VarBuilder, prefix: &str) -> Result<Self> {
         let ln = layer_norm(cfg.n_embd, cfg.layer_norm_epsilon, vb.pp("ln"))?;
-        let mixer = MHA::new(cfg, vb.pp("mixer"))?;
-        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
+        let mixer = MHA::new(cfg, vb.pp("mixer"), &format!("{prefix}.mixer"))?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"), &format!("{prefix}.mlp"))?;
         Ok(Self {
             ln,
             mixer,
@@ -390,7 +406,7 @@ impl MixFormerSequentialForCausalLM {
         let embedding = Embedding::new(cfg, vb.pp(0))?;
         let mut blocks = Vec::with_capacity(cfg.n_layer);
         for i in 0..cfg.n_layer {
-            let block = ParallelBlock::new(cfg, vb.pp(i + 1))?;
+            let block = ParallelBlock::new(cfg, vb.pp(i + 1), &format!("layers.{}", i + 1))?;
             blocks.push(block)
         }
         let head = CausalLMHead::new(cfg, vb.pp(cfg.n_layer + 1))?;
@@ -408,7 +424,7 @@ impl MixFormerSequentialForCausalLM {
         let embedding = Embedding::new(cfg, vb.pp("embd"))?;
         let mut blocks = Vec::new();
         for i in 0..cfg.n_layer {
-            let block = ParallelBlock::new(cfg, vb.pp("h").pp(i))?;
+            let block = ParallelBlock::new(cfg, vb.pp("h").pp(i), &format!("transformer.h.{i}"))?;
             blocks.push(block)
         }
         let head = CausalLMHead::new(cfg, vb_head)?;
@@ -420,20 +436,79 @@ impl MixFormerSequentialForCausalLM {
         })
     }
 
+    /// Replace the LoRA adapters applied to the model with the given adapters and the scale of each adapter.
+    ///
+    /// Adapters can use the names of the layers in this model (`transformer.h.0.mixer.Wqkv`), the names of the transformers Phi model (`model.layers.0.self_attn.q_proj`) or the GGUF names (`blk.0.attn_qkv`). Separate query, key and value adapters are combined into one update of the fused query, key and value projection. Every adapter must adapt at least one layer of the model.
+    pub fn set_lora_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) -> Result<()> {
+        let mut matched = vec![false; adapters.len()];
+        let mut updates = Vec::new();
+        for (layer_idx, block) in self.blocks.iter_mut().enumerate() {
+            let hf_name = |name: &str| format!("model.layers.{layer_idx}.{name}");
+            let gguf_name = |name: &str| format!("blk.{layer_idx}.{name}");
+            let projection_size = block.mixer.head_dim * block.mixer.n_head;
+
+            let mut weights = Vec::new();
+            for ((adapter, scale), matched) in adapters.iter().zip(&mut matched) {
+                let weight = match adapter
+                    .weight(block.mixer.wqkv.name())
+                    .or_else(|| adapter.weight(&gguf_name("attn_qkv")))
+                {
+                    Some(weight) => Some(weight.clone()),
+                    None => fused_qkv_weight(
+                        ["q_proj", "k_proj", "v_proj"]
+                            .map(|name| adapter.weight(&hf_name(&format!("self_attn.{name}")))),
+                        projection_size,
+                    )?,
+                };
+                if let Some(weight) = weight {
+                    weights.push(weight.scaled(*scale));
+                    *matched = true;
+                }
+            }
+            updates.push((&mut block.mixer.wqkv, weights));
+
+            for (layer, hf, gguf) in [
+                (&mut block.mixer.out_proj, "self_attn.dense", "attn_output"),
+                (&mut block.mlp.fc1, "mlp.fc1", "ffn_up"),
+                (&mut block.mlp.fc2, "mlp.fc2", "ffn_down"),
+            ] {
+                let mut weights = Vec::new();
+                for ((adapter, scale), matched) in adapters.iter().zip(&mut matched) {
+                    if let Some(weight) = adapter
+                        .weight(layer.name())
+                        .or_else(|| adapter.weight(&hf_name(hf)))
+                        .or_else(|| adapter.weight(&gguf_name(gguf)))
+                    {
+                        weights.push(weight.scaled(*scale));
+                        *matched = true;
+                    }
+                }
+                updates.push((layer, weights));
+            }
+        }
+
+        if let Some(unmatched) = matched.iter().position(|matched| !matched) {
+            candle_core::bail!("LoRA adapter {unmatched} does not adapt any layer of the model");
+        }
+        for (layer, weights) in updates {
+            layer.set_adapter_weights(weights);
+        }
+        Ok(())
+    }
+
     pub fn forward(&self, xs: &Tensor, mut cache: Option<&mut PhiCache>) -> Result<Tensor> {
         let _enter = self.span.enter();
         let (_b_size, seq_len) = xs.dims2()?;
         let mut xs = xs.apply(&self.embedding)?;
-        let mask = match &mut cache {
-            Some(cache) => {
-                if cache.first_token {
-                    cache.first_token = false;
-                    Some(get_mask(seq_len, xs.device())?)
-                } else {
-                    None
-                }
-            }
-            _ => Some(get_mask(seq_len, xs.device())?),
+        // A single new token can attend to every cached token, so it doesn't need a mask
+        let mask = if seq_len > 1 {
+            let offset = match &cache {
+                Some(cache) => cache.len()?,
+                None => 0,
+            };
+            Some(get_mask(seq_len, offset, xs.device())?)
+        } else {
+            None
         };
         for (i, block) in self.blocks.iter().enumerate() {
             xs = block.forward(&xs, mask.as_ref(), cache.as_mut().map(|c| &mut c.blocks[i]))?;
@@ -442,10 +517,46 @@ impl MixFormerSequentialForCausalLM {
     }
 }
 
+/// Combine separate query, key and value adapters into one adapter for the fused query, key and value projection. Returns `None` if none of the projections have an adapter.
+///
+/// The A matrices are stacked so each projection gets its own columns of the combined rank, and the rows of each projection in the combined B matrix only use those columns. Projections without an adapter get a zero update.
+fn fused_qkv_weight(
+    weights: [Option<&LoraWeight>; 3],
+    projection_size: usize,
+) -> Result<Option<LoraWeight>> {
+    let present: Vec<&LoraWeight> = weights.iter().flatten().copied().collect();
+    let Some(first) = present.first() else {
+        return Ok(None);
+    };
+    let rank: usize = present.iter().map(|weight| weight.rank()).sum();
+    let a = Tensor::cat(
+        &present.iter().map(|weight| &weight.a).collect::<Vec<_>>(),
+        0,
+    )?;
+    let mut offset = 0;
+    let mut rows = Vec::with_capacity(weights.len());
+    for weight in weights {
+        rows.push(match weight {
+            Some(weight) => {
+                // The scale of each projection is folded into its rows since the combined weight has one scale
+                let b = (&weight.b * weight.scale as f64)?;
+                let b = b.pad_with_zeros(1, offset, rank - offset - weight.rank())?;
+                offset += weight.rank();
+                b
+            }
+            None => Tensor::zeros((projection_size, rank), first.b.dtype(), first.b.device())?,
+        });
+    }
+    Ok(Some(LoraWeight {
+        a,
+        b: Tensor::cat(&rows, 0)?,
+        scale: 1.,
+    }))
+}
+
 /// A cache for phi inference. This cache will speed up generation of sequential text significantly.
 #[derive(Debug, Clone)]
 pub struct PhiCache {
-    first_token: bool,
     pub(crate) blocks: Vec<ParallelBlockCache>,
 }
 
@@ -456,9 +567,15 @@ impl PhiCache {
         for _ in 0..config.n_layer {
             blocks.push(ParallelBlockCache(None))
         }
-        Self {
-            blocks,
-            first_token: true,
+        Self { blocks }
+    }
+
+    /// Get the number of tokens in the cache.
+    fn len(&self) -> Result<usize> {
+        match self.blocks.first() {
+            // The key and value are stored as (batch, seq_len, heads, head_dim)
+            Some(ParallelBlockCache(Some(ParallelBlockCacheValue { key, .. }))) => key.dim(1),
+            _ => Ok(0),
         }
     }
 
@@ -526,10 +643,31 @@ impl PhiCache {
                 }
             }
         }
-        Self {
-            blocks,
-            first_token: true,
+        Self { blocks }
+    }
+}
+
+impl PrefixCacheEntry for PhiCache {
+    fn truncate(&mut self, len: usize) -> anyhow::Result<()> {
+        for block in &mut self.blocks {
+            if let ParallelBlockCache(Some(ParallelBlockCacheValue { key, value })) = block {
+                let len = len.min(key.dim(1)?);
+                *key = key.narrow(1, 0, len)?;
+                *value = value.narrow(1, 0, len)?;
+            }
         }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter_map(|block| block.0.as_ref())
+            .map(|ParallelBlockCacheValue { key, value }| {
+                key.elem_count() * key.dtype().size_in_bytes()
+                    + value.elem_count() * value.dtype().size_in_bytes()
+            })
+            .sum()
     }
 }
 
@@ -541,3 +679,167 @@ pub(crate) struct ParallelBlockCacheValue {
     pub(crate) key: Tensor,
     value: Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+    use std::io::Cursor;
+
+    const VOCAB: usize = 8;
+    const HIDDEN: usize = 8;
+    const INNER: usize = 16;
+
+    fn config() -> Config {
+        Config {
+            vocab_size: VOCAB,
+            n_positions: 64,
+            n_embd: HIDDEN,
+            n_layer: 1,
+            n_inner: Some(INNER),
+            n_head: 2,
+            rotary_dim: 2,
+            activation_function: Activation::Gelu,
+            layer_norm_epsilon: 1e-5,
+            tie_word_embeddings: false,
+            pad_vocab_size_multiple: 1,
+        }
+    }
+
+    fn random(shape: &[usize]) -> Tensor {
+        Tensor::randn(0f32, 0.5, shape, &Device::Cpu).unwrap()
+    }
+
+    /// The tensors of a single layer phi-2 model.
+    fn tensors() -> Vec<(String, Tensor)> {
+        let mut tensors = vec![(
+            "transformer.embd.wte.weight".to_string(),
+            random(&[VOCAB, HIDDEN]),
+        )];
+        for (name, out, input) in [
+            ("transformer.h.0.ln", HIDDEN, None),
+            ("transformer.h.0.mixer.Wqkv", 3 * HIDDEN, Some(HIDDEN)),
+            ("transformer.h.0.mixer.out_proj", HIDDEN, Some(HIDDEN)),
+            ("transformer.h.0.mlp.fc1", INNER, Some(HIDDEN)),
+            ("transformer.h.0.mlp.fc2", HIDDEN, Some(INNER)),
+            ("lm_head.ln", HIDDEN, None),
+            ("lm_head.linear", VOCAB, Some(HIDDEN)),
+        ] {
+            let weight = match input {
+                Some(input) => random(&[out, input]),
+                None => random(&[out]),
+            };
+            tensors.push((format!("{name}.weight"), weight));
+            tensors.push((format!("{name}.bias"), random(&[out])));
+        }
+        tensors
+    }
+
+    fn load(tensors: &[(String, Tensor)]) -> MixFormerSequentialForCausalLM {
+        let tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, tensor)| (name, QTensor::quantize(tensor, GgmlDType::F32).unwrap()))
+            .collect();
+        let tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, tensor)| (name.as_str(), tensor))
+            .collect();
+        let mut file = Cursor::new(Vec::new());
+        let metadata: &[(&str, &gguf_file::Value)] = &[];
+        gguf_file::write(&mut file, metadata, &tensors).unwrap();
+        let vb = VarBuilder::from_gguf_buffer(file.get_ref(), &Device::Cpu).unwrap();
+        MixFormerSequentialForCausalLM::new_v2(&config(), vb).unwrap()
+    }
+
+    fn max_error(first: &Tensor, second: &Tensor) -> f32 {
+        (first - second)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap()
+    }
+
+    #[test]
+    fn lora_adapters_match_merged_weights() {
+        let tensors = tensors();
+        let mut model = load(&tensors);
+        let tokens = Tensor::new(&[[1u32, 5, 2, 7]], &Device::Cpu).unwrap();
+        let base = model.forward(&tokens, None).unwrap();
+
+        let rank = 2;
+        let weight = |input: usize, output: usize| LoraWeight {
+            a: random(&[rank, input]),
+            b: random(&[output, rank]),
+            scale: 0.5,
+        };
+        // PEFT adapters for the transformers model usually only adapt the query and value projections
+        let adapted_layers = [
+            ("model.layers.0.self_attn.q_proj", weight(HIDDEN, HIDDEN)),
+            ("model.layers.0.self_attn.v_proj", weight(HIDDEN, HIDDEN)),
+            ("model.layers.0.self_attn.dense", weight(HIDDEN, HIDDEN)),
+            ("blk.0.ffn_down", weight(INNER, HIDDEN)),
+        ];
+        let adapter = Arc::new(LoraAdapter::new(
+            adapted_layers
+                .iter()
+                .map(|(name, weight)| (name.to_string(), weight.clone()))
+                .collect(),
+        ));
+        model.set_lora_adapters(&[(adapter.clone(), 2.)]).unwrap();
+        let adapted = model.forward(&tokens, None).unwrap();
+
+        // Merge the adapter into the weights to get the expected output
+        let update = |index: usize| {
+            let weight = &adapted_layers[index].1;
+            weight.b.matmul(&weight.a).unwrap()
+        };
+        let qkv_update = Tensor::cat(
+            &[
+                update(0),
+                Tensor::zeros((HIDDEN, HIDDEN), DType::F32, &Device::Cpu).unwrap(),
+                update(1),
+            ],
+            0,
+        )
+        .unwrap();
+        let merged_tensors: Vec<_> = tensors
+            .into_iter()
+            .map(|(name, tensor)| {
+                let update = match name.as_str() {
+                    "transformer.h.0.mixer.Wqkv.weight" => Some(qkv_update.clone()),
+                    "transformer.h.0.mixer.out_proj.weight" => Some(update(2)),
+                    "transformer.h.0.mlp.fc2.weight" => Some(update(3)),
+                    _ => None,
+                };
+                let tensor = match update {
+                    Some(update) => (tensor + update).unwrap(),
+                    None => tensor,
+                };
+                (name, tensor)
+            })
+            .collect();
+        let expected = load(&merged_tensors).forward(&tokens, None).unwrap();
+        let error = max_error(&adapted, &expected);
+        assert!(error < 1e-4, "error {error}");
+
+        // Removing the adapter restores the base model
+        model.set_lora_adapters(&[]).unwrap();
+        let restored = model.forward(&tokens, None).unwrap();
+        assert_eq!(max_error(&restored, &base), 0.);
+
+        // Adapters that do not match any layer are rejected without changing the model
+        let unmatched = Arc::new(LoraAdapter::new(
+            [("blk.7.attn_qkv".to_string(), weight(HIDDEN, 3 * HIDDEN))].into(),
+        ));
+        assert!(model
+            .set_lora_adapters(&[(adapter, 1.), (unmatched, 1.)])
+            .is_err());
+        let unchanged = model.forward(&tokens, None).unwrap();
+        assert_eq!(max_error(&unchanged, &base), 0.);
+    }
+}